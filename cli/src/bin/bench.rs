@@ -0,0 +1,250 @@
+use anyhow::{bail, Context, Result};
+use lsm_db_core::Database;
+use std::process::ExitCode;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use std::{env, thread};
+
+const USAGE: &str = "\
+usage: bench <working-dir> [options]
+
+options:
+    --benchmarks <list>     comma separated workloads to run in order (default fillseq,readrandom)
+    --num <n>               operations per workload (default 100000)
+    --key-size <bytes>      size of keys (default 16)
+    --value-size <bytes>    size of values (default 100)
+    --threads <n>           threads sharing operations of each workload (default 1)
+
+workloads:
+    fillseq                 write keys in ascending order
+    fillrandom              write keys in random order
+    overwrite               same as fillrandom, meant to run over existing keys
+    readrandom              read keys in random order
+    readseq                 scan whole database, each thread reads every key";
+
+#[derive(Clone, Copy, PartialEq)]
+enum Workload {
+    FillSeq,
+    FillRandom,
+    Overwrite,
+    ReadRandom,
+    ReadSeq,
+}
+
+impl Workload {
+    fn parse(name: &str) -> Result<Self> {
+        Ok(match name {
+            "fillseq" => Self::FillSeq,
+            "fillrandom" => Self::FillRandom,
+            "overwrite" => Self::Overwrite,
+            "readrandom" => Self::ReadRandom,
+            "readseq" => Self::ReadSeq,
+            _ => bail!("unknown workload {name}"),
+        })
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            Self::FillSeq => "fillseq",
+            Self::FillRandom => "fillrandom",
+            Self::Overwrite => "overwrite",
+            Self::ReadRandom => "readrandom",
+            Self::ReadSeq => "readseq",
+        }
+    }
+}
+
+struct Config {
+    working_dir: String,
+    workloads: Vec<Workload>,
+    num: u64,
+    key_size: usize,
+    value_size: usize,
+    threads: u64,
+}
+
+impl Config {
+    fn parse(args: &[String]) -> Result<Self> {
+        let [working_dir, rest @ ..] = args else {
+            bail!("missing working dir");
+        };
+        let mut rest = rest;
+        let mut config = Self {
+            working_dir: working_dir.clone(),
+            workloads: vec![Workload::FillSeq, Workload::ReadRandom],
+            num: 100_000,
+            key_size: 16,
+            value_size: 100,
+            threads: 1,
+        };
+        while let [flag, value, tail @ ..] = rest {
+            let number = || {
+                value
+                    .parse::<u64>()
+                    .with_context(|| format!("invalid {flag} {value}"))
+            };
+            match flag.as_str() {
+                "--benchmarks" => {
+                    config.workloads = value
+                        .split(',')
+                        .map(Workload::parse)
+                        .collect::<Result<_>>()?
+                }
+                "--num" => config.num = number()?,
+                "--key-size" => config.key_size = number()? as usize,
+                "--value-size" => config.value_size = number()? as usize,
+                "--threads" => config.threads = number()?.max(1),
+                _ => bail!("unknown option {flag}"),
+            }
+            rest = tail;
+        }
+        if let [flag] = rest {
+            bail!("missing value of {flag}");
+        }
+        let digits = config.num.max(1).ilog10() as usize + 1;
+        if config.key_size < digits {
+            bail!(
+                "key size {} can't hold {} keys",
+                config.key_size,
+                config.num
+            );
+        }
+        Ok(config)
+    }
+
+    fn key(&self, index: u64) -> Vec<u8> {
+        format!("{index:0width$}", width = self.key_size).into_bytes()
+    }
+}
+
+/// Xorshift generator, seeded per thread so that runs are repeatable
+struct Random(u64);
+
+impl Random {
+    fn new(seed: u64) -> Self {
+        Self(seed.wrapping_mul(0x9e37_79b9_7f4a_7c15) | 1)
+    }
+
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0
+    }
+
+    fn value(&mut self, size: usize) -> Vec<u8> {
+        (0..size).map(|_| b'a' + (self.next() % 26) as u8).collect()
+    }
+}
+
+/// Operations done and bytes moved by a single thread
+#[derive(Default)]
+struct Progress {
+    ops: u64,
+    bytes: u64,
+    found: u64,
+}
+
+fn main() -> ExitCode {
+    let args: Vec<String> = env::args().skip(1).collect();
+    if args.is_empty() {
+        eprintln!("{USAGE}");
+        return ExitCode::FAILURE;
+    }
+    match run(&args) {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("error: {e:#}");
+            ExitCode::FAILURE
+        }
+    }
+}
+
+fn run(args: &[String]) -> Result<()> {
+    let config = Config::parse(args)?;
+    let db = Database::options()
+        .set_working_dir(&config.working_dir)
+        .init()
+        .with_context(|| format!("failed to open database in {}", config.working_dir))?;
+    let db = Mutex::new(db);
+    println!(
+        "keys: {} bytes, values: {} bytes, entries: {}, threads: {}",
+        config.key_size, config.value_size, config.num, config.threads
+    );
+    for &workload in &config.workloads {
+        let start = Instant::now();
+        let (config, db) = (&config, &db);
+        let progress = thread::scope(|scope| {
+            let handles: Vec<_> = (0..config.threads)
+                .map(|id| scope.spawn(move || run_thread(config, db, workload, id)))
+                .collect();
+            handles
+                .into_iter()
+                .map(|handle| handle.join().expect("bench thread panicked"))
+                .try_fold(Progress::default(), |mut total, progress| {
+                    let progress = progress?;
+                    total.ops += progress.ops;
+                    total.bytes += progress.bytes;
+                    total.found += progress.found;
+                    Ok::<_, anyhow::Error>(total)
+                })
+        })?;
+        report(workload, &progress, start.elapsed());
+    }
+    Ok(())
+}
+
+fn run_thread(
+    config: &Config,
+    db: &Mutex<Database>,
+    workload: Workload,
+    id: u64,
+) -> Result<Progress> {
+    let mut random = Random::new(id + 1);
+    let mut progress = Progress::default();
+    if workload == Workload::ReadSeq {
+        let db = db.lock().expect("database lock poisoned");
+        (progress.ops, progress.bytes) = db.fold(.., (0, 0), |(ops, bytes), key, value| {
+            (ops + 1, bytes + (key.len() + value.len()) as u64)
+        })?;
+        progress.found = progress.ops;
+        return Ok(progress);
+    }
+    // threads split operations of the workload evenly
+    for i in (id..config.num).step_by(config.threads as usize) {
+        let index = match workload {
+            Workload::FillSeq => i,
+            _ => random.next() % config.num,
+        };
+        let key = config.key(index);
+        progress.bytes += key.len() as u64;
+        if workload == Workload::ReadRandom {
+            let value = db.lock().expect("database lock poisoned").query(&key)?;
+            if let Some(value) = value {
+                progress.bytes += value.len() as u64;
+                progress.found += 1;
+            }
+        } else {
+            let value = random.value(config.value_size);
+            progress.bytes += value.len() as u64;
+            db.lock().expect("database lock poisoned").put(key, value)?;
+        }
+        progress.ops += 1;
+    }
+    Ok(progress)
+}
+
+fn report(workload: Workload, progress: &Progress, elapsed: Duration) {
+    let seconds = elapsed.as_secs_f64().max(f64::EPSILON);
+    let micros_per_op = elapsed.as_secs_f64() * 1e6 / progress.ops.max(1) as f64;
+    let mut line = format!(
+        "{:<12}: {micros_per_op:>10.3} micros/op {:>12.0} ops/sec {:>8.1} MB/s",
+        workload.name(),
+        progress.ops as f64 / seconds,
+        progress.bytes as f64 / seconds / 1_048_576.0
+    );
+    if matches!(workload, Workload::ReadRandom) {
+        line += &format!(" ({} of {} found)", progress.found, progress.ops);
+    }
+    println!("{line}");
+}
@@ -0,0 +1,379 @@
+use anyhow::{bail, Context, Result};
+use lsm_db_core::export::Format;
+use lsm_db_core::format;
+use lsm_db_core::sstable::{SstReader, SstWriter};
+use lsm_db_core::wal::WriteAheadLog;
+use lsm_db_core::Database;
+use lsm_db_core::{CommonBinaryFormat, Corruption};
+use std::iter::Peekable;
+use std::ops::Bound;
+use std::path::Path;
+use std::process::ExitCode;
+use std::{env, fs, io, mem};
+
+const USAGE: &str = "\
+usage: lsmdb-cli <working-dir> <command> [args]
+       lsmdb-cli sst-dump <file> [--entries | --verify]
+       lsmdb-cli wal-dump <file> [--truncate-corrupt | --verify]
+       lsmdb-cli sst-merge <output-dir> <file>... [--max-file-size <bytes>]
+       lsmdb-cli format [--check <descriptor>]
+       lsmdb-cli orphans <working-dir>
+       lsmdb-cli repair <working-dir>
+
+commands:
+    put <key> <value>       insert or overwrite key
+    get <key>               print value of key
+    delete <key>            remove key
+    scan [start] [end]      print key-value pairs in [start, end)
+    stats                   print memtable and level statistics
+    flush                   write memtable to a table on level 0
+    compact                 merge all tables into the last level
+    export <json|csv>       write all key-value pairs to stdout
+    import <json|csv>       put all key-value pairs read from stdin
+    rebuild-filters <bits>  rewrite bloom filters of existing tables with given bits per key
+
+tools:
+    sst-dump                print sst metadata, lookup table and optionally all entries
+    wal-dump                print wal records with offsets, optionally trim file at the first bad record
+    --verify                only check structure and checksums, exit with failure on the first corruption
+    sst-merge               merge tables into fewer files keeping the newest version of each key
+    format                  print on-disk format descriptor as json, or compare it with a stored one
+    orphans                 list files left by interrupted writes which opening the database deletes
+    repair                  rebuild closed database from intact records, damaged files are moved aside";
+
+fn main() -> ExitCode {
+    let args: Vec<String> = env::args().skip(1).collect();
+    match run(&args) {
+        Ok(code) => code,
+        Err(e) => {
+            eprintln!("error: {e:#}");
+            ExitCode::FAILURE
+        }
+    }
+}
+
+fn run(args: &[String]) -> Result<ExitCode> {
+    if let [tool, rest @ ..] = args {
+        match tool.as_str() {
+            "sst-dump" => return sst_dump(rest),
+            "wal-dump" => return wal_dump(rest),
+            "sst-merge" => return sst_merge(rest),
+            "format" => return format_descriptor(rest),
+            "orphans" => return orphans(rest),
+            "repair" => return repair(rest),
+            _ => {}
+        }
+    }
+    let [working_dir, command, rest @ ..] = args else {
+        eprintln!("{USAGE}");
+        return Ok(ExitCode::FAILURE);
+    };
+    let mut options = Database::options().set_working_dir(working_dir);
+    if let ("rebuild-filters", [bits_per_key]) = (command.as_str(), rest) {
+        let bits_per_key = bits_per_key
+            .parse()
+            .with_context(|| format!("invalid bits per key {bits_per_key}"))?;
+        options = options.set_bloom_bits_per_key(bits_per_key);
+    }
+    let mut db = options
+        .init()
+        .with_context(|| format!("failed to open database in {working_dir}"))?;
+
+    match (command.as_str(), rest) {
+        ("put", [key, value]) => db.put(key.as_bytes().to_vec(), value.as_bytes().to_vec())?,
+        ("get", [key]) => match db.query(key)? {
+            Some(value) => println!("{}", String::from_utf8_lossy(&value)),
+            None => {
+                eprintln!("key not found");
+                return Ok(ExitCode::FAILURE);
+            }
+        },
+        ("delete", [key]) => db.delete(key.as_bytes().to_vec())?,
+        ("scan", bounds) if bounds.len() <= 2 => {
+            let start = bounds.first().map_or(Bound::Unbounded, |key| {
+                Bound::Included(key.as_bytes().to_vec())
+            });
+            let end = bounds.get(1).map_or(Bound::Unbounded, |key| {
+                Bound::Excluded(key.as_bytes().to_vec())
+            });
+            for (key, value) in db.scan((start, end))? {
+                println!(
+                    "{}\t{}",
+                    String::from_utf8_lossy(&key),
+                    String::from_utf8_lossy(&value)
+                );
+            }
+        }
+        ("stats", []) => {
+            let stats = db.stats()?;
+            println!(
+                "rw memtable: {} entries, {} bytes",
+                stats.rw_memtable_entries, stats.rw_memtable_size
+            );
+            println!(
+                "ro memtables: {} entries, {} queued flushes",
+                stats.ro_memtable_entries, stats.queued_flushes
+            );
+            println!(
+                "wal: {} unsynced bytes, oldest unsynced write age {:?}",
+                stats.wal_unsynced_bytes, stats.wal_oldest_unsynced_write_age
+            );
+            for (level, level_stats) in stats.levels.iter().enumerate() {
+                println!(
+                    "level {level}: {} files, {} entries, {} tombstones, {} bytes ({} raw), {} filter bytes",
+                    level_stats.files,
+                    level_stats.entries,
+                    level_stats.tombstones,
+                    level_stats.size,
+                    level_stats.raw_size,
+                    level_stats.filter_size
+                );
+            }
+        }
+        ("flush", []) => db.flush()?,
+        ("compact", []) => db.compact()?,
+        ("export", [format]) => {
+            db.export(io::stdout().lock(), parse_format(format)?)?;
+        }
+        ("import", [format]) => {
+            let count = db.import(io::stdin().lock(), parse_format(format)?)?;
+            eprintln!("imported {count} records");
+        }
+        ("rebuild-filters", [_]) => {
+            let count = db.rebuild_filters()?;
+            eprintln!("rebuilt filters of {count} tables");
+        }
+        _ => bail!("unknown command or wrong arguments\n{USAGE}"),
+    }
+    Ok(ExitCode::SUCCESS)
+}
+
+fn parse_format(format: &str) -> Result<Format> {
+    match format {
+        "json" => Ok(Format::Json),
+        "csv" => Ok(Format::Csv),
+        _ => bail!("unknown format {format}, expected json or csv"),
+    }
+}
+
+fn sst_dump(args: &[String]) -> Result<ExitCode> {
+    let (path, with_entries) = match args {
+        [path] => (path, false),
+        [path, flag] if flag == "--entries" => (path, true),
+        [path, flag] if flag == "--verify" => {
+            let corruption = match SstReader::open(path) {
+                Ok(table) => table.verify()?,
+                Err(error) => Some(Corruption { offset: 0, error }),
+            };
+            return Ok(report_verification(corruption));
+        }
+        _ => bail!("wrong arguments\n{USAGE}"),
+    };
+    let table = SstReader::open(path).with_context(|| format!("failed to open sst {path}"))?;
+    table.dump(io::stdout().lock(), with_entries)?;
+    Ok(ExitCode::SUCCESS)
+}
+
+fn format_descriptor(args: &[String]) -> Result<ExitCode> {
+    match args {
+        [] => {
+            println!("{:#}", format::descriptor());
+            Ok(ExitCode::SUCCESS)
+        }
+        [flag, path] if flag == "--check" => {
+            let stored = fs::read(path).with_context(|| format!("failed to read {path}"))?;
+            let stored = serde_json::from_slice(&stored)
+                .with_context(|| format!("invalid descriptor {path}"))?;
+            let incompatibilities = format::incompatibilities(&stored);
+            if incompatibilities.is_empty() {
+                println!("compatible with format version {}", format::FORMAT_VERSION);
+                return Ok(ExitCode::SUCCESS);
+            }
+            for incompatibility in incompatibilities {
+                println!("{incompatibility}");
+            }
+            Ok(ExitCode::FAILURE)
+        }
+        _ => bail!("wrong arguments\n{USAGE}"),
+    }
+}
+
+fn orphans(args: &[String]) -> Result<ExitCode> {
+    let [working_dir] = args else {
+        bail!("wrong arguments\n{USAGE}");
+    };
+    let orphans = Database::options()
+        .set_working_dir(working_dir)
+        .find_orphan_files()?;
+    let kinds = [
+        ("temp", &orphans.temp_files),
+        ("partial-table", &orphans.partial_tables),
+        ("flushed-wal", &orphans.flushed_wals),
+    ];
+    for (kind, paths) in kinds {
+        for path in paths {
+            println!("{kind}\t{}", path.display());
+        }
+    }
+    Ok(ExitCode::SUCCESS)
+}
+
+fn repair(args: &[String]) -> Result<ExitCode> {
+    let [working_dir] = args else {
+        bail!("wrong arguments\n{USAGE}");
+    };
+    let report = Database::repair(working_dir)?;
+    println!("salvaged {} records", report.salvaged_records);
+    for path in &report.quarantined {
+        println!("quarantined\t{}", path.display());
+    }
+    Ok(ExitCode::SUCCESS)
+}
+
+fn wal_dump(args: &[String]) -> Result<ExitCode> {
+    let (path, truncate) = match args {
+        [path] => (path, false),
+        [path, flag] if flag == "--truncate-corrupt" => (path, true),
+        [path, flag] if flag == "--verify" => {
+            let corruption = WriteAheadLog::verify(path)
+                .with_context(|| format!("failed to read wal {path}"))?;
+            return Ok(report_verification(corruption));
+        }
+        _ => bail!("wrong arguments\n{USAGE}"),
+    };
+    let inspection =
+        WriteAheadLog::inspect(path).with_context(|| format!("failed to read wal {path}"))?;
+    for (offset, entry) in inspection.records.iter() {
+        match &entry.value {
+            Some(value) => println!(
+                "{offset}\t{}\tput\t{}\t{}",
+                entry.timestamp,
+                entry.key.escape_ascii(),
+                value.escape_ascii()
+            ),
+            None => println!(
+                "{offset}\t{}\tdelete\t{}",
+                entry.timestamp,
+                entry.key.escape_ascii()
+            ),
+        }
+    }
+    let Some(error) = &inspection.error else {
+        println!(
+            "{} records, {} bytes, intact",
+            inspection.records.len(),
+            inspection.file_len
+        );
+        return Ok(ExitCode::SUCCESS);
+    };
+    println!(
+        "{} records, bad record at offset {}: {error}",
+        inspection.records.len(),
+        inspection.valid_len
+    );
+    if !truncate {
+        return Ok(ExitCode::FAILURE);
+    }
+    let removed = WriteAheadLog::truncate_corrupt(path)?;
+    println!("truncated {removed} bytes");
+    Ok(ExitCode::SUCCESS)
+}
+
+fn report_verification(corruption: Option<Corruption>) -> ExitCode {
+    match corruption {
+        Some(corruption) => {
+            println!("{corruption}");
+            ExitCode::FAILURE
+        }
+        None => {
+            println!("ok");
+            ExitCode::SUCCESS
+        }
+    }
+}
+
+/// Merges tables outside of database, of several versions of a key the newest one is kept,
+/// tombstones are preserved since merged tables may be ingested over other data
+fn sst_merge(args: &[String]) -> Result<ExitCode> {
+    let mut max_file_size = usize::MAX;
+    let mut paths = Vec::new();
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        if arg == "--max-file-size" {
+            let size = args.next().context("missing --max-file-size value")?;
+            max_file_size = size
+                .parse()
+                .with_context(|| format!("invalid file size {size}"))?;
+        } else {
+            paths.push(arg);
+        }
+    }
+    let [output_dir, inputs @ ..] = paths.as_slice() else {
+        bail!("wrong arguments\n{USAGE}");
+    };
+    if inputs.is_empty() {
+        bail!("no input files\n{USAGE}");
+    }
+    let output_dir = Path::new(output_dir);
+    fs::create_dir_all(output_dir)?;
+
+    let mut sources: Vec<Peekable<_>> = Vec::with_capacity(inputs.len());
+    for path in inputs {
+        let table = SstReader::open(path).with_context(|| format!("failed to open sst {path}"))?;
+        sources.push(table.iter()?.peekable());
+    }
+    let mut outputs = Vec::new();
+    let mut writer = SstWriter::new(0);
+    while let Some(entry) = next_newest(&mut sources)? {
+        writer.push(entry.as_cbf_ref())?;
+        if writer.data_size() >= max_file_size {
+            let path = output_dir.join(format!("{}.sst", outputs.len()));
+            outputs.push(mem::replace(&mut writer, SstWriter::new(0)).finish(path)?);
+        }
+    }
+    if !writer.is_empty() {
+        let path = output_dir.join(format!("{}.sst", outputs.len()));
+        outputs.push(writer.finish(path)?);
+    }
+    for table in outputs.iter() {
+        println!("{}\t{} entries", table.path.display(), table.len());
+    }
+    Ok(ExitCode::SUCCESS)
+}
+
+/// Takes all entries with the smallest key across sources and returns the newest of them
+fn next_newest(
+    sources: &mut [Peekable<impl Iterator<Item = io::Result<CommonBinaryFormat>>>],
+) -> Result<Option<CommonBinaryFormat>> {
+    let mut smallest: Option<Vec<u8>> = None;
+    for source in sources.iter_mut() {
+        match source.peek() {
+            Some(Ok(entry)) if smallest.as_ref().is_none_or(|key| entry.key < *key) => {
+                smallest = Some(entry.key.clone());
+            }
+            Some(Err(_)) => {
+                source.next().expect("peeked")?;
+            }
+            _ => {}
+        }
+    }
+    let Some(key) = smallest else {
+        return Ok(None);
+    };
+    let mut newest: Option<CommonBinaryFormat> = None;
+    for source in sources.iter_mut() {
+        while source
+            .peek()
+            .is_some_and(|entry| matches!(entry, Ok(entry) if entry.key == key))
+        {
+            let entry = source.next().expect("peeked")?;
+            if newest
+                .as_ref()
+                .is_none_or(|newest| entry.timestamp > newest.timestamp)
+            {
+                newest = Some(entry);
+            }
+        }
+    }
+    Ok(newest)
+}
@@ -0,0 +1,30 @@
+use criterion::{black_box, criterion_group, criterion_main, Criterion};
+use lsm_db_core::{CommonBinaryFormat, CommonBinaryFormatRef};
+
+fn cbf(c: &mut Criterion) {
+    let key = b"0000000000001234";
+    let value = [b'v'; 100];
+    let record = CommonBinaryFormatRef::new(1, key, Some(&value));
+    let mut encoded = Vec::new();
+    record.write(&mut encoded).unwrap();
+
+    c.bench_function("cbf/write", |b| {
+        let mut buffer = Vec::with_capacity(encoded.len());
+        b.iter(|| {
+            buffer.clear();
+            CommonBinaryFormatRef::new(1, key, Some(&value))
+                .write(&mut buffer)
+                .unwrap();
+            black_box(&buffer);
+        })
+    });
+    c.bench_function("cbf/read", |b| {
+        b.iter(|| black_box(CommonBinaryFormat::read(&mut encoded.as_slice()).unwrap()))
+    });
+    c.bench_function("cbf/parse", |b| {
+        b.iter(|| black_box(CommonBinaryFormatRef::parse(&encoded).unwrap()))
+    });
+}
+
+criterion_group!(benches, cbf);
+criterion_main!(benches);
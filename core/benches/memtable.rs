@@ -0,0 +1,86 @@
+use criterion::{black_box, criterion_group, criterion_main, BatchSize, Criterion};
+use lsm_db_core::memtable::MemTable;
+use std::thread;
+
+const ENTRIES: u64 = 10_000;
+const VALUE: [u8; 100] = [b'v'; 100];
+const WRITERS: usize = 4;
+
+/// Keys in pseudo-random order, the same on every run
+fn shuffled_keys() -> Vec<Vec<u8>> {
+    let mut state = 0x9e37_79b9_7f4a_7c15u64;
+    (0..ENTRIES)
+        .map(|_| {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            format!("{:016}", state % ENTRIES).into_bytes()
+        })
+        .collect()
+}
+
+fn filled(keys: &[Vec<u8>]) -> MemTable {
+    let memtable = MemTable::new();
+    for (timestamp, key) in keys.iter().enumerate() {
+        memtable.put(timestamp as u128, key.clone(), VALUE.to_vec());
+    }
+    memtable
+}
+
+fn memtable(c: &mut Criterion) {
+    let sequential: Vec<_> = (0..ENTRIES)
+        .map(|i| format!("{i:016}").into_bytes())
+        .collect();
+    let random = shuffled_keys();
+
+    c.bench_function("memtable/put_sequential", |b| {
+        b.iter_batched(
+            || sequential.clone(),
+            |keys| filled(&keys),
+            BatchSize::LargeInput,
+        )
+    });
+    c.bench_function("memtable/put_random", |b| {
+        b.iter_batched(
+            || random.clone(),
+            |keys| filled(&keys),
+            BatchSize::LargeInput,
+        )
+    });
+    c.bench_function("memtable/put_random_concurrent", |b| {
+        b.iter_batched(
+            || random.clone(),
+            |keys| {
+                let memtable = MemTable::new();
+                thread::scope(|scope| {
+                    for (writer, keys) in keys.chunks(keys.len() / WRITERS).enumerate() {
+                        let memtable = &memtable;
+                        scope.spawn(move || {
+                            for (i, key) in keys.iter().enumerate() {
+                                let timestamp = (writer * keys.len() + i) as u128;
+                                memtable.put(timestamp, key.clone(), VALUE.to_vec());
+                            }
+                        });
+                    }
+                });
+                memtable
+            },
+            BatchSize::LargeInput,
+        )
+    });
+
+    let memtable = filled(&sequential);
+    c.bench_function("memtable/get_random", |b| {
+        b.iter(|| {
+            for key in &random {
+                black_box(memtable.get(key));
+            }
+        })
+    });
+    c.bench_function("memtable/iter", |b| {
+        b.iter(|| black_box(memtable.iter().count()))
+    });
+}
+
+criterion_group!(benches, memtable);
+criterion_main!(benches);
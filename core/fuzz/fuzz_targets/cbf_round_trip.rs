@@ -0,0 +1,19 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use lsm_db_core::{CommonBinaryFormat, CommonBinaryFormatRef};
+
+// arbitrary bytes never panic the decoders, and whatever decodes encodes back to the same bytes
+fuzz_target!(|data: &[u8]| {
+    let read = CommonBinaryFormat::read(&mut &data[..]);
+    let parsed = CommonBinaryFormatRef::parse(data);
+    assert_eq!(read.is_ok(), parsed.is_ok());
+    if let Ok((record, size)) = parsed {
+        let mut encoded = Vec::new();
+        CommonBinaryFormatRef::new(record.timestamp, record.key, record.value)
+            .with_meta(record.meta)
+            .write(&mut encoded)
+            .unwrap();
+        assert_eq!(encoded, data[..size]);
+    }
+});
@@ -0,0 +1,57 @@
+#![no_main]
+
+use arbitrary::Arbitrary;
+use libfuzzer_sys::fuzz_target;
+use lsm_db_core::{Database, INTERNAL_KEY_PREFIX};
+use std::collections::BTreeMap;
+use std::{fs, mem};
+
+#[derive(Debug, Arbitrary)]
+enum Op {
+    Put(Vec<u8>, Vec<u8>),
+    Delete(Vec<u8>),
+    Flush,
+    Compact,
+    Reopen,
+}
+
+// database matches a map model across flushes, compactions and crashes
+fuzz_target!(|ops: Vec<Op>| {
+    let dir = std::env::temp_dir().join(format!("lsmdb-fuzz-db-{}", std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    let options = Database::options()
+        .set_working_dir(&dir)
+        .set_memtable_threshold(4096)
+        .set_level_zero_memtables_limit(2)
+        .set_level_num(3);
+    let mut db = options.clone().init().unwrap();
+    let mut model = BTreeMap::new();
+    for op in ops {
+        // internal keyspace is rejected for user writes
+        if let Op::Put(key, _) | Op::Delete(key) = &op {
+            if key.starts_with(INTERNAL_KEY_PREFIX) {
+                continue;
+            }
+        }
+        match op {
+            Op::Put(key, value) => {
+                db.put(key.clone(), value.clone()).unwrap();
+                model.insert(key, value);
+            }
+            Op::Delete(key) => {
+                db.delete(key.clone()).unwrap();
+                model.remove(&key);
+            }
+            Op::Flush => db.flush().unwrap(),
+            Op::Compact => db.compact().unwrap(),
+            Op::Reopen => {
+                db.sync_wal().unwrap();
+                // no destructors run on crash
+                mem::forget(db);
+                db = options.clone().init().unwrap();
+            }
+        }
+    }
+    let scanned: BTreeMap<_, _> = db.scan(..).unwrap().into_iter().collect();
+    assert_eq!(scanned, model);
+});
@@ -0,0 +1,23 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use lsm_db_core::sstable::SstReader;
+use std::fs;
+
+// damaged table fails to open or verify, reads of whatever opens don't panic
+fuzz_target!(|data: &[u8]| {
+    let dir = std::env::temp_dir().join(format!("lsmdb-fuzz-sst-{}", std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    let path = dir.join("1.sst");
+    fs::write(&path, data).unwrap();
+    let Ok(table) = SstReader::open(&path) else {
+        return;
+    };
+    let _ = table.check_key_range(true);
+    let _ = table.verify();
+    if let Ok(entries) = table.iter() {
+        entries.take(1024).for_each(drop);
+    }
+    let _ = table.get(&table.metadata.low_key);
+});
@@ -0,0 +1,21 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use lsm_db_core::wal::WriteAheadLog;
+use std::fs;
+
+// damaged wal is reported as corruption or error, never as a panic
+fuzz_target!(|data: &[u8]| {
+    let dir = std::env::temp_dir().join(format!("lsmdb-fuzz-wal-{}", std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    let path = dir.join("1.wal");
+    fs::write(&path, data).unwrap();
+    let _ = WriteAheadLog::verify(&path);
+    if let Ok(wal) = WriteAheadLog::load(&path) {
+        if let Ok(entries) = wal.into_iter() {
+            entries.for_each(drop);
+        }
+    }
+    let _ = WriteAheadLog::load_dir(&dir);
+});
@@ -0,0 +1,137 @@
+use crate::keyspace;
+use crate::utils::{CommonBinaryFormat, CommonBinaryFormatRef};
+use std::{io, mem};
+
+/// Group of writes applied to database at once under a single timestamp
+///
+/// Serialized layout:
+/// > ops count (8 bytes) | ops in common binary format with zero timestamp
+///
+/// Serialized batches are trusted, internal keys in them are applied as is.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct WriteBatch {
+    /// key -> new value, None if corresponds to delete
+    ops: Vec<(Vec<u8>, Option<Vec<u8>>)>,
+    /// first key in reserved keyspace passed through user api, batch with it is rejected on write
+    reserved_key: Option<Vec<u8>>,
+}
+
+impl WriteBatch {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn put(&mut self, key: Vec<u8>, value: Vec<u8>) -> &mut Self {
+        self.check_reserved(&key);
+        self.ops.push((key, Some(value)));
+        self
+    }
+
+    pub fn delete(&mut self, key: Vec<u8>) -> &mut Self {
+        self.check_reserved(&key);
+        self.ops.push((key, None));
+        self
+    }
+
+    /// Puts subsystem metadata into internal keyspace, committed together with the rest of batch
+    pub fn put_internal(&mut self, key: &[u8], value: Vec<u8>) -> &mut Self {
+        self.ops.push((keyspace::internal_key(key), Some(value)));
+        self
+    }
+
+    pub fn delete_internal(&mut self, key: &[u8]) -> &mut Self {
+        self.ops.push((keyspace::internal_key(key), None));
+        self
+    }
+
+    fn check_reserved(&mut self, key: &[u8]) {
+        if self.reserved_key.is_none() && keyspace::is_internal_key(key) {
+            self.reserved_key = Some(key.to_vec());
+        }
+    }
+
+    pub(crate) fn reserved_key(&self) -> Option<&[u8]> {
+        self.reserved_key.as_deref()
+    }
+
+    pub fn len(&self) -> usize {
+        self.ops.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ops.is_empty()
+    }
+
+    /// Ops in insertion order, later ops on the same key win
+    pub fn iter(&self) -> impl Iterator<Item = (&[u8], Option<&[u8]>)> {
+        self.ops
+            .iter()
+            .map(|(key, value)| (key.as_slice(), value.as_deref()))
+    }
+
+    pub fn into_ops(self) -> Vec<(Vec<u8>, Option<Vec<u8>>)> {
+        self.ops
+    }
+
+    /// Rebuilds batch from ops of an already validated one
+    pub(crate) fn from_ops(ops: Vec<(Vec<u8>, Option<Vec<u8>>)>) -> Self {
+        Self {
+            ops,
+            reserved_key: None,
+        }
+    }
+
+    pub fn serialize(&self) -> Vec<u8> {
+        let mut out = Vec::new();
+        out.extend_from_slice(&self.ops.len().to_le_bytes());
+        for (key, value) in self.iter() {
+            CommonBinaryFormatRef::new(0, key, value)
+                .write(&mut out)
+                .expect("writing to vec never fails");
+        }
+        out
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> io::Result<Self> {
+        let mut reader = bytes;
+        let mut count_buf = [0; mem::size_of::<usize>()];
+        io::Read::read_exact(&mut reader, &mut count_buf)?;
+        let count = usize::from_le_bytes(count_buf);
+        let mut ops = Vec::new();
+        for _ in 0..count {
+            let cbf = CommonBinaryFormat::read(&mut reader)?;
+            ops.push((cbf.key, cbf.value));
+        }
+        if !reader.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "trailing bytes after write batch",
+            ));
+        }
+        Ok(Self {
+            ops,
+            reserved_key: None,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn serialize_round_trip() {
+        let mut batch = WriteBatch::new();
+        batch
+            .put(vec![1, 2], vec![3])
+            .delete(vec![4])
+            .put(vec![], vec![]);
+        let bytes = batch.serialize();
+        assert_eq!(WriteBatch::from_bytes(&bytes).unwrap(), batch);
+        assert!(WriteBatch::from_bytes(&bytes[..bytes.len() - 1]).is_err());
+
+        let mut extended = bytes.clone();
+        extended.push(0);
+        assert!(WriteBatch::from_bytes(&extended).is_err());
+    }
+}
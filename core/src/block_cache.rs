@@ -0,0 +1,165 @@
+use crate::sstable::IndexPartition;
+use std::collections::{BTreeMap, HashMap};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::{fmt, io};
+
+type Key = (PathBuf, usize);
+
+struct Cached {
+    partition: Arc<IndexPartition>,
+    size: usize,
+    last_use: u64,
+}
+
+#[derive(Default)]
+struct Lru {
+    entries: HashMap<Key, Cached>,
+    /// last use -> key, the first one is evicted first
+    order: BTreeMap<u64, Key>,
+    usage: usize,
+    tick: u64,
+}
+
+/// Least recently used cache of index and filter partitions of partitioned tables,
+/// one cache can be shared by several databases to bound their total memory
+pub struct BlockCache {
+    /// size limit in bytes
+    capacity: usize,
+    lru: Mutex<Lru>,
+}
+
+impl BlockCache {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            lru: Mutex::new(Lru::default()),
+        }
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    /// Bytes taken by cached partitions
+    pub fn usage(&self) -> usize {
+        self.lock().usage
+    }
+
+    /// Cached partition of table or the one returned by `load` along with its size,
+    /// partitions larger than the whole cache are returned without caching
+    pub(crate) fn get_or_load(
+        &self,
+        path: &Path,
+        partition: usize,
+        load: impl FnOnce() -> io::Result<(IndexPartition, usize)>,
+    ) -> io::Result<Arc<IndexPartition>> {
+        let key = (path.to_path_buf(), partition);
+        {
+            let mut lru = self.lock();
+            lru.tick += 1;
+            let tick = lru.tick;
+            if let Some(cached) = lru.entries.get_mut(&key) {
+                let previous = std::mem::replace(&mut cached.last_use, tick);
+                let partition = cached.partition.clone();
+                lru.order.remove(&previous);
+                lru.order.insert(tick, key);
+                return Ok(partition);
+            }
+        }
+        // loading happens without the lock, concurrent loads of one partition are harmless
+        let (partition, size) = load()?;
+        let partition = Arc::new(partition);
+        if size > self.capacity {
+            return Ok(partition);
+        }
+        let mut lru = self.lock();
+        while lru.usage + size > self.capacity {
+            let Some((_, evicted)) = lru.order.pop_first() else {
+                break;
+            };
+            if let Some(cached) = lru.entries.remove(&evicted) {
+                lru.usage -= cached.size;
+            }
+        }
+        lru.tick += 1;
+        let tick = lru.tick;
+        let cached = Cached {
+            partition: partition.clone(),
+            size,
+            last_use: tick,
+        };
+        if let Some(replaced) = lru.entries.insert(key.clone(), cached) {
+            lru.order.remove(&replaced.last_use);
+            lru.usage -= replaced.size;
+        }
+        lru.order.insert(tick, key);
+        lru.usage += size;
+        Ok(partition)
+    }
+
+    pub(crate) fn contains(&self, path: &Path, partition: usize) -> bool {
+        let key = (path.to_path_buf(), partition);
+        self.lock().entries.contains_key(&key)
+    }
+
+    /// Caches partition loaded by `load` without evicting others, returns false if it doesn't
+    /// fit into free space
+    pub(crate) fn preload(
+        &self,
+        path: &Path,
+        partition: usize,
+        load: impl FnOnce() -> io::Result<(IndexPartition, usize)>,
+    ) -> io::Result<bool> {
+        let key = (path.to_path_buf(), partition);
+        let (partition, size) = load()?;
+        let mut lru = self.lock();
+        if lru.usage + size > self.capacity {
+            return Ok(false);
+        }
+        lru.tick += 1;
+        let tick = lru.tick;
+        let cached = Cached {
+            partition: Arc::new(partition),
+            size,
+            last_use: tick,
+        };
+        if let Some(replaced) = lru.entries.insert(key.clone(), cached) {
+            lru.order.remove(&replaced.last_use);
+            lru.usage -= replaced.size;
+        }
+        lru.order.insert(tick, key);
+        lru.usage += size;
+        Ok(true)
+    }
+
+    /// Drops cached partitions of table whose file was rewritten
+    pub(crate) fn evict_table(&self, path: &Path) {
+        let mut lru = self.lock();
+        let evicted: Vec<_> = lru
+            .entries
+            .iter()
+            .filter(|((table, _), _)| table == path)
+            .map(|(key, cached)| (key.clone(), cached.last_use))
+            .collect();
+        for (key, last_use) in evicted {
+            if let Some(cached) = lru.entries.remove(&key) {
+                lru.usage -= cached.size;
+            }
+            lru.order.remove(&last_use);
+        }
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, Lru> {
+        self.lru.lock().expect("block cache mutex poisoned")
+    }
+}
+
+impl fmt::Debug for BlockCache {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("BlockCache")
+            .field("capacity", &self.capacity)
+            .field("usage", &self.usage())
+            .finish()
+    }
+}
@@ -0,0 +1,175 @@
+use std::io::Read;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::{io, mem};
+
+/// Bloom filter over keys of a single table, lets point lookups skip tables without the key
+///
+/// Layout on disk:
+/// > bits per key (8 bytes) | hash count (4 bytes) | bits size (8 bytes) | bits
+///
+/// Filter with zero bits per key is disabled and matches every key.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct BloomFilter {
+    /// parameter filter was built with
+    pub bits_per_key: usize,
+    hash_count: u32,
+    bits: Vec<u8>,
+}
+
+impl BloomFilter {
+    pub fn build<'a>(keys: impl ExactSizeIterator<Item = &'a [u8]>, bits_per_key: usize) -> Self {
+        if bits_per_key == 0 {
+            return Self::default();
+        }
+        // ln(2) * bits per key minimizes false positive rate
+        let hash_count = ((bits_per_key as f64 * 0.69) as u32).clamp(1, 30);
+        let bits_count = (keys.len() * bits_per_key).max(64);
+        let mut filter = Self {
+            bits_per_key,
+            hash_count,
+            bits: vec![0; bits_count.div_ceil(8)],
+        };
+        for key in keys {
+            for bit in filter.bit_positions(key) {
+                filter.bits[bit / 8] |= 1 << (bit % 8);
+            }
+        }
+        filter
+    }
+
+    /// False means key is definitely absent
+    pub fn may_contain(&self, key: &[u8]) -> bool {
+        if self.bits.is_empty() {
+            return true;
+        }
+        self.bit_positions(key)
+            .all(|bit| self.bits[bit / 8] & (1 << (bit % 8)) != 0)
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        !self.bits.is_empty()
+    }
+
+    /// Size of filter bits in bytes, filters of open tables are kept in memory
+    pub fn size(&self) -> usize {
+        self.bits.len()
+    }
+
+    /// size in bytes of serialized filter
+    pub fn encoded_size(&self) -> usize {
+        2 * mem::size_of::<usize>() + mem::size_of::<u32>() + self.bits.len()
+    }
+
+    /// Double hashing over crc32 of key as in leveldb
+    fn bit_positions(&self, key: &[u8]) -> impl Iterator<Item = usize> {
+        let bits_count = self.bits.len() * 8;
+        let mut hash = crc32fast::hash(key);
+        let delta = hash.rotate_right(17);
+        (0..self.hash_count).map(move |_| {
+            let bit = hash as usize % bits_count;
+            hash = hash.wrapping_add(delta);
+            bit
+        })
+    }
+
+    pub fn write(&self, mut writer: impl io::Write) -> io::Result<()> {
+        writer.write_all(&self.bits_per_key.to_le_bytes())?;
+        writer.write_all(&self.hash_count.to_le_bytes())?;
+        writer.write_all(&self.bits.len().to_le_bytes())?;
+        writer.write_all(&self.bits)
+    }
+
+    pub fn read(mut reader: impl io::Read) -> io::Result<Self> {
+        let mut usize_buf = [0; mem::size_of::<usize>()];
+        reader.read_exact(&mut usize_buf)?;
+        let bits_per_key = usize::from_le_bytes(usize_buf);
+        let mut u32_buf = [0; mem::size_of::<u32>()];
+        reader.read_exact(&mut u32_buf)?;
+        let hash_count = u32::from_le_bytes(u32_buf);
+        reader.read_exact(&mut usize_buf)?;
+        let mut bits = Vec::new();
+        let size = usize::from_le_bytes(usize_buf);
+        reader.take(size as u64).read_to_end(&mut bits)?;
+        if bits.len() != size {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        Ok(Self {
+            bits_per_key,
+            hash_count,
+            bits,
+        })
+    }
+}
+
+/// Outcomes of enabled filters consulted by point lookups within table key ranges
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct FilterStats {
+    pub checks: u64,
+    /// filter excluded table without reading it
+    pub negatives: u64,
+    /// filter matched but table didn't have the key
+    pub false_positives: u64,
+}
+
+impl FilterStats {
+    /// Measured share of absent keys matched by filters
+    pub fn false_positive_rate(&self) -> f64 {
+        let absent = self.negatives + self.false_positives;
+        if absent == 0 {
+            return 0.0;
+        }
+        self.false_positives as f64 / absent as f64
+    }
+}
+
+#[derive(Debug, Default)]
+pub(crate) struct FilterCounters {
+    checks: AtomicU64,
+    negatives: AtomicU64,
+    false_positives: AtomicU64,
+}
+
+impl FilterCounters {
+    pub fn record_negative(&self) {
+        self.checks.fetch_add(1, Ordering::Relaxed);
+        self.negatives.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Filter matched, `found` tells whether table had the key
+    pub fn record_match(&self, found: bool) {
+        self.checks.fetch_add(1, Ordering::Relaxed);
+        if !found {
+            self.false_positives.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    pub fn stats(&self) -> FilterStats {
+        FilterStats {
+            checks: self.checks.load(Ordering::Relaxed),
+            negatives: self.negatives.load(Ordering::Relaxed),
+            false_positives: self.false_positives.load(Ordering::Relaxed),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn filters_absent_keys() {
+        let keys: Vec<Vec<u8>> = (0..1000u32).map(|i| i.to_be_bytes().to_vec()).collect();
+        let filter = BloomFilter::build(keys.iter().map(|key| key.as_slice()), 10);
+        assert!(keys.iter().all(|key| filter.may_contain(key)));
+        let false_positives = (1000..11000u32)
+            .filter(|i| filter.may_contain(&i.to_be_bytes()))
+            .count();
+        assert!(false_positives < 300, "{false_positives} false positives");
+
+        let mut encoded = Vec::new();
+        filter.write(&mut encoded).unwrap();
+        assert_eq!(BloomFilter::read(encoded.as_slice()).unwrap(), filter);
+        let disabled = BloomFilter::build(keys.iter().map(|key| key.as_slice()), 0);
+        assert!(disabled.may_contain(b"anything"));
+    }
+}
@@ -0,0 +1,161 @@
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::hash::{Hash, Hasher};
+use std::path::{Path, PathBuf};
+
+/// Simulated hit rate of a block cache with given capacity
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct CacheAdvice {
+    /// cache size in bytes
+    pub capacity: u64,
+    /// share of sampled block reads served from cache, zero until anything is sampled
+    pub hit_rate: f64,
+}
+
+impl fmt::Display for CacheAdvice {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} bytes cache would give {:.1}% hits",
+            self.capacity,
+            self.hit_rate * 100.0
+        )
+    }
+}
+
+/// Table file and index interval within it, interval is the unit read by point lookups
+type BlockId = (PathBuf, usize);
+
+/// Replays sampled table block reads against ghost caches of several sizes,
+/// ghost caches track block ids only, so simulating big caches costs little memory
+///
+/// Sampling is spatial: a block is either always or never sampled, simulated capacities are
+/// scaled down by the sample rate, which keeps hit rates close to the unsampled ones.
+#[derive(Debug)]
+pub(crate) struct CacheAdvisor {
+    sample_rate: u64,
+    ghosts: Vec<GhostCache>,
+}
+
+impl CacheAdvisor {
+    /// One of `sample_rate` blocks is tracked
+    pub fn new(capacities: &[u64], sample_rate: u64) -> Self {
+        let sample_rate = sample_rate.max(1);
+        Self {
+            sample_rate,
+            ghosts: capacities
+                .iter()
+                .map(|&capacity| GhostCache::new(capacity, capacity / sample_rate))
+                .collect(),
+        }
+    }
+
+    pub fn record_read(&mut self, path: &Path, block: usize, size: usize) {
+        let mut hasher = DefaultHasher::new();
+        (path, block).hash(&mut hasher);
+        if !hasher.finish().is_multiple_of(self.sample_rate) {
+            return;
+        }
+        let id = (path.to_path_buf(), block);
+        for ghost in self.ghosts.iter_mut() {
+            ghost.access(&id, size as u64);
+        }
+    }
+
+    pub fn advice(&self) -> Vec<CacheAdvice> {
+        self.ghosts.iter().map(GhostCache::advice).collect()
+    }
+}
+
+/// LRU cache of block ids and sizes without block data
+#[derive(Debug)]
+struct GhostCache {
+    /// simulated capacity reported to user
+    capacity: u64,
+    /// capacity scaled down to sampled reads
+    sampled_capacity: u64,
+    used: u64,
+    /// incremented on every access, orders blocks by recency
+    clock: u64,
+    /// block -> last access and size
+    blocks: HashMap<BlockId, (u64, u64)>,
+    /// last access -> block, the first one is evicted
+    recency: BTreeMap<u64, BlockId>,
+    hits: u64,
+    misses: u64,
+}
+
+impl GhostCache {
+    fn new(capacity: u64, sampled_capacity: u64) -> Self {
+        Self {
+            capacity,
+            sampled_capacity,
+            used: 0,
+            clock: 0,
+            blocks: HashMap::new(),
+            recency: BTreeMap::new(),
+            hits: 0,
+            misses: 0,
+        }
+    }
+
+    fn access(&mut self, id: &BlockId, size: u64) {
+        self.clock += 1;
+        if let Some((last_access, _)) = self.blocks.get_mut(id) {
+            self.hits += 1;
+            let id = self
+                .recency
+                .remove(last_access)
+                .expect("block is in recency list");
+            *last_access = self.clock;
+            self.recency.insert(self.clock, id);
+            return;
+        }
+        self.misses += 1;
+        if size > self.sampled_capacity {
+            return;
+        }
+        self.blocks.insert(id.clone(), (self.clock, size));
+        self.recency.insert(self.clock, id.clone());
+        self.used += size;
+        while self.used > self.sampled_capacity {
+            let (_, evicted) = self.recency.pop_first().expect("cache is not empty");
+            let (_, evicted_size) = self.blocks.remove(&evicted).expect("block is cached");
+            self.used -= evicted_size;
+        }
+    }
+
+    fn advice(&self) -> CacheAdvice {
+        let reads = self.hits + self.misses;
+        CacheAdvice {
+            capacity: self.capacity,
+            hit_rate: if reads == 0 {
+                0.0
+            } else {
+                self.hits as f64 / reads as f64
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn evicts_least_recently_used_blocks() {
+        let mut advisor = CacheAdvisor::new(&[0, 200, 300], 1);
+        let path = Path::new("1.sst");
+        // blocks 0 and 1 fit into 200 bytes only while 2 is not read in between
+        for block in [0, 1, 0, 1, 2, 0, 1] {
+            advisor.record_read(path, block, 100);
+        }
+        let hit_rates: Vec<_> = advisor.advice().iter().map(|a| a.hit_rate).collect();
+        assert_eq!(hit_rates, vec![0.0, 2.0 / 7.0, 4.0 / 7.0]);
+        assert_eq!(
+            advisor.advice()[2].to_string(),
+            "300 bytes cache would give 57.1% hits"
+        );
+    }
+}
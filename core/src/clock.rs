@@ -0,0 +1,46 @@
+use crate::utils;
+use std::sync::Mutex;
+use std::time::Duration;
+
+/// Source of commit times and tombstone ages, in microseconds since unix epoch
+pub trait Clock: Send + Sync {
+    fn now(&self) -> u128;
+}
+
+/// Wall clock, reads zero on targets without system clock like wasm32-unknown-unknown
+#[derive(Debug, Default, Clone, Copy)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> u128 {
+        utils::timestamp_now()
+    }
+}
+
+/// Clock moved only by tests, for deterministic timestamps
+#[derive(Debug)]
+pub struct SimulatedClock {
+    now: Mutex<u128>,
+}
+
+impl SimulatedClock {
+    pub fn new(start: u128) -> Self {
+        Self {
+            now: Mutex::new(start),
+        }
+    }
+
+    pub fn set(&self, now: u128) {
+        *self.now.lock().expect("clock mutex poisoned") = now;
+    }
+
+    pub fn advance(&self, by: Duration) {
+        *self.now.lock().expect("clock mutex poisoned") += by.as_micros();
+    }
+}
+
+impl Clock for SimulatedClock {
+    fn now(&self) -> u128 {
+        *self.now.lock().expect("clock mutex poisoned")
+    }
+}
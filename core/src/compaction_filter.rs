@@ -0,0 +1,18 @@
+/// What compaction does with a version of key offered to `CompactionFilter`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FilterDecision {
+    Keep,
+    /// version and older versions of key are dropped, a tombstone takes their place unless
+    /// compaction already drops tombstones
+    Remove,
+    /// version is written with new value
+    ChangeValue(Vec<u8>),
+}
+
+/// Drops or rewrites entries while compaction merges tables, e.g. purges rows of an expired
+/// application-level schema version. Called from compaction threads for every version with
+/// a value that compaction keeps, values are seen as stored, after value transformers
+pub trait CompactionFilter: Send + Sync {
+    /// `level` is the level merged table is written to
+    fn filter(&self, level: usize, key: &[u8], value: &[u8]) -> FilterDecision;
+}
@@ -0,0 +1,126 @@
+use crate::keyspace;
+use std::cmp::Ordering;
+use std::fmt;
+use std::ops::{Bound, RangeBounds};
+use std::sync::Arc;
+
+/// Order of user keys used by memtables, tables and merges. Keys may compare equal only
+/// if their bytes are equal
+pub trait Comparator: Send + Sync {
+    /// Stored in working directory, opening database with comparator of another name fails
+    fn name(&self) -> &str;
+
+    fn compare(&self, a: &[u8], b: &[u8]) -> Ordering;
+}
+
+/// Lexicographic order of key bytes, the default
+#[derive(Debug, Default, Clone, Copy)]
+pub struct BytewiseComparator;
+
+impl Comparator for BytewiseComparator {
+    fn name(&self) -> &str {
+        "lsmdb.BytewiseComparator"
+    }
+
+    fn compare(&self, a: &[u8], b: &[u8]) -> Ordering {
+        a.cmp(b)
+    }
+}
+
+/// Order of all keys. With custom comparator empty key sorts first, as scans start from it,
+/// and internal keyspace sorts after every user key, in byte order. Bytewise order compares
+/// plain bytes, so files written before comparators were configurable keep their order
+#[derive(Clone, Default)]
+pub(crate) struct KeyOrder(Option<Arc<dyn Comparator>>);
+
+impl KeyOrder {
+    pub fn new(comparator: Arc<dyn Comparator>) -> Self {
+        Self(Some(comparator))
+    }
+
+    pub fn name(&self) -> &str {
+        match &self.0 {
+            Some(comparator) => comparator.name(),
+            None => BytewiseComparator.name(),
+        }
+    }
+
+    pub fn cmp(&self, a: &[u8], b: &[u8]) -> Ordering {
+        let Some(comparator) = &self.0 else {
+            return a.cmp(b);
+        };
+        if a.is_empty() || b.is_empty() {
+            return a.len().min(1).cmp(&b.len().min(1));
+        }
+        match (keyspace::is_internal_key(a), keyspace::is_internal_key(b)) {
+            (false, false) => comparator.compare(a, b),
+            (true, true) => a.cmp(b),
+            (a_internal, b_internal) => a_internal.cmp(&b_internal),
+        }
+    }
+
+    pub fn lt(&self, a: &[u8], b: &[u8]) -> bool {
+        self.cmp(a, b) == Ordering::Less
+    }
+
+    pub fn le(&self, a: &[u8], b: &[u8]) -> bool {
+        self.cmp(a, b) != Ordering::Greater
+    }
+
+    pub fn min<T: AsRef<[u8]>>(&self, a: Option<T>, b: Option<T>) -> Option<T> {
+        match (a, b) {
+            (Some(a), Some(b)) if self.lt(b.as_ref(), a.as_ref()) => Some(b),
+            (Some(a), _) => Some(a),
+            (None, b) => b,
+        }
+    }
+
+    pub fn max<T: AsRef<[u8]>>(&self, a: Option<T>, b: Option<T>) -> Option<T> {
+        match (a, b) {
+            (Some(a), Some(b)) if self.lt(a.as_ref(), b.as_ref()) => Some(b),
+            (Some(a), _) => Some(a),
+            (None, b) => b,
+        }
+    }
+
+    /// Smallest key after key, known only for bytewise order
+    pub fn successor(&self, key: &[u8]) -> Option<Vec<u8>> {
+        match self.0 {
+            Some(_) => None,
+            None => Some([key, &[0]].concat()),
+        }
+    }
+
+    /// Whether key is within range under this order
+    pub fn contains(&self, range: &impl RangeBounds<Vec<u8>>, key: &[u8]) -> bool {
+        let after_start = match range.start_bound() {
+            Bound::Included(start) => self.le(start, key),
+            Bound::Excluded(start) => self.lt(start, key),
+            Bound::Unbounded => true,
+        };
+        after_start && !self.is_past_end(range, key)
+    }
+
+    /// Whether key and every greater one are past the end of range
+    pub fn is_past_end(&self, range: &impl RangeBounds<Vec<u8>>, key: &[u8]) -> bool {
+        match range.end_bound() {
+            Bound::Included(end) => self.lt(end, key),
+            Bound::Excluded(end) => self.le(end, key),
+            Bound::Unbounded => false,
+        }
+    }
+}
+
+impl PartialEq for KeyOrder {
+    fn eq(&self, other: &Self) -> bool {
+        self.name() == other.name()
+    }
+}
+
+impl Eq for KeyOrder {}
+
+impl fmt::Debug for KeyOrder {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
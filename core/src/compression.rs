@@ -0,0 +1,106 @@
+use std::io;
+
+/// Algorithm values of new tables are compressed with, set per level in `DatabaseOptions`.
+/// Algorithms are compiled in by cargo features of the same name
+///
+/// Every value is compressed on its own and stored as is when that doesn't make it smaller,
+/// so records stay addressable by lookup table offsets. Compressed value is prefixed with
+/// the id of algorithm, tables of mixed or changed settings are read alike
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Compression {
+    #[default]
+    None,
+    Lz4,
+    /// compression level, higher ones are slower and compress better, zstd's default is 3
+    Zstd(i32),
+}
+
+const LZ4_ID: u8 = 1;
+const ZSTD_ID: u8 = 2;
+
+impl Compression {
+    /// Whether algorithm is compiled in
+    pub fn is_supported(self) -> bool {
+        match self {
+            Self::None => true,
+            Self::Lz4 => cfg!(feature = "lz4"),
+            Self::Zstd(_) => cfg!(feature = "zstd"),
+        }
+    }
+
+    /// Id of algorithm followed by compressed value, None if compression doesn't pay off
+    pub(crate) fn compress(self, value: &[u8]) -> io::Result<Option<Vec<u8>>> {
+        let compressed = match self {
+            Self::None => return Ok(None),
+            Self::Lz4 => lz4_compress(value)?,
+            Self::Zstd(level) => zstd_compress(value, level)?,
+        };
+        Ok(Some(compressed).filter(|compressed| compressed.len() < value.len()))
+    }
+}
+
+/// Value compressed by `Compression::compress`
+pub(crate) fn decompress(compressed: &[u8]) -> io::Result<Vec<u8>> {
+    match compressed.split_first() {
+        Some((&LZ4_ID, data)) => lz4_decompress(data),
+        Some((&ZSTD_ID, data)) => zstd_decompress(data),
+        _ => Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "unknown compression algorithm",
+        )),
+    }
+}
+
+#[cfg(feature = "lz4")]
+fn lz4_compress(value: &[u8]) -> io::Result<Vec<u8>> {
+    let mut compressed = vec![LZ4_ID];
+    compressed.extend(lz4_flex::compress_prepend_size(value));
+    Ok(compressed)
+}
+
+#[cfg(feature = "lz4")]
+fn lz4_decompress(data: &[u8]) -> io::Result<Vec<u8>> {
+    lz4_flex::decompress_size_prepended(data)
+        .map_err(|error| io::Error::new(io::ErrorKind::InvalidData, error))
+}
+
+#[cfg(feature = "zstd")]
+fn zstd_compress(value: &[u8], level: i32) -> io::Result<Vec<u8>> {
+    let mut compressed = vec![ZSTD_ID];
+    compressed.extend(zstd::bulk::compress(value, level)?);
+    Ok(compressed)
+}
+
+#[cfg(feature = "zstd")]
+fn zstd_decompress(data: &[u8]) -> io::Result<Vec<u8>> {
+    zstd::decode_all(data)
+}
+
+#[cfg(not(feature = "lz4"))]
+fn lz4_compress(_: &[u8]) -> io::Result<Vec<u8>> {
+    Err(unsupported())
+}
+
+#[cfg(not(feature = "lz4"))]
+fn lz4_decompress(_: &[u8]) -> io::Result<Vec<u8>> {
+    Err(unsupported())
+}
+
+#[cfg(not(feature = "zstd"))]
+fn zstd_compress(_: &[u8], _: i32) -> io::Result<Vec<u8>> {
+    Err(unsupported())
+}
+
+#[cfg(not(feature = "zstd"))]
+fn zstd_decompress(_: &[u8]) -> io::Result<Vec<u8>> {
+    Err(unsupported())
+}
+
+/// Table was written by build with the algorithm compiled in
+#[cfg(not(all(feature = "lz4", feature = "zstd")))]
+fn unsupported() -> io::Error {
+    io::Error::new(
+        io::ErrorKind::Unsupported,
+        "compression algorithm is not compiled in",
+    )
+}
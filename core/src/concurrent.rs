@@ -0,0 +1,206 @@
+use crate::batch::WriteBatch;
+use crate::database::{Database, PreparedWrite};
+use crate::error::{DBError, Result};
+use crate::latency::{LatencyRecorder, Operation};
+use crate::utils::Stopwatch;
+use std::collections::HashMap;
+use std::io;
+use std::mem;
+use std::sync::{Arc, Condvar, Mutex, MutexGuard, RwLock, RwLockReadGuard, RwLockWriteGuard};
+
+/// Database shared between threads writing in parallel
+///
+/// Puts and deletes take database shared and are committed in groups: the first writer
+/// to find no group in progress appends writes queued so far to wal under a single sync,
+/// then every writer inserts its entry into memtable, which takes concurrent inserts.
+/// Writes of a group become visible in the order their inserts finish.
+/// Batches and memtable swaps take database exclusively and wait for running writes.
+pub struct ConcurrentDb {
+    db: RwLock<Database>,
+    queue: Mutex<CommitQueue>,
+    /// notified whenever a group is committed
+    committed: Condvar,
+    latencies: Arc<LatencyRecorder>,
+}
+
+#[derive(Default)]
+struct CommitQueue {
+    /// writes waiting for the next group by ticket
+    waiting: Vec<(u64, PreparedWrite)>,
+    next_ticket: u64,
+    /// set while a group is appended to wal
+    committing: bool,
+    /// ticket -> sequence of logged write, taken back by its writer
+    logged: HashMap<u64, Result<(u128, PreparedWrite)>>,
+}
+
+impl ConcurrentDb {
+    pub fn new(db: Database) -> Self {
+        Self {
+            latencies: db.latency_recorder(),
+            db: RwLock::new(db),
+            queue: Mutex::new(CommitQueue::default()),
+            committed: Condvar::new(),
+        }
+    }
+
+    pub fn put(&self, key: Vec<u8>, value: Vec<u8>) -> Result<()> {
+        self.put_with_meta(key, value, Vec::new())
+    }
+
+    /// Same as `Database::put_with_meta`
+    pub fn put_with_meta(&self, key: Vec<u8>, value: Vec<u8>, meta: Vec<u8>) -> Result<()> {
+        let start = Stopwatch::start();
+        let db = self.read();
+        let write = db.prepare_put(key, value, meta)?;
+        self.commit(db, write)?;
+        self.latencies.record_since(Operation::Put, start);
+        Ok(())
+    }
+
+    pub fn delete(&self, key: Vec<u8>) -> Result<()> {
+        let start = Stopwatch::start();
+        let db = self.read();
+        let write = db.prepare_delete(key)?;
+        self.commit(db, write)?;
+        self.latencies.record_since(Operation::Delete, start);
+        Ok(())
+    }
+
+    /// Applies batch atomically, waits for running writes
+    pub fn write(&self, batch: WriteBatch) -> Result<()> {
+        self.db().write(batch)
+    }
+
+    pub fn query(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        self.read().query(key)
+    }
+
+    /// Shared access to the underlying database, writes of other threads go on meanwhile
+    pub fn read(&self) -> RwLockReadGuard<'_, Database> {
+        self.db.read().expect("database lock poisoned")
+    }
+
+    /// Exclusive access to the underlying database, waits for running writes
+    pub fn db(&self) -> RwLockWriteGuard<'_, Database> {
+        self.db.write().expect("database lock poisoned")
+    }
+
+    pub fn into_inner(self) -> Database {
+        self.db.into_inner().expect("database lock poisoned")
+    }
+
+    fn commit(&self, db: RwLockReadGuard<'_, Database>, write: PreparedWrite) -> Result<()> {
+        let (timestamp, write) = self.log(&db, write)?;
+        db.apply(timestamp, write);
+        let full = db.memtable_full();
+        drop(db);
+        if full {
+            self.db().swap_memtable_if_full()?;
+        }
+        Ok(())
+    }
+
+    /// Queues write for the next group, leads the group if no other writer does
+    fn log(&self, db: &Database, write: PreparedWrite) -> Result<(u128, PreparedWrite)> {
+        let mut queue = self.queue();
+        let ticket = queue.next_ticket;
+        queue.next_ticket += 1;
+        queue.waiting.push((ticket, write));
+        loop {
+            if let Some(logged) = queue.logged.remove(&ticket) {
+                return logged;
+            }
+            if queue.committing {
+                queue = self
+                    .committed
+                    .wait(queue)
+                    .expect("commit queue mutex poisoned");
+                continue;
+            }
+            queue.committing = true;
+            let group = mem::take(&mut queue.waiting);
+            drop(queue);
+            let first = db.log_group(group.iter().map(|(_, write)| write));
+            queue = self.queue();
+            queue.committing = false;
+            match first {
+                Ok(first) => {
+                    for ((ticket, write), timestamp) in group.into_iter().zip(first..) {
+                        queue.logged.insert(ticket, Ok((timestamp, write)));
+                    }
+                }
+                Err(error) => {
+                    for (other, _) in group.iter().filter(|(other, _)| *other != ticket) {
+                        queue.logged.insert(*other, Err(group_error(&error)));
+                    }
+                    queue.logged.insert(ticket, Err(error));
+                }
+            }
+            self.committed.notify_all();
+        }
+    }
+
+    fn queue(&self) -> MutexGuard<'_, CommitQueue> {
+        self.queue.lock().expect("commit queue mutex poisoned")
+    }
+}
+
+/// Error reported to writers of a group whose leader failed
+fn group_error(error: &DBError) -> DBError {
+    let source = match error {
+        DBError::Io { source, .. } => io::Error::new(source.kind(), source.to_string()),
+        error => io::Error::other(error.to_string()),
+    };
+    source.into()
+}
+
+#[cfg(all(test, feature = "std-fs"))]
+mod tests {
+    use super::*;
+    use std::fs;
+    use std::path::PathBuf;
+    use std::thread;
+
+    #[test]
+    fn concurrent_writers_commit_all_writes() {
+        let test_dir = &PathBuf::from("./tests/concurrent_writers_commit_all_writes");
+        if test_dir.exists() {
+            fs::remove_dir_all(test_dir).unwrap();
+        }
+        let db = Database::options()
+            .set_working_dir(test_dir)
+            .set_memtable_threshold(4096)
+            .init()
+            .unwrap();
+        let db = ConcurrentDb::new(db);
+        thread::scope(|scope| {
+            for writer in 0..4u8 {
+                let db = &db;
+                scope.spawn(move || {
+                    for i in 0..200u32 {
+                        let key = [&[writer][..], &i.to_be_bytes()].concat();
+                        db.put(key.clone(), i.to_le_bytes().to_vec()).unwrap();
+                        if i % 10 == 0 {
+                            db.delete(key).unwrap();
+                        }
+                    }
+                });
+            }
+        });
+        assert_eq!(db.read().last_sequence(), 4 * 220);
+        drop(db.into_inner());
+
+        let db = Database::options()
+            .set_working_dir(test_dir)
+            .init()
+            .unwrap();
+        for writer in 0..4u8 {
+            for i in 0..200u32 {
+                let key = [&[writer][..], &i.to_be_bytes()].concat();
+                let expected = (i % 10 != 0).then(|| i.to_le_bytes().to_vec());
+                assert_eq!(db.query(&key).unwrap(), expected);
+            }
+        }
+    }
+}
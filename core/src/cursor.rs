@@ -0,0 +1,318 @@
+use crate::comparator::KeyOrder;
+use crate::database::newest_entry;
+use crate::error::Result;
+use crate::iterators::IteratorGuard;
+use crate::keyspace;
+use crate::memtable::MemTable;
+use crate::merge::MergingIterator;
+use crate::sstable::SstReader;
+use crate::transform::ValueTransformers;
+
+/// Live pairs read ahead by one refill of forward batch
+const BATCH_SIZE: usize = 64;
+
+/// How forward batch continues once cursor moves past its end
+enum Ahead<'a> {
+    Entries(MergingIterator<'a>),
+    /// merging iterator is created from key on demand, set by seeks
+    From(Vec<u8>),
+    /// same as `From`, but key itself is skipped, set after backward moves
+    After(Vec<u8>),
+    Exhausted,
+}
+
+/// Live pair under cursor together with metadata of its entry
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct EntryRef<'a> {
+    pub key: &'a [u8],
+    pub value: &'a [u8],
+    /// empty if entry was put without metadata
+    pub meta: &'a [u8],
+}
+
+/// Owned counterpart of `EntryRef` kept in forward batch
+struct LiveEntry {
+    key: Vec<u8>,
+    value: Vec<u8>,
+    meta: Vec<u8>,
+}
+
+/// Cursor over live pairs of database, internal keyspace is skipped. Cursor is invalid until
+/// positioned by one of the seeks and after it moves past either end of data.
+/// Moving forward reads batches of pairs from merged sources, moving backward before the batch
+/// looks up the preceding key in every memtable and table
+pub struct DbCursor<'a> {
+    order: &'a KeyOrder,
+    memtables: Vec<&'a MemTable>,
+    levels: &'a [Vec<SstReader>],
+    transformers: &'a ValueTransformers,
+    /// pairs read ahead, cursor points at `batch[pos]`
+    batch: Vec<LiveEntry>,
+    pos: usize,
+    ahead: Ahead<'a>,
+    _tracking: IteratorGuard<'a>,
+}
+
+impl<'a> DbCursor<'a> {
+    pub(crate) fn new(
+        order: &'a KeyOrder,
+        memtables: Vec<&'a MemTable>,
+        levels: &'a [Vec<SstReader>],
+        transformers: &'a ValueTransformers,
+        tracking: IteratorGuard<'a>,
+    ) -> Self {
+        Self {
+            order,
+            memtables,
+            levels,
+            transformers,
+            batch: Vec::new(),
+            pos: 0,
+            ahead: Ahead::Exhausted,
+            _tracking: tracking,
+        }
+    }
+
+    pub fn valid(&self) -> bool {
+        self.pos < self.batch.len()
+    }
+
+    pub fn key(&self) -> Option<&[u8]> {
+        self.batch.get(self.pos).map(|entry| entry.key.as_slice())
+    }
+
+    pub fn value(&self) -> Option<&[u8]> {
+        self.batch.get(self.pos).map(|entry| entry.value.as_slice())
+    }
+
+    pub fn entry(&self) -> Option<EntryRef<'_>> {
+        self.batch.get(self.pos).map(|entry| EntryRef {
+            key: &entry.key,
+            value: &entry.value,
+            meta: &entry.meta,
+        })
+    }
+
+    /// Positions at the first key not less than `key`
+    pub fn seek(&mut self, key: impl AsRef<[u8]>) -> Result<()> {
+        self.ahead = Ahead::From(key.as_ref().to_vec());
+        self.refill()
+    }
+
+    /// Positions at the last key not greater than `key`
+    pub fn seek_for_prev(&mut self, key: impl AsRef<[u8]>) -> Result<()> {
+        let key = key.as_ref();
+        let found = match self.live(key)? {
+            Some(entry) => Some(entry),
+            None => self.find_before(Some(key))?,
+        };
+        self.position_at(found);
+        Ok(())
+    }
+
+    pub fn seek_to_first(&mut self) -> Result<()> {
+        self.seek([])
+    }
+
+    pub fn seek_to_last(&mut self) -> Result<()> {
+        let found = self.find_before(None)?;
+        self.position_at(found);
+        Ok(())
+    }
+
+    /// Moves to the next key, invalid cursor stays invalid
+    #[allow(clippy::should_implement_trait)]
+    pub fn next(&mut self) -> Result<()> {
+        if !self.valid() {
+            return Ok(());
+        }
+        self.pos += 1;
+        if self.pos == self.batch.len() {
+            self.refill()?;
+        }
+        Ok(())
+    }
+
+    /// Moves to the previous key, invalid cursor stays invalid
+    pub fn prev(&mut self) -> Result<()> {
+        if !self.valid() {
+            return Ok(());
+        }
+        if self.pos > 0 {
+            self.pos -= 1;
+            return Ok(());
+        }
+        let key = self.batch[self.pos].key.clone();
+        let found = self.find_before(Some(&key))?;
+        self.position_at(found);
+        Ok(())
+    }
+
+    /// Replaces batch with the next pairs of forward iteration, empty at the end of data
+    fn refill(&mut self) -> Result<()> {
+        self.batch.clear();
+        self.pos = 0;
+        let skip = match &self.ahead {
+            Ahead::From(from) | Ahead::After(from) => {
+                let skip = matches!(self.ahead, Ahead::After(_)).then(|| from.clone());
+                let entries = MergingIterator::new(self.order, &self.memtables, self.levels, from)?;
+                self.ahead = Ahead::Entries(entries);
+                skip
+            }
+            _ => None,
+        };
+        let Ahead::Entries(entries) = &mut self.ahead else {
+            return Ok(());
+        };
+        while self.batch.len() < BATCH_SIZE {
+            let Some(entry) = entries.next() else {
+                self.ahead = Ahead::Exhausted;
+                break;
+            };
+            let entry = entry?;
+            if keyspace::is_internal_key(&entry.key) || skip.as_ref() == Some(&entry.key) {
+                continue;
+            }
+            if let Some(value) = entry.value {
+                let value = self.transformers.decode(&entry.key, value)?;
+                self.batch.push(LiveEntry {
+                    key: entry.key,
+                    value,
+                    meta: entry.meta,
+                });
+            }
+        }
+        Ok(())
+    }
+
+    /// Makes found pair the whole batch, forward iteration continues right after it
+    fn position_at(&mut self, found: Option<LiveEntry>) {
+        self.pos = 0;
+        self.batch.clear();
+        self.ahead = match found {
+            Some(entry) => {
+                let after = entry.key.clone();
+                self.batch.push(entry);
+                Ahead::After(after)
+            }
+            None => Ahead::Exhausted,
+        };
+    }
+
+    /// Entry of key with decoded value if its newest version is live and key isn't internal
+    fn live(&self, key: &[u8]) -> Result<Option<LiveEntry>> {
+        if keyspace::is_internal_key(key) {
+            return Ok(None);
+        }
+        let Some(entry) = newest_entry(self.order, &self.memtables, self.levels, key)? else {
+            return Ok(None);
+        };
+        let Some(value) = entry.value else {
+            return Ok(None);
+        };
+        Ok(Some(LiveEntry {
+            value: self.transformers.decode(key, value)?,
+            key: entry.key,
+            meta: entry.meta,
+        }))
+    }
+
+    /// The greatest live pair with key less than `bound`, with any key if unbounded
+    fn find_before(&self, bound: Option<&[u8]>) -> Result<Option<LiveEntry>> {
+        let mut bound = bound.map(<[u8]>::to_vec);
+        loop {
+            let mut candidate = None;
+            for memtable in &self.memtables {
+                let before = memtable.key_before(bound.as_deref()).map(<[u8]>::to_vec);
+                candidate = self.order.max(candidate, before);
+            }
+            for table in self.levels.iter().flatten() {
+                let before = match &bound {
+                    Some(bound) => table.key_before(bound)?,
+                    None => (!table.is_empty()).then(|| table.metadata.high_key.clone()),
+                };
+                candidate = self.order.max(candidate, before);
+            }
+            let Some(key) = candidate else {
+                return Ok(None);
+            };
+            if keyspace::is_internal_key(&key) {
+                // internal keys are the greatest ones, they are skipped at once
+                bound = Some(keyspace::INTERNAL_KEY_PREFIX.to_vec());
+                continue;
+            }
+            if let Some(entry) = self.live(&key)? {
+                return Ok(Some(entry));
+            }
+            bound = Some(key);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::batch::WriteBatch;
+    use crate::database::Database;
+    use std::collections::BTreeMap;
+    use std::fs;
+    use std::path::PathBuf;
+
+    #[test]
+    fn cursor_moves_both_ways() {
+        let test_dir = &PathBuf::from("./tests/cursor_moves_both_ways");
+        if test_dir.exists() {
+            fs::remove_dir_all(test_dir).unwrap();
+        }
+        let mut db = Database::options()
+            .set_working_dir(test_dir)
+            .set_index_interval(4)
+            .init()
+            .unwrap();
+        let mut model = BTreeMap::new();
+        for i in 0..300u16 {
+            db.put(i.to_be_bytes().to_vec(), vec![1]).unwrap();
+            model.insert(i.to_be_bytes().to_vec(), vec![1]);
+            if i % 100 == 99 {
+                db.flush().unwrap();
+            }
+        }
+        for i in (0..300u16).filter(|i| i % 7 < 3) {
+            db.delete(i.to_be_bytes().to_vec()).unwrap();
+            model.remove(i.to_be_bytes().as_slice());
+        }
+        let mut batch = WriteBatch::new();
+        batch.put_internal(b"hidden", vec![0]);
+        db.write(batch).unwrap();
+        let pairs: Vec<_> = model.into_iter().collect();
+
+        let mut cursor = db.cursor();
+        assert!(!cursor.valid());
+        cursor.seek_to_first().unwrap();
+        let mut forward = Vec::new();
+        while let (Some(key), Some(value)) = (cursor.key(), cursor.value()) {
+            forward.push((key.to_vec(), value.to_vec()));
+            cursor.next().unwrap();
+        }
+        assert_eq!(forward, pairs);
+
+        cursor.seek_to_last().unwrap();
+        let mut backward = Vec::new();
+        while let (Some(key), Some(value)) = (cursor.key(), cursor.value()) {
+            backward.push((key.to_vec(), value.to_vec()));
+            cursor.prev().unwrap();
+        }
+        backward.reverse();
+        assert_eq!(backward, pairs);
+
+        // 70 and 71 are deleted
+        cursor.seek_for_prev(71u16.to_be_bytes()).unwrap();
+        assert_eq!(cursor.key(), Some(&69u16.to_be_bytes()[..]));
+        cursor.next().unwrap();
+        assert_eq!(cursor.key(), Some(&73u16.to_be_bytes()[..]));
+        cursor.seek(70u16.to_be_bytes()).unwrap();
+        cursor.prev().unwrap();
+        assert_eq!(cursor.key(), Some(&69u16.to_be_bytes()[..]));
+        cursor.seek_for_prev(0u16.to_be_bytes()).unwrap();
+        assert!(!cursor.valid());
+    }
+}
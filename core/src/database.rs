@@ -1,39 +1,426 @@
+use crate::batch::WriteBatch;
+use crate::block_cache::BlockCache;
+use crate::bloom::{FilterCounters, FilterStats};
+use crate::cache_advisor::{CacheAdvice, CacheAdvisor};
+use crate::clock::{Clock, SystemClock};
+use crate::compaction_filter::{CompactionFilter, FilterDecision};
+use crate::comparator::{BytewiseComparator, Comparator, KeyOrder};
+use crate::compression::Compression;
+use crate::cursor::DbCursor;
+use crate::error::DBError;
+use crate::error::Result;
+use crate::events::EventListener;
+use crate::export;
+use crate::export::Format;
+#[cfg(feature = "std-fs")]
+use crate::follower::Follower;
+use crate::gc::OrphanFiles;
+use crate::index;
+use crate::iterators::{IteratorGuard, IteratorInfo, IteratorRegistry};
+use crate::keyspace;
+use crate::latency::{LatencyRecorder, Operation, OperationLatencies};
+use crate::maintenance::{MaintenanceReport, MaintenanceSchedule};
 use crate::memtable::MemTable;
-use crate::utils::{timestamp_now, CommonBinaryFormatRef};
-use crate::wal::WriteAheadLog;
-use anyhow::Result;
-use std::fs::File;
-use std::io::{BufReader, Write};
-use std::path::{Path, PathBuf};
-use std::{fs, mem};
-use crate::sstable::SstMetadata;
+use crate::merge::MergingIterator;
+use crate::pinned::PinnedValue;
+use crate::range_del::{self, RangeTombstone};
+use crate::rate_limiter::RateLimiter;
+use crate::repair::{self, RepairReport, RepairSource};
+use crate::scheduler::{JobPriority, JobScheduler};
+use crate::sequence::SequenceTimes;
+#[cfg(feature = "parquet")]
+use crate::sstable::SstBuilder;
+use crate::sstable::{
+    SstReader, SstWriter, DEFAULT_BLOOM_BITS_PER_KEY, DEFAULT_INDEX_INTERVAL,
+    DEFAULT_TWO_LEVEL_INDEX_THRESHOLD,
+};
+use crate::subscription::{ChangeEvent, Subscriptions};
+use crate::trace;
+use crate::transform::{ValueTransformer, ValueTransformers};
+use crate::txn::Txn;
 use crate::utils;
+use crate::utils::{
+    CommonBinaryFormat, CommonBinaryFormatRef, Corruption, Stopwatch, MAX_META_SIZE,
+};
+#[cfg(feature = "parquet")]
+use crate::vfs::LocalStorage;
+use crate::vfs::{self, MemStorage, Storage, StorageReader};
+use crate::wal::{
+    UnknownRecordPolicy, WalSyncPolicy, WriteAheadLog, WriteAheadLogEntry, WriteAheadLogIterator,
+};
+use crate::workload::{self, ReplayReport, TraceOp, TraceRecord, Tracer};
+#[cfg(feature = "parquet")]
+use arrow_array::RecordBatch;
+#[cfg(feature = "parquet")]
+use arrow_schema::ArrowError;
+#[cfg(feature = "parquet")]
+use parquet::arrow::arrow_reader::ParquetRecordBatchReaderBuilder;
+use std::collections::VecDeque;
+use std::io::{Read, Write};
+use std::ops::{Bound, RangeBounds};
+use std::panic::Location;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{self, RecvTimeoutError, TryRecvError};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::Duration;
+use std::{fmt, fs, io, iter, mem, thread};
 
 pub struct Database {
-    /// write-ahead log for data loss prevention
-    wal: WriteAheadLog,
+    /// wal and sequences, locked by writers committing through shared reference
+    log: Arc<Mutex<CommitLog>>,
+    /// syncs wal of periodic sync policy once its interval expires
+    _wal_syncer: Option<WalSyncer>,
+    /// files of wals retired after flush, reused by new wals
+    recycled_wals: Vec<PathBuf>,
+    /// lock of working directory, released on drop
+    _lock: Option<fs::File>,
     /// read-write memtable
     rw_memtable: MemTable,
-    /// read-only memtable
-    ro_memtable: MemTable,
-    /// level num -> sorted vec of filepaths
-    on_disk_levels: Vec<Vec<PathBuf>>,
+    /// frozen memtables from newest to oldest, shared with their flush jobs. Memtables of
+    /// pending flushes come first, the last one may mirror the newest flushed table
+    immutable_memtables: VecDeque<Arc<MemTable>>,
+    /// level num -> tables sorted from oldest to newest
+    on_disk_levels: Vec<Vec<SstReader>>,
+    /// newest tombstone dropped by compaction, commit timestamps of such keys are lost
+    dropped_tombstones_timestamp: u128,
+    /// latency histograms of foreground operations and background jobs
+    latencies: Arc<LatencyRecorder>,
+    /// open iterators checked for leaks
+    iterators: IteratorRegistry,
+    /// outcomes of bloom filter checks made by point reads
+    filter_counters: FilterCounters,
+    /// simulates block cache sizes on sampled point reads
+    cache_advisor: Option<Mutex<CacheAdvisor>>,
+    /// runs flushes and compactions when background threads are configured
+    scheduler: Option<JobScheduler>,
+    /// flushes running in background from oldest to newest, wal of each is removed once its
+    /// table is installed
+    pending_flushes: VecDeque<PendingFlush>,
+    /// compaction running in background, its inputs stay readable until output is installed
+    pending_compaction: Option<PendingCompaction>,
+    /// names new tables of foreground and background jobs
+    sst_paths: SstPaths,
+    /// position of the next table checked by scrubbing among tables of all levels
+    scrub_cursor: usize,
+    /// tables whose reads failed, rewritten from repair source by the next write or maintenance
+    pending_repairs: Mutex<Vec<PathBuf>>,
+    /// channels notified about committed writes
+    subscriptions: Subscriptions,
+    /// workload trace in progress, if any
+    tracer: Tracer,
+    /// fencing epoch, raised when a replica is promoted, replication rejects writes of older epochs
+    epoch: u64,
     /// configuration
     options: DatabaseOptions,
 }
 
+/// File naming wal and table of each flush in progress, left behind if flushes are interrupted
+const FLUSH_MARKER: &str = "FLUSHING";
+
+/// File holding name of comparator database was created with
+const COMPARATOR_FILE: &str = "COMPARATOR";
+/// File holding fencing epoch of database as 8 bytes LE, missing file stands for epoch 0
+const EPOCH_FILE: &str = "EPOCH";
+
+/// File locked by open database, so that another instance can't open the same directory
+const LOCK_FILE: &str = "LOCK";
+
+/// State advanced by every commit
+struct CommitLog {
+    /// write-ahead log for data loss prevention
+    wal: WriteAheadLog,
+    /// sequence of the last committed write, sequences grow by one with each commit
+    last_sequence: u128,
+    /// wall clock time of sampled sequences
+    sequence_times: SequenceTimes,
+}
+
+impl CommitLog {
+    /// Takes `count` consecutive sequences, returns the first one
+    fn take_sequences(&mut self, count: u128, now: u128) -> u128 {
+        let first = self.last_sequence + 1;
+        self.last_sequence += count;
+        self.sequence_times.record(self.last_sequence, now);
+        first
+    }
+}
+
+/// Put or delete checked and encoded ahead of commit
+pub(crate) struct PreparedWrite {
+    key: Vec<u8>,
+    /// encoded value, None for delete
+    value: Option<Vec<u8>>,
+    meta: Vec<u8>,
+    /// event published to subscribers once write is applied
+    change: Option<(Vec<u8>, Option<Vec<u8>>)>,
+}
+
+impl PreparedWrite {
+    fn append_to(&self, wal: &mut WriteAheadLog, timestamp: u128) -> io::Result<()> {
+        match &self.value {
+            Some(value) => wal.put_with_meta(timestamp, &self.key, value, &self.meta),
+            None => wal.delete(timestamp, &self.key),
+        }
+    }
+}
+
+/// Background thread syncing wal once its oldest unsynced write is older than interval
+/// of periodic sync policy, so that idle database doesn't hold writes unsynced until the next one
+struct WalSyncer {
+    stop: mpsc::Sender<()>,
+    worker: Option<thread::JoinHandle<()>>,
+}
+
+impl WalSyncer {
+    fn start(log: Arc<Mutex<CommitLog>>, policy: WalSyncPolicy) -> Option<Self> {
+        let WalSyncPolicy::Periodic { interval, .. } = policy else {
+            return None;
+        };
+        // no threads nor timers on targets without system clock
+        if !utils::HAS_SYSTEM_CLOCK {
+            return None;
+        }
+        let (stop, stopped) = mpsc::channel();
+        let worker = thread::spawn(move || {
+            let mut wait = interval;
+            while let Err(RecvTimeoutError::Timeout) = stopped.recv_timeout(wait) {
+                let mut log = log.lock().expect("commit log mutex poisoned");
+                // failed sync leaves writes pending, the next write retries and reports it
+                let _ = log.wal.sync_if_needed(policy);
+                wait = log
+                    .wal
+                    .oldest_unsynced_age()
+                    .and_then(|age| interval.checked_sub(age))
+                    .filter(|wait| !wait.is_zero())
+                    .unwrap_or(interval);
+            }
+        });
+        Some(Self {
+            stop,
+            worker: Some(worker),
+        })
+    }
+}
+
+impl Drop for WalSyncer {
+    fn drop(&mut self) {
+        let _ = self.stop.send(());
+        if let Some(worker) = self.worker.take() {
+            let _ = worker.join();
+        }
+    }
+}
+
+// receivers are locked only to keep database shareable between threads
+struct PendingFlush {
+    wal_path: PathBuf,
+    table_path: PathBuf,
+    result: Mutex<mpsc::Receiver<io::Result<Option<SstReader>>>>,
+}
+
+struct PendingCompaction {
+    level: usize,
+    result: Mutex<mpsc::Receiver<io::Result<MergeOutput>>>,
+}
+
 #[derive(Default, Clone, Debug)]
 pub struct DatabaseOptions {
     /// path where all the db files will be stored
-    working_dir: PathBuf,
+    pub(crate) working_dir: PathBuf,
     /// size in bytes to store memtable on disk
     memtable_threshold: usize,
+    /// frozen memtables waiting for background flush before swaps block
+    max_immutable_memtables: usize,
     /// limit of memtables count on level 0
     level_zero_memtables_limit: usize,
     /// number of levels
-    level_num: usize,
+    pub(crate) level_num: usize,
     /// factor of count threshold between levels
     level_factor: usize,
+    /// target size in bytes of level 1, deeper levels grow by `level_factor`,
+    /// zero keeps count thresholds
+    level_base_size: usize,
+    /// how tables are merged
+    compaction_style: CompactionStyle,
+    /// bottom level is reserved for tables ingested behind existing data
+    allow_ingest_behind: bool,
+    /// verify every record of tables and wals on open instead of table boundaries only
+    paranoid_checks: bool,
+    /// delete files left behind by interrupted writes on open
+    delete_orphan_files: bool,
+    /// bloom filter size of new tables, zero disables filters
+    bloom_bits_per_key: usize,
+    /// level num -> bits per key overriding `bloom_bits_per_key`
+    level_bloom_bits_per_key: Vec<Option<usize>>,
+    /// number of records per sst lookup table entry
+    index_interval: usize,
+    /// lookup table entries per index partition of new tables, zero disables partitioning
+    partition_entries: usize,
+    /// lookup table size in bytes above which new tables get two-level index
+    two_level_index_threshold: usize,
+    /// size in bytes at which compaction output rolls over to a new table, zero disables
+    target_file_size: usize,
+    /// level num -> target file size overriding `target_file_size`
+    level_target_file_size: Vec<Option<usize>>,
+    /// compression of values in new tables
+    compression: Compression,
+    /// level num -> compression overriding `compression`
+    level_compression: Vec<Option<Compression>>,
+    /// holds index and filter partitions of partitioned tables
+    block_cache: Option<Arc<BlockCache>>,
+    /// partitions are loaded into block cache on open
+    preload_index: bool,
+    /// read tables through memory mapping instead of file seeks
+    mmap_reads: bool,
+    /// write tables bypassing page cache
+    use_direct_io: bool,
+    /// throttles table writes of flush and compaction
+    rate_limiter: Option<Arc<RateLimiter>>,
+    /// encode values under key prefixes before they are stored
+    pub(crate) value_transformers: ValueTransformers,
+    /// number of the newest versions of key preserved by compaction
+    versions_to_keep: usize,
+    /// minimal age of tombstone before compaction may drop it
+    tombstone_grace: Duration,
+    /// worker threads running flushes and compactions, zero runs them inline with writes
+    background_threads: usize,
+    /// cache capacities simulated by cache advisor and its block sample rate
+    cache_advisor: Option<(Vec<u64>, u64)>,
+    /// age of iterator after which it's reported as long-running
+    iterator_leak_threshold: Duration,
+    /// record backtraces of iterator creation
+    capture_iterator_backtraces: bool,
+    /// notified about database events
+    event_listener: Option<Listener>,
+    /// healthy copy of data for rewriting corrupted tables
+    repair_source: Option<SharedRepairSource>,
+    /// drops or rewrites entries during compaction
+    compaction_filter: Option<SharedCompactionFilter>,
+    /// when `Database::run_maintenance` may do its work
+    maintenance_schedule: MaintenanceSchedule,
+    /// tables above the last level holding only data older than this are pushed down, zero disables
+    periodic_compaction: Duration,
+    /// tables verified by one maintenance run
+    scrub_tables_per_run: usize,
+    /// when wal writes are forced to disk
+    wal_sync_policy: WalSyncPolicy,
+    /// what recovery does with wal records it can't apply
+    unknown_wal_records: UnknownRecordPolicy,
+    /// space in bytes reserved for new wal files
+    wal_preallocate_size: u64,
+    /// files of retired wals kept for reuse
+    recycle_wals: usize,
+    /// consulted before every write
+    write_guard: Option<WriteGuard>,
+    /// rejects malformed keys of writes
+    key_validator: Option<KeyValidator>,
+    /// source of commit timestamps
+    clock: SharedClock,
+    /// holds wal and table files
+    storage: SharedStorage,
+    /// order of keys in memtables and tables
+    pub(crate) comparator: KeyOrder,
+    /// every open starts empty with tables kept in memory and wal writes skipped
+    in_memory: bool,
+}
+
+/// Strategy choosing which tables are merged
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum CompactionStyle {
+    /// overflowing level is merged into the next one, limits grow by level factor
+    #[default]
+    Leveled,
+    /// every table stays on level 0 as a sorted run, newest runs are merged once
+    /// their total size is within `size_ratio` percent of the next older run
+    /// or there are more than `max_sorted_runs` of them
+    Universal {
+        max_sorted_runs: usize,
+        size_ratio: usize,
+    },
+}
+
+/// Kind of write passed to write guard
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WriteKind {
+    Put,
+    Delete,
+    /// checked against start of deleted range
+    DeleteRange,
+}
+
+type WriteGuardFn = dyn Fn(&[u8], WriteKind) -> bool + Send + Sync;
+
+/// Callback deciding whether write to key is allowed
+#[derive(Clone)]
+struct WriteGuard(Arc<WriteGuardFn>);
+
+impl fmt::Debug for WriteGuard {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("WriteGuard")
+    }
+}
+
+type KeyValidatorFn = dyn Fn(&[u8]) -> std::result::Result<(), String> + Send + Sync;
+
+/// Callback returning reason why key is malformed
+#[derive(Clone)]
+struct KeyValidator(Arc<KeyValidatorFn>);
+
+impl fmt::Debug for KeyValidator {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("KeyValidator")
+    }
+}
+
+#[derive(Clone)]
+struct Listener(Arc<dyn EventListener>);
+
+impl fmt::Debug for Listener {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("EventListener")
+    }
+}
+
+#[derive(Clone)]
+struct SharedRepairSource(Arc<dyn RepairSource>);
+
+impl fmt::Debug for SharedRepairSource {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("RepairSource")
+    }
+}
+
+#[derive(Clone)]
+struct SharedCompactionFilter(Arc<dyn CompactionFilter>);
+
+impl fmt::Debug for SharedCompactionFilter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("CompactionFilter")
+    }
+}
+
+#[derive(Clone)]
+struct SharedClock(Arc<dyn Clock>);
+
+impl Default for SharedClock {
+    fn default() -> Self {
+        Self(Arc::new(SystemClock))
+    }
+}
+
+impl fmt::Debug for SharedClock {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Clock")
+    }
+}
+
+#[derive(Debug, Clone)]
+struct SharedStorage(Arc<dyn Storage>);
+
+impl Default for SharedStorage {
+    fn default() -> Self {
+        Self(vfs::default_storage())
+    }
 }
 
 impl DatabaseOptions {
@@ -41,9 +428,52 @@ impl DatabaseOptions {
         Self {
             working_dir: PathBuf::from("."),
             memtable_threshold: 67_108_864, // 64 MB
+            max_immutable_memtables: 1,
             level_zero_memtables_limit: 8,
             level_num: 7,
             level_factor: 10,
+            level_base_size: 0,
+            compaction_style: CompactionStyle::default(),
+            allow_ingest_behind: false,
+            paranoid_checks: false,
+            delete_orphan_files: true,
+            bloom_bits_per_key: DEFAULT_BLOOM_BITS_PER_KEY,
+            level_bloom_bits_per_key: Vec::new(),
+            index_interval: DEFAULT_INDEX_INTERVAL,
+            partition_entries: 0,
+            two_level_index_threshold: DEFAULT_TWO_LEVEL_INDEX_THRESHOLD,
+            target_file_size: 0,
+            level_target_file_size: Vec::new(),
+            compression: Compression::None,
+            level_compression: Vec::new(),
+            block_cache: None,
+            preload_index: false,
+            mmap_reads: false,
+            use_direct_io: false,
+            rate_limiter: None,
+            value_transformers: ValueTransformers::default(),
+            versions_to_keep: 1,
+            tombstone_grace: Duration::ZERO,
+            background_threads: 0,
+            cache_advisor: None,
+            iterator_leak_threshold: Duration::from_secs(60),
+            capture_iterator_backtraces: false,
+            event_listener: None,
+            repair_source: None,
+            compaction_filter: None,
+            maintenance_schedule: MaintenanceSchedule::default(),
+            periodic_compaction: Duration::ZERO,
+            scrub_tables_per_run: 0,
+            wal_sync_policy: WalSyncPolicy::default(),
+            unknown_wal_records: UnknownRecordPolicy::default(),
+            wal_preallocate_size: 0,
+            recycle_wals: 0,
+            write_guard: None,
+            key_validator: None,
+            clock: SharedClock::default(),
+            storage: SharedStorage::default(),
+            comparator: KeyOrder::default(),
+            in_memory: false,
         }
     }
 
@@ -57,6 +487,13 @@ impl DatabaseOptions {
         self
     }
 
+    /// Frozen memtables allowed to wait for background flush, swapping memtable with this many
+    /// flushes running blocks until the oldest one is installed
+    pub fn set_max_immutable_memtables(mut self, count: usize) -> Self {
+        self.max_immutable_memtables = count;
+        self
+    }
+
     pub fn set_level_zero_memtables_limit(mut self, count: usize) -> Self {
         self.level_zero_memtables_limit = count;
         self
@@ -72,9 +509,381 @@ impl DatabaseOptions {
         self
     }
 
+    /// Levels below level 0 are compacted once their size in bytes exceeds target instead of
+    /// tables count, a table with the least overlap with the next level is moved down at a time
+    pub fn set_level_base_size(mut self, bytes: usize) -> Self {
+        self.level_base_size = bytes;
+        self
+    }
+
+    pub fn set_compaction_style(mut self, style: CompactionStyle) -> Self {
+        self.compaction_style = style;
+        self
+    }
+
+    /// Adds a bottom level below `level_num` levels which compaction never writes to,
+    /// it's filled only by `Database::ingest_behind`
+    pub fn set_allow_ingest_behind(mut self, enabled: bool) -> Self {
+        self.allow_ingest_behind = enabled;
+        self
+    }
+
+    /// Open fails fast on damaged files: key range of every table is checked against its
+    /// metadata, checksum of every table and wal record is verified. Torn wal tail left by
+    /// a crash is still replayed up to the last intact record
+    pub fn set_paranoid_checks(mut self, enabled: bool) -> Self {
+        self.paranoid_checks = enabled;
+        self
+    }
+
+    /// Enabled by default, see `OrphanFiles`
+    pub fn set_delete_orphan_files(mut self, enabled: bool) -> Self {
+        self.delete_orphan_files = enabled;
+        self
+    }
+
+    /// Applies to tables written from now on, existing ones are updated by `Database::rebuild_filters`
+    pub fn set_bloom_bits_per_key(mut self, bits_per_key: usize) -> Self {
+        self.bloom_bits_per_key = bits_per_key;
+        self
+    }
+
+    /// Overrides bits per key for tables of one level, zero disables their filters
+    pub fn set_level_bloom_bits_per_key(mut self, level: usize, bits_per_key: usize) -> Self {
+        if self.level_bloom_bits_per_key.len() <= level {
+            self.level_bloom_bits_per_key.resize(level + 1, None);
+        }
+        self.level_bloom_bits_per_key[level] = Some(bits_per_key);
+        self
+    }
+
+    fn bloom_bits_per_key(&self, level: usize) -> usize {
+        self.level_bloom_bits_per_key
+            .get(level)
+            .copied()
+            .flatten()
+            .unwrap_or(self.bloom_bits_per_key)
+    }
+
+    /// Larger interval shrinks lookup tables kept in memory at the cost of longer in-table scans
+    pub fn set_index_interval(mut self, index_interval: usize) -> Self {
+        self.index_interval = index_interval;
+        self
+    }
+
+    /// New tables split lookup table and bloom filter into partitions of `partition_entries`
+    /// lookup table entries, only the small partition index of such table stays in memory
+    /// and partitions are loaded on demand through block cache. Zero disables partitioning
+    pub fn set_partitioned_index(mut self, partition_entries: usize) -> Self {
+        self.partition_entries = partition_entries;
+        self
+    }
+
+    /// Tables with lookup table over `bytes` are written partitioned even when
+    /// `set_partitioned_index` is off, so huge tables don't load whole index into memory.
+    /// Zero disables
+    pub fn set_two_level_index_threshold(mut self, bytes: usize) -> Self {
+        self.two_level_index_threshold = bytes;
+        self
+    }
+
+    /// Compaction output rolls over to a new table once it holds `bytes` of records, so that
+    /// later compactions rewrite only tables overlapping the moved one. Zero writes a single
+    /// table per compaction. Tables of level 0 are sorted runs and never split
+    pub fn set_target_file_size(mut self, bytes: usize) -> Self {
+        self.target_file_size = bytes;
+        self
+    }
+
+    /// Overrides `set_target_file_size` for tables written to level
+    pub fn set_level_target_file_size(mut self, level: usize, bytes: usize) -> Self {
+        if self.level_target_file_size.len() <= level {
+            self.level_target_file_size.resize(level + 1, None);
+        }
+        self.level_target_file_size[level] = Some(bytes);
+        self
+    }
+
+    /// Values of new tables are compressed one by one, algorithm has to be compiled in by
+    /// cargo feature of the same name. Changed setting applies to tables written afterwards,
+    /// tables of any setting are readable
+    pub fn set_compression(mut self, compression: Compression) -> Self {
+        self.compression = compression;
+        self
+    }
+
+    /// Overrides `set_compression` for tables written to level, e.g. none for upper levels
+    /// rewritten often and heavier one for the bottom level holding cold data
+    pub fn set_level_compression(mut self, level: usize, compression: Compression) -> Self {
+        if self.level_compression.len() <= level {
+            self.level_compression.resize(level + 1, None);
+        }
+        self.level_compression[level] = Some(compression);
+        self
+    }
+
+    fn compression(&self, level: usize) -> Compression {
+        self.level_compression
+            .get(level)
+            .copied()
+            .flatten()
+            .unwrap_or(self.compression)
+    }
+
+    fn target_file_size(&self, level: usize) -> usize {
+        match level {
+            0 => 0,
+            level => self
+                .level_target_file_size
+                .get(level)
+                .copied()
+                .flatten()
+                .unwrap_or(self.target_file_size),
+        }
+    }
+
+    /// Bounds memory of loaded partitions, without cache they are read on every lookup
+    pub fn set_block_cache(mut self, block_cache: Arc<BlockCache>) -> Self {
+        self.block_cache = Some(block_cache);
+        self
+    }
+
+    /// Open fills block cache with partitions of existing tables, see `Database::warm_cache`
+    pub fn set_preload_index(mut self, enabled: bool) -> Self {
+        self.preload_index = enabled;
+        self
+    }
+
+    /// Memory-maps every table, saves syscalls for read-heavy workloads
+    pub fn set_mmap_reads(mut self, enabled: bool) -> Self {
+        self.mmap_reads = enabled;
+        self
+    }
+
+    /// Flush and compaction write tables with direct io, so they don't evict pages cached for reads
+    pub fn set_use_direct_io(mut self, enabled: bool) -> Self {
+        self.use_direct_io = enabled;
+        self
+    }
+
+    /// Limiter may be shared with other databases to cap their total background write throughput
+    pub fn set_rate_limiter(mut self, rate_limiter: Arc<RateLimiter>) -> Self {
+        self.rate_limiter = Some(rate_limiter);
+        self
+    }
+
+    /// Values of keys starting with prefix are stored encoded by transformer, the longest matching
+    /// prefix wins, tables passed to `Database::ingest_sst` must already hold encoded values
+    pub fn set_value_transformer(
+        mut self,
+        prefix: impl Into<Vec<u8>>,
+        transformer: impl ValueTransformer + 'static,
+    ) -> Self {
+        self.value_transformers
+            .set(prefix.into(), Arc::new(transformer));
+        self
+    }
+
+    /// Older versions are visible through `Database::get_versions` until compaction discards them,
+    /// memtable keeps only the newest version of key so overwrites before a flush aren't retained
+    pub fn set_versions_to_keep(mut self, count: usize) -> Self {
+        self.versions_to_keep = count;
+        self
+    }
+
+    /// Younger tombstones survive compaction into the last level
+    pub fn set_tombstone_grace(mut self, grace: Duration) -> Self {
+        self.tombstone_grace = grace;
+        self
+    }
+
+    /// Flushes and compactions run on a pool of worker threads instead of blocking writes,
+    /// flushes are picked before compactions
+    pub fn set_background_threads(mut self, threads: usize) -> Self {
+        self.background_threads = threads;
+        self
+    }
+
+    /// Point reads of table blocks are replayed against simulated caches of given capacities in
+    /// bytes, one of `sample_rate` blocks is tracked, see `Database::cache_advice`
+    pub fn set_cache_advisor(mut self, capacities: impl Into<Vec<u64>>, sample_rate: u64) -> Self {
+        self.cache_advisor = Some((capacities.into(), sample_rate));
+        self
+    }
+
+    /// Iterators open for longer are listed by `Database::long_running_iterators`
+    /// and reported to event listener
+    pub fn set_iterator_leak_threshold(mut self, threshold: Duration) -> Self {
+        self.iterator_leak_threshold = threshold;
+        self
+    }
+
+    /// Backtrace of every iterator creation is kept for leak reports, capturing it is slow
+    pub fn set_capture_iterator_backtraces(mut self, enabled: bool) -> Self {
+        self.capture_iterator_backtraces = enabled;
+        self
+    }
+
+    pub fn set_event_listener(mut self, listener: impl EventListener + 'static) -> Self {
+        self.event_listener = Some(Listener(Arc::new(listener)));
+        self
+    }
+
+    /// Source of healthy copies, such as replica or backup, used to rewrite tables whose point
+    /// reads fail checksum verification or which maintenance scrubbing finds corrupted
+    pub fn set_repair_source(mut self, source: Arc<dyn RepairSource>) -> Self {
+        self.repair_source = Some(SharedRepairSource(source));
+        self
+    }
+
+    /// Consulted for every version compaction keeps, flushes don't call it
+    pub fn set_compaction_filter(mut self, filter: Arc<dyn CompactionFilter>) -> Self {
+        self.compaction_filter = Some(SharedCompactionFilter(filter));
+        self
+    }
+
+    pub fn set_maintenance_schedule(mut self, schedule: MaintenanceSchedule) -> Self {
+        self.maintenance_schedule = schedule;
+        self
+    }
+
+    /// Maintenance pushes data older than `age` down to the last level, so stale versions and
+    /// tombstones don't linger in rarely compacted levels. Leveled style only, zero disables
+    pub fn set_periodic_compaction(mut self, age: Duration) -> Self {
+        self.periodic_compaction = age;
+        self
+    }
+
+    /// Maintenance verifies checksums of this many tables per run in round robin, zero disables
+    pub fn set_scrub_tables_per_run(mut self, tables: usize) -> Self {
+        self.scrub_tables_per_run = tables;
+        self
+    }
+
+    pub fn set_wal_sync_policy(mut self, policy: WalSyncPolicy) -> Self {
+        self.wal_sync_policy = policy;
+        self
+    }
+
+    /// Records of types unknown to this build, e.g. written by a newer version, fail opening
+    /// by default, `UnknownRecordPolicy::Skip` drops them instead
+    pub fn set_unknown_wal_record_policy(mut self, policy: UnknownRecordPolicy) -> Self {
+        self.unknown_wal_records = policy;
+        self
+    }
+
+    /// Reserves space for each new wal file up front, so that appends don't allocate blocks
+    pub fn set_wal_preallocate_size(mut self, bytes: u64) -> Self {
+        self.wal_preallocate_size = bytes;
+        self
+    }
+
+    /// Keeps up to `count` files of wals whose memtables are flushed and writes new wals over
+    /// them instead of creating files
+    pub fn set_recycle_wals(mut self, count: usize) -> Self {
+        self.recycle_wals = count;
+        self
+    }
+
+    /// Guard returns false to reject write, rejected writes fail with `DBError::PermissionDenied`
+    pub fn set_write_guard(
+        mut self,
+        guard: impl Fn(&[u8], WriteKind) -> bool + Send + Sync + 'static,
+    ) -> Self {
+        self.write_guard = Some(WriteGuard(Arc::new(guard)));
+        self
+    }
+
+    /// Validator returns reason to reject key of put or delete, such writes fail with
+    /// `DBError::InvalidKey`. `KeyRules::validate` covers common constraints
+    pub fn set_key_validator(
+        mut self,
+        validator: impl Fn(&[u8]) -> std::result::Result<(), String> + Send + Sync + 'static,
+    ) -> Self {
+        self.key_validator = Some(KeyValidator(Arc::new(validator)));
+        self
+    }
+
+    /// Replaces wall clock used for tombstone grace and commit times
+    pub fn set_clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = SharedClock(clock);
+        self
+    }
+
+    /// Backend holding wal and table files. Followers, exports and ingestion
+    /// of external tables, as well as memory mapping and direct io, work with local files only
+    pub fn set_storage(mut self, storage: Arc<dyn Storage>) -> Self {
+        self.storage = SharedStorage(storage);
+        self
+    }
+
+    /// Order of keys, bytewise by default. Its name is stored in working directory, so database
+    /// can't be reopened with another order. Bounds of scans and ranges follow it, while
+    /// key prefix helpers such as `TypedDb::scan_prefix` assume bytewise order
+    pub fn set_comparator(mut self, comparator: Arc<dyn Comparator>) -> Self {
+        self.comparator = KeyOrder::new(comparator);
+        self
+    }
+
+    /// Keeps tables in memory storage and skips wal, so nothing touches disk and every open
+    /// starts with empty database. Memory mapping and direct io are ignored
+    pub fn set_in_memory(mut self, enabled: bool) -> Self {
+        self.in_memory = enabled;
+        self
+    }
+
     pub fn init(self) -> Result<Database> {
         Database::init(self)
     }
+
+    /// Files of working directory open would delete, nothing is changed
+    pub fn find_orphan_files(&self) -> Result<OrphanFiles> {
+        Ok(OrphanFiles::find(
+            &self.working_dir,
+            &self.storage.0,
+            &self.comparator,
+        )?)
+    }
+
+    /// Opens read-only view of database owned by another process in the same directory
+    #[cfg(feature = "std-fs")]
+    pub fn init_follower(self) -> Result<Follower> {
+        Follower::init(self)
+    }
+
+    /// Rebuilds database in working directory with these options, see `Database::repair`
+    pub fn repair(self) -> Result<RepairReport> {
+        Database::repair_with(self)
+    }
+
+    /// Rejects settings database can't work with, fails with `DBError::InvalidOptions`
+    fn validate(&self) -> Result<()> {
+        if self.level_zero_memtables_limit == 0 {
+            return Err(DBError::InvalidOptions(
+                "level zero memtables limit must be positive".to_string(),
+            ));
+        }
+        if self.max_immutable_memtables == 0 {
+            return Err(DBError::InvalidOptions(
+                "at least one immutable memtable must be allowed".to_string(),
+            ));
+        }
+        if self.level_factor == 0 {
+            return Err(DBError::InvalidOptions(
+                "level factor must be positive".to_string(),
+            ));
+        }
+        let configured = self.level_compression.iter().flatten();
+        if let Some(compression) = configured
+            .chain([&self.compression])
+            .find(|compression| !compression.is_supported())
+        {
+            return Err(DBError::InvalidOptions(format!(
+                "{compression:?} compression is not compiled in"
+            )));
+        }
+        Ok(())
+    }
 }
 
 impl Database {
@@ -82,101 +891,5500 @@ impl Database {
         DatabaseOptions::new()
     }
 
-    pub fn init(options: DatabaseOptions) -> Result<Self> {
-        let (wal, rw_memtable) = WriteAheadLog::load_dir(&options.working_dir)?;
-        let ro_memtable = MemTable::new(); // TODO: fill with latest sst?
-        Ok(Self {
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(name = "open", skip_all, fields(dir = %options.working_dir.display()))
+    )]
+    pub fn init(mut options: DatabaseOptions) -> Result<Self> {
+        options.validate()?;
+        if options.in_memory {
+            options.storage = SharedStorage(Arc::new(MemStorage::new()));
+            options.mmap_reads = false;
+            options.use_direct_io = false;
+        }
+        let lock = Self::lock_working_dir(&options)?;
+        Self::check_comparator(&options)?;
+        let epoch = Self::read_epoch(&options)?;
+        if options.paranoid_checks {
+            Self::verify_wals(&options)?;
+        }
+        let mirror = Self::recover_flush(
+            &options.working_dir,
+            &options.storage.0,
+            &options.comparator,
+        )?;
+        if options.delete_orphan_files {
+            let orphans = OrphanFiles::find(
+                &options.working_dir,
+                &options.storage.0,
+                &options.comparator,
+            )?;
+            orphans.delete(&*options.storage.0)?;
+            trace::info!(
+                temp_files = orphans.temp_files.len(),
+                partial_tables = orphans.partial_tables.len(),
+                flushed_wals = orphans.flushed_wals.len(),
+                "deleted orphan files"
+            );
+        }
+        let mut recycled_wals =
+            utils::scan_storage(&*options.storage.0, &options.working_dir, &["recycled"])?;
+        for path in recycled_wals.split_off(options.recycle_wals.min(recycled_wals.len())) {
+            options.storage.0.delete(&path)?;
+        }
+        let new_wal = Self::create_wal(&options, &mut recycled_wals)?;
+        let (wal, mut rw_memtable) = WriteAheadLog::load_dir_into(
+            &options.working_dir,
+            &*options.storage.0,
+            new_wal,
+            options.unknown_wal_records,
+        )?;
+        rw_memtable.set_order(options.comparator.clone());
+        let level_count = options.level_num.max(1) + usize::from(options.allow_ingest_behind);
+        let mut on_disk_levels = vec![Vec::new(); level_count];
+        for mut table in Self::find_existing_ssts(&options.working_dir, &options.storage.0)? {
+            table.set_block_cache(options.block_cache.clone());
+            table.set_key_order(options.comparator.clone())?;
+            if options.mmap_reads {
+                table.map()?;
+            }
+            if !table.check_key_range(false)? {
+                return Err(DBError::SstKeyRangeMismatch(table.path));
+            }
+            // every record is checked against its checksum, lookup table and metadata key range
+            if options.paranoid_checks {
+                if let Some(Corruption { offset, error }) = table.verify()? {
+                    let path = table.path;
+                    return Err(DBError::Corruption {
+                        path,
+                        offset,
+                        error,
+                    });
+                }
+            }
+            let level = table.metadata.level;
+            if level >= on_disk_levels.len() {
+                on_disk_levels.resize(level + 1, Vec::new());
+            }
+            on_disk_levels[level].push(table);
+        }
+        for level in on_disk_levels.iter_mut() {
+            level.sort_by(|a, b| utils::compare_file_names(&a.path, &b.path));
+        }
+        let last_sequence = rw_memtable
+            .iter()
+            .map(|entry| entry.timestamp)
+            .chain(
+                on_disk_levels
+                    .iter()
+                    .flatten()
+                    .map(|table| table.metadata.max_timestamp),
+            )
+            .max()
+            .unwrap_or(0);
+        trace::info!(
+            tables = on_disk_levels.iter().map(Vec::len).sum::<usize>(),
+            memtable_entries = rw_memtable.len(),
+            last_sequence = last_sequence as u64,
+            "opened database"
+        );
+        let log = Arc::new(Mutex::new(CommitLog {
             wal,
+            last_sequence,
+            sequence_times: SequenceTimes::new(last_sequence, options.clock.0.now()),
+        }));
+        let db = Self {
+            _wal_syncer: (!options.in_memory)
+                .then(|| WalSyncer::start(log.clone(), options.wal_sync_policy))
+                .flatten(),
+            log,
+            recycled_wals,
+            _lock: lock,
             rw_memtable,
-            ro_memtable,
+            immutable_memtables: (!mirror.is_empty())
+                .then(|| Arc::new(mirror))
+                .into_iter()
+                .collect(),
+            on_disk_levels,
+            dropped_tombstones_timestamp: 0,
+            latencies: Arc::new(LatencyRecorder::new()),
+            iterators: IteratorRegistry::new(
+                options.iterator_leak_threshold,
+                options.capture_iterator_backtraces,
+                options
+                    .event_listener
+                    .as_ref()
+                    .map(|listener| listener.0.clone()),
+            ),
+            filter_counters: FilterCounters::default(),
+            cache_advisor: options
+                .cache_advisor
+                .as_ref()
+                .map(|(capacities, rate)| Mutex::new(CacheAdvisor::new(capacities, *rate))),
+            scheduler: (options.background_threads > 0)
+                .then(|| JobScheduler::new(options.background_threads)),
+            pending_flushes: VecDeque::new(),
+            pending_compaction: None,
+            sst_paths: SstPaths {
+                storage: options.storage.0.clone(),
+                dir: options.working_dir.clone(),
+                reserved: Arc::default(),
+            },
+            scrub_cursor: 0,
+            pending_repairs: Mutex::new(Vec::new()),
+            subscriptions: Subscriptions::default(),
+            tracer: Tracer::default(),
+            epoch,
             options,
-            on_disk_levels: todo this,
-        })
+        };
+        if db.options.preload_index {
+            db.warm_cache()?;
+        }
+        Ok(db)
     }
 
-    // TODO: async io, async swapping and compaction
+    // TODO: async io
 
+    /// Empty key and empty value are regular data, empty value is not the same as a deleted key
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(level = "trace", skip_all, fields(key_len = key.len(), value_len = value.len()))
+    )]
     pub fn put(&mut self, key: Vec<u8>, value: Vec<u8>) -> Result<()> {
-        let timestamp = timestamp_now();
-        self.wal.put(timestamp, &key, &value)?;
-        self.rw_memtable.put(timestamp, key, value);
-
-        if self.rw_memtable.data_size > self.options.memtable_threshold {
-            self.swap_memtable()?;
-        }
+        self.put_with_meta(key, value, Vec::new())
+    }
 
+    /// Same as `put`, entry carries up to `MAX_META_SIZE` bytes of metadata stored next to value
+    /// and returned by `query_with_meta` and `DbCursor::entry`. Value transformers don't touch it
+    pub fn put_with_meta(&mut self, key: Vec<u8>, value: Vec<u8>, meta: Vec<u8>) -> Result<()> {
+        let start = Stopwatch::start();
+        let write = self.prepare_put(key, value, meta)?;
+        self.commit(write)?;
+        self.latencies.record_since(Operation::Put, start);
         Ok(())
     }
 
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(level = "trace", skip_all, fields(key_len = key.len()))
+    )]
     pub fn delete(&mut self, key: Vec<u8>) -> Result<()> {
-        let timestamp = timestamp_now();
-        self.wal.delete(timestamp, &key)?;
-        self.rw_memtable.delete(timestamp, key);
+        let start = Stopwatch::start();
+        let write = self.prepare_delete(key)?;
+        self.commit(write)?;
+        self.latencies.record_since(Operation::Delete, start);
+        Ok(())
+    }
 
-        if self.rw_memtable.data_size > self.options.memtable_threshold {
-            self.swap_memtable()?;
+    /// Checks put and encodes its value, write takes effect once logged and applied
+    pub(crate) fn prepare_put(
+        &self,
+        key: Vec<u8>,
+        value: Vec<u8>,
+        meta: Vec<u8>,
+    ) -> Result<PreparedWrite> {
+        self.check_write(&key, WriteKind::Put)?;
+        if meta.len() > MAX_META_SIZE {
+            return Err(DBError::MetaTooLarge(meta.len()));
         }
+        let change = self
+            .subscriptions
+            .is_watched(&key)
+            .then(|| (key.clone(), Some(value.clone())));
+        self.tracer.record(TraceOp::Put, &key, value.len() as u64);
+        let value = self.options.value_transformers.encode(&key, value)?;
+        Ok(PreparedWrite {
+            key,
+            value: Some(value),
+            meta,
+            change,
+        })
+    }
 
-        Ok(())
+    pub(crate) fn prepare_delete(&self, key: Vec<u8>) -> Result<PreparedWrite> {
+        self.check_write(&key, WriteKind::Delete)?;
+        self.tracer.record(TraceOp::Delete, &key, 0);
+        let change = self
+            .subscriptions
+            .is_watched(&key)
+            .then(|| (key.clone(), None));
+        Ok(PreparedWrite {
+            key,
+            value: None,
+            meta: Vec::new(),
+            change,
+        })
     }
 
-    pub fn query(&self, key: Vec<u8>) -> Result<Vec<u8>> {
-        todo!()
+    fn commit(&mut self, write: PreparedWrite) -> Result<()> {
+        let timestamp = self.log_group([&write].into_iter())?;
+        self.apply(timestamp, write);
+        self.swap_memtable_if_full()
     }
 
-    /// Swapping logic:
-    /// 1) rw memtable overflows
-    /// 2) (async) ro memtable is sent to dumping queue, when dump is completed its wal file is deleted
-    /// 3) ro memtable is replaced with current rw memtable, new wal is created for new rw memtable
-    pub fn swap_memtable(&mut self) -> Result<()> {
-        self.ro_memtable = MemTable::new();
-        let old_wal_path = self.wal.path.clone();
-        assert!(old_wal_path.exists());
-        self.wal = WriteAheadLog::new(&self.options.working_dir)?;
-        mem::swap(&mut self.rw_memtable, &mut self.ro_memtable);
-
-        let timestamp = timestamp_now();
-        let level = 0;
-        let save_path = self.options.working_dir.join(format!("{timestamp}.sst"));
-        assert!(!save_path.exists(), "trying to create sst file that already exists");
-        let mut out_file = File::options().write(true).create(true).open(save_path)?;
-        for entry in self.ro_memtable.entries.iter() {
-            CommonBinaryFormatRef::new(
-                entry.timestamp,
-                &entry.key,
-                entry.value.as_ref().map(|vec| vec.as_ref()),
-            )
-            .write(&mut out_file)?;
+    /// Assigns consecutive sequences to writes and appends them to wal with a single sync,
+    /// returns sequence of the first one. Writes are inserted into memtable by `apply` afterwards
+    pub(crate) fn log_group<'a>(
+        &self,
+        writes: impl ExactSizeIterator<Item = &'a PreparedWrite>,
+    ) -> Result<u128> {
+        let mut log = self.log();
+        let first = log.take_sequences(writes.len() as u128, self.options.clock.0.now());
+        if !self.options.in_memory {
+            for (write, timestamp) in writes.zip(first..) {
+                write.append_to(&mut log.wal, timestamp)?;
+            }
+            log.wal.sync_if_needed(self.options.wal_sync_policy)?;
         }
-        fs::remove_file(old_wal_path)?;
-        Ok(())
+        Ok(first)
     }
 
-    fn find_existing_ssts(&mut self, working_dir: impl AsRef<Path>) -> Result<Vec<(PathBuf, SstMetadata)>> {
-        let mut found = Vec::new();
-        for file in utils::scan_dir(working_dir.as_ref(), &["sst"])? {
-            let mut reader = BufReader::new(File::open(&file)?);
-            let meta = SstMetadata::read(reader)?;
-            found.push((file, meta));
+    /// Inserts logged write into rw memtable and notifies subscribers, memtable takes inserts
+    /// of concurrent writers
+    pub(crate) fn apply(&self, timestamp: u128, write: PreparedWrite) {
+        match write.value {
+            Some(value) => self
+                .rw_memtable
+                .put_with_meta(timestamp, write.key, value, write.meta),
+            None => self.rw_memtable.delete(timestamp, write.key),
         }
-        Ok(found)
+        self.publish(timestamp, write.change);
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    #[test]
-    fn swapping_memtable_works() {
+    pub(crate) fn memtable_full(&self) -> bool {
+        self.rw_memtable.size() > self.options.memtable_threshold
+    }
+
+    /// Installs finished background work and swaps memtable once it outgrows threshold
+    pub(crate) fn swap_memtable_if_full(&mut self) -> Result<()> {
+        self.poll_background_work()?;
+        if self.memtable_full() {
+            self.swap_memtable()?;
+        }
+        Ok(())
+    }
+
+    pub(crate) fn latency_recorder(&self) -> Arc<LatencyRecorder> {
+        self.latencies.clone()
+    }
+
+    fn log(&self) -> MutexGuard<'_, CommitLog> {
+        self.log.lock().expect("commit log mutex poisoned")
+    }
+
+    /// Appends write to wal and syncs it as configured, skipped by in memory database
+    fn log_write(&self, append: impl FnOnce(&mut WriteAheadLog) -> io::Result<()>) -> Result<()> {
+        if self.options.in_memory {
+            return Ok(());
+        }
+        let wal = &mut self.log().wal;
+        append(wal)?;
+        Ok(wal.sync_if_needed(self.options.wal_sync_policy)?)
+    }
+
+    /// Deletes every key within [start, end) with a single range tombstone, empty range is a no-op.
+    /// Internal keyspace is never deleted
+    pub fn delete_range(&mut self, start: Vec<u8>, end: Vec<u8>) -> Result<()> {
+        if self.options.comparator.le(&end, &start) {
+            return Ok(());
+        }
+        self.check_write(&start, WriteKind::DeleteRange)?;
+        if self.tracer.is_active() {
+            let op = TraceOp::DeleteRange { end: end.clone() };
+            self.tracer.record(op, &start, 0);
+        }
+        let mut batch = WriteBatch::new();
+        batch.put_internal(&RangeTombstone::record_key(&start, &end), Vec::new());
+        self.write(batch)
+    }
+
+    /// Applies all ops of batch under a single timestamp, readers never observe a part of it
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(level = "trace", skip_all, fields(ops = batch.len()))
+    )]
+    pub fn write(&mut self, batch: WriteBatch) -> Result<()> {
+        if let Some(key) = batch.reserved_key() {
+            return Err(DBError::ReservedKey(key.to_vec()));
+        }
+        if batch.is_empty() {
+            return Ok(());
+        }
+        // internal ops come from subsystems and are not subject to write guard
+        for (key, value) in batch
+            .iter()
+            .filter(|(key, _)| !keyspace::is_internal_key(key))
+        {
+            let kind = match value {
+                Some(_) => WriteKind::Put,
+                None => WriteKind::Delete,
+            };
+            self.check_write(key, kind)?;
+        }
+        for (key, value) in batch
+            .iter()
+            .filter(|(key, _)| self.tracer.is_active() && !keyspace::is_internal_key(key))
+        {
+            match value {
+                Some(value) => self.tracer.record(TraceOp::Put, key, value.len() as u64),
+                None => self.tracer.record(TraceOp::Delete, key, 0),
+            }
+        }
+        let changes: Vec<_> = batch
+            .iter()
+            .filter(|(key, _)| {
+                !keyspace::is_internal_key(key) && self.subscriptions.is_watched(key)
+            })
+            .map(|(key, value)| (key.to_vec(), value.map(<[u8]>::to_vec)))
+            .collect();
+        let batch = self.options.value_transformers.encode_batch(batch)?;
+        let timestamp = self.next_sequence();
+        self.log_write(|wal| wal.write_batch(timestamp, &batch))?;
+        for (key, value) in batch.into_ops() {
+            match value {
+                Some(value) => self.rw_memtable.put(timestamp, key, value),
+                None => self.rw_memtable.delete(timestamp, key),
+            }
+        }
+        self.publish(timestamp, changes);
+        self.swap_memtable_if_full()
+    }
+
+    /// Receives every committed put and delete of keys starting with prefix, empty prefix
+    /// watches all keys. Range deletions are not expanded into events of deleted keys.
+    /// Subscription ends when receiver is dropped
+    pub fn subscribe(&self, prefix: Vec<u8>) -> mpsc::Receiver<ChangeEvent> {
+        self.subscriptions.subscribe(prefix)
+    }
+
+    fn publish(
+        &self,
+        timestamp: u128,
+        changes: impl IntoIterator<Item = (Vec<u8>, Option<Vec<u8>>)>,
+    ) {
+        self.subscriptions
+            .publish(changes.into_iter().map(|(key, value)| ChangeEvent {
+                key,
+                value,
+                timestamp,
+            }));
+    }
+
+    /// Records every get, put, delete, range deletion and scan to writer until `end_trace`,
+    /// values are recorded by size only. Trace in progress is ended first
+    pub fn start_trace(&self, writer: impl io::Write + Send + 'static) -> Result<()> {
+        Ok(self.tracer.start(Box::new(writer))?)
+    }
+
+    /// Ends trace in progress, returns the first error of writing to it
+    pub fn end_trace(&self) -> Result<()> {
+        Ok(self.tracer.end()?)
+    }
+
+    /// Applies operations of trace recorded by `start_trace`, written values are filled to their
+    /// traced size. If `paced` is set operations are issued at their traced offsets from start,
+    /// otherwise as fast as possible
+    pub fn replay_trace(&mut self, reader: impl io::Read, paced: bool) -> Result<ReplayReport> {
+        let mut reader = io::BufReader::new(reader);
+        workload::read_header(&mut reader)?;
+        let start = Stopwatch::start();
+        let mut report = ReplayReport::default();
+        while let Some(record) = TraceRecord::read(&mut reader)? {
+            if let Some(wait) = record
+                .timestamp
+                .checked_sub(start.elapsed())
+                .filter(|_| paced)
+            {
+                thread::sleep(wait);
+            }
+            match record.op {
+                TraceOp::Get => drop(self.query(&record.key)?),
+                TraceOp::Put => self.put(record.key, workload::filler(record.value_size))?,
+                TraceOp::Delete => self.delete(record.key)?,
+                TraceOp::DeleteRange { end } => self.delete_range(record.key, end)?,
+                TraceOp::Scan => {
+                    let mut left = record.value_size;
+                    self.for_each_live(record.key.., false, |_, _| {
+                        left = left.saturating_sub(1);
+                        Ok(left > 0)
+                    })?;
+                }
+            }
+            report.operations += 1;
+        }
+        report.elapsed = start.elapsed();
+        Ok(report)
+    }
+
+    /// Applies write received from primary under its original sequence, returns false if
+    /// the sequence is already applied
+    #[cfg(feature = "std-fs")]
+    pub(crate) fn apply_replicated(&mut self, record: WalRecord) -> Result<bool> {
+        if record.sequence <= self.log().last_sequence {
+            return Ok(false);
+        }
+        // sequence is assigned by primary
+        self.log().last_sequence = record.sequence - 1;
+        let timestamp = self.next_sequence();
+        let changes: Vec<_> = record
+            .entries
+            .iter()
+            .filter(|entry| {
+                !keyspace::is_internal_key(&entry.key) && self.subscriptions.is_watched(&entry.key)
+            })
+            .map(|entry| (entry.key.clone(), entry.value.clone()))
+            .collect();
+        match <[WriteAheadLogEntry; 1]>::try_from(record.entries) {
+            // single writes keep their metadata
+            Ok([entry]) => {
+                let transformers = &self.options.value_transformers;
+                let value = (entry.value)
+                    .map(|value| transformers.encode(&entry.key, value))
+                    .transpose()?;
+                self.log_write(|wal| match &value {
+                    Some(value) => wal.put_with_meta(timestamp, &entry.key, value, &entry.meta),
+                    None => wal.delete(timestamp, &entry.key),
+                })?;
+                match value {
+                    Some(value) => self
+                        .rw_memtable
+                        .put_with_meta(timestamp, entry.key, value, entry.meta),
+                    None => self.rw_memtable.delete(timestamp, entry.key),
+                }
+            }
+            Err(entries) => {
+                let ops = entries
+                    .into_iter()
+                    .map(|entry| (entry.key, entry.value))
+                    .collect();
+                let batch = self
+                    .options
+                    .value_transformers
+                    .encode_batch(WriteBatch::from_ops(ops))?;
+                self.log_write(|wal| wal.write_batch(timestamp, &batch))?;
+                for (key, value) in batch.into_ops() {
+                    match value {
+                        Some(value) => self.rw_memtable.put(timestamp, key, value),
+                        None => self.rw_memtable.delete(timestamp, key),
+                    }
+                }
+            }
+        }
+        self.publish(timestamp, changes);
+        self.swap_memtable_if_full()?;
+        Ok(true)
+    }
+
+    /// Replaces value of key with `new` only if current value equals `expected`, None stands
+    /// for a missing key on both sides, returns whether value was replaced
+    pub fn compare_and_swap(
+        &mut self,
+        key: Vec<u8>,
+        expected: Option<&[u8]>,
+        new: Option<Vec<u8>>,
+    ) -> Result<bool> {
+        if self.query(&key)?.as_deref() != expected {
+            return Ok(false);
+        }
+        match new {
+            Some(value) => self.put(key, value)?,
+            None => self.delete(key)?,
+        }
+        Ok(true)
+    }
+
+    /// Applies serialized batch only if the last committed write is `expected_sequence`,
+    /// returns new sequence of database
+    pub fn apply_batch_if(&mut self, batch: &[u8], expected_sequence: u128) -> Result<u128> {
+        let batch = WriteBatch::from_bytes(batch)?;
+        let actual = self.log().last_sequence;
+        if actual != expected_sequence {
+            return Err(DBError::SequenceMismatch {
+                expected: expected_sequence,
+                actual,
+            });
+        }
+        self.write(batch)?;
+        Ok(self.log().last_sequence)
+    }
+
+    /// Sequence of the last committed write
+    pub fn last_sequence(&self) -> u128 {
+        self.log().last_sequence
+    }
+
+    /// Starts optimistic transaction, conflicts are detected on commit
+    pub fn transaction(&self) -> Txn {
+        Txn::new(self.last_sequence())
+    }
+
+    /// Timestamp of the last write to key, keys with unknown history report the newest dropped tombstone
+    pub(crate) fn last_commit_timestamp(&self, key: &[u8]) -> Result<u128> {
+        let version = newest_version(
+            &self.options.comparator,
+            &self.memtables(),
+            &self.on_disk_levels,
+            key,
+        )?;
+        Ok(match version {
+            Some((timestamp, _)) => timestamp,
+            None => self.dropped_tombstones_timestamp,
+        })
+    }
+
+    fn check_write(&self, key: &[u8], kind: WriteKind) -> Result<()> {
+        if keyspace::is_internal_key(key) {
+            return Err(DBError::ReservedKey(key.to_vec()));
+        }
+        if let Some(KeyValidator(validator)) = &self.options.key_validator {
+            if let Err(reason) = validator(key) {
+                return Err(DBError::InvalidKey {
+                    key: key.to_vec(),
+                    reason,
+                });
+            }
+        }
+        match &self.options.write_guard {
+            Some(WriteGuard(guard)) if !guard(key, kind) => {
+                Err(DBError::PermissionDenied(key.to_vec()))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Versions are ordered by sequence, wall clock time is only sampled alongside
+    fn next_sequence(&mut self) -> u128 {
+        let now = self.options.clock.0.now();
+        self.log().take_sequences(1, now)
+    }
+
+    /// Wall clock time in microseconds by which write with given sequence was committed,
+    /// precise to about a second, writes made before database was opened get the open time
+    pub fn approximate_commit_time(&self, sequence: u128) -> Option<u128> {
+        let log = self.log();
+        if sequence > log.last_sequence {
+            return None;
+        }
+        let now = self.options.clock.0.now();
+        Some(log.sequence_times.time_of(sequence).unwrap_or(now))
+    }
+
+    /// Tombstones up to this sequence are older than configured grace
+    fn grace_sequence(&self) -> u128 {
+        let grace = self.options.tombstone_grace;
+        if grace.is_zero() {
+            return u128::MAX;
+        }
+        let grace_end = self.options.clock.0.now().saturating_sub(grace.as_micros());
+        self.log().sequence_times.sequence_at(grace_end)
+    }
+
+    /// Forces all wal writes to disk regardless of sync policy
+    pub fn sync_wal(&mut self) -> Result<()> {
+        Ok(self.log().wal.sync()?)
+    }
+
+    /// Committed writes from sequence `from_seq` on read from wal files in commit order, follow-up
+    /// calls pass sequence after the last returned one. Records already flushed to tables
+    /// are gone from wal, tail starting before them fails with `WalTruncated`
+    pub fn wal_tail(&mut self, from_seq: u128) -> Result<WalTail<'_>> {
+        self.log().wal.flush()?;
+        let storage = &*self.options.storage.0;
+        let mut wals = utils::scan_storage(storage, &self.options.working_dir, &["wal"])?;
+        wals.sort_by(|a, b| utils::compare_file_names(a, b));
+        let flushed = self
+            .on_disk_levels
+            .iter()
+            .flatten()
+            .map(|table| table.metadata.max_timestamp)
+            .max()
+            .unwrap_or(0);
+        Ok(WalTail {
+            storage,
+            wals: wals.into(),
+            entries: None,
+            policy: self.options.unknown_wal_records,
+            pending: None,
+            transformers: &self.options.value_transformers,
+            from_seq,
+            flushed,
+            checked: false,
+            done: false,
+        })
+    }
+
+    /// Looks up the newest version of key, memtables first, then levels from top to bottom
+    pub fn query(&self, key: impl AsRef<[u8]>) -> Result<Option<Vec<u8>>> {
+        let start = Stopwatch::start();
+        let key = key.as_ref();
+        let memtables = self.memtables();
+        let levels = &self.on_disk_levels;
+        let filters = Some(&self.filter_counters);
+        let order = &self.options.comparator;
+        let found = match &self.cache_advisor {
+            Some(advisor) => {
+                let mut advisor = advisor.lock().expect("cache advisor mutex poisoned");
+                newest_version_traced(order, &memtables, levels, key, filters, |table, key| {
+                    let (block, size) = table.read_interval(key);
+                    advisor.record_read(&table.path, block, size);
+                })
+            }
+            None => newest_version_traced(order, &memtables, levels, key, filters, |_, _| {}),
+        };
+        let found = self.note_corruption(found)?.and_then(|entry| entry.value);
+        let value = found
+            .map(|value| self.options.value_transformers.decode(key, value))
+            .transpose()?;
+        self.tracer.record(TraceOp::Get, key, 0);
+        self.latencies.record_since(Operation::Get, start);
+        Ok(value)
+    }
+
+    /// Same as `query`, value is returned together with metadata of entry, empty if it has none
+    pub fn query_with_meta(&self, key: impl AsRef<[u8]>) -> Result<Option<(Vec<u8>, Vec<u8>)>> {
+        let key = key.as_ref();
+        self.tracer.record(TraceOp::Get, key, 0);
+        let memtables = self.memtables();
+        let found = newest_entry(
+            &self.options.comparator,
+            &memtables,
+            &self.on_disk_levels,
+            key,
+        );
+        let Some(entry) = self.note_corruption(found)? else {
+            return Ok(None);
+        };
+        let Some(value) = entry.value else {
+            return Ok(None);
+        };
+        let value = self.options.value_transformers.decode(key, value)?;
+        Ok(Some((value, entry.meta)))
+    }
+
+    /// Same as `query`, but value borrows from memtable or memory-mapped table instead of
+    /// being copied, values of keys with value transformer are decoded into new buffer
+    pub fn query_pinned(&self, key: impl AsRef<[u8]>) -> Result<Option<PinnedValue<'_>>> {
+        let start = Stopwatch::start();
+        let key = key.as_ref();
+        let found = pinned_version(
+            &self.options.comparator,
+            &self.memtables(),
+            &self.on_disk_levels,
+            key,
+            &self.filter_counters,
+        );
+        let value = match self.note_corruption(found)? {
+            Some(value) if self.options.value_transformers.transforms(key) => {
+                let decoded = self
+                    .options
+                    .value_transformers
+                    .decode(key, value.into_vec())?;
+                Some(PinnedValue::owned(decoded))
+            }
+            value => value,
+        };
+        self.tracer.record(TraceOp::Get, key, 0);
+        self.latencies.record_since(Operation::Get, start);
+        Ok(value)
+    }
+
+    /// Queues table of read failed with `DBError::CorruptedTable` for repair if source is set
+    fn note_corruption<T>(&self, result: Result<T>) -> Result<T> {
+        if let (Err(DBError::CorruptedTable { path, .. }), Some(_)) =
+            (&result, &self.options.repair_source)
+        {
+            self.queue_repair(path.clone());
+        }
+        result
+    }
+
+    fn queue_repair(&self, path: PathBuf) {
+        let mut pending = self
+            .pending_repairs
+            .lock()
+            .expect("pending repairs mutex poisoned");
+        if !pending.contains(&path) {
+            pending.push(path);
+        }
+    }
+
+    /// Simulated hit rates of configured cache capacities, empty unless cache advisor is set
+    pub fn cache_advice(&self) -> Vec<CacheAdvice> {
+        self.cache_advisor
+            .as_ref()
+            .map_or_else(Vec::new, |advisor| {
+                advisor
+                    .lock()
+                    .expect("cache advisor mutex poisoned")
+                    .advice()
+            })
+    }
+
+    /// Every retained version of key from newest to oldest as commit sequence and value,
+    /// None value stands for a tombstone. Each memtable holds only the newest version of key,
+    /// older ones are retained once they were flushed to disk
+    pub fn get_versions(&self, key: impl AsRef<[u8]>) -> Result<Vec<(u128, Option<Vec<u8>>)>> {
+        let key = key.as_ref();
+        let mut versions = Vec::new();
+        let memtables = self.memtables();
+        // entries of mirror are on disk, where compaction decides which versions are retained
+        for memtable in &memtables[..=self.pending_flushes.len()] {
+            if let Some(entry) = memtable.get(key) {
+                versions.push((entry.timestamp, entry.value.map(<[u8]>::to_vec)));
+            }
+        }
+        for level in self.on_disk_levels.iter() {
+            for table in level.iter().rev() {
+                for entry in table.get_versions(key)? {
+                    versions.push((entry.timestamp, entry.value));
+                }
+            }
+        }
+        for tombstone in range_del::in_sources(&memtables, &self.on_disk_levels) {
+            if tombstone.covers(&self.options.comparator, key, 0) {
+                versions.push((tombstone.sequence, None));
+            }
+        }
+        versions.sort_by(|(a, _), (b, _)| b.cmp(a));
+        versions.dedup_by_key(|(timestamp, _)| *timestamp);
+        let transformers = &self.options.value_transformers;
+        versions
+            .into_iter()
+            .map(|(timestamp, value)| {
+                let value = value.map(|value| transformers.decode(key, value));
+                Ok((timestamp, value.transpose()?))
+            })
+            .collect()
+    }
+
+    /// Collects live key-value pairs within range in key order, internal keyspace is skipped
+    pub fn scan(&self, range: impl RangeBounds<Vec<u8>>) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        let start = Stopwatch::start();
+        let traced_from = self
+            .tracer
+            .is_active()
+            .then(|| range_start(&range).to_vec());
+        let mut entries = scan_sources(
+            &self.options.comparator,
+            &self.memtables(),
+            &self.on_disk_levels,
+            range,
+        )?;
+        entries.retain(|(key, _)| !keyspace::is_internal_key(key));
+        let entries = self.options.value_transformers.decode_pairs(entries)?;
+        if let Some(from) = traced_from {
+            self.tracer
+                .record(TraceOp::Scan, &from, entries.len() as u64);
+        }
+        self.latencies.record_since(Operation::Scan, start);
+        Ok(entries)
+    }
+
+    /// Live keys within range, values are not read from tables which makes it much cheaper
+    /// than `scan` for large values
+    pub fn scan_keys(&self, range: impl RangeBounds<Vec<u8>>) -> Result<Vec<Vec<u8>>> {
+        let mut keys = Vec::new();
+        self.for_each_live(range, true, |key, _| {
+            keys.push(key);
+            Ok(true)
+        })?;
+        Ok(keys)
+    }
+
+    /// Number of live keys within range, counted without reading values
+    pub fn count(&self, range: impl RangeBounds<Vec<u8>>) -> Result<usize> {
+        let mut count = 0;
+        self.for_each_live(range, true, |_, _| {
+            count += 1;
+            Ok(true)
+        })?;
+        Ok(count)
+    }
+
+    /// Folds live pairs within range in key order without collecting them
+    pub fn fold<B>(
+        &self,
+        range: impl RangeBounds<Vec<u8>>,
+        init: B,
+        mut f: impl FnMut(B, &[u8], &[u8]) -> B,
+    ) -> Result<B> {
+        let mut acc = Some(init);
+        self.for_each_live(range, false, |key, value| {
+            let value = self.options.value_transformers.decode(&key, value)?;
+            acc = acc.take().map(|acc| f(acc, &key, &value));
+            Ok(true)
+        })?;
+        Ok(acc.expect("accumulator is put back by every step"))
+    }
+
+    /// Page of at most `limit` live pairs within range that follow the `after` key,
+    /// the returned continuation is passed as `after` to fetch the next page
+    pub fn scan_page(
+        &self,
+        range: impl RangeBounds<Vec<u8>>,
+        limit: usize,
+        after: Option<Vec<u8>>,
+    ) -> Result<ScanPage> {
+        let order = &self.options.comparator;
+        let start = match after {
+            Some(after) if follows_start(order, &range, &after) => Bound::Excluded(after),
+            _ => range.start_bound().cloned(),
+        };
+        let limit = limit.max(1);
+        // one extra entry is read to tell whether the range continues after the page
+        let mut entries = Vec::with_capacity(limit + 1);
+        self.for_each_live((start, range.end_bound().cloned()), false, |key, value| {
+            entries.push((key, value));
+            Ok(entries.len() <= limit)
+        })?;
+        let continuation = match entries.len() > limit {
+            true => {
+                entries.truncate(limit);
+                entries.last().map(|(key, _)| key.clone())
+            }
+            false => None,
+        };
+        let entries = self.options.value_transformers.decode_pairs(entries)?;
+        Ok(ScanPage {
+            entries,
+            continuation,
+        })
+    }
+
+    /// Calls `f` with every live user pair within range in key order until it returns false,
+    /// values are left empty and not read from tables if `key_only` is set
+    fn for_each_live(
+        &self,
+        range: impl RangeBounds<Vec<u8>>,
+        key_only: bool,
+        mut f: impl FnMut(Vec<u8>, Vec<u8>) -> Result<bool>,
+    ) -> Result<()> {
+        let start = Stopwatch::start();
+        let order = &self.options.comparator;
+        let memtables = self.memtables();
+        let from = range_start(&range);
+        let entries = match key_only {
+            true => MergingIterator::keys(order, &memtables, &self.on_disk_levels, from)?,
+            false => MergingIterator::new(order, &memtables, &self.on_disk_levels, from)?,
+        };
+        let mut read = 0;
+        for entry in entries {
+            let entry = entry?;
+            if order.is_past_end(&range, &entry.key) {
+                break;
+            }
+            if let Some(value) = entry.value.filter(|_| {
+                order.contains(&range, &entry.key) && !keyspace::is_internal_key(&entry.key)
+            }) {
+                read += 1;
+                if !f(entry.key, value)? {
+                    break;
+                }
+            }
+        }
+        self.tracer.record(TraceOp::Scan, from, read);
+        self.latencies.record_since(Operation::Scan, start);
+        Ok(())
+    }
+
+    /// Streams live pairs within range as key-ordered chunks of roughly `max_bytes` of keys and values,
+    /// only one chunk is kept in memory at a time
+    #[track_caller]
+    pub fn scan_chunks<R: RangeBounds<Vec<u8>>>(
+        &self,
+        range: R,
+        max_bytes: usize,
+    ) -> Result<ScanChunks<'_, R>> {
+        let tracking = self.iterators.register(Location::caller());
+        let entries = MergingIterator::new(
+            &self.options.comparator,
+            &self.memtables(),
+            &self.on_disk_levels,
+            range_start(&range),
+        )?;
+        Ok(ScanChunks {
+            entries,
+            order: &self.options.comparator,
+            pending: None,
+            transformers: &self.options.value_transformers,
+            range,
+            max_bytes,
+            done: false,
+            _tracking: tracking,
+        })
+    }
+
+    /// Unpositioned cursor over live pairs, supports seeks in both directions and stepping back
+    #[track_caller]
+    pub fn cursor(&self) -> DbCursor<'_> {
+        DbCursor::new(
+            &self.options.comparator,
+            self.memtables(),
+            &self.on_disk_levels,
+            &self.options.value_transformers,
+            self.iterators.register(Location::caller()),
+        )
+    }
+
+    /// Iterators open for longer than configured threshold from the oldest one,
+    /// event listener is notified about the ones found for the first time
+    pub fn long_running_iterators(&self) -> Vec<IteratorInfo> {
+        self.iterators.long_running()
+    }
+
+    /// Looks up subsystem key in internal keyspace
+    pub fn query_internal(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        self.query(keyspace::internal_key(key))
+    }
+
+    /// Primary keys indexed by `indexed_value` in index maintained through `IndexedWrite`
+    pub fn index_lookup(&self, index: &[u8], indexed_value: &[u8]) -> Result<Vec<Vec<u8>>> {
+        let prefix = index::index_lookup_prefix(index, indexed_value);
+        Ok(self
+            .scan_internal(&prefix)?
+            .into_iter()
+            .map(|(key, _)| key[prefix.len()..].to_vec())
+            .collect())
+    }
+
+    /// Collects internal pairs with subsystem keys starting with prefix, keys are returned
+    /// without internal keyspace prefix
+    pub fn scan_internal(&self, prefix: &[u8]) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        let start = keyspace::internal_key(prefix);
+        let entries = scan_sources(
+            &self.options.comparator,
+            &self.memtables(),
+            &self.on_disk_levels,
+            start.clone()..,
+        )?;
+        Ok(entries
+            .into_iter()
+            .take_while(|(key, _)| key.starts_with(&start))
+            .map(|(key, value)| (key[keyspace::INTERNAL_KEY_PREFIX.len()..].to_vec(), value))
+            .collect())
+    }
+
+    /// Memtables from newest to oldest, the order reads consult them in
+    fn memtables(&self) -> Vec<&MemTable> {
+        iter::once(&self.rw_memtable)
+            .chain(self.immutable_memtables.iter().map(|memtable| &**memtable))
+            .collect()
+    }
+
+    /// Swapping logic:
+    /// 1) rw memtable overflows
+    /// 2) (async) memtable is frozen and queued for flush, when dump is completed its wal file is deleted
+    /// 3) new rw memtable and wal replace it, queue is bounded by `max_immutable_memtables`
+    #[cfg_attr(feature = "tracing", tracing::instrument(level = "debug", skip_all))]
+    pub fn swap_memtable(&mut self) -> Result<()> {
+        while self.pending_flushes.len() >= self.options.max_immutable_memtables {
+            self.collect_flush(true)?;
+        }
+        let old_wal_path = self.log().wal.path.clone();
+        assert!(self.options.storage.0.exists(&old_wal_path));
+        self.log().wal = Self::create_wal(&self.options, &mut self.recycled_wals)?;
+        let memtable = Arc::new(mem::replace(
+            &mut self.rw_memtable,
+            MemTable::with_order(self.options.comparator.clone()),
+        ));
+        // mirror of flushed table is replaced by the memtable of a newer one
+        self.immutable_memtables
+            .truncate(self.pending_flushes.len());
+        self.immutable_memtables.push_front(memtable.clone());
+        trace::debug!(
+            entries = memtable.len(),
+            bytes = memtable.size(),
+            wal = %old_wal_path.display(),
+            queued = self.pending_flushes.len(),
+            "froze memtable"
+        );
+
+        let writer = self.new_sst_writer(0);
+        let table_path = self.new_sst_path();
+        let mut flushes = self.pending_flush_files();
+        flushes.push((&old_wal_path, &table_path));
+        self.write_flush_marker(&flushes)?;
+        let mmap = self.options.mmap_reads;
+        let latencies = self.latencies.clone();
+        if let Some(scheduler) = &self.scheduler {
+            let (sender, result) = mpsc::channel();
+            let path = table_path.clone();
+            scheduler.submit(JobPriority::High, move || {
+                let _ = sender.send(write_memtable(&memtable, writer, path, mmap, &latencies));
+            });
+            self.pending_flushes.push_back(PendingFlush {
+                wal_path: old_wal_path,
+                table_path,
+                result: Mutex::new(result),
+            });
+            return Ok(());
+        }
+        if let Some(table) = write_memtable(&memtable, writer, table_path, mmap, &latencies)? {
+            self.on_disk_levels[0].push(table);
+        }
+        self.retire_wal(&old_wal_path)?;
+        self.write_flush_marker(&[])?;
+        self.maybe_compact()
+    }
+
+    /// Persists rw memtable to a table on level 0 and waits until it and every queued memtable
+    /// are installed, compactions they trigger may keep running in background
+    pub fn flush(&mut self) -> Result<()> {
+        if !self.rw_memtable.is_empty() {
+            self.swap_memtable()?;
+        }
+        let mut flushed = false;
+        while !self.pending_flushes.is_empty() {
+            flushed |= self.collect_flush(true)?;
+        }
+        if flushed {
+            self.maybe_compact()?;
+        }
+        Ok(())
+    }
+
+    /// Blocks until every queued flush and compaction is finished and installs their tables,
+    /// returns immediately when background threads are not configured
+    pub fn wait_for_background_work(&mut self) -> Result<()> {
+        while !self.pending_flushes.is_empty() || self.pending_compaction.is_some() {
+            self.collect_flush(true)?;
+            self.collect_compaction(true)?;
+            // installed tables may overflow levels again
+            self.maybe_compact()?;
+        }
+        Ok(())
+    }
+
+    /// Installs results of background jobs finished so far without blocking
+    fn poll_background_work(&mut self) -> Result<()> {
+        let mut collected = false;
+        while self.collect_flush(false)? {
+            collected = true;
+        }
+        if collected | self.collect_compaction(false)? {
+            self.maybe_compact()?;
+        }
+        self.repair_tables()?;
+        Ok(())
+    }
+
+    /// Wal in recyclable format when its files are preallocated or reused
+    fn create_wal(
+        options: &DatabaseOptions,
+        recycled_wals: &mut Vec<PathBuf>,
+    ) -> io::Result<WriteAheadLog> {
+        let storage = &*options.storage.0;
+        if options.wal_preallocate_size == 0 && options.recycle_wals == 0 {
+            return WriteAheadLog::new_with_storage(&options.working_dir, storage);
+        }
+        WriteAheadLog::new_recyclable(
+            &options.working_dir,
+            storage,
+            recycled_wals.pop(),
+            options.wal_preallocate_size,
+        )
+    }
+
+    /// Keeps file of wal whose memtable is flushed for reuse, deletes it once enough are kept
+    fn retire_wal(&mut self, path: &Path) -> io::Result<()> {
+        let storage = &*self.options.storage.0;
+        if self.recycled_wals.len() >= self.options.recycle_wals {
+            return storage.delete(path);
+        }
+        // renamed so that it isn't replayed on open
+        let recycled = utils::unique_storage_path(storage, &self.options.working_dir, "recycled");
+        storage.rename(path, &recycled)?;
+        self.recycled_wals.push(recycled);
+        Ok(())
+    }
+
+    /// Installs table of the oldest queued flush and removes wal of its memtable, which is kept
+    /// as mirror of the table. Flushes are installed in order, returns whether one was
+    fn collect_flush(&mut self, wait: bool) -> Result<bool> {
+        let Some(pending) = self.pending_flushes.front() else {
+            return Ok(false);
+        };
+        let table = match receive(&pending.result, wait) {
+            Ok(table) => table,
+            Err(TryRecvError::Empty) => return Ok(false),
+            // cancelled, wal stays for recovery
+            Err(TryRecvError::Disconnected) => {
+                self.pending_flushes.pop_front();
+                return Ok(false);
+            }
+        };
+        // memtable of installed flush is the last one, it replaces the previous mirror
+        self.immutable_memtables
+            .truncate(self.pending_flushes.len());
+        let pending = self.pending_flushes.pop_front().expect("flush is pending");
+        if let Some(table) = table? {
+            self.on_disk_levels[0].push(table);
+        }
+        trace::debug!(wal = %pending.wal_path.display(), "installed background flush");
+        self.retire_wal(&pending.wal_path)?;
+        self.write_flush_marker(&self.pending_flush_files())?;
+        Ok(true)
+    }
+
+    /// Installs merged table in place of its inputs, returns whether compaction is done
+    fn collect_compaction(&mut self, wait: bool) -> Result<bool> {
+        let Some(pending) = &self.pending_compaction else {
+            return Ok(false);
+        };
+        let output = match receive(&pending.result, wait) {
+            Ok(output) => output,
+            Err(TryRecvError::Empty) => return Ok(false),
+            // cancelled, inputs are left untouched
+            Err(TryRecvError::Disconnected) => {
+                self.pending_compaction = None;
+                return Ok(false);
+            }
+        };
+        let pending = self
+            .pending_compaction
+            .take()
+            .expect("compaction is pending");
+        trace::debug!(level = pending.level, "installed background compaction");
+        self.install_merge(pending.level, output?)?;
+        Ok(true)
+    }
+
+    /// Flushes memtable entries with keys in [start, end) to a table on level 0,
+    /// the rest of memtable stays in memory
+    pub fn flush_range(&mut self, start: &[u8], end: &[u8]) -> Result<()> {
+        self.wait_for_background_work()?;
+        let entries = self.rw_memtable.take_range(start, end);
+        // mirror of the newest flushed table must not shadow the new one
+        self.immutable_memtables.clear();
+        if entries.is_empty() {
+            return Ok(());
+        }
+        let mut writer = self.new_sst_writer(0);
+        for entry in entries.iter() {
+            writer.push(entry.as_cbf_ref())?;
+        }
+        let table = self.finish_sst(writer)?;
+        self.on_disk_levels[0].push(table);
+        self.maybe_compact()
+    }
+
+    /// Pushes tables overlapping [start, end) down level by level into the last level
+    pub fn compact_range(&mut self, start: &[u8], end: &[u8]) -> Result<()> {
+        self.wait_for_background_work()?;
+        for level in 0..self.compaction_levels() - 1 {
+            self.push_down(level, start, end)?;
+        }
+        Ok(())
+    }
+
+    /// Merges tables of level overlapping [start, end) into the next level,
+    /// returns number of merged tables
+    fn push_down(&mut self, level: usize, start: &[u8], end: &[u8]) -> Result<usize> {
+        let tables = self.take_overlapping(level, start, end);
+        // range tombstones reach keys below their records
+        let starts = tables
+            .iter()
+            .flat_map(|t| t.range_tombstones())
+            .map(|tombstone| tombstone.start.clone());
+        let order = &self.options.comparator;
+        let (Some(low), Some(high)) = (
+            tables
+                .iter()
+                .map(|t| t.metadata.low_key.clone())
+                .chain(starts)
+                .fold(None, |low, key| order.min(low, Some(key))),
+            tables
+                .iter()
+                .map(|t| t.metadata.high_key.clone())
+                .fold(None, |high, key| order.max(high, Some(key))),
+        ) else {
+            return Ok(0);
+        };
+        let count = tables.len();
+        let drop_tombstones = self.on_disk_levels[level + 1..]
+            .iter()
+            .flatten()
+            .all(|table| !table.overlaps(&low, &high));
+        self.merge_into_level(tables, level + 1, drop_tombstones)?;
+        Ok(count)
+    }
+
+    /// Runs periodic compaction and checksum scrubbing if maintenance schedule allows it now,
+    /// meant to be called periodically by application
+    pub fn run_maintenance(&mut self) -> Result<MaintenanceReport> {
+        let mut report = MaintenanceReport::default();
+        if !self
+            .options
+            .maintenance_schedule
+            .allows(self.options.clock.0.now())
+        {
+            return Ok(report);
+        }
+        self.wait_for_background_work()?;
+        report.ran = true;
+        report.compacted_tables = self.compact_periodically()?;
+        for table in self.scrub_tables()? {
+            report.scrubbed_tables += 1;
+            if let Some((path, corruption)) = table {
+                if let Some(listener) = &self.options.event_listener {
+                    listener.0.on_table_corruption(&path, &corruption);
+                }
+                if self.options.repair_source.is_some() {
+                    self.queue_repair(path.clone());
+                }
+                report.corrupted_tables.push(path);
+            }
+        }
+        report.repaired_tables = self.repair_tables()?;
+        Ok(report)
+    }
+
+    /// Pushes tables whose newest data is older than periodic compaction age down level
+    /// by level, returns number of merged tables
+    fn compact_periodically(&mut self) -> Result<usize> {
+        let age = self.options.periodic_compaction;
+        if age.is_zero() || self.options.compaction_style != CompactionStyle::Leveled {
+            return Ok(0);
+        }
+        let Some(deadline) = self.options.clock.0.now().checked_sub(age.as_micros()) else {
+            return Ok(0);
+        };
+        // sequences are sampled sparsely, so tables may be pushed down somewhat later than due
+        let expired_sequence = self.log().sequence_times.sequence_at(deadline);
+        let mut compacted = 0;
+        for level in 0..self.compaction_levels() - 1 {
+            let expired: Vec<_> = self.on_disk_levels[level]
+                .iter()
+                .filter(|table| table.metadata.max_timestamp <= expired_sequence)
+                .map(|table| {
+                    (
+                        table.metadata.low_key.clone(),
+                        table.metadata.high_key.clone(),
+                    )
+                })
+                .collect();
+            for (low, high) in expired {
+                // the smallest key above high
+                let mut end = high;
+                end.push(0);
+                compacted += self.push_down(level, &low, &end)?;
+            }
+        }
+        Ok(compacted)
+    }
+
+    /// Verifies the next tables in round robin, returns path and corruption of corrupted ones
+    fn scrub_tables(&mut self) -> Result<Vec<Option<(PathBuf, Corruption)>>> {
+        let tables: Vec<_> = self.on_disk_levels.iter().flatten().collect();
+        if tables.is_empty() {
+            return Ok(Vec::new());
+        }
+        let count = self.options.scrub_tables_per_run.min(tables.len());
+        let mut scrubbed = Vec::with_capacity(count);
+        for idx in 0..count {
+            let table = tables[(self.scrub_cursor + idx) % tables.len()];
+            let corruption = table.verify()?;
+            scrubbed.push(corruption.map(|corruption| (table.path.clone(), corruption)));
+        }
+        self.scrub_cursor = (self.scrub_cursor + count) % tables.len();
+        Ok(scrubbed)
+    }
+
+    /// Rewrites tables queued for repair from repair source, returns paths of the new tables.
+    /// Tables are queued by point reads failing checksum verification and by scrubbing,
+    /// repairs run after writes and maintenance too
+    pub fn repair_tables(&mut self) -> Result<Vec<PathBuf>> {
+        let Some(SharedRepairSource(source)) = self.options.repair_source.clone() else {
+            return Ok(Vec::new());
+        };
+        let pending = mem::take(
+            &mut *self
+                .pending_repairs
+                .lock()
+                .expect("pending repairs mutex poisoned"),
+        );
+        if pending.is_empty() {
+            return Ok(Vec::new());
+        }
+        // background merge may be reading the damaged table
+        self.wait_for_background_work()?;
+        let mut repaired = Vec::new();
+        for (idx, path) in pending.iter().enumerate() {
+            match self.repair_table(&*source, path) {
+                Ok(table) => repaired.extend(table),
+                Err(error) => {
+                    // retried by the next call
+                    for path in &pending[idx..] {
+                        self.queue_repair(path.clone());
+                    }
+                    return Err(error);
+                }
+            }
+        }
+        Ok(repaired)
+    }
+
+    /// Replaces table with one written from versions fetched for its key range, None if table
+    /// is gone already. Fetched versions newer than the table are left out, older ones
+    /// are kept as they are shadowed by newer tables anyway
+    fn repair_table(&mut self, source: &dyn RepairSource, path: &Path) -> Result<Option<PathBuf>> {
+        let Some((level, position)) =
+            self.on_disk_levels
+                .iter()
+                .enumerate()
+                .find_map(|(level, tables)| {
+                    let position = tables.iter().position(|table| table.path == path)?;
+                    Some((level, position))
+                })
+        else {
+            return Ok(None);
+        };
+        let metadata = &self.on_disk_levels[level][position].metadata;
+        let (low, high) = (metadata.low_key.clone(), metadata.high_key.clone());
+        let max_timestamp = metadata.max_timestamp;
+        let order = &self.options.comparator;
+        let mut entries: Vec<_> = source
+            .fetch_range(&low, &high)?
+            .into_iter()
+            .filter(|entry| {
+                order.le(&low, &entry.key)
+                    && order.le(&entry.key, &high)
+                    && entry.timestamp <= max_timestamp
+            })
+            .collect();
+        if entries.is_empty() {
+            return Err(DBError::InvalidData(format!(
+                "repair source has no data for table {}",
+                path.display()
+            )));
+        }
+        entries.sort_by(|a, b| (order.cmp(&a.key, &b.key)).then(b.timestamp.cmp(&a.timestamp)));
+        entries.dedup_by(|a, b| a.key == b.key && a.timestamp == b.timestamp);
+        let mut writer = self.new_sst_writer(level);
+        for entry in &entries {
+            writer.push(entry.as_cbf_ref())?;
+        }
+        let table = self.finish_sst(writer)?;
+        let repaired = table.path.clone();
+        let damaged = mem::replace(&mut self.on_disk_levels[level][position], table);
+        if let Some(cache) = &self.options.block_cache {
+            cache.evict_table(&damaged.path);
+        }
+        damaged.delete()?;
+        if let Some(listener) = &self.options.event_listener {
+            listener.0.on_table_repaired(path, &repaired);
+        }
+        Ok(Some(repaired))
+    }
+
+    /// Removes tables overlapping [start, end) from level along with older tables they overlap,
+    /// otherwise those older tables would shadow newer data once it's moved below
+    fn take_overlapping(&mut self, level: usize, start: &[u8], end: &[u8]) -> Vec<SstReader> {
+        let tables = &self.on_disk_levels[level];
+        let mut picked: Vec<_> = tables
+            .iter()
+            .map(|table| table.overlaps_range(start, end))
+            .collect();
+        let mut changed = true;
+        while changed {
+            changed = false;
+            for newer in 0..tables.len() {
+                if !picked[newer] {
+                    continue;
+                }
+                let meta = &tables[newer].metadata;
+                for older in 0..newer {
+                    if !picked[older] && tables[older].overlaps(&meta.low_key, &meta.high_key) {
+                        picked[older] = true;
+                        changed = true;
+                    }
+                }
+            }
+        }
+        let (taken, kept): (Vec<_>, Vec<_>) = mem::take(&mut self.on_disk_levels[level])
+            .into_iter()
+            .zip(picked)
+            .partition(|(_, picked)| *picked);
+        self.on_disk_levels[level] = kept.into_iter().map(|(table, _)| table).collect();
+        taken.into_iter().map(|(table, _)| table).collect()
+    }
+
+    /// Adds externally built tables to database without going through memtable,
+    /// each table is placed on the deepest level where it doesn't overlap with newer data
+    #[cfg(feature = "std-fs")]
+    pub fn ingest_sst(&mut self, paths: &[impl AsRef<Path>]) -> Result<()> {
+        self.wait_for_background_work()?;
+        let tables = open_ingested(&self.options.comparator, paths)?;
+
+        // ingested data is newer than anything in memtable, so overlapping memtable goes to disk first
+        let overlaps_memtable = tables.iter().any(|table| {
+            self.rw_memtable
+                .iter()
+                .any(|entry| table.overlaps(entry.key, entry.key))
+        });
+        if overlaps_memtable {
+            self.swap_memtable()?;
+        }
+        // mirror of the newest flushed table must not shadow ingested data
+        self.immutable_memtables
+            .truncate(self.pending_flushes.len());
+
+        // tables of one call don't overlap, so they share a single sequence
+        let sequence = self.next_sequence();
+        for table in tables {
+            let (low, high) = (&table.metadata.low_key, &table.metadata.high_key);
+            let first_overlapping = self
+                .on_disk_levels
+                .iter()
+                .position(|level| level.iter().any(|other| other.overlaps(low, high)));
+            let level = match first_overlapping {
+                Some(level) => level.saturating_sub(1),
+                None => self.compaction_levels() - 1,
+            };
+            self.install_ingested(&table, level, sequence)?;
+        }
+        self.maybe_compact()
+    }
+
+    /// Adds externally built tables to the reserved bottom level, beneath all existing data,
+    /// so keys already in database shadow ingested ones and no compaction is triggered.
+    /// Tables ingested behind later shadow the ones ingested behind earlier
+    #[cfg(feature = "std-fs")]
+    pub fn ingest_behind(&mut self, paths: &[impl AsRef<Path>]) -> Result<()> {
+        if !self.options.allow_ingest_behind {
+            return Err(DBError::IngestBehindDisabled);
+        }
+        self.wait_for_background_work()?;
+        let bottom = self.on_disk_levels.len() - 1;
+        for table in open_ingested(&self.options.comparator, paths)? {
+            // older than any committed write
+            self.install_ingested(&table, bottom, 0)?;
+        }
+        Ok(())
+    }
+
+    /// Rewrites external table into database directory with every entry stamped by `sequence`
+    /// and places it on level as the newest table
+    #[cfg(feature = "std-fs")]
+    fn install_ingested(&mut self, table: &SstReader, level: usize, sequence: u128) -> Result<()> {
+        let mut writer = self.new_sst_writer(level);
+        for entry in table.iter()? {
+            let entry = entry?;
+            writer.push(CommonBinaryFormatRef {
+                timestamp: sequence,
+                ..entry.as_cbf_ref()
+            })?;
+        }
+        let ingested = self.finish_sst(writer)?;
+        self.on_disk_levels[level].push(ingested);
+        Ok(())
+    }
+
+    /// Merges every on-disk table into a single table on the last level, dropping tombstones,
+    /// universal style keeps the table on level 0
+    pub fn compact(&mut self) -> Result<()> {
+        self.wait_for_background_work()?;
+        let levels = self.compaction_levels();
+        let last_level = match self.options.compaction_style {
+            CompactionStyle::Leveled => levels - 1,
+            CompactionStyle::Universal { .. } => 0,
+        };
+        // tombstones still have to shadow data ingested behind
+        let drop_tombstones = self.on_disk_levels[levels..].iter().all(Vec::is_empty);
+        let tables: Vec<_> = self.on_disk_levels[..levels]
+            .iter_mut()
+            .rev()
+            .flat_map(mem::take)
+            .collect();
+        self.merge_into_level(tables, last_level, drop_tombstones)
+    }
+
+    /// Rewrites bloom filters of tables built with a different bits per key than configured
+    /// for their level, returns number of updated tables
+    pub fn rebuild_filters(&mut self) -> Result<usize> {
+        self.wait_for_background_work()?;
+        let mut rebuilt = 0;
+        for (level, tables) in self.on_disk_levels.iter_mut().enumerate() {
+            let bits_per_key = self.options.bloom_bits_per_key(level);
+            for table in tables.iter_mut() {
+                if table.filter_bits_per_key() != bits_per_key {
+                    table.rebuild_filter(bits_per_key)?;
+                    rebuilt += 1;
+                }
+            }
+        }
+        Ok(rebuilt)
+    }
+
+    /// Writes every live key-value pair in key order, returns number of exported pairs
+    pub fn export(&self, writer: impl io::Write, format: Format) -> Result<usize> {
+        let memtables = self.memtables();
+        let order = &self.options.comparator;
+        let entries = MergingIterator::new(order, &memtables, &self.on_disk_levels, &[])?
+            .filter(|entry| {
+                entry.as_ref().map_or(true, |entry| {
+                    entry.value.is_some() && !keyspace::is_internal_key(&entry.key)
+                })
+            })
+            .map(|entry| {
+                let entry = entry?;
+                let value = entry.value.unwrap_or_default();
+                let value = self.options.value_transformers.decode(&entry.key, value)?;
+                Ok((entry.key, value))
+            });
+        export::write_records(entries, writer, format)
+    }
+
+    /// Writes live pairs within range together with their commit sequences as a parquet file,
+    /// returns number of written rows
+    #[cfg(feature = "parquet")]
+    pub fn export_parquet(
+        &self,
+        range: impl RangeBounds<Vec<u8>>,
+        writer: impl io::Write + Send,
+    ) -> Result<usize> {
+        let memtables = self.memtables();
+        let order = &self.options.comparator;
+        let from = range_start(&range);
+        let entries = MergingIterator::new(order, &memtables, &self.on_disk_levels, from)?
+            .take_while(|entry| {
+                !entry
+                    .as_ref()
+                    .is_ok_and(|entry| order.is_past_end(&range, &entry.key))
+            })
+            .filter(|entry| {
+                entry.as_ref().map_or(true, |entry| {
+                    entry.value.is_some()
+                        && order.contains(&range, &entry.key)
+                        && !keyspace::is_internal_key(&entry.key)
+                })
+            })
+            .map(|entry| {
+                let mut entry = entry?;
+                if let Some(value) = entry.value.take() {
+                    entry.value = Some(self.options.value_transformers.decode(&entry.key, value)?);
+                }
+                Ok(entry)
+            });
+        export::write_parquet(entries, writer)
+    }
+
+    /// Bulk loads rows of record batches with `key` and `value` columns as a single ingested table,
+    /// rows must be sorted by strictly increasing key, null value deletes key
+    #[cfg(feature = "parquet")]
+    pub fn import_record_batches(
+        &mut self,
+        batches: impl IntoIterator<Item = Result<RecordBatch, ArrowError>>,
+    ) -> Result<usize> {
+        let mut builder = SstBuilder::new();
+        let transformers = &self.options.value_transformers;
+        let count = export::read_record_batches(batches, |key, value| {
+            if keyspace::is_internal_key(key) {
+                return Err(DBError::ReservedKey(key.to_vec()));
+            }
+            match value {
+                Some(value) => {
+                    let value = transformers.encode(key, value.to_vec())?;
+                    builder.put(key, &value)?
+                }
+                None => builder.delete(key)?,
+            }
+            Ok(())
+        })?;
+        if count == 0 {
+            return Ok(0);
+        }
+        let path = utils::unique_storage_path(&LocalStorage, &self.options.working_dir, "import");
+        builder.finish(&path)?;
+        let ingested = self.ingest_sst(&[&path]);
+        fs::remove_file(&path)?;
+        ingested.map(|_| count)
+    }
+
+    /// Bulk loads parquet file written by `export_parquet` or any other with sorted
+    /// `key` and `value` columns, see `import_record_batches`
+    #[cfg(feature = "parquet")]
+    pub fn import_parquet(&mut self, file: fs::File) -> Result<usize> {
+        let batches = ParquetRecordBatchReaderBuilder::try_new(file)?.build()?;
+        self.import_record_batches(batches)
+    }
+
+    /// Puts every key-value pair read from reader, returns number of imported pairs
+    pub fn import(&mut self, reader: impl io::Read, format: Format) -> Result<usize> {
+        export::read_records(reader, format, |key, value| self.put(key, value))
+    }
+
+    /// Creates consistent copy of database in a new directory of the same storage which can be
+    /// opened independently, immutable tables are hard linked where storage allows, wal is copied
+    pub fn checkpoint(&mut self, path: impl AsRef<Path>) -> Result<()> {
+        self.wait_for_background_work()?;
+        let path = path.as_ref();
+        let storage = &*self.options.storage.0;
+        if storage.exists(path) {
+            let error = io::Error::new(io::ErrorKind::AlreadyExists, "checkpoint directory exists");
+            return Err(DBError::io(path, error));
+        }
+        let in_memory = self.options.in_memory;
+        let mut log = self.log();
+        let last_sequence = log.last_sequence;
+        if !in_memory {
+            log.wal.checkpoint(last_sequence)?;
+        }
+        log.wal.flush()?;
+        let wal_path = log.wal.path.clone();
+        storage.create_dir_all(path)?;
+        for table in self.on_disk_levels.iter().flatten() {
+            let file_name = table.path.file_name().expect("sst path has file name");
+            let target = path.join(file_name);
+            if storage.hard_link(&table.path, &target).is_err() {
+                storage.copy(&table.path, &target)?;
+            }
+        }
+        let wal_name = wal_path.file_name().expect("wal path has file name");
+        storage.copy(&wal_path, &path.join(wal_name))?;
+        let comparator = self.options.comparator.name();
+        for (name, contents) in [
+            (COMPARATOR_FILE, comparator.as_bytes()),
+            (EPOCH_FILE, &self.epoch.to_le_bytes()),
+        ] {
+            let mut file = storage.create_new(&path.join(name))?;
+            file.write_all(contents)?;
+            file.sync_data()?;
+        }
+        Ok(storage.sync_dir(path)?)
+    }
+
+    /// Estimated size in bytes of data within range, read from table indexes without scanning,
+    /// versions and tombstones count until compaction drops them
+    pub fn approximate_size(&self, range: impl RangeBounds<Vec<u8>>) -> u64 {
+        let (_, size) = self.approximate_range(&range);
+        size as u64
+    }
+
+    /// Estimated number of keys within range, see `Database::approximate_size`
+    pub fn approximate_key_count(&self, range: impl RangeBounds<Vec<u8>>) -> usize {
+        let (count, _) = self.approximate_range(&range);
+        count
+    }
+
+    fn approximate_range(&self, range: &impl RangeBounds<Vec<u8>>) -> (usize, usize) {
+        let (start, end) = (
+            range.start_bound().map(Vec::as_slice),
+            range.end_bound().map(Vec::as_slice),
+        );
+        let (mut count, mut size) = (0, 0);
+        for table in self.on_disk_levels.iter().flatten() {
+            let (table_count, table_size) = table.approximate_range(start, end);
+            count += table_count;
+            size += table_size;
+        }
+        // memtables of running flushes aren't on disk yet, the mirror of flushed table is
+        let memtables = self.memtables();
+        for entry in memtables[..=self.pending_flushes.len()]
+            .iter()
+            .flat_map(|memtable| memtable.iter())
+            .filter(|entry| self.options.comparator.contains(range, entry.key))
+        {
+            count += 1;
+            size += entry.cost();
+        }
+        (count, size)
+    }
+
+    /// Loads index and filter partitions of partitioned tables into block cache, upper levels
+    /// and newer tables first, until cache is full, so that first reads don't wait for them.
+    /// Cached partitions aren't evicted. Returns number of partitions cached by this call,
+    /// nothing is loaded without block cache
+    pub fn warm_cache(&self) -> Result<usize> {
+        let mut cached = 0;
+        for table in self
+            .on_disk_levels
+            .iter()
+            .flat_map(|level| level.iter().rev())
+        {
+            let (loaded, full) = table
+                .warm_cache()
+                .map_err(|error| table_error(table, error))?;
+            cached += loaded;
+            if full {
+                break;
+            }
+        }
+        Ok(cached)
+    }
+
+    pub fn stats(&self) -> Result<DatabaseStats> {
+        let mut levels = Vec::with_capacity(self.on_disk_levels.len());
+        for level in self.on_disk_levels.iter() {
+            let mut stats = LevelStats {
+                files: level.len(),
+                ..Default::default()
+            };
+            for table in level.iter() {
+                stats.entries += table.len();
+                stats.tombstones += table.metadata.tombstone_count;
+                stats.raw_size += table.metadata.raw_key_size + table.metadata.raw_value_size;
+                stats.size += table.file_size()?;
+                stats.filter_size += table.filter.size();
+            }
+            levels.push(stats);
+        }
+        let log = self.log();
+        Ok(DatabaseStats {
+            rw_memtable_entries: self.rw_memtable.len(),
+            rw_memtable_size: self.rw_memtable.size(),
+            ro_memtable_entries: self.immutable_memtables.iter().map(|m| m.len()).sum(),
+            queued_flushes: self.pending_flushes.len(),
+            wal_unsynced_bytes: log.wal.unsynced_bytes(),
+            wal_oldest_unsynced_write_age: log.wal.oldest_unsynced_age(),
+            levels,
+            latencies: self.latencies.percentiles(),
+            filters: self.filter_counters.stats(),
+        })
+    }
+
+    /// Clears latency histograms reported by `Database::stats`
+    pub fn reset_latencies(&self) {
+        self.latencies.reset();
+    }
+
+    /// Number of levels compaction works with, reserved bottom level is excluded
+    fn compaction_levels(&self) -> usize {
+        self.on_disk_levels.len() - usize::from(self.options.allow_ingest_behind)
+    }
+
+    /// Limit of tables count on level, grows by `level_factor` with each level
+    fn level_tables_limit(&self, level: usize) -> usize {
+        self.options
+            .level_zero_memtables_limit
+            .saturating_mul(self.options.level_factor.saturating_pow(level as u32))
+    }
+
+    /// Overflowing level is merged into tables of target file size placed on the next level,
+    /// in background mode only one compaction runs at a time
+    fn maybe_compact(&mut self) -> Result<()> {
+        if let CompactionStyle::Universal {
+            max_sorted_runs,
+            size_ratio,
+        } = self.options.compaction_style
+        {
+            return self.maybe_compact_sorted_runs(max_sorted_runs, size_ratio);
+        }
+        for level in 0..self.compaction_levels() - 1 {
+            while let Some((tables, drop_tombstones)) = self.compaction_inputs(level) {
+                if self.scheduler.is_none() {
+                    self.merge_into_level(tables, level + 1, drop_tombstones)?;
+                    continue;
+                }
+                if self.pending_compaction.is_some() {
+                    return Ok(());
+                }
+                // inputs are cloned handles, they serve reads until merged table is installed
+                self.schedule_merge(tables, level + 1, drop_tombstones);
+                return Ok(());
+            }
+        }
+        Ok(())
+    }
+
+    /// Target size in bytes of level, zero for level 0 and when byte targets are disabled
+    fn level_target_size(&self, level: usize) -> usize {
+        match level {
+            0 => 0,
+            level => self
+                .options
+                .level_base_size
+                .saturating_mul(self.options.level_factor.saturating_pow(level as u32 - 1)),
+        }
+    }
+
+    /// Tables of overflowing level to merge into the next one, ordered from oldest to newest,
+    /// and whether tombstones can be dropped by the merge
+    fn compaction_inputs(&self, level: usize) -> Option<(Vec<SstReader>, bool)> {
+        let tables = &self.on_disk_levels[level];
+        let target = self.level_target_size(level);
+        if target == 0 {
+            if tables.len() <= self.level_tables_limit(level) {
+                return None;
+            }
+            let drop_tombstones = self.on_disk_levels[level + 1..]
+                .iter()
+                .all(|level| level.is_empty());
+            return Some((tables.clone(), drop_tombstones));
+        }
+        if tables.iter().map(table_size).sum::<usize>() <= target {
+            return None;
+        }
+        // table moved below an older overlapping one would be shadowed by it
+        let (table, overlapping) = tables
+            .iter()
+            .enumerate()
+            .filter(|(i, table)| {
+                let meta = &table.metadata;
+                !tables[..*i]
+                    .iter()
+                    .any(|older| older.overlaps(&meta.low_key, &meta.high_key))
+            })
+            .map(|(_, table)| (table, self.next_level_overlapping(level, table)))
+            .min_by(|(a, a_next), (b, b_next)| {
+                compaction_cost(a, a_next)
+                    .total_cmp(&compaction_cost(b, b_next))
+                    .then(tombstone_density(b).total_cmp(&tombstone_density(a)))
+                    .then(a.metadata.min_timestamp.cmp(&b.metadata.min_timestamp))
+            })?;
+        // all overlapping tables of the next level are merged, so output is the newest there
+        let drop_tombstones = self.on_disk_levels[level + 2..]
+            .iter()
+            .all(|level| level.is_empty());
+        let mut inputs = overlapping;
+        inputs.push(table.clone());
+        Some((inputs, drop_tombstones))
+    }
+
+    /// Tables of the next level overlapping table, along with ones overlapping merged range
+    fn next_level_overlapping(&self, level: usize, table: &SstReader) -> Vec<SstReader> {
+        let next = &self.on_disk_levels[level + 1];
+        let order = &self.options.comparator;
+        let mut low = table.metadata.low_key.clone();
+        let mut high = table.metadata.high_key.clone();
+        let mut picked = vec![false; next.len()];
+        let mut changed = true;
+        while changed {
+            changed = false;
+            for (other, picked) in next.iter().zip(picked.iter_mut()) {
+                if !*picked && other.overlaps(&low, &high) {
+                    *picked = true;
+                    changed = true;
+                    low = order
+                        .min(Some(low), Some(other.metadata.low_key.clone()))
+                        .unwrap_or_default();
+                    high = order
+                        .max(Some(high), Some(other.metadata.high_key.clone()))
+                        .unwrap_or_default();
+                }
+            }
+        }
+        next.iter()
+            .zip(picked)
+            .filter(|(_, picked)| *picked)
+            .map(|(table, _)| table.clone())
+            .collect()
+    }
+
+    /// Universal style, merges the newest sorted runs of level 0 back into level 0
+    fn maybe_compact_sorted_runs(
+        &mut self,
+        max_sorted_runs: usize,
+        size_ratio: usize,
+    ) -> Result<()> {
+        loop {
+            if self.pending_compaction.is_some() {
+                return Ok(());
+            }
+            let sizes: Vec<_> = self.on_disk_levels[0].iter().map(table_size).collect();
+            let Some(first) = pick_sorted_runs(&sizes, max_sorted_runs, size_ratio) else {
+                return Ok(());
+            };
+            let drop_tombstones = first == 0
+                && self.on_disk_levels[1..]
+                    .iter()
+                    .all(|level| level.is_empty());
+            if self.scheduler.is_none() {
+                let tables = self.on_disk_levels[0].split_off(first);
+                self.merge_into_level(tables, 0, drop_tombstones)?;
+                continue;
+            }
+            let tables = self.on_disk_levels[0][first..].to_vec();
+            self.schedule_merge(tables, 0, drop_tombstones);
+        }
+    }
+
+    fn schedule_merge(&mut self, tables: Vec<SstReader>, level: usize, drop_tombstones: bool) {
+        let job = self.merge_job(tables, level, drop_tombstones);
+        let (sender, result) = mpsc::channel();
+        if let Some(scheduler) = &self.scheduler {
+            scheduler.submit(JobPriority::Low, move || {
+                let _ = sender.send(job.run());
+            });
+        }
+        self.pending_compaction = Some(PendingCompaction {
+            level,
+            result: Mutex::new(result),
+        });
+    }
+
+    /// Tables must be ordered from oldest to newest, resulting tables become the newest on level
+    fn merge_into_level(
+        &mut self,
+        tables: Vec<SstReader>,
+        level: usize,
+        drop_tombstones: bool,
+    ) -> Result<()> {
+        let output = self.merge_job(tables, level, drop_tombstones).run()?;
+        self.install_merge(level, output)
+    }
+
+    fn merge_job(&self, tables: Vec<SstReader>, level: usize, drop_tombstones: bool) -> MergeJob {
+        MergeJob {
+            tables,
+            writer: self.new_sst_writer(level),
+            paths: self.sst_paths.clone(),
+            target_file_size: self.options.target_file_size(level),
+            mmap: self.options.mmap_reads,
+            versions_to_keep: self.options.versions_to_keep.max(1),
+            drop_tombstones,
+            grace_sequence: self.grace_sequence(),
+            level,
+            filter: self.options.compaction_filter.clone(),
+            order: self.options.comparator.clone(),
+            latencies: self.latencies.clone(),
+        }
+    }
+
+    /// Replaces merge inputs with merged tables and deletes their files
+    fn install_merge(&mut self, level: usize, output: MergeOutput) -> Result<()> {
+        self.dropped_tombstones_timestamp = self
+            .dropped_tombstones_timestamp
+            .max(output.dropped_tombstones_timestamp);
+        let is_input = |table: &SstReader| output.inputs.iter().any(|i| i.path == table.path);
+        // sorted runs merged in background may be followed by tables flushed meanwhile,
+        // output takes place of the inputs to stay older than those
+        let position = self.on_disk_levels[level].iter().position(is_input);
+        // inputs of background merge are still listed on their level
+        for tables in self.on_disk_levels.iter_mut() {
+            tables.retain(|table| !is_input(table));
+        }
+        let position = position.unwrap_or(self.on_disk_levels[level].len());
+        self.on_disk_levels[level].splice(position..position, output.tables);
+        for table in output.inputs {
+            if let Some(cache) = &self.options.block_cache {
+                cache.evict_table(&table.path);
+            }
+            table.delete()?;
+        }
+        Ok(self.options.storage.0.sync_dir(&self.options.working_dir)?)
+    }
+
+    fn new_sst_writer(&self, level: usize) -> SstWriter {
+        SstWriter::new(level)
+            .set_bloom_bits_per_key(self.options.bloom_bits_per_key(level))
+            .set_index_interval(self.options.index_interval)
+            .set_partition_entries(self.options.partition_entries)
+            .set_two_level_index_threshold(self.options.two_level_index_threshold)
+            .set_compression(self.options.compression(level))
+            .set_block_cache(self.options.block_cache.clone())
+            .set_storage(self.options.storage.0.clone())
+            .set_direct_io(self.options.use_direct_io)
+            .set_rate_limiter(self.options.rate_limiter.clone())
+            .set_key_order(self.options.comparator.clone())
+            .set_created_at(self.options.clock.0.now())
+    }
+
+    /// Writes table to a new file, mapped into memory if enabled
+    fn finish_sst(&self, writer: SstWriter) -> io::Result<SstReader> {
+        finish_table(writer, self.new_sst_path(), self.options.mmap_reads)
+    }
+
+    /// Files of background jobs don't exist until jobs finish, so their paths are skipped too
+    fn new_sst_path(&self) -> PathBuf {
+        // tables of finished jobs exist by now or were never written
+        if self.pending_flushes.is_empty() && self.pending_compaction.is_none() {
+            self.sst_paths.release_all();
+        }
+        self.sst_paths.next()
+    }
+
+    /// Wal and table of each queued flush from oldest to newest
+    fn pending_flush_files(&self) -> Vec<(&Path, &Path)> {
+        self.pending_flushes
+            .iter()
+            .map(|flush| (flush.wal_path.as_path(), flush.table_path.as_path()))
+            .collect()
+    }
+
+    /// Records wal and table of every running flush, so that init can finish or undo them after
+    /// a crash. Marker is replaced atomically and removed once no flush runs
+    fn write_flush_marker(&self, flushes: &[(&Path, &Path)]) -> io::Result<()> {
+        let storage = &*self.options.storage.0;
+        let marker = self.options.working_dir.join(FLUSH_MARKER);
+        // wals retired along with marker update are removed durably too
+        if flushes.is_empty() {
+            if storage.exists(&marker) {
+                storage.delete(&marker)?;
+            }
+            return storage.sync_dir(&self.options.working_dir);
+        }
+        let mut contents = String::new();
+        for path in flushes.iter().flat_map(|(wal, table)| [wal, table]) {
+            let name = path.file_name().unwrap_or_default().to_string_lossy();
+            contents.push_str(&name);
+            contents.push('\n');
+        }
+        let temp = marker.with_extension("tmp");
+        if storage.exists(&temp) {
+            storage.delete(&temp)?;
+        }
+        let mut file = storage.create_new(&temp)?;
+        file.write_all(contents.as_bytes())?;
+        file.sync_data()?;
+        storage.rename(&temp, &marker)?;
+        storage.sync_dir(&self.options.working_dir)
+    }
+
+    /// Finishes or undoes flushes interrupted by a crash in the order they were started. Wals
+    /// of intact tables are deleted, the newest one is loaded into returned memtable first, which
+    /// mirrors its table as immutable memtable does. Flushes from the first partial table on are
+    /// undone: their tables are deleted and wals left to be replayed into rw memtable, as newer
+    /// tables would be shadowed by the replayed data otherwise
+    fn recover_flush(
+        working_dir: &Path,
+        storage: &Arc<dyn Storage>,
+        order: &KeyOrder,
+    ) -> io::Result<MemTable> {
+        let mut memtable = MemTable::with_order(order.clone());
+        let marker = working_dir.join(FLUSH_MARKER);
+        if !storage.exists(&marker) {
+            return Ok(memtable);
+        }
+        let mut contents = String::new();
+        StorageReader::open_at(&**storage, &marker, 0)?.read_to_string(&mut contents)?;
+        // markers replaced in place by earlier versions may be torn by crash
+        let names: Vec<_> = contents
+            .strip_suffix('\n')
+            .map(|names| names.split('\n').collect())
+            .unwrap_or_default();
+        let mut intact = true;
+        for pair in names.chunks_exact(2) {
+            let (wal, table) = (working_dir.join(pair[0]), working_dir.join(pair[1]));
+            intact = intact
+                && storage.exists(&table)
+                && SstReader::open_with_storage(&table, storage.clone())
+                    .and_then(|mut table| {
+                        table.set_key_order(order.clone())?;
+                        table.check_key_range(true)
+                    })
+                    .unwrap_or(false);
+            if intact && storage.exists(&wal) {
+                memtable = MemTable::with_order(order.clone());
+                for entry in WriteAheadLogIterator::new_with_storage(&wal, &**storage)? {
+                    match entry.value {
+                        Some(value) => {
+                            memtable.put_with_meta(entry.timestamp, entry.key, value, entry.meta)
+                        }
+                        None => memtable.delete(entry.timestamp, entry.key),
+                    }
+                }
+                storage.delete(&wal)?;
+            } else if !intact && storage.exists(&table) {
+                storage.delete(&table)?;
+            }
+            trace::info!(
+                wal = %wal.display(),
+                table = %table.display(),
+                finished = intact,
+                "recovered interrupted flush"
+            );
+        }
+        storage.delete(&marker)?;
+        storage.sync_dir(working_dir)?;
+        Ok(memtable)
+    }
+
+    /// Fails if database was created with another comparator, name of configured one is
+    /// stored otherwise. Databases created before the name was stored are ordered bytewise
+    /// Last resort recovery of closed database after corruption or lost files: every intact
+    /// record of tables and wals is rewritten into a single table on the last level and damaged
+    /// files are moved to `LOST_DIR` subdirectory. Directory must have been written with
+    /// default options, otherwise use `DatabaseOptions::repair`
+    pub fn repair(dir: impl AsRef<Path>) -> Result<RepairReport> {
+        Self::options().set_working_dir(dir.as_ref()).repair()
+    }
+
+    fn repair_with(options: DatabaseOptions) -> Result<RepairReport> {
+        Self::check_comparator(&options)?;
+        let storage = &options.storage.0;
+        let marker = options.working_dir.join(FLUSH_MARKER);
+        if storage.exists(&marker) {
+            storage.delete(&marker)?;
+        }
+        let (records, quarantined) =
+            repair::salvage(&options.working_dir, storage, &options.comparator)?;
+        let mut db = options.init()?;
+        let level = db.compaction_levels() - 1;
+        let mut writer = db.new_sst_writer(level);
+        for record in &records {
+            writer.push(record.as_cbf_ref())?;
+        }
+        if !writer.is_empty() {
+            let table = db.finish_sst(writer)?;
+            db.on_disk_levels[level].push(table);
+        }
+        trace::info!(
+            salvaged_records = records.len(),
+            quarantined = quarantined.len(),
+            "repaired database"
+        );
+        Ok(RepairReport {
+            salvaged_records: records.len(),
+            quarantined,
+        })
+    }
+
+    fn lock_working_dir(options: &DatabaseOptions) -> Result<Option<fs::File>> {
+        let storage = &*options.storage.0;
+        storage.create_dir_all(&options.working_dir)?;
+        let path = options.working_dir.join(LOCK_FILE);
+        storage.lock(&path).map_err(|e| match e.kind() {
+            io::ErrorKind::WouldBlock => DBError::LockHeld(options.working_dir.clone()),
+            _ => DBError::io(path, e),
+        })
+    }
+
+    fn check_comparator(options: &DatabaseOptions) -> Result<()> {
+        let storage = &*options.storage.0;
+        let dir = &options.working_dir;
+        storage.create_dir_all(dir)?;
+        let path = dir.join(COMPARATOR_FILE);
+        let configured = options.comparator.name();
+        let stored = if storage.exists(&path) {
+            let mut name = String::new();
+            StorageReader::open_at(storage, &path, 0)?.read_to_string(&mut name)?;
+            Some(name)
+        } else if !utils::scan_storage(storage, dir, &["sst", "wal"])?.is_empty() {
+            Some(BytewiseComparator.name().to_string())
+        } else {
+            None
+        };
+        match stored {
+            Some(stored) if stored != configured => Err(DBError::ComparatorMismatch {
+                stored,
+                configured: configured.to_string(),
+            }),
+            Some(_) if storage.exists(&path) => Ok(()),
+            _ => {
+                // written under temp name, torn file would fail every open
+                let temp = dir.join(format!("{COMPARATOR_FILE}.tmp"));
+                if storage.exists(&temp) {
+                    storage.delete(&temp)?;
+                }
+                let mut file = storage.create_new(&temp)?;
+                file.write_all(configured.as_bytes())?;
+                file.sync_data()?;
+                storage.rename(&temp, &path)?;
+                Ok(storage.sync_dir(dir)?)
+            }
+        }
+    }
+
+    fn read_epoch(options: &DatabaseOptions) -> Result<u64> {
+        let storage = &*options.storage.0;
+        let path = options.working_dir.join(EPOCH_FILE);
+        if !storage.exists(&path) {
+            return Ok(0);
+        }
+        let mut bytes = Vec::new();
+        StorageReader::open_at(storage, &path, 0)?.read_to_end(&mut bytes)?;
+        let epoch = bytes
+            .try_into()
+            .map_err(|_| DBError::InvalidData("epoch file is not 8 bytes long".to_string()))?;
+        Ok(u64::from_le_bytes(epoch))
+    }
+
+    /// Fencing epoch of database, see `bump_epoch`
+    pub fn epoch(&self) -> u64 {
+        self.epoch
+    }
+
+    /// Raises epoch by one when database takes over as primary, e.g. after promotion of replica.
+    /// Replicas which saw the new epoch reject writes of previous primary. Returns new epoch
+    pub fn bump_epoch(&mut self) -> Result<u64> {
+        self.advance_epoch(self.epoch + 1)?;
+        Ok(self.epoch)
+    }
+
+    /// Persists epoch seen from primary, lower epochs are ignored
+    pub(crate) fn advance_epoch(&mut self, epoch: u64) -> Result<()> {
+        if epoch <= self.epoch {
+            return Ok(());
+        }
+        let storage = &*self.options.storage.0;
+        let dir = &self.options.working_dir;
+        // written under temp name, torn file would fail every open
+        let temp = dir.join(format!("{EPOCH_FILE}.tmp"));
+        if storage.exists(&temp) {
+            storage.delete(&temp)?;
+        }
+        let mut file = storage.create_new(&temp)?;
+        file.write_all(&epoch.to_le_bytes())?;
+        file.sync_data()?;
+        storage.rename(&temp, &dir.join(EPOCH_FILE))?;
+        storage.sync_dir(dir)?;
+        self.epoch = epoch;
+        Ok(())
+    }
+
+    /// Fails on the first damaged record of any wal, records cut off at the end of file
+    /// are left to replay which drops them
+    fn verify_wals(options: &DatabaseOptions) -> Result<()> {
+        let storage = &*options.storage.0;
+        if !storage.exists(&options.working_dir) {
+            return Ok(());
+        }
+        for path in utils::scan_storage(storage, &options.working_dir, &["wal"])? {
+            let Some(Corruption { offset, error }) =
+                WriteAheadLog::verify_with_storage(&path, storage, options.unknown_wal_records)?
+            else {
+                continue;
+            };
+            if error.kind() != io::ErrorKind::UnexpectedEof {
+                return Err(DBError::Corruption {
+                    path,
+                    offset,
+                    error,
+                });
+            }
+        }
+        Ok(())
+    }
+
+    fn find_existing_ssts(
+        working_dir: impl AsRef<Path>,
+        storage: &Arc<dyn Storage>,
+    ) -> Result<Vec<SstReader>> {
+        let mut found = Vec::new();
+        for file in utils::scan_storage(&**storage, working_dir.as_ref(), &["sst"])? {
+            found.push(SstReader::open_with_storage(file, storage.clone())?);
+        }
+        Ok(found)
+    }
+}
+
+/// Point lookup over memtables ordered from newest to oldest and levels from top to bottom
+impl Drop for Database {
+    /// Queued jobs are cancelled, finished ones are still installed
+    fn drop(&mut self) {
+        if let Some(mut scheduler) = self.scheduler.take() {
+            scheduler.shutdown();
+            let _ = self.collect_flush(false);
+            let _ = self.collect_compaction(false);
+        }
+    }
+}
+
+/// Opens external tables checking that their key ranges don't overlap, empty ones are skipped
+#[cfg(feature = "std-fs")]
+fn open_ingested(order: &KeyOrder, paths: &[impl AsRef<Path>]) -> Result<Vec<SstReader>> {
+    let mut tables = Vec::with_capacity(paths.len());
+    for path in paths {
+        let mut table = SstReader::open(path)?;
+        table.set_key_order(order.clone())?;
+        if !table.check_key_range(true)? {
+            return Err(DBError::SstKeyRangeMismatch(table.path));
+        }
+        if !table.is_empty() {
+            tables.push(table);
+        }
+    }
+    tables.sort_by(|a, b| order.cmp(&a.metadata.low_key, &b.metadata.low_key));
+    for pair in tables.windows(2) {
+        if order.le(&pair[1].metadata.low_key, &pair[0].metadata.high_key) {
+            return Err(DBError::IngestedTablesOverlap(
+                pair[0].path.clone(),
+                pair[1].path.clone(),
+            ));
+        }
+    }
+    Ok(tables)
+}
+
+/// Start of the newest sorted runs to merge, sizes are ordered from oldest to newest.
+/// Runs are added from the newest while the next older run is at most `size_ratio` percent
+/// larger than all added ones, if that picks a single run while the count is above
+/// `max_sorted_runs`, just enough of the newest runs are merged to get to the limit
+fn pick_sorted_runs(sizes: &[usize], max_sorted_runs: usize, size_ratio: usize) -> Option<usize> {
+    let newest = sizes.len().checked_sub(1)?;
+    let mut first = newest;
+    let mut total = sizes[newest];
+    while first > 0 && total.saturating_mul(100 + size_ratio) / 100 >= sizes[first - 1] {
+        first -= 1;
+        total += sizes[first];
+    }
+    if first < newest {
+        return Some(first);
+    }
+    let max_sorted_runs = max_sorted_runs.max(1);
+    (sizes.len() > max_sorted_runs).then(|| max_sorted_runs - 1)
+}
+
+fn table_size(table: &SstReader) -> usize {
+    table
+        .approximate_range(Bound::Unbounded, Bound::Unbounded)
+        .1
+}
+
+/// Bytes of the next level rewritten per byte moved down, discounted by tombstone density
+/// of table as its tombstones reclaim space of the versions they delete
+fn compaction_cost(table: &SstReader, overlapping: &[SstReader]) -> f64 {
+    let rewritten: usize = overlapping.iter().map(table_size).sum();
+    let overlap_ratio = rewritten as f64 / table_size(table).max(1) as f64;
+    overlap_ratio * (1.0 - tombstone_density(table))
+}
+
+fn tombstone_density(table: &SstReader) -> f64 {
+    let meta = &table.metadata;
+    meta.tombstone_count as f64 / meta.entry_count.max(1) as f64
+}
+
+/// Names of new tables, handed out names stay reserved until released so that jobs running
+/// in background don't pick the same one before its file exists
+#[derive(Clone)]
+struct SstPaths {
+    storage: Arc<dyn Storage>,
+    dir: PathBuf,
+    reserved: Arc<Mutex<Vec<PathBuf>>>,
+}
+
+impl SstPaths {
+    fn next(&self) -> PathBuf {
+        let mut reserved = self.reserved.lock().expect("sst paths mutex poisoned");
+        loop {
+            let path = utils::unique_storage_path(&*self.storage, &self.dir, "sst");
+            if !reserved.contains(&path) {
+                reserved.push(path.clone());
+                return path;
+            }
+        }
+    }
+
+    /// Must be called only while no job is writing tables
+    fn release_all(&self) {
+        self.reserved
+            .lock()
+            .expect("sst paths mutex poisoned")
+            .clear();
+    }
+}
+
+/// Inputs of a merge, detached from database so that it can run on a worker thread
+struct MergeJob {
+    /// ordered from oldest to newest
+    tables: Vec<SstReader>,
+    /// writer of the current output table
+    writer: SstWriter,
+    paths: SstPaths,
+    /// output rolls over to a new table at this size, zero writes a single table
+    target_file_size: usize,
+    mmap: bool,
+    versions_to_keep: usize,
+    drop_tombstones: bool,
+    /// tombstones up to this sequence may be dropped
+    grace_sequence: u128,
+    /// level merged table is written to
+    level: usize,
+    filter: Option<SharedCompactionFilter>,
+    order: KeyOrder,
+    latencies: Arc<LatencyRecorder>,
+}
+
+struct MergeOutput {
+    inputs: Vec<SstReader>,
+    /// ordered by key, empty if every entry was dropped
+    tables: Vec<SstReader>,
+    dropped_tombstones_timestamp: u128,
+}
+
+impl MergeJob {
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(
+            name = "compaction",
+            skip_all,
+            fields(inputs = self.tables.len(), level = self.level)
+        )
+    )]
+    fn run(mut self) -> io::Result<MergeOutput> {
+        let start = Stopwatch::start();
+        let mut merged = Vec::new();
+        for table in self.tables.iter().rev() {
+            for entry in table.iter()? {
+                merged.push(entry?);
+            }
+        }
+        // stable sort keeps versions of key from newest to oldest
+        merged.sort_by(|a, b| self.order.cmp(&a.key, &b.key));
+        let range_tombstones: Vec<RangeTombstone> = self
+            .tables
+            .iter()
+            .flat_map(|table| table.range_tombstones())
+            .cloned()
+            .collect();
+        let mut dropped_tombstones_timestamp = 0;
+        let mut tables = Vec::new();
+        for versions in merged.chunk_by(|a, b| a.key == b.key) {
+            // versions of key are never split between tables
+            if self.target_file_size > 0 && self.writer.data_size() >= self.target_file_size {
+                let next = self.writer.next_table();
+                let writer = mem::replace(&mut self.writer, next);
+                tables.push(finish_table(writer, self.paths.next(), self.mmap)?);
+            }
+            for entry in versions.iter().take(self.versions_to_keep) {
+                // versions deleted by range tombstone are dropped along with older ones
+                if range_del::newest_covering(
+                    &self.order,
+                    &range_tombstones,
+                    &entry.key,
+                    entry.timestamp,
+                )
+                .is_some()
+                {
+                    break;
+                }
+                if self.drop_tombstones
+                    && self.grace_sequence >= entry.timestamp
+                    && RangeTombstone::from_record(entry.timestamp, &entry.key).is_some()
+                {
+                    dropped_tombstones_timestamp =
+                        dropped_tombstones_timestamp.max(entry.timestamp);
+                    continue;
+                }
+                // versions older than dropped tombstone are dropped too so key is not resurrected
+                if self.drop_tombstones
+                    && entry.value.is_none()
+                    && self.grace_sequence >= entry.timestamp
+                {
+                    dropped_tombstones_timestamp =
+                        dropped_tombstones_timestamp.max(entry.timestamp);
+                    break;
+                }
+                let decision = match (&self.filter, &entry.value) {
+                    (Some(SharedCompactionFilter(filter)), Some(value))
+                        if !keyspace::is_internal_key(&entry.key) =>
+                    {
+                        filter.filter(self.level, &entry.key, value)
+                    }
+                    _ => FilterDecision::Keep,
+                };
+                match decision {
+                    FilterDecision::Keep => self.writer.push(entry.as_cbf_ref())?,
+                    FilterDecision::ChangeValue(value) => self.writer.push(
+                        CommonBinaryFormatRef::new(entry.timestamp, &entry.key, Some(&value))
+                            .with_meta(&entry.meta),
+                    )?,
+                    FilterDecision::Remove => {
+                        if self.drop_tombstones && self.grace_sequence >= entry.timestamp {
+                            dropped_tombstones_timestamp =
+                                dropped_tombstones_timestamp.max(entry.timestamp);
+                        } else {
+                            self.writer.push(CommonBinaryFormatRef::new(
+                                entry.timestamp,
+                                &entry.key,
+                                None,
+                            ))?;
+                        }
+                        break;
+                    }
+                }
+            }
+        }
+        if !self.writer.is_empty() {
+            tables.push(finish_table(self.writer, self.paths.next(), self.mmap)?);
+        }
+        self.latencies.record_since(Operation::Compaction, start);
+        trace::info!(
+            input_entries = self.tables.iter().map(SstReader::len).sum::<usize>(),
+            output_entries = tables.iter().map(SstReader::len).sum::<usize>(),
+            output_tables = tables.len(),
+            bytes = tables
+                .iter()
+                .filter_map(|table| table.file_size().ok())
+                .sum::<u64>(),
+            dropped_tombstones = dropped_tombstones_timestamp > 0,
+            elapsed_us = start.elapsed().as_micros() as u64,
+            "compacted tables"
+        );
+        Ok(MergeOutput {
+            inputs: self.tables,
+            tables,
+            dropped_tombstones_timestamp,
+        })
+    }
+}
+
+/// Writes memtable entries to a new table, nothing is written for empty memtable
+#[cfg_attr(
+    feature = "tracing",
+    tracing::instrument(name = "flush", skip_all, fields(path = %path.display()))
+)]
+fn write_memtable(
+    memtable: &MemTable,
+    mut writer: SstWriter,
+    path: PathBuf,
+    mmap: bool,
+    latencies: &LatencyRecorder,
+) -> io::Result<Option<SstReader>> {
+    let start = Stopwatch::start();
+    for entry in memtable.iter() {
+        writer.push(entry.as_cbf_ref())?;
+    }
+    if writer.is_empty() {
+        return Ok(None);
+    }
+    let table = finish_table(writer, path, mmap)?;
+    latencies.record_since(Operation::Flush, start);
+    trace::info!(
+        entries = table.len(),
+        bytes = table.file_size().unwrap_or(0),
+        elapsed_us = start.elapsed().as_micros() as u64,
+        "flushed memtable"
+    );
+    Ok(Some(table))
+}
+
+fn finish_table(writer: SstWriter, path: PathBuf, mmap: bool) -> io::Result<SstReader> {
+    let mut table = writer.finish(path)?;
+    if mmap {
+        table.map()?;
+    }
+    Ok(table)
+}
+
+/// Result of background job, disconnected if job was cancelled
+fn receive<T>(receiver: &Mutex<mpsc::Receiver<T>>, wait: bool) -> Result<T, TryRecvError> {
+    let receiver = receiver.lock().expect("job result mutex poisoned");
+    if wait {
+        receiver.recv().map_err(|_| TryRecvError::Disconnected)
+    } else {
+        receiver.try_recv()
+    }
+}
+
+#[cfg(feature = "std-fs")]
+pub(crate) fn query_sources(
+    order: &KeyOrder,
+    memtables: &[&MemTable],
+    levels: &[Vec<SstReader>],
+    key: &[u8],
+) -> Result<Option<Vec<u8>>> {
+    Ok(newest_version(order, memtables, levels, key)?.and_then(|(_, value)| value))
+}
+
+/// Timestamp and value of the newest version of key including tombstones
+pub(crate) fn newest_version(
+    order: &KeyOrder,
+    memtables: &[&MemTable],
+    levels: &[Vec<SstReader>],
+    key: &[u8],
+) -> Result<Option<(u128, Option<Vec<u8>>)>> {
+    let entry = newest_entry(order, memtables, levels, key)?;
+    Ok(entry.map(|entry| (entry.timestamp, entry.value)))
+}
+
+/// Newest record of key including tombstones, together with its metadata
+pub(crate) fn newest_entry(
+    order: &KeyOrder,
+    memtables: &[&MemTable],
+    levels: &[Vec<SstReader>],
+    key: &[u8],
+) -> Result<Option<CommonBinaryFormat>> {
+    newest_version_traced(order, memtables, levels, key, None, |_, _| {})
+}
+
+/// Same as `newest_version`, `on_read` is called for every table which has to read records
+/// and outcomes of enabled filters are counted if counters are given
+fn newest_version_traced(
+    order: &KeyOrder,
+    memtables: &[&MemTable],
+    levels: &[Vec<SstReader>],
+    key: &[u8],
+    filters: Option<&FilterCounters>,
+    on_read: impl FnMut(&SstReader, &[u8]),
+) -> Result<Option<CommonBinaryFormat>> {
+    let stored = stored_version_traced(memtables, levels, key, filters, on_read)?;
+    let timestamp = stored.as_ref().map_or(0, |entry| entry.timestamp);
+    // version deleted by range tombstone reads as tombstone of its sequence
+    match range_del::covering(order, memtables, levels, key, timestamp) {
+        Some(sequence) => Ok(Some(CommonBinaryFormat {
+            timestamp: sequence,
+            key: key.to_vec(),
+            value: None,
+            meta: Vec::new(),
+        })),
+        None => Ok(stored),
+    }
+}
+
+/// Newest version of key stored in sources, range tombstones aren't applied
+fn stored_version_traced(
+    memtables: &[&MemTable],
+    levels: &[Vec<SstReader>],
+    key: &[u8],
+    filters: Option<&FilterCounters>,
+    mut on_read: impl FnMut(&SstReader, &[u8]),
+) -> Result<Option<CommonBinaryFormat>> {
+    for memtable in memtables {
+        if let Some(entry) = memtable.get(key) {
+            return Ok(Some(entry.as_cbf_ref().into_owned()));
+        }
+    }
+    for level in levels.iter() {
+        for table in level.iter().rev() {
+            if !table.in_key_range(key) {
+                continue;
+            }
+            let counters = filters.filter(|_| table.has_filter());
+            if !table.filter_may_contain(key) {
+                counters.inspect(|counters| counters.record_negative());
+                continue;
+            }
+            on_read(table, key);
+            let entry = table.get(key).map_err(|error| table_error(table, error))?;
+            counters.inspect(|counters| counters.record_match(entry.is_some()));
+            if entry.is_some() {
+                return Ok(entry);
+            }
+        }
+    }
+    Ok(None)
+}
+
+/// Newest live value of key like `newest_version_traced` does, pinned where source allows it
+fn pinned_version<'a>(
+    order: &KeyOrder,
+    memtables: &[&'a MemTable],
+    levels: &'a [Vec<SstReader>],
+    key: &[u8],
+    filters: &FilterCounters,
+) -> Result<Option<PinnedValue<'a>>> {
+    let stored = pinned_stored_version(memtables, levels, key, filters)?;
+    let timestamp = stored.as_ref().map_or(0, |(timestamp, _)| *timestamp);
+    if range_del::covering(order, memtables, levels, key, timestamp).is_some() {
+        return Ok(None);
+    }
+    Ok(stored.and_then(|(_, value)| value))
+}
+
+/// Newest version of key stored in sources with value pinned, range tombstones aren't applied
+fn pinned_stored_version<'a>(
+    memtables: &[&'a MemTable],
+    levels: &'a [Vec<SstReader>],
+    key: &[u8],
+    filters: &FilterCounters,
+) -> Result<Option<(u128, Option<PinnedValue<'a>>)>> {
+    for memtable in memtables {
+        if let Some(entry) = memtable.get(key) {
+            let value = entry.value.map(PinnedValue::memtable);
+            return Ok(Some((entry.timestamp, value)));
+        }
+    }
+    for level in levels.iter() {
+        for table in level.iter().rev() {
+            if !table.in_key_range(key) {
+                continue;
+            }
+            let counters = Some(filters).filter(|_| table.has_filter());
+            if !table.filter_may_contain(key) {
+                counters.inspect(|counters| counters.record_negative());
+                continue;
+            }
+            let found = if table.is_mapped() {
+                let entry = table
+                    .get_mapped(key)
+                    .map_err(|error| table_error(table, error))?;
+                entry.map(|entry| (entry.timestamp, entry.value.map(PinnedValue::mapped)))
+            } else {
+                let entry = table.get(key).map_err(|error| table_error(table, error))?;
+                entry.map(|entry| (entry.timestamp, entry.value.map(PinnedValue::owned)))
+            };
+            counters.inspect(|counters| counters.record_match(found.is_some()));
+            if found.is_some() {
+                return Ok(found);
+            }
+        }
+    }
+    Ok(None)
+}
+
+/// Checksum and decoding failures of table reads are reported as `DBError::CorruptedTable`
+fn table_error(table: &SstReader, error: io::Error) -> DBError {
+    match error.kind() {
+        io::ErrorKind::InvalidData | io::ErrorKind::UnexpectedEof => DBError::CorruptedTable {
+            path: table.path.clone(),
+            error,
+        },
+        _ => DBError::io(&table.path, error),
+    }
+}
+
+/// Range scan over the same sources as `query_sources`
+pub(crate) fn scan_sources(
+    order: &KeyOrder,
+    memtables: &[&MemTable],
+    levels: &[Vec<SstReader>],
+    range: impl RangeBounds<Vec<u8>>,
+) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+    let mut out = Vec::new();
+    for entry in MergingIterator::new(order, memtables, levels, range_start(&range))? {
+        let entry = entry?;
+        if order.is_past_end(&range, &entry.key) {
+            break;
+        }
+        if let (true, Some(value)) = (order.contains(&range, &entry.key), entry.value) {
+            out.push((entry.key, value));
+        }
+    }
+    Ok(out)
+}
+
+fn range_start(range: &impl RangeBounds<Vec<u8>>) -> &[u8] {
+    match range.start_bound() {
+        Bound::Included(key) | Bound::Excluded(key) => key.as_slice(),
+        Bound::Unbounded => &[],
+    }
+}
+
+/// Entries of a single commit, batch ops share its sequence
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct WalRecord {
+    pub sequence: u128,
+    /// ops in the order of batch
+    pub entries: Vec<WriteAheadLogEntry>,
+}
+
+/// Iterator returned by `Database::wal_tail`
+pub struct WalTail<'a> {
+    storage: &'a dyn Storage,
+    /// wal files not opened yet from oldest to newest
+    wals: VecDeque<PathBuf>,
+    entries: Option<WriteAheadLogIterator>,
+    policy: UnknownRecordPolicy,
+    /// entry read ahead to find the end of commit, starts the next record
+    pending: Option<WriteAheadLogEntry>,
+    transformers: &'a ValueTransformers,
+    from_seq: u128,
+    /// newest sequence in tables, older records are no longer in wal
+    flushed: u128,
+    /// whether retained records were checked to cover `from_seq`
+    checked: bool,
+    done: bool,
+}
+
+impl WalTail<'_> {
+    fn next_entry(&mut self) -> io::Result<Option<WriteAheadLogEntry>> {
+        loop {
+            if let Some(entries) = self.entries.as_mut() {
+                if let Some(entry) = entries.next() {
+                    return Ok(Some(entry));
+                }
+                if let Some(error) = entries.take_failure() {
+                    return Err(error);
+                }
+            }
+            let Some(path) = self.wals.pop_front() else {
+                return Ok(None);
+            };
+            let entries = WriteAheadLogIterator::new_with_storage(&path, self.storage)?;
+            self.entries = Some(entries.with_unknown_record_policy(self.policy));
+        }
+    }
+
+    /// Fails once if records between `from_seq` and the oldest retained one are flushed
+    fn check_retained(&mut self, oldest: Option<u128>) -> Result<()> {
+        if self.checked {
+            return Ok(());
+        }
+        self.checked = true;
+        if self.from_seq <= self.flushed && oldest.is_none_or(|oldest| oldest > self.from_seq) {
+            return Err(DBError::WalTruncated(self.from_seq));
+        }
+        Ok(())
+    }
+
+    fn next_record(&mut self) -> Result<Option<WalRecord>> {
+        loop {
+            let first = match self.pending.take() {
+                Some(entry) => Some(entry),
+                None => self.next_entry()?,
+            };
+            self.check_retained(first.as_ref().map(|entry| entry.timestamp))?;
+            let Some(first) = first else {
+                return Ok(None);
+            };
+            let sequence = first.timestamp;
+            let mut entries = vec![first];
+            while let Some(entry) = self.next_entry()? {
+                if entry.timestamp != sequence {
+                    self.pending = Some(entry);
+                    break;
+                }
+                entries.push(entry);
+            }
+            if sequence < self.from_seq {
+                continue;
+            }
+            for entry in &mut entries {
+                if let Some(value) = entry.value.take() {
+                    entry.value = Some(self.transformers.decode(&entry.key, value)?);
+                }
+            }
+            return Ok(Some(WalRecord { sequence, entries }));
+        }
+    }
+}
+
+impl Iterator for WalTail<'_> {
+    type Item = Result<WalRecord>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+        let record = self.next_record();
+        self.done = !matches!(record, Ok(Some(_)));
+        record.transpose()
+    }
+}
+
+/// Whether the key is not before the start of range
+fn follows_start(order: &KeyOrder, range: &impl RangeBounds<Vec<u8>>, key: &[u8]) -> bool {
+    match range.start_bound() {
+        Bound::Included(start) => order.le(start, key),
+        Bound::Excluded(start) => order.lt(start, key),
+        Bound::Unbounded => true,
+    }
+}
+
+/// Page of live pairs returned by `Database::scan_page`
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ScanPage {
+    pub entries: Vec<(Vec<u8>, Vec<u8>)>,
+    /// opaque token to pass as `after` for the next page, None if range is exhausted
+    pub continuation: Option<Vec<u8>>,
+}
+
+/// Iterator returned by `Database::scan_chunks`
+pub struct ScanChunks<'a, R> {
+    entries: MergingIterator<'a>,
+    order: &'a KeyOrder,
+    /// entry read ahead to find resume key, returned first by the next chunk
+    pending: Option<CommonBinaryFormat>,
+    transformers: &'a ValueTransformers,
+    range: R,
+    max_bytes: usize,
+    done: bool,
+    _tracking: IteratorGuard<'a>,
+}
+
+/// Key-ordered part of scanned range
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ScanChunk {
+    pub entries: Vec<(Vec<u8>, Vec<u8>)>,
+    /// first key of the rest of range, scan interrupted after this chunk continues from it,
+    /// None if range is exhausted
+    pub resume_key: Option<Vec<u8>>,
+}
+
+impl<R: RangeBounds<Vec<u8>>> ScanChunks<'_, R> {
+    /// The smallest key after the last one, with custom comparator the next stored key
+    /// is read ahead as successors of keys are unknown
+    fn resume_key(&mut self, key: &[u8]) -> io::Result<Option<Vec<u8>>> {
+        if let Some(successor) = self.order.successor(key) {
+            return Ok(Some(successor));
+        }
+        match self.entries.next().transpose()? {
+            Some(next) if !self.order.is_past_end(&self.range, &next.key) => {
+                let resume_key = next.key.clone();
+                self.pending = Some(next);
+                Ok(Some(resume_key))
+            }
+            _ => {
+                self.done = true;
+                Ok(None)
+            }
+        }
+    }
+}
+
+impl<R: RangeBounds<Vec<u8>>> Iterator for ScanChunks<'_, R> {
+    type Item = Result<ScanChunk>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+        let mut chunk = ScanChunk::default();
+        let mut size = 0;
+        loop {
+            let entry = match self.pending.take().map(Ok).or_else(|| self.entries.next()) {
+                Some(Ok(entry)) => entry,
+                Some(Err(e)) => {
+                    self.done = true;
+                    return Some(Err(e.into()));
+                }
+                None => {
+                    self.done = true;
+                    break;
+                }
+            };
+            if self.order.is_past_end(&self.range, &entry.key) {
+                self.done = true;
+                break;
+            }
+            if !self.order.contains(&self.range, &entry.key)
+                || keyspace::is_internal_key(&entry.key)
+            {
+                continue;
+            }
+            let Some(value) = entry.value else {
+                continue;
+            };
+            let value = match self.transformers.decode(&entry.key, value) {
+                Ok(value) => value,
+                Err(e) => {
+                    self.done = true;
+                    return Some(Err(e));
+                }
+            };
+            size += entry.key.len() + value.len();
+            if size >= self.max_bytes {
+                chunk.resume_key = match self.resume_key(&entry.key) {
+                    Ok(key) => key,
+                    Err(e) => {
+                        self.done = true;
+                        return Some(Err(e.into()));
+                    }
+                };
+                chunk.entries.push((entry.key, value));
+                break;
+            }
+            chunk.entries.push((entry.key, value));
+        }
+        if chunk.entries.is_empty() {
+            return None;
+        }
+        Some(Ok(chunk))
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct DatabaseStats {
+    pub rw_memtable_entries: usize,
+    /// size in bytes as accounted by memtable
+    pub rw_memtable_size: usize,
+    /// entries of frozen memtables, including the one mirroring the newest flushed table
+    pub ro_memtable_entries: usize,
+    /// frozen memtables waiting for their flush to be installed
+    pub queued_flushes: usize,
+    /// bytes written to wal but not synced to disk yet
+    pub wal_unsynced_bytes: usize,
+    /// how long the oldest unsynced wal write has been exposed to power loss
+    pub wal_oldest_unsynced_write_age: Option<Duration>,
+    /// level num -> level stats
+    pub levels: Vec<LevelStats>,
+    /// since database was opened or `Database::reset_latencies` was called
+    pub latencies: OperationLatencies,
+    /// bloom filter checks of point reads since database was opened
+    pub filters: FilterStats,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct LevelStats {
+    pub files: usize,
+    pub entries: usize,
+    pub tombstones: usize,
+    /// size of keys and values of level tables before encoding
+    pub raw_size: usize,
+    /// size of level files in bytes
+    pub size: u64,
+    /// memory taken by bloom filters of level tables in bytes
+    pub filter_size: usize,
+}
+
+#[cfg(all(test, feature = "std-fs"))]
+mod tests {
+    use super::*;
+    use crate::clock::SimulatedClock;
+    use crate::index::IndexedWrite;
+    use crate::maintenance::MaintenanceWindow;
+    use crate::repair::LOST_DIR;
+    use crate::sstable::SstBuilder;
+    use crate::utils::scan_storage;
+    use crate::validation::KeyRules;
+    use crate::vfs::FaultInjectionFs;
+    #[cfg(feature = "object-store")]
+    use crate::vfs::LocalStorage;
+    use crate::wal::{RecordType, RECORD_MAGIC};
+    use proptest::prelude::*;
+    use std::collections::BTreeMap;
+    use std::io::{Seek, SeekFrom, Write};
+    use std::sync::atomic::{AtomicBool, Ordering};
+    #[test]
+    fn swapping_memtable_works() {
         let test_dir = &PathBuf::from("./tests/swapping_memtable_works");
         if test_dir.exists() {
             fs::remove_dir_all(test_dir).unwrap();
         }
 
-        let options = Database::options().set_working_dir(test_dir).set_memtable_threshold(256);
-        let mut db = options.init().expect("failed to init db");
+        let options = Database::options().set_working_dir(test_dir).set_memtable_threshold(256);
+        let mut db = options.init().expect("failed to init db");
+
+        db.put(b"key1".to_vec(), vec![1;150]).unwrap();
+        db.put(b"key2".to_vec(), vec![2;150]).unwrap();
+    }
+
+    #[test]
+    fn reads_survive_flush_compaction_and_reopen() {
+        let test_dir = &PathBuf::from("./tests/reads_survive_flush_compaction_and_reopen");
+        if test_dir.exists() {
+            fs::remove_dir_all(test_dir).unwrap();
+        }
+
+        let options = Database::options()
+            .set_working_dir(test_dir)
+            .set_memtable_threshold(512)
+            .set_level_zero_memtables_limit(2)
+            .set_level_num(3);
+        let mut db = options.clone().init().unwrap();
+        for i in 0..100u8 {
+            db.put(vec![i], vec![i; 20]).unwrap();
+        }
+        for i in (0..100u8).step_by(3) {
+            db.delete(vec![i]).unwrap();
+        }
+        db.put(vec![1], vec![42]).unwrap();
+        assert!(db.stats().unwrap().levels.iter().any(|level| level.files > 0));
+        drop(db);
+
+        let mut db = options.init().unwrap();
+        assert_eq!(db.query([0]).unwrap(), None);
+        assert_eq!(db.query([1]).unwrap(), Some(vec![42]));
+        assert_eq!(db.query([2]).unwrap(), Some(vec![2; 20]));
+        let scanned = db.scan(vec![10]..vec![20]).unwrap();
+        let keys: Vec<_> = scanned.iter().map(|(key, _)| key[0]).collect();
+        assert_eq!(keys, vec![10, 11, 13, 14, 16, 17, 19]);
+
+        db.compact().unwrap();
+        let stats = db.stats().unwrap();
+        assert_eq!(stats.levels[2].files, 1);
+        assert_eq!(db.query([1]).unwrap(), Some(vec![42]));
+        assert_eq!(db.query([3]).unwrap(), None);
+        assert_eq!(db.scan(..).unwrap().len(), 66);
+    }
+
+    #[test]
+    fn paranoid_checks_reject_corrupt_records() {
+        let test_dir = &PathBuf::from("./tests/paranoid_checks_reject_corrupt_records");
+        if test_dir.exists() {
+            fs::remove_dir_all(test_dir).unwrap();
+        }
+        let options = Database::options()
+            .set_working_dir(test_dir)
+            .set_paranoid_checks(true);
+        let mut db = options.clone().init().unwrap();
+        for i in 0..40u8 {
+            db.put(vec![i], vec![i]).unwrap();
+        }
+        db.flush().unwrap();
+        db.put(b"c".to_vec(), b"3".to_vec()).unwrap();
+        drop(db);
+
+        // value of a record in the middle of table is damaged, table boundaries are intact
+        let sst_path = utils::scan_dir(test_dir, &["sst"]).unwrap().pop().unwrap();
+        let values_offset = SstReader::open(&sst_path)
+            .unwrap()
+            .metadata
+            .values_table_offset;
+        let record_size = CommonBinaryFormatRef::new(0, &[0], Some(&[0])).encoded_size();
+        let damaged = values_offset + 5 * record_size;
+        let intact = fs::read(&sst_path).unwrap();
+        let mut data = intact.clone();
+        data[damaged + record_size - 5] ^= 1;
+        fs::write(&sst_path, data).unwrap();
+        let err = options.clone().init().err().unwrap();
+        assert!(matches!(
+            &err,
+            DBError::Corruption { path, offset, .. }
+                if *path == sst_path && *offset == damaged as u64
+        ));
+        fs::write(&sst_path, intact).unwrap();
+
+        // torn tail is replayed as usual
+        let wal_path = utils::scan_dir(test_dir, &["wal"]).unwrap().pop().unwrap();
+        let mut wal = fs::OpenOptions::new().append(true).open(&wal_path).unwrap();
+        wal.write_all(&[RECORD_MAGIC, RecordType::Put as u8, 3])
+            .unwrap();
+        drop(wal);
+        let db = options.clone().init().unwrap();
+        assert_eq!(db.query(b"c").unwrap(), Some(b"3".to_vec()));
+        drop(db);
+
+        let wal_path = utils::scan_dir(test_dir, &["wal"]).unwrap().pop().unwrap();
+        let mut data = fs::read(&wal_path).unwrap();
+        data[0] ^= 1;
+        fs::write(&wal_path, data).unwrap();
+        let err = options.init().err().unwrap();
+        assert!(matches!(
+            &err,
+            DBError::Corruption { path, offset: 0, .. } if *path == wal_path
+        ));
+    }
+
+    #[test]
+    fn repair_salvages_intact_records() {
+        let test_dir = &PathBuf::from("./tests/repair_salvages_intact_records");
+        if test_dir.exists() {
+            fs::remove_dir_all(test_dir).unwrap();
+        }
+        let mut db = Database::options()
+            .set_working_dir(test_dir)
+            .init()
+            .unwrap();
+        for i in 0..40u8 {
+            db.put(vec![i], vec![i]).unwrap();
+        }
+        db.flush().unwrap();
+        db.delete(vec![3]).unwrap();
+        for i in 40..45u8 {
+            db.put(vec![i], vec![i]).unwrap();
+        }
+        drop(db);
+
+        // the sixth record of table is damaged, records after it are lost
+        let sst_path = utils::scan_dir(test_dir, &["sst"]).unwrap().pop().unwrap();
+        let values_offset = SstReader::open(&sst_path)
+            .unwrap()
+            .metadata
+            .values_table_offset;
+        let record_size = CommonBinaryFormatRef::new(0, &[0], Some(&[0])).encoded_size();
+        let mut data = fs::read(&sst_path).unwrap();
+        data[values_offset + 6 * record_size - 5] ^= 1;
+        fs::write(&sst_path, data).unwrap();
+        let garbage = test_dir.join("1.sst");
+        fs::write(&garbage, b"not a table").unwrap();
+
+        let report = Database::repair(test_dir).unwrap();
+        assert_eq!(report.salvaged_records, 5 + 1 + 5);
+        let lost_dir = test_dir.join(LOST_DIR);
+        let mut expected_lost = vec![
+            lost_dir.join(garbage.file_name().unwrap()),
+            lost_dir.join(sst_path.file_name().unwrap()),
+        ];
+        expected_lost.sort();
+        let mut quarantined = report.quarantined.clone();
+        quarantined.sort();
+        assert_eq!(quarantined, expected_lost);
+        assert!(expected_lost.iter().all(|path| path.exists()));
+
+        let db = Database::options()
+            .set_working_dir(test_dir)
+            .init()
+            .unwrap();
+        let expected: Vec<_> = [0, 1, 2, 4, 40, 41, 42, 43, 44]
+            .into_iter()
+            .map(|i| (vec![i], vec![i]))
+            .collect();
+        assert_eq!(db.scan(..).unwrap(), expected);
+        let stats = db.stats().unwrap();
+        assert_eq!(
+            stats.levels.iter().map(|level| level.files).sum::<usize>(),
+            1
+        );
+    }
+
+    #[test]
+    fn errors_report_their_cause() {
+        let test_dir = &PathBuf::from("./tests/errors_report_their_cause");
+        if test_dir.exists() {
+            fs::remove_dir_all(test_dir).unwrap();
+        }
+
+        let err = Database::options()
+            .set_working_dir(test_dir.join("db"))
+            .set_level_factor(0)
+            .init()
+            .err()
+            .unwrap();
+        assert!(matches!(err, DBError::InvalidOptions(_)));
+
+        let mut db = Database::options()
+            .set_working_dir(test_dir.join("db"))
+            .init()
+            .unwrap();
+        let checkpoint = test_dir.join("checkpoint");
+        fs::create_dir_all(&checkpoint).unwrap();
+        let err = db.checkpoint(&checkpoint).unwrap_err();
+        assert!(matches!(
+            &err,
+            DBError::Io { path: Some(path), source }
+                if *path == checkpoint && source.kind() == io::ErrorKind::AlreadyExists
+        ));
+    }
+
+    #[test]
+    fn recycles_wal_files() {
+        let test_dir = &PathBuf::from("./tests/recycles_wal_files");
+        if test_dir.exists() {
+            fs::remove_dir_all(test_dir).unwrap();
+        }
+        let options = Database::options()
+            .set_working_dir(test_dir)
+            .set_wal_preallocate_size(1 << 16)
+            .set_recycle_wals(1);
+        let mut db = options.clone().init().unwrap();
+        let count = |ext| utils::scan_dir(test_dir, &[ext]).unwrap().len();
+        db.put(vec![1], vec![1; 100]).unwrap();
+        db.flush().unwrap();
+        assert_eq!((count("wal"), count("recycled")), (1, 1));
+        db.put(vec![2], vec![2; 10]).unwrap();
+        db.flush().unwrap();
+        // the second wal took the recycled file, the first one is recycled in turn
+        assert_eq!((count("wal"), count("recycled")), (1, 1));
+        db.put(vec![3], vec![3; 10]).unwrap();
+        drop(db);
+
+        let db = options.init().unwrap();
+        assert_eq!(db.query([1]).unwrap(), Some(vec![1; 100]));
+        assert_eq!(db.query([2]).unwrap(), Some(vec![2; 10]));
+        assert_eq!(db.query([3]).unwrap(), Some(vec![3; 10]));
+        assert_eq!(db.rw_memtable.len(), 1);
+    }
+
+    #[test]
+    fn locks_working_dir() {
+        let test_dir = &PathBuf::from("./tests/locks_working_dir");
+        if test_dir.exists() {
+            fs::remove_dir_all(test_dir).unwrap();
+        }
+        let options = Database::options().set_working_dir(test_dir);
+        let db = options.clone().init().unwrap();
+        let held = options.clone().init();
+        assert!(matches!(&held, Err(DBError::LockHeld(dir)) if dir == test_dir));
+        drop(db);
+        options.init().unwrap();
+
+        let names = ["9.sst", "10.sst", "COMPARATOR"].map(PathBuf::from);
+        let mut sorted = names.clone();
+        sorted.sort_by(|a, b| utils::compare_file_names(a, b));
+        assert_eq!(sorted, [&names[2], &names[0], &names[1]].map(Clone::clone));
+    }
+
+    #[test]
+    fn scans_and_counts_keys_without_values() {
+        let test_dir = &PathBuf::from("./tests/scans_and_counts_keys_without_values");
+        if test_dir.exists() {
+            fs::remove_dir_all(test_dir).unwrap();
+        }
+        let mut db = Database::options()
+            .set_working_dir(test_dir)
+            .set_memtable_threshold(64 * 1024)
+            .init()
+            .unwrap();
+        for i in 0..20u8 {
+            db.put(vec![i], vec![i; 10_000]).unwrap();
+        }
+        db.delete(vec![3]).unwrap();
+        db.delete_range(vec![10], vec![15]).unwrap();
+        db.flush().unwrap();
+        db.put(vec![11], vec![]).unwrap();
+        db.delete(vec![19]).unwrap();
+
+        let keys = db.scan_keys(vec![2]..).unwrap();
+        let expected: Vec<_> = db
+            .scan(vec![2]..)
+            .unwrap()
+            .into_iter()
+            .map(|(k, _)| k)
+            .collect();
+        assert_eq!(keys, expected);
+        assert_eq!(keys.len(), 12);
+        assert_eq!(db.count(vec![2]..).unwrap(), 12);
+        assert_eq!(db.count(..vec![2]).unwrap(), 2);
+        let total = db.fold(.., 0, |total, _, value| total + value.len());
+        assert_eq!(total.unwrap(), 13 * 10_000);
+    }
+
+    #[test]
+    fn detects_sst_key_range_mismatch() {
+        let test_dir = &PathBuf::from("./tests/detects_sst_key_range_mismatch");
+        if test_dir.exists() {
+            fs::remove_dir_all(test_dir).unwrap();
+        }
+
+        let options = Database::options().set_working_dir(test_dir);
+        let mut db = options.clone().init().unwrap();
+        db.put(b"aaa".to_vec(), b"1".to_vec()).unwrap();
+        db.put(b"zzz".to_vec(), b"2".to_vec()).unwrap();
+        db.swap_memtable().unwrap();
+        drop(db);
+
+        // low key is stored right after level, three offsets, entry count, index interval and its own size
+        let sst_path = utils::scan_dir(test_dir, &["sst"]).unwrap().pop().unwrap();
+        let mut data = fs::read(&sst_path).unwrap();
+        data[7 * mem::size_of::<usize>()] = b'b';
+        fs::write(&sst_path, data).unwrap();
+
+        let err = options.set_paranoid_checks(true).init().err().unwrap();
+        assert!(matches!(
+            &err,
+            DBError::SstKeyRangeMismatch(path) if *path == sst_path
+        ));
+    }
+
+    #[test]
+    fn checkpoint_is_consistent_copy() {
+        let test_dir = &PathBuf::from("./tests/checkpoint_is_consistent_copy");
+        if test_dir.exists() {
+            fs::remove_dir_all(test_dir).unwrap();
+        }
+        let db_dir = test_dir.join("db");
+        let checkpoint_dir = test_dir.join("checkpoint");
+
+        let mut db = Database::options().set_working_dir(&db_dir).init().unwrap();
+        db.put(b"flushed".to_vec(), b"1".to_vec()).unwrap();
+        db.swap_memtable().unwrap();
+        db.put(b"in wal".to_vec(), b"2".to_vec()).unwrap();
+        db.checkpoint(&checkpoint_dir).unwrap();
+        assert!(db.checkpoint(&checkpoint_dir).is_err());
+        db.put(b"after".to_vec(), b"3".to_vec()).unwrap();
+
+        let copy = Database::options()
+            .set_working_dir(&checkpoint_dir)
+            .init()
+            .unwrap();
+        assert_eq!(copy.query(b"flushed").unwrap(), Some(b"1".to_vec()));
+        assert_eq!(copy.query(b"in wal").unwrap(), Some(b"2".to_vec()));
+        assert_eq!(copy.query(b"after").unwrap(), None);
+        assert_eq!(db.query(b"after").unwrap(), Some(b"3".to_vec()));
+    }
+
+    #[test]
+    fn checkpoint_stays_in_storage() {
+        let test_dir = &PathBuf::from("./tests/checkpoint_stays_in_storage");
+        if test_dir.exists() {
+            fs::remove_dir_all(test_dir).unwrap();
+        }
+        let storage = MemStorage::new();
+        let checkpoint_dir = test_dir.join("checkpoint");
+        let mut db = Database::options()
+            .set_working_dir(test_dir.join("db"))
+            .set_storage(Arc::new(storage.clone()))
+            .init()
+            .unwrap();
+        db.put(b"flushed".to_vec(), b"1".to_vec()).unwrap();
+        db.swap_memtable().unwrap();
+        db.put(b"in wal".to_vec(), b"2".to_vec()).unwrap();
+        db.checkpoint(&checkpoint_dir).unwrap();
+        assert!(!test_dir.exists());
+
+        let copy = Database::options()
+            .set_working_dir(&checkpoint_dir)
+            .set_storage(Arc::new(storage))
+            .init()
+            .unwrap();
+        assert_eq!(copy.query(b"flushed").unwrap(), Some(b"1".to_vec()));
+        assert_eq!(copy.query(b"in wal").unwrap(), Some(b"2".to_vec()));
+    }
+
+    #[test]
+    fn wal_sync_policy_bounds_unsynced_bytes() {
+        let test_dir = &PathBuf::from("./tests/wal_sync_policy_bounds_unsynced_bytes");
+        if test_dir.exists() {
+            fs::remove_dir_all(test_dir).unwrap();
+        }
+        let options = Database::options().set_working_dir(test_dir);
+
+        let mut db = options
+            .clone()
+            .set_wal_sync_policy(WalSyncPolicy::Periodic {
+                interval: Duration::from_secs(3600),
+                max_unsynced_bytes: 100,
+            })
+            .init()
+            .unwrap();
+        db.put(b"key".to_vec(), vec![0; 10]).unwrap();
+        let stats = db.stats().unwrap();
+        assert!(stats.wal_unsynced_bytes > 0);
+        assert!(stats.wal_oldest_unsynced_write_age.is_some());
+        db.put(b"key".to_vec(), vec![0; 100]).unwrap();
+        let stats = db.stats().unwrap();
+        assert_eq!(stats.wal_unsynced_bytes, 0);
+        assert_eq!(stats.wal_oldest_unsynced_write_age, None);
+        drop(db);
+
+        let mut db = options
+            .set_wal_sync_policy(WalSyncPolicy::Manual)
+            .init()
+            .unwrap();
+        db.put(b"key".to_vec(), vec![0; 1000]).unwrap();
+        assert!(db.stats().unwrap().wal_unsynced_bytes > 1000);
+        db.sync_wal().unwrap();
+        assert_eq!(db.stats().unwrap().wal_unsynced_bytes, 0);
+    }
+
+    #[test]
+    fn periodic_wal_sync_runs_without_writes() {
+        let test_dir = &PathBuf::from("./tests/periodic_wal_sync_runs_without_writes");
+        if test_dir.exists() {
+            fs::remove_dir_all(test_dir).unwrap();
+        }
+        let mut db = Database::options()
+            .set_working_dir(test_dir)
+            .set_wal_sync_policy(WalSyncPolicy::Periodic {
+                interval: Duration::from_millis(20),
+                max_unsynced_bytes: usize::MAX,
+            })
+            .init()
+            .unwrap();
+        db.put(b"key".to_vec(), b"value".to_vec()).unwrap();
+        let start = Stopwatch::start();
+        while db.stats().unwrap().wal_unsynced_bytes > 0 {
+            assert!(start.elapsed() < Duration::from_secs(10));
+            thread::sleep(Duration::from_millis(5));
+        }
+        assert_eq!(db.stats().unwrap().wal_oldest_unsynced_write_age, None);
+    }
+
+    #[test]
+    fn write_batch_survives_restart() {
+        let test_dir = &PathBuf::from("./tests/write_batch_survives_restart");
+        if test_dir.exists() {
+            fs::remove_dir_all(test_dir).unwrap();
+        }
+        let options = Database::options().set_working_dir(test_dir);
+        let mut db = options.clone().init().unwrap();
+        db.put(b"removed".to_vec(), b"0".to_vec()).unwrap();
+
+        let mut batch = WriteBatch::new();
+        batch
+            .put(b"a".to_vec(), b"1".to_vec())
+            .delete(b"removed".to_vec())
+            .put(b"b".to_vec(), b"2".to_vec())
+            .put(b"a".to_vec(), b"3".to_vec());
+        let shipped = WriteBatch::from_bytes(&batch.serialize()).unwrap();
+        db.write(shipped).unwrap();
+        drop(db);
+
+        let db = options.init().unwrap();
+        assert_eq!(
+            db.scan(..).unwrap(),
+            vec![
+                (b"a".to_vec(), b"3".to_vec()),
+                (b"b".to_vec(), b"2".to_vec())
+            ]
+        );
+    }
+
+    #[test]
+    fn apply_batch_if_checks_sequence() {
+        let test_dir = &PathBuf::from("./tests/apply_batch_if_checks_sequence");
+        if test_dir.exists() {
+            fs::remove_dir_all(test_dir).unwrap();
+        }
+        let options = Database::options().set_working_dir(test_dir);
+        let mut db = options.clone().init().unwrap();
+        db.put(b"a".to_vec(), b"1".to_vec()).unwrap();
+        let sequence = db.last_sequence();
+        drop(db);
+
+        let mut db = options.init().unwrap();
+        assert_eq!(db.last_sequence(), sequence);
+        let mut batch = WriteBatch::new();
+        batch.put(b"a".to_vec(), b"2".to_vec());
+        let batch = batch.serialize();
+
+        let err = db.apply_batch_if(&batch, sequence - 1).unwrap_err();
+        assert!(matches!(
+            &err,
+            DBError::SequenceMismatch { actual, .. } if *actual == sequence
+        ));
+        assert_eq!(db.query(b"a").unwrap(), Some(b"1".to_vec()));
+
+        let new_sequence = db.apply_batch_if(&batch, sequence).unwrap();
+        assert!(new_sequence > sequence);
+        assert_eq!(db.query(b"a").unwrap(), Some(b"2".to_vec()));
+        assert!(db.apply_batch_if(&batch, sequence).is_err());
+    }
+
+    #[test]
+    fn ingests_tables_on_deepest_free_level() {
+        let test_dir = &PathBuf::from("./tests/ingests_tables_on_deepest_free_level");
+        if test_dir.exists() {
+            fs::remove_dir_all(test_dir).unwrap();
+        }
+        let external_dir = test_dir.join("external");
+        fs::create_dir_all(&external_dir).unwrap();
+        let mut db = Database::options()
+            .set_working_dir(test_dir.join("db"))
+            .set_level_num(3)
+            .init()
+            .unwrap();
+        db.put(b"b".to_vec(), b"old".to_vec()).unwrap();
+        db.swap_memtable().unwrap();
+        db.put(b"x".to_vec(), b"memtable".to_vec()).unwrap();
+
+        let mut builder = SstBuilder::new();
+        builder.put(b"a", b"1").unwrap();
+        builder.put(b"b", b"new").unwrap();
+        assert!(builder.put(b"b", b"again").is_err());
+        builder
+            .finish(external_dir.join("overlapping.sst"))
+            .unwrap();
+        let mut builder = SstBuilder::new();
+        builder.put(b"m", b"2").unwrap();
+        builder.delete(b"n").unwrap();
+        builder.finish(external_dir.join("disjoint.sst")).unwrap();
+
+        db.ingest_sst(&[
+            external_dir.join("overlapping.sst"),
+            external_dir.join("disjoint.sst"),
+        ])
+        .unwrap();
+        // ingested entries share the sequence following the last write
+        assert_eq!(db.last_sequence(), 3);
+        assert_eq!(db.last_commit_timestamp(b"m").unwrap(), 3);
+        assert_eq!(db.query(b"b").unwrap(), Some(b"new".to_vec()));
+        assert_eq!(db.query(b"m").unwrap(), Some(b"2".to_vec()));
+        assert_eq!(db.query(b"x").unwrap(), Some(b"memtable".to_vec()));
+        let files: Vec<_> = db.stats().unwrap().levels.iter().map(|l| l.files).collect();
+        assert_eq!(files, vec![2, 0, 1]);
+
+        let mut builder = SstBuilder::new();
+        builder.put(b"a", b"3").unwrap();
+        builder
+            .finish(external_dir.join("conflicting.sst"))
+            .unwrap();
+        let err = db
+            .ingest_sst(&[
+                external_dir.join("overlapping.sst"),
+                external_dir.join("conflicting.sst"),
+            ])
+            .unwrap_err();
+        assert!(matches!(&err, DBError::IngestedTablesOverlap(..)));
+    }
+
+    #[test]
+    fn ingests_behind_existing_data() {
+        let test_dir = &PathBuf::from("./tests/ingests_behind_existing_data");
+        if test_dir.exists() {
+            fs::remove_dir_all(test_dir).unwrap();
+        }
+        let external_dir = &test_dir.join("external");
+        fs::create_dir_all(external_dir).unwrap();
+        let mut builder = SstBuilder::new();
+        builder.put(b"a", b"historical").unwrap();
+        builder.put(b"b", b"historical").unwrap();
+        builder.put(b"c", b"historical").unwrap();
+        builder.finish(external_dir.join("history.sst")).unwrap();
+
+        let options = Database::options()
+            .set_working_dir(test_dir)
+            .set_level_num(2);
+        let mut db = options.clone().init().unwrap();
+        let err = db
+            .ingest_behind(&[external_dir.join("history.sst")])
+            .unwrap_err();
+        assert!(matches!(&err, DBError::IngestBehindDisabled));
+        drop(db);
+
+        let options = options.set_allow_ingest_behind(true);
+        let mut db = options.clone().init().unwrap();
+        db.put(b"a".to_vec(), b"live".to_vec()).unwrap();
+        db.delete(b"b".to_vec()).unwrap();
+        db.flush().unwrap();
+        db.ingest_behind(&[external_dir.join("history.sst")])
+            .unwrap();
+        assert_eq!(db.last_sequence(), 2);
+        assert_eq!(db.last_commit_timestamp(b"c").unwrap(), 0);
+        let files: Vec<_> = db.stats().unwrap().levels.iter().map(|l| l.files).collect();
+        assert_eq!(files, vec![1, 0, 1]);
+        assert_eq!(db.query(b"a").unwrap(), Some(b"live".to_vec()));
+        assert_eq!(db.query(b"b").unwrap(), None);
+        assert_eq!(db.query(b"c").unwrap(), Some(b"historical".to_vec()));
+
+        // compaction leaves reserved level alone and keeps tombstones shadowing it
+        db.compact().unwrap();
+        let files: Vec<_> = db.stats().unwrap().levels.iter().map(|l| l.files).collect();
+        assert_eq!(files, vec![0, 1, 1]);
+        drop(db);
+        let db = options.init().unwrap();
+        let keys: Vec<_> = db.scan(..).unwrap();
+        assert_eq!(
+            keys,
+            vec![
+                (b"a".to_vec(), b"live".to_vec()),
+                (b"c".to_vec(), b"historical".to_vec())
+            ]
+        );
+    }
+
+    #[test]
+    fn range_scoped_flush_and_compaction() {
+        let test_dir = &PathBuf::from("./tests/range_scoped_flush_and_compaction");
+        if test_dir.exists() {
+            fs::remove_dir_all(test_dir).unwrap();
+        }
+        let mut db = Database::options()
+            .set_working_dir(test_dir)
+            .set_level_num(3)
+            .init()
+            .unwrap();
+        for tenant in [b"a/", b"b/", b"c/"] {
+            for i in 0..5u8 {
+                db.put([tenant.as_slice(), &[i]].concat(), vec![i]).unwrap();
+            }
+        }
+        db.flush_range(b"b/", b"c/").unwrap();
+        let stats = db.stats().unwrap();
+        assert_eq!(stats.rw_memtable_entries, 10);
+        assert_eq!(stats.levels[0].entries, 5);
+
+        db.delete(b"b/\x01".to_vec()).unwrap();
+        db.flush_range(b"b/", b"c/").unwrap();
+        db.flush_range(b"a/", b"b/").unwrap();
+        db.compact_range(b"b/", b"c/").unwrap();
+        let files: Vec<_> = db.stats().unwrap().levels.iter().map(|l| l.files).collect();
+        assert_eq!(files, vec![1, 0, 1]);
+        assert_eq!(db.stats().unwrap().levels[2].entries, 4);
+        assert_eq!(db.query(b"b/\x01").unwrap(), None);
+        assert_eq!(db.query(b"b/\x02").unwrap(), Some(vec![2]));
+        assert_eq!(db.query(b"a/\x02").unwrap(), Some(vec![2]));
+        assert_eq!(db.scan(..).unwrap().len(), 14);
+    }
+
+    #[test]
+    fn write_guard_rejects_reserved_prefix() {
+        let test_dir = &PathBuf::from("./tests/write_guard_rejects_reserved_prefix");
+        if test_dir.exists() {
+            fs::remove_dir_all(test_dir).unwrap();
+        }
+        let mut db = Database::options()
+            .set_working_dir(test_dir)
+            .set_write_guard(|key, kind| !(key.starts_with(b"sys/") && kind == WriteKind::Put))
+            .init()
+            .unwrap();
+        db.put(b"user/1".to_vec(), b"1".to_vec()).unwrap();
+        db.delete(b"sys/1".to_vec()).unwrap();
+        let err = db.put(b"sys/1".to_vec(), b"1".to_vec()).unwrap_err();
+        assert!(matches!(
+            &err,
+            DBError::PermissionDenied(key) if key == b"sys/1"
+        ));
+
+        let mut batch = WriteBatch::new();
+        batch
+            .put(b"user/2".to_vec(), b"2".to_vec())
+            .put(b"sys/2".to_vec(), b"2".to_vec());
+        assert!(db.write(batch).is_err());
+        assert_eq!(db.query(b"user/2").unwrap(), None);
+    }
+
+    #[test]
+    fn key_validator_rejects_malformed_keys() {
+        let test_dir = &PathBuf::from("./tests/key_validator_rejects_malformed_keys");
+        if test_dir.exists() {
+            fs::remove_dir_all(test_dir).unwrap();
+        }
+        let rules = KeyRules::new()
+            .set_max_len(16)
+            .set_allowed_prefixes(vec![b"user/".to_vec(), b"bin/".to_vec()])
+            .set_utf8_prefixes(vec![b"user/".to_vec()]);
+        let mut db = Database::options()
+            .set_working_dir(test_dir)
+            .set_key_validator(move |key| rules.validate(key))
+            .init()
+            .unwrap();
+        db.put(b"user/alice".to_vec(), b"1".to_vec()).unwrap();
+        db.put(b"bin/\xff".to_vec(), b"2".to_vec()).unwrap();
+        for key in [&b"other/1"[..], b"user/\xff", b"user/0123456789ab"] {
+            let err = db.put(key.to_vec(), b"1".to_vec()).unwrap_err();
+            assert!(matches!(
+                &err,
+                DBError::InvalidKey { key: rejected, .. } if rejected == key
+            ));
+        }
+        assert!(db.delete(b"other/1".to_vec()).is_err());
+
+        let mut batch = WriteBatch::new();
+        batch
+            .put(b"user/bob".to_vec(), b"3".to_vec())
+            .delete(b"other/2".to_vec());
+        assert!(db.write(batch).is_err());
+        assert_eq!(db.query(b"user/bob").unwrap(), None);
+        assert_eq!(db.query(b"user/alice").unwrap(), Some(b"1".to_vec()));
+    }
+
+    #[test]
+    fn internal_keyspace_is_hidden_from_users() {
+        let test_dir = &PathBuf::from("./tests/internal_keyspace_is_hidden_from_users");
+        if test_dir.exists() {
+            fs::remove_dir_all(test_dir).unwrap();
+        }
+        let options = Database::options().set_working_dir(test_dir);
+        let mut db = options.clone().init().unwrap();
+        let mut batch = WriteBatch::new();
+        batch
+            .put(b"user".to_vec(), b"1".to_vec())
+            .put_internal(b"repl/position", b"42".to_vec());
+        db.write(batch).unwrap();
+
+        let reserved = keyspace::internal_key(b"repl/position");
+        let err = db.put(reserved.clone(), b"0".to_vec()).unwrap_err();
+        assert!(matches!(
+            &err,
+            DBError::ReservedKey(key) if *key == reserved
+        ));
+        let mut batch = WriteBatch::new();
+        batch.put(b"other".to_vec(), b"2".to_vec()).delete(reserved);
+        assert!(db.write(batch).is_err());
+        db.swap_memtable().unwrap();
+        drop(db);
+
+        let db = options.init().unwrap();
+        assert_eq!(
+            db.scan(..).unwrap(),
+            vec![(b"user".to_vec(), b"1".to_vec())]
+        );
+        assert_eq!(
+            db.query_internal(b"repl/position").unwrap(),
+            Some(b"42".to_vec())
+        );
+        assert_eq!(
+            db.scan_internal(b"repl/").unwrap(),
+            vec![(b"repl/position".to_vec(), b"42".to_vec())]
+        );
+        assert!(db.scan_internal(b"index/").unwrap().is_empty());
+    }
+
+    #[test]
+    fn indexed_writes_keep_index_consistent() {
+        let test_dir = &PathBuf::from("./tests/indexed_writes_keep_index_consistent");
+        if test_dir.exists() {
+            fs::remove_dir_all(test_dir).unwrap();
+        }
+        let options = Database::options().set_working_dir(test_dir);
+        let mut db = options.clone().init().unwrap();
+        let write = IndexedWrite::put(b"user/1".to_vec(), b"alice@a.com".to_vec())
+            .add_index(b"email", b"alice@a.com");
+        db.write(write.into_batch()).unwrap();
+        let write = IndexedWrite::put(b"user/2".to_vec(), b"bob@a.com".to_vec())
+            .add_index(b"email", b"bob@a.com");
+        db.write(write.into_batch()).unwrap();
+        let write = IndexedWrite::put(b"user/1".to_vec(), b"alice@b.com".to_vec())
+            .remove_index(b"email", b"alice@a.com")
+            .add_index(b"email", b"alice@b.com");
+        db.write(write.into_batch()).unwrap();
+        drop(db);
+
+        let mut db = options.init().unwrap();
+        assert!(db
+            .index_lookup(b"email", b"alice@a.com")
+            .unwrap()
+            .is_empty());
+        assert_eq!(
+            db.index_lookup(b"email", b"alice@b.com").unwrap(),
+            vec![b"user/1".to_vec()]
+        );
+        let write = IndexedWrite::delete(b"user/2".to_vec()).remove_index(b"email", b"bob@a.com");
+        db.write(write.into_batch()).unwrap();
+        assert!(db.index_lookup(b"email", b"bob@a.com").unwrap().is_empty());
+        assert_eq!(db.scan(..).unwrap().len(), 1);
+    }
+
+    #[test]
+    fn scan_chunks_are_bounded_and_resumable() {
+        let test_dir = &PathBuf::from("./tests/scan_chunks_are_bounded_and_resumable");
+        if test_dir.exists() {
+            fs::remove_dir_all(test_dir).unwrap();
+        }
+        let mut db = Database::options()
+            .set_working_dir(test_dir)
+            .init()
+            .unwrap();
+        for i in 0..20u8 {
+            db.put(vec![i], vec![i; 9]).unwrap();
+        }
+        db.swap_memtable().unwrap();
+        for i in (0..20u8).step_by(2) {
+            db.delete(vec![i]).unwrap();
+        }
+        db.put(vec![5], vec![0; 29]).unwrap();
+
+        let chunks: Vec<_> = db
+            .scan_chunks(vec![3]..vec![15], 30)
+            .unwrap()
+            .collect::<Result<_>>()
+            .unwrap();
+        let keys: Vec<Vec<u8>> = chunks
+            .iter()
+            .map(|chunk| chunk.entries.iter().map(|(key, _)| key[0]).collect())
+            .collect();
+        assert_eq!(keys, vec![vec![3, 5], vec![7, 9, 11], vec![13]]);
+        assert_eq!(chunks[0].resume_key, Some(vec![5, 0]));
+        assert_eq!(chunks[2].resume_key, None);
+
+        let resumed = db
+            .scan_chunks(chunks[0].resume_key.clone().unwrap()..vec![15], 30)
+            .unwrap()
+            .next()
+            .unwrap()
+            .unwrap();
+        assert_eq!(resumed, chunks[1]);
+    }
+
+    #[test]
+    fn scan_pages_continue_after_token() {
+        let test_dir = &PathBuf::from("./tests/scan_pages_continue_after_token");
+        if test_dir.exists() {
+            fs::remove_dir_all(test_dir).unwrap();
+        }
+        let mut db = Database::options()
+            .set_working_dir(test_dir)
+            .init()
+            .unwrap();
+        for i in 0..10u8 {
+            db.put(vec![i], vec![i]).unwrap();
+        }
+        db.swap_memtable().unwrap();
+        db.delete(vec![4]).unwrap();
+
+        let mut pages = Vec::new();
+        let mut after = None;
+        loop {
+            let page = db.scan_page(vec![2]..vec![9], 3, after).unwrap();
+            pages.push(
+                page.entries
+                    .iter()
+                    .map(|(key, _)| key[0])
+                    .collect::<Vec<_>>(),
+            );
+            after = page.continuation;
+            if after.is_none() {
+                break;
+            }
+        }
+        assert_eq!(pages, vec![vec![2, 3, 5], vec![6, 7, 8]]);
+
+        let page = db.scan_page(vec![2]..vec![9], 2, Some(vec![0])).unwrap();
+        assert_eq!(page.entries, vec![(vec![2], vec![2]), (vec![3], vec![3])]);
+        assert_eq!(page.continuation, Some(vec![3]));
+    }
+
+    #[test]
+    fn tails_wal_by_commit() {
+        let test_dir = &PathBuf::from("./tests/tails_wal_by_commit");
+        if test_dir.exists() {
+            fs::remove_dir_all(test_dir).unwrap();
+        }
+        let mut db = Database::options()
+            .set_working_dir(test_dir)
+            .init()
+            .unwrap();
+        db.put(vec![1], vec![1]).unwrap();
+        db.swap_memtable().unwrap();
+        db.put(vec![2], vec![2]).unwrap();
+        db.swap_memtable().unwrap();
+        let mut batch = WriteBatch::new();
+        batch.put(vec![3], vec![3]).delete(vec![1]);
+        db.write(batch).unwrap();
+        db.delete(vec![2]).unwrap();
+
+        let truncated = db.wal_tail(1).unwrap().next().unwrap();
+        assert!(matches!(truncated, Err(DBError::WalTruncated(1))));
+        let records: Vec<_> = db
+            .wal_tail(3)
+            .unwrap()
+            .map(|record| {
+                let record = record.unwrap();
+                let ops: Vec<_> = record
+                    .entries
+                    .into_iter()
+                    .map(|entry| (entry.key, entry.value))
+                    .collect();
+                (record.sequence, ops)
+            })
+            .collect();
+        assert_eq!(
+            records,
+            vec![
+                (3, vec![(vec![3], Some(vec![3])), (vec![1], None)]),
+                (4, vec![(vec![2], None)]),
+            ]
+        );
+        assert_eq!(db.wal_tail(5).unwrap().count(), 0);
+    }
+
+    #[test]
+    fn subscribers_receive_committed_changes() {
+        let test_dir = &PathBuf::from("./tests/subscribers_receive_committed_changes");
+        if test_dir.exists() {
+            fs::remove_dir_all(test_dir).unwrap();
+        }
+        let mut db = Database::options()
+            .set_working_dir(test_dir)
+            .init()
+            .unwrap();
+        let users = db.subscribe(b"user/".to_vec());
+        let all = db.subscribe(Vec::new());
+        drop(db.subscribe(b"user/".to_vec()));
+
+        db.put(b"user/1".to_vec(), b"a".to_vec()).unwrap();
+        db.put(b"item/1".to_vec(), b"b".to_vec()).unwrap();
+        let mut batch = WriteBatch::new();
+        batch.put(b"user/2".to_vec(), b"c".to_vec());
+        batch.delete(b"user/1".to_vec());
+        db.write(batch).unwrap();
+
+        let changes: Vec<_> = users
+            .try_iter()
+            .map(|change| (change.key, change.value, change.timestamp))
+            .collect();
+        assert_eq!(
+            changes,
+            vec![
+                (b"user/1".to_vec(), Some(b"a".to_vec()), 1),
+                (b"user/2".to_vec(), Some(b"c".to_vec()), 3),
+                (b"user/1".to_vec(), None, 3),
+            ]
+        );
+        assert_eq!(all.try_iter().count(), 4);
+    }
+
+    #[test]
+    fn replays_recorded_workload() {
+        let test_dir = &PathBuf::from("./tests/replays_recorded_workload");
+        if test_dir.exists() {
+            fs::remove_dir_all(test_dir).unwrap();
+        }
+        let mut db = Database::options()
+            .set_working_dir(test_dir.join("source"))
+            .init()
+            .unwrap();
+        let trace_path = test_dir.join("trace");
+        db.start_trace(fs::File::create(&trace_path).unwrap())
+            .unwrap();
+        db.put(b"a".to_vec(), b"123".to_vec()).unwrap();
+        db.put(b"b".to_vec(), b"12345".to_vec()).unwrap();
+        db.delete(b"a".to_vec()).unwrap();
+        db.delete_range(b"c".to_vec(), b"d".to_vec()).unwrap();
+        db.query(b"b").unwrap();
+        db.scan(..).unwrap();
+        db.end_trace().unwrap();
+        db.put(b"untraced".to_vec(), Vec::new()).unwrap();
+
+        let mut reader = io::BufReader::new(fs::File::open(&trace_path).unwrap());
+        workload::read_header(&mut reader).unwrap();
+        let mut records = Vec::new();
+        while let Some(record) = TraceRecord::read(&mut reader).unwrap() {
+            records.push((record.op, record.key, record.value_size));
+        }
+        assert_eq!(
+            records,
+            vec![
+                (TraceOp::Put, b"a".to_vec(), 3),
+                (TraceOp::Put, b"b".to_vec(), 5),
+                (TraceOp::Delete, b"a".to_vec(), 0),
+                (
+                    TraceOp::DeleteRange { end: b"d".to_vec() },
+                    b"c".to_vec(),
+                    0
+                ),
+                (TraceOp::Get, b"b".to_vec(), 0),
+                (TraceOp::Scan, Vec::new(), 1),
+            ]
+        );
+
+        let mut replayed = Database::options()
+            .set_working_dir(test_dir.join("replay"))
+            .init()
+            .unwrap();
+        let report = replayed
+            .replay_trace(fs::File::open(&trace_path).unwrap(), false)
+            .unwrap();
+        assert_eq!(report.operations, 6);
+        assert_eq!(
+            replayed.scan(..).unwrap(),
+            vec![(b"b".to_vec(), b"xxxxx".to_vec())]
+        );
+    }
+
+    #[test]
+    fn compare_and_swap_acts_only_on_expected_value() {
+        let test_dir = &PathBuf::from("./tests/compare_and_swap_acts_only_on_expected_value");
+        if test_dir.exists() {
+            fs::remove_dir_all(test_dir).unwrap();
+        }
+        let mut db = Database::options()
+            .set_working_dir(test_dir)
+            .init()
+            .unwrap();
+        let key = b"lease".to_vec();
+        assert!(db
+            .compare_and_swap(key.clone(), None, Some(b"owner1".to_vec()))
+            .unwrap());
+        assert!(!db
+            .compare_and_swap(key.clone(), None, Some(b"owner2".to_vec()))
+            .unwrap());
+        assert!(!db
+            .compare_and_swap(key.clone(), Some(b"owner2"), None)
+            .unwrap());
+        assert_eq!(db.query(&key).unwrap(), Some(b"owner1".to_vec()));
+        db.swap_memtable().unwrap();
+        assert!(db
+            .compare_and_swap(key.clone(), Some(b"owner1"), None)
+            .unwrap());
+        assert_eq!(db.query(&key).unwrap(), None);
+    }
+
+    #[test]
+    fn reports_filter_false_positives() {
+        let test_dir = &PathBuf::from("./tests/reports_filter_false_positives");
+        if test_dir.exists() {
+            fs::remove_dir_all(test_dir).unwrap();
+        }
+        let mut db = Database::options()
+            .set_working_dir(test_dir)
+            .set_bloom_bits_per_key(4)
+            .init()
+            .unwrap();
+        for i in (0..400u16).step_by(2) {
+            db.put(i.to_be_bytes().to_vec(), vec![1]).unwrap();
+        }
+        db.swap_memtable().unwrap();
+        // ro memtable mirrors the newest table, so that one is out of queried range
+        db.put(vec![0xff; 3], vec![1]).unwrap();
+        db.swap_memtable().unwrap();
+
+        // the last odd key is past table key range and skips filter
+        for i in 0..400u16 {
+            assert_eq!(db.query(i.to_be_bytes()).unwrap().is_some(), i % 2 == 0);
+        }
+        let stats = db.stats().unwrap();
+        let filters = stats.filters;
+        assert_eq!(filters.checks, 399);
+        assert_eq!(filters.negatives + filters.false_positives, 199);
+        let rate = filters.false_positive_rate();
+        assert!(rate > 0.0 && rate < 0.5, "false positive rate {rate}");
+        assert!(stats.levels[0].filter_size >= 200 * 4 / 8);
+    }
+
+    #[test]
+    fn rebuilds_filters_after_policy_change() {
+        let test_dir = &PathBuf::from("./tests/rebuilds_filters_after_policy_change");
+        if test_dir.exists() {
+            fs::remove_dir_all(test_dir).unwrap();
+        }
+        let options = Database::options().set_working_dir(test_dir);
+        let mut db = options.clone().set_bloom_bits_per_key(0).init().unwrap();
+        for i in 0..5u8 {
+            db.put(vec![i], vec![i]).unwrap();
+            db.swap_memtable().unwrap();
+        }
+        drop(db);
+
+        let options = options.set_bloom_bits_per_key(16);
+        let mut db = options.clone().init().unwrap();
+        assert_eq!(db.rebuild_filters().unwrap(), 5);
+        assert_eq!(db.rebuild_filters().unwrap(), 0);
+        for table in db.on_disk_levels.iter().flatten() {
+            let key = &table.metadata.low_key;
+            assert!(table.filter.may_contain(key));
+            assert!(!table.filter.may_contain(&[key[0] + 100]));
+        }
+        assert_eq!(db.query([3]).unwrap(), Some(vec![3]));
+        assert_eq!(db.scan(..).unwrap().len(), 5);
+        drop(db);
+
+        // bottom level goes without filters while the rest keeps the default
+        let options = options.set_level_num(2).set_level_bloom_bits_per_key(1, 0);
+        let mut db = options.init().unwrap();
+        assert_eq!(db.rebuild_filters().unwrap(), 0);
+        db.compact().unwrap();
+        db.put(vec![9], vec![9]).unwrap();
+        db.swap_memtable().unwrap();
+        let filter_sizes: Vec<_> = db
+            .stats()
+            .unwrap()
+            .levels
+            .iter()
+            .map(|l| l.filter_size)
+            .collect();
+        assert!(filter_sizes[0] > 0);
+        assert_eq!(filter_sizes[1], 0);
+        assert_eq!(db.query([3]).unwrap(), Some(vec![3]));
+    }
+
+    #[cfg(feature = "parquet")]
+    #[test]
+    fn parquet_export_import_round_trip() {
+        let test_dir = &PathBuf::from("./tests/parquet_export_import_round_trip");
+        if test_dir.exists() {
+            fs::remove_dir_all(test_dir).unwrap();
+        }
+        let mut db = Database::options()
+            .set_working_dir(test_dir.join("source"))
+            .init()
+            .unwrap();
+        for i in 0..10u8 {
+            db.put(vec![i], vec![i; 3]).unwrap();
+        }
+        db.swap_memtable().unwrap();
+        db.delete(vec![4]).unwrap();
+        let path = test_dir.join("export.parquet");
+        let exported = db
+            .export_parquet(vec![2]..vec![8], fs::File::create(&path).unwrap())
+            .unwrap();
+        assert_eq!(exported, 5);
+
+        let mut restored = Database::options()
+            .set_working_dir(test_dir.join("restored"))
+            .init()
+            .unwrap();
+        restored.put(vec![2], b"old".to_vec()).unwrap();
+        let imported = restored
+            .import_parquet(fs::File::open(&path).unwrap())
+            .unwrap();
+        assert_eq!(imported, 5);
+        assert_eq!(
+            restored.scan(..).unwrap(),
+            db.scan(vec![2]..vec![8]).unwrap()
+        );
+    }
+
+    #[test]
+    fn value_transformers_apply_per_prefix() {
+        let test_dir = &PathBuf::from("./tests/value_transformers_apply_per_prefix");
+        if test_dir.exists() {
+            fs::remove_dir_all(test_dir).unwrap();
+        }
+        struct Xor(u8);
+        impl ValueTransformer for Xor {
+            fn encode(&self, _key: &[u8], value: Vec<u8>) -> Result<Vec<u8>> {
+                Ok(value.into_iter().map(|byte| byte ^ self.0).collect())
+            }
+
+            fn decode(&self, key: &[u8], stored: Vec<u8>) -> Result<Vec<u8>> {
+                self.encode(key, stored)
+            }
+        }
+        let mut db = Database::options()
+            .set_working_dir(test_dir)
+            .set_value_transformer("secret/", Xor(1))
+            .set_value_transformer("secret/more/", Xor(2))
+            .init()
+            .unwrap();
+        db.put(b"plain".to_vec(), vec![0]).unwrap();
+        db.put(b"secret/a".to_vec(), vec![0]).unwrap();
+        let mut batch = WriteBatch::new();
+        batch.put(b"secret/more/b".to_vec(), vec![0]);
+        db.write(batch).unwrap();
+        let stored =
+            |db: &Database, key: &[u8]| db.rw_memtable.get(key).unwrap().value.map(<[u8]>::to_vec);
+        assert_eq!(stored(&db, b"plain"), Some(vec![0]));
+        assert_eq!(stored(&db, b"secret/a"), Some(vec![1]));
+        assert_eq!(stored(&db, b"secret/more/b"), Some(vec![2]));
+
+        db.swap_memtable().unwrap();
+        assert_eq!(db.query(b"secret/a").unwrap(), Some(vec![0]));
+        assert!(db.scan(..).unwrap().iter().all(|(_, value)| *value == [0]));
+        assert!(db
+            .compare_and_swap(b"secret/more/b".to_vec(), Some(&[0]), Some(vec![3]))
+            .unwrap());
+        assert_eq!(
+            db.get_versions(b"secret/more/b").unwrap()[0].1,
+            Some(vec![3])
+        );
+    }
+
+    #[test]
+    fn empty_keys_and_values_are_regular_data() {
+        let test_dir = &PathBuf::from("./tests/empty_keys_and_values_are_regular_data");
+        if test_dir.exists() {
+            fs::remove_dir_all(test_dir).unwrap();
+        }
+        let options = Database::options()
+            .set_working_dir(test_dir)
+            .set_paranoid_checks(true);
+        let mut db = options.clone().init().unwrap();
+        db.put(vec![], b"root".to_vec()).unwrap();
+        db.put(b"a".to_vec(), vec![]).unwrap();
+        let expected = vec![(vec![], b"root".to_vec()), (b"a".to_vec(), vec![])];
+        drop(db);
+
+        // replayed from wal
+        let mut db = options.clone().init().unwrap();
+        assert_eq!(db.scan(..).unwrap(), expected);
+        db.swap_memtable().unwrap();
+        drop(db);
+
+        // read from table through bloom filter and sparse index
+        let mut db = options.init().unwrap();
+        assert_eq!(db.query([]).unwrap(), Some(b"root".to_vec()));
+        assert_eq!(db.query(b"a").unwrap(), Some(vec![]));
+        assert_eq!(db.scan(..).unwrap(), expected);
+        let chunks: Vec<_> = db.scan_chunks(.., 1).unwrap().map(|c| c.unwrap()).collect();
+        assert_eq!(chunks[0].entries, expected[..1]);
+        assert_eq!(chunks[0].resume_key, Some(vec![0]));
+
+        db.delete(vec![]).unwrap();
+        assert_eq!(db.query([]).unwrap(), None);
+        db.compact().unwrap();
+        assert_eq!(db.query([]).unwrap(), None);
+        assert_eq!(db.scan(..).unwrap(), expected[1..]);
+    }
+
+    #[test]
+    fn compaction_keeps_configured_versions() {
+        let test_dir = &PathBuf::from("./tests/compaction_keeps_configured_versions");
+        if test_dir.exists() {
+            fs::remove_dir_all(test_dir).unwrap();
+        }
+        let mut db = Database::options()
+            .set_working_dir(test_dir)
+            .set_level_num(3)
+            .set_versions_to_keep(2)
+            .init()
+            .unwrap();
+        for i in 1..=3u8 {
+            db.put(b"k".to_vec(), vec![i]).unwrap();
+            db.put(b"gone".to_vec(), vec![i]).unwrap();
+            db.swap_memtable().unwrap();
+        }
+        db.delete(b"gone".to_vec()).unwrap();
+        db.put(b"k".to_vec(), vec![4]).unwrap();
+        let values = |db: &Database, key: &[u8]| -> Vec<Option<Vec<u8>>> {
+            let versions = db.get_versions(key).unwrap();
+            assert!(versions.windows(2).all(|pair| pair[0].0 > pair[1].0));
+            versions.into_iter().map(|(_, value)| value).collect()
+        };
+        assert_eq!(
+            values(&db, b"k"),
+            vec![Some(vec![4]), Some(vec![3]), Some(vec![2]), Some(vec![1])]
+        );
+
+        db.swap_memtable().unwrap();
+        db.compact_range(b"a", b"z").unwrap();
+        assert_eq!(values(&db, b"k"), vec![Some(vec![4]), Some(vec![3])]);
+        assert_eq!(db.query(b"k").unwrap(), Some(vec![4]));
+        assert_eq!(db.query(b"gone").unwrap(), None);
+        assert!(values(&db, b"gone").is_empty());
+        assert_eq!(db.scan(..).unwrap(), vec![(b"k".to_vec(), vec![4])]);
+    }
+
+    #[test]
+    fn memtable_overwrites_keep_newest_version() {
+        let test_dir = &PathBuf::from("./tests/memtable_overwrites_keep_newest_version");
+        if test_dir.exists() {
+            fs::remove_dir_all(test_dir).unwrap();
+        }
+        let mut db = Database::options()
+            .set_working_dir(test_dir)
+            .set_versions_to_keep(5)
+            .init()
+            .unwrap();
+        for i in 1..=3u8 {
+            db.put(b"k".to_vec(), vec![i]).unwrap();
+        }
+        let versions = db.get_versions(b"k").unwrap();
+        assert_eq!(versions.len(), 1);
+        assert_eq!(versions[0].1, Some(vec![3]));
+
+        db.swap_memtable().unwrap();
+        db.put(b"k".to_vec(), vec![4]).unwrap();
+        let values: Vec<_> = db
+            .get_versions(b"k")
+            .unwrap()
+            .into_iter()
+            .map(|(_, value)| value)
+            .collect();
+        assert_eq!(values, vec![Some(vec![4]), Some(vec![3])]);
+    }
+
+    #[test]
+    fn versions_include_pending_memtables() {
+        let test_dir = &PathBuf::from("./tests/versions_include_pending_memtables");
+        if test_dir.exists() {
+            fs::remove_dir_all(test_dir).unwrap();
+        }
+        let mut db = Database::options()
+            .set_working_dir(test_dir)
+            .set_max_immutable_memtables(2)
+            .set_background_threads(1)
+            .init()
+            .unwrap();
+        db.put(b"k".to_vec(), vec![40]).unwrap();
+        db.swap_memtable().unwrap();
+        db.put(b"k".to_vec(), vec![50]).unwrap();
+        db.swap_memtable().unwrap();
+        // finished flushes are installed on the next write, so the newest table is pending here
+        // and the older one either pending too or already on disk
+        assert!(db.stats().unwrap().queued_flushes >= 1);
+        let values: Vec<_> = db
+            .get_versions(b"k")
+            .unwrap()
+            .into_iter()
+            .map(|(_, value)| value)
+            .collect();
+        assert_eq!(values, vec![Some(vec![50]), Some(vec![40])]);
+        assert_eq!(db.query(b"k").unwrap(), Some(vec![50]));
+
+        db.flush().unwrap();
+        assert_eq!(db.get_versions(b"k").unwrap().len(), 2);
+    }
 
-        db.put(b"key1".to_vec(), vec![1;150]).unwrap();
-        db.put(b"key2".to_vec(), vec![2;150]).unwrap();
+    #[test]
+    fn tombstones_survive_compaction_within_grace() {
+        let test_dir = &PathBuf::from("./tests/tombstones_survive_compaction_within_grace");
+        if test_dir.exists() {
+            fs::remove_dir_all(test_dir).unwrap();
+        }
+        let options = Database::options().set_working_dir(test_dir);
+        let mut db = options
+            .clone()
+            .set_tombstone_grace(Duration::from_secs(3600))
+            .init()
+            .unwrap();
+        db.put(b"a".to_vec(), b"1".to_vec()).unwrap();
+        db.swap_memtable().unwrap();
+        db.delete(b"a".to_vec()).unwrap();
+        db.swap_memtable().unwrap();
+        db.compact().unwrap();
+        assert_eq!(db.get_versions(b"a").unwrap().len(), 1);
+        assert_eq!(db.query(b"a").unwrap(), None);
+        drop(db);
+
+        let mut db = options.init().unwrap();
+        db.compact().unwrap();
+        assert!(db.get_versions(b"a").unwrap().is_empty());
+        assert_eq!(
+            db.stats()
+                .unwrap()
+                .levels
+                .iter()
+                .map(|l| l.files)
+                .sum::<usize>(),
+            0
+        );
+    }
+
+    #[test]
+    fn universal_compaction_bounds_sorted_runs() {
+        assert_eq!(pick_sorted_runs(&[], 2, 0), None);
+        assert_eq!(pick_sorted_runs(&[400, 100, 100], 4, 0), Some(1));
+        assert_eq!(pick_sorted_runs(&[400, 200, 100], 4, 0), None);
+        assert_eq!(pick_sorted_runs(&[400, 200, 100], 4, 100), Some(0));
+        assert_eq!(pick_sorted_runs(&[800, 400, 200, 100], 2, 0), Some(1));
+
+        let test_dir = &PathBuf::from("./tests/universal_compaction_bounds_sorted_runs");
+        if test_dir.exists() {
+            fs::remove_dir_all(test_dir).unwrap();
+        }
+        let options = Database::options()
+            .set_working_dir(test_dir)
+            .set_memtable_threshold(512)
+            .set_level_num(3)
+            .set_compaction_style(CompactionStyle::Universal {
+                max_sorted_runs: 3,
+                size_ratio: 0,
+            });
+        let mut db = options.clone().init().unwrap();
+        for i in 0..200u8 {
+            db.put(vec![i], vec![i; 20]).unwrap();
+            let levels = db.stats().unwrap().levels;
+            assert!(levels[0].files <= 3);
+            assert!(levels[1..].iter().all(|level| level.files == 0));
+        }
+        for i in (0..200u8).step_by(3) {
+            db.delete(vec![i]).unwrap();
+        }
+        drop(db);
+
+        let mut db = options.init().unwrap();
+        assert_eq!(db.query([0]).unwrap(), None);
+        assert_eq!(db.query([1]).unwrap(), Some(vec![1; 20]));
+        db.compact().unwrap();
+        assert_eq!(db.stats().unwrap().levels[0].files, 1);
+        assert_eq!(db.scan(..).unwrap().len(), 133);
+    }
+
+    #[test]
+    fn levels_compact_by_target_size() {
+        let test_dir = &PathBuf::from("./tests/levels_compact_by_target_size");
+        if test_dir.exists() {
+            fs::remove_dir_all(test_dir).unwrap();
+        }
+        let options = Database::options()
+            .set_working_dir(test_dir)
+            .set_memtable_threshold(512)
+            .set_level_zero_memtables_limit(2)
+            .set_level_num(4)
+            .set_level_factor(4)
+            .set_level_base_size(2048);
+        let mut db = options.clone().init().unwrap();
+        let value = |i: u8| vec![i; if i.is_multiple_of(16) { 300 } else { 8 }];
+        for i in 0..=250u8 {
+            let key = i.wrapping_mul(37);
+            db.put(vec![key], value(key)).unwrap();
+            for level in 1..3 {
+                let size: usize = db.on_disk_levels[level].iter().map(table_size).sum();
+                assert!(size <= db.level_target_size(level));
+            }
+        }
+        assert_eq!(db.level_target_size(2), 8192);
+        // tables are moved down one at a time rather than as whole level
+        assert!(!db.on_disk_levels[1].is_empty());
+        assert!(!db.on_disk_levels[2].is_empty());
+        drop(db);
+
+        let db = options.init().unwrap();
+        for i in 0..=250u8 {
+            let key = i.wrapping_mul(37);
+            assert_eq!(db.query([key]).unwrap(), Some(value(key)));
+        }
+    }
+
+    #[test]
+    fn compaction_output_splits_at_target_file_size() {
+        let test_dir = &PathBuf::from("./tests/compaction_output_splits_at_target_file_size");
+        if test_dir.exists() {
+            fs::remove_dir_all(test_dir).unwrap();
+        }
+        let options = Database::options()
+            .set_working_dir(test_dir)
+            .set_level_num(3)
+            .set_target_file_size(1024)
+            .set_level_target_file_size(1, 0);
+        let mut db = options.clone().init().unwrap();
+        for i in 0..200u8 {
+            db.put(vec![i], vec![i; 20]).unwrap();
+            db.put(vec![i], vec![i; 30]).unwrap();
+        }
+        db.flush().unwrap();
+        db.compact().unwrap();
+        let tables = &db.on_disk_levels[2];
+        assert!(tables.len() > 1);
+        for table in tables.iter() {
+            let record_size = table.metadata.filter_offset - table.metadata.values_table_offset;
+            assert!(record_size < 1024 + 64);
+        }
+        for pair in tables.windows(2) {
+            assert!(pair[0].metadata.high_key < pair[1].metadata.low_key);
+        }
+        drop(db);
+
+        let mut db = options.set_level_target_file_size(2, 0).init().unwrap();
+        for i in 0..200u8 {
+            assert_eq!(db.query([i]).unwrap(), Some(vec![i; 30]));
+        }
+        db.put(vec![0], vec![1]).unwrap();
+        db.flush().unwrap();
+        db.compact().unwrap();
+        assert_eq!(db.on_disk_levels[2].len(), 1);
+    }
+
+    #[cfg(all(feature = "lz4", feature = "zstd"))]
+    #[test]
+    fn compresses_values_per_level() {
+        let test_dir = &PathBuf::from("./tests/compresses_values_per_level");
+        if test_dir.exists() {
+            fs::remove_dir_all(test_dir).unwrap();
+        }
+        let options = Database::options()
+            .set_working_dir(test_dir)
+            .set_level_num(3)
+            .set_compression(Compression::Lz4)
+            .set_level_compression(0, Compression::None)
+            .set_level_compression(2, Compression::Zstd(19));
+        let mut db = options.clone().init().unwrap();
+        let value = |i: u8| [vec![i; 4], b"compressible".repeat(20)].concat();
+        for i in 0..100u8 {
+            db.put(vec![i], value(i)).unwrap();
+        }
+        // incompressible value is stored as is
+        db.put(vec![200], vec![7]).unwrap();
+        db.flush().unwrap();
+        let flushed = table_size(&db.on_disk_levels[0][0]);
+        db.compact().unwrap();
+        let compacted = table_size(&db.on_disk_levels[2][0]);
+        assert!(compacted * 4 < flushed);
+        drop(db);
+
+        let db = options.set_mmap_reads(true).init().unwrap();
+        for i in 0..100u8 {
+            let pinned = db.query_pinned([i]).unwrap().unwrap();
+            assert!(!pinned.is_pinned());
+            assert_eq!(pinned.into_vec(), value(i));
+        }
+        assert!(db.query_pinned([200]).unwrap().unwrap().is_pinned());
+        assert_eq!(db.scan(..).unwrap().len(), 101);
+        assert!(db.on_disk_levels[2][0].verify().unwrap().is_none());
+    }
+
+    #[test]
+    fn picks_tables_dense_with_tombstones() {
+        let test_dir = &PathBuf::from("./tests/picks_tables_dense_with_tombstones");
+        if test_dir.exists() {
+            fs::remove_dir_all(test_dir).unwrap();
+        }
+        let mut db = Database::options()
+            .set_working_dir(test_dir)
+            .set_level_num(3)
+            .set_level_base_size(1)
+            .init()
+            .unwrap();
+        let table = |db: &Database, level, prefix: u8, value: Option<&[u8]>| {
+            let mut writer = db.new_sst_writer(level);
+            for i in 0..10 {
+                let key = [prefix, i];
+                let entry = CommonBinaryFormatRef::new(level as u128, &key, value);
+                writer.push(entry).unwrap();
+            }
+            db.finish_sst(writer).unwrap()
+        };
+        let puts = table(&db, 1, b'a', Some(&[0; 10]));
+        db.on_disk_levels[1].push(puts);
+        let deletes = table(&db, 1, b'b', None);
+        db.on_disk_levels[1].push(deletes);
+        let below_puts = table(&db, 2, b'a', Some(&[0; 10]));
+        db.on_disk_levels[2].push(below_puts);
+        let below_deletes = table(&db, 2, b'b', Some(&[0; 100]));
+        db.on_disk_levels[2].push(below_deletes);
+        assert_eq!(db.on_disk_levels[1][1].metadata.tombstone_count, 10);
+
+        // deletes overlap more bytes below, but reclaim all of them
+        let (inputs, _) = db.compaction_inputs(1).unwrap();
+        let paths: Vec<_> = inputs.iter().map(|table| &table.path).collect();
+        assert_eq!(
+            paths,
+            [&db.on_disk_levels[2][1].path, &db.on_disk_levels[1][1].path]
+        );
+    }
+
+    #[test]
+    fn sequences_ignore_clock_jumps() {
+        let test_dir = &PathBuf::from("./tests/sequences_ignore_clock_jumps");
+        if test_dir.exists() {
+            fs::remove_dir_all(test_dir).unwrap();
+        }
+        let clock = Arc::new(SimulatedClock::new(1_000_000));
+        let mut db = Database::options()
+            .set_working_dir(test_dir)
+            .set_tombstone_grace(Duration::from_secs(10))
+            .set_clock(clock.clone())
+            .init()
+            .unwrap();
+        db.put(b"a".to_vec(), b"1".to_vec()).unwrap();
+        clock.set(500);
+        db.put(b"a".to_vec(), b"2".to_vec()).unwrap();
+        assert_eq!(db.query(b"a").unwrap(), Some(b"2".to_vec()));
+        db.delete(b"a".to_vec()).unwrap();
+        assert_eq!(db.last_sequence(), 3);
+        db.flush().unwrap();
+        db.compact().unwrap();
+        assert_eq!(db.get_versions(b"a").unwrap().len(), 1);
+
+        clock.set(2_000_000);
+        db.put(b"b".to_vec(), b"1".to_vec()).unwrap();
+        assert_eq!(db.approximate_commit_time(4), Some(2_000_000));
+        clock.advance(Duration::from_secs(30));
+        db.flush().unwrap();
+        db.compact().unwrap();
+        assert!(db.get_versions(b"a").unwrap().is_empty());
+        assert_eq!(db.query(b"b").unwrap(), Some(b"1".to_vec()));
+    }
+
+    #[test]
+    fn maintenance_runs_within_schedule() {
+        let test_dir = &PathBuf::from("./tests/maintenance_runs_within_schedule");
+        if test_dir.exists() {
+            fs::remove_dir_all(test_dir).unwrap();
+        }
+        let hour = Duration::from_secs(3600);
+        let clock = Arc::new(SimulatedClock::new(hour.as_micros()));
+        let idle = Arc::new(AtomicBool::new(false));
+        let is_idle = idle.clone();
+        let schedule = MaintenanceSchedule::new()
+            .set_windows(vec![MaintenanceWindow::new(2 * hour, 3 * hour)])
+            .set_idle_check(move || is_idle.load(Ordering::Relaxed));
+        let mut db = Database::options()
+            .set_working_dir(test_dir)
+            .set_level_num(3)
+            .set_clock(clock.clone())
+            .set_maintenance_schedule(schedule)
+            .set_periodic_compaction(hour)
+            .set_scrub_tables_per_run(1)
+            .init()
+            .unwrap();
+        clock.advance(Duration::from_secs(2));
+        db.put(b"a".to_vec(), b"1".to_vec()).unwrap();
+        db.flush().unwrap();
+        assert!(!db.run_maintenance().unwrap().ran);
+
+        idle.store(true, Ordering::Relaxed);
+        let report = db.run_maintenance().unwrap();
+        assert!(report.ran);
+        assert_eq!((report.compacted_tables, report.scrubbed_tables), (0, 1));
+        idle.store(false, Ordering::Relaxed);
+
+        // inside the window data is already older than periodic compaction age
+        clock.advance(hour + hour / 2);
+        db.put(b"b".to_vec(), b"2".to_vec()).unwrap();
+        let report = db.run_maintenance().unwrap();
+        assert_eq!(report.compacted_tables, 2);
+        assert!(db.on_disk_levels[..2].iter().all(Vec::is_empty));
+        assert_eq!(db.on_disk_levels[2].len(), 1);
+        assert_eq!(db.query(b"a").unwrap(), Some(b"1".to_vec()));
+
+        let table = db.on_disk_levels[2][0].clone();
+        let mut file = fs::OpenOptions::new()
+            .write(true)
+            .open(&table.path)
+            .unwrap();
+        file.seek(SeekFrom::Start(
+            table.metadata.values_table_offset as u64 + 20,
+        ))
+        .unwrap();
+        file.write_all(&[0xff]).unwrap();
+        let report = db.run_maintenance().unwrap();
+        assert_eq!(report.corrupted_tables, vec![table.path]);
+    }
+
+    struct SavedVersions(Vec<CommonBinaryFormat>);
+
+    impl RepairSource for SavedVersions {
+        fn fetch_range(&self, low: &[u8], high: &[u8]) -> Result<Vec<CommonBinaryFormat>> {
+            let versions = self
+                .0
+                .iter()
+                .filter(|entry| low <= &entry.key[..] && &entry.key[..] <= high);
+            Ok(versions.cloned().collect())
+        }
+    }
+
+    #[test]
+    fn corrupted_table_is_repaired_from_source() {
+        let test_dir = &PathBuf::from("./tests/corrupted_table_is_repaired_from_source");
+        if test_dir.exists() {
+            fs::remove_dir_all(test_dir).unwrap();
+        }
+        let options = Database::options().set_working_dir(test_dir);
+        let mut db = options.clone().init().unwrap();
+        db.put(b"a".to_vec(), b"1".to_vec()).unwrap();
+        db.put(b"b".to_vec(), b"2".to_vec()).unwrap();
+        db.put(b"c".to_vec(), b"3".to_vec()).unwrap();
+        db.flush().unwrap();
+        let table = db.on_disk_levels[0][0].clone();
+        let saved = table.iter().unwrap().collect::<io::Result<_>>().unwrap();
+        drop(db);
+
+        // checksum of the middle record, open checks the first and the last one
+        let record_size = CommonBinaryFormatRef::new(0, b"a", Some(b"1")).encoded_size();
+        let mut file = fs::OpenOptions::new()
+            .write(true)
+            .open(&table.path)
+            .unwrap();
+        file.seek(SeekFrom::Start(
+            (table.metadata.values_table_offset + 2 * record_size - 4) as u64,
+        ))
+        .unwrap();
+        file.write_all(&[0xff]).unwrap();
+
+        let mut db = options
+            .set_repair_source(Arc::new(SavedVersions(saved)))
+            .init()
+            .unwrap();
+        assert_eq!(db.query(b"a").unwrap(), Some(b"1".to_vec()));
+        let error = db.query(b"b").unwrap_err();
+        assert!(matches!(
+            &error,
+            DBError::CorruptedTable { path, .. } if *path == table.path
+        ));
+        // repaired by the next write
+        db.put(b"d".to_vec(), b"4".to_vec()).unwrap();
+        assert!(!table.path.exists());
+        assert_eq!(db.query(b"a").unwrap(), Some(b"1".to_vec()));
+        assert_eq!(db.query(b"b").unwrap(), Some(b"2".to_vec()));
+        assert!(db.repair_tables().unwrap().is_empty());
+    }
+
+    #[cfg(feature = "object-store")]
+    #[test]
+    fn tables_live_in_object_store() {
+        use crate::object_storage::MemObjectStore;
+
+        let test_dir = PathBuf::from("./tests/tables_live_in_object_store");
+        check_tables_in_object_store(&test_dir, Arc::new(MemObjectStore::new()));
+    }
+
+    #[cfg(feature = "object-store")]
+    #[test]
+    fn tables_live_in_cloud_object_store() {
+        use crate::object_storage::CloudObjectStore;
+        use object_store::memory::InMemory;
+
+        let test_dir = PathBuf::from("./tests/tables_live_in_cloud_object_store");
+        let store = CloudObjectStore::new(Arc::new(InMemory::new()), "tables").unwrap();
+        check_tables_in_object_store(&test_dir, Arc::new(store));
+    }
+
+    #[cfg(feature = "object-store")]
+    fn check_tables_in_object_store(
+        test_dir: &Path,
+        store: Arc<dyn crate::object_storage::ObjectStore>,
+    ) {
+        use crate::object_storage::ObjectStorage;
+
+        if test_dir.exists() {
+            fs::remove_dir_all(test_dir).unwrap();
+        }
+        let db_dir = test_dir.join("db");
+        let cache_dir = test_dir.join("cache");
+        let options = Database::options()
+            .set_working_dir(&db_dir)
+            .set_level_num(2)
+            .set_storage(Arc::new(ObjectStorage::new(store.clone(), &cache_dir)));
+        let mut db = options.clone().init().unwrap();
+        for i in 0..10u8 {
+            db.put(vec![i], vec![i; 10]).unwrap();
+        }
+        db.flush().unwrap();
+        db.delete(vec![3]).unwrap();
+        db.flush().unwrap();
+        db.compact().unwrap();
+        db.put(vec![20], vec![20]).unwrap();
+        drop(db);
+        assert_eq!(store.list("").unwrap().len(), 1);
+        assert!(scan_storage(&LocalStorage, &db_dir, &["sst"])
+            .unwrap()
+            .is_empty());
+
+        // tables are downloaded again once cache is lost
+        fs::remove_dir_all(&cache_dir).unwrap();
+        let db = options.init().unwrap();
+        assert_eq!(db.query([2]).unwrap(), Some(vec![2; 10]));
+        assert_eq!(db.query([3]).unwrap(), None);
+        assert_eq!(db.query([20]).unwrap(), Some(vec![20]));
+    }
+
+    #[test]
+    fn runs_on_memory_storage() {
+        let test_dir = &PathBuf::from("./tests/runs_on_memory_storage");
+        if test_dir.exists() {
+            fs::remove_dir_all(test_dir).unwrap();
+        }
+        let storage = MemStorage::new();
+        let options = Database::options()
+            .set_working_dir(test_dir)
+            .set_level_num(2)
+            .set_storage(Arc::new(storage.clone()));
+        let mut db = options.clone().init().unwrap();
+        for i in 0..10u8 {
+            db.put(vec![i], vec![i; 10]).unwrap();
+        }
+        db.flush().unwrap();
+        db.delete(vec![3]).unwrap();
+        db.flush().unwrap();
+        db.compact().unwrap();
+        db.put(vec![20], vec![20]).unwrap();
+        assert_eq!(db.stats().unwrap().levels[1].files, 1);
+        drop(db);
+
+        let tables = scan_storage(&storage, test_dir, &["sst"]).unwrap();
+        assert_eq!(tables.len(), 1);
+        assert!(!test_dir.exists());
+        let db = options.init().unwrap();
+        assert_eq!(db.query([2]).unwrap(), Some(vec![2; 10]));
+        assert_eq!(db.query([3]).unwrap(), None);
+        assert_eq!(db.query([20]).unwrap(), Some(vec![20]));
+    }
+
+    #[test]
+    fn in_memory_database_skips_disk() {
+        let test_dir = &PathBuf::from("./tests/in_memory_database_skips_disk");
+        if test_dir.exists() {
+            fs::remove_dir_all(test_dir).unwrap();
+        }
+        let options = Database::options()
+            .set_working_dir(test_dir)
+            .set_level_num(2)
+            .set_mmap_reads(true)
+            .set_in_memory(true);
+        let mut db = options.clone().init().unwrap();
+        db.put(b"a".to_vec(), b"1".to_vec()).unwrap();
+        db.flush().unwrap();
+        db.put(b"b".to_vec(), b"2".to_vec()).unwrap();
+        db.compact().unwrap();
+        assert_eq!(db.query(b"a").unwrap(), Some(b"1".to_vec()));
+        assert_eq!(db.query(b"b").unwrap(), Some(b"2".to_vec()));
+        assert_eq!(db.stats().unwrap().wal_unsynced_bytes, 0);
+        drop(db);
+
+        assert!(!test_dir.exists());
+        let db = options.init().unwrap();
+        assert_eq!(db.query(b"a").unwrap(), None);
+    }
+
+    #[test]
+    fn pinned_reads_avoid_copies() {
+        let test_dir = &PathBuf::from("./tests/pinned_reads_avoid_copies");
+        if test_dir.exists() {
+            fs::remove_dir_all(test_dir).unwrap();
+        }
+        let options = Database::options().set_working_dir(test_dir);
+        let mut db = options.clone().set_mmap_reads(true).init().unwrap();
+        db.put(b"flushed".to_vec(), vec![7; 4096]).unwrap();
+        db.put(b"deleted".to_vec(), vec![1]).unwrap();
+        db.flush().unwrap();
+        db.put(b"fresh".to_vec(), b"1".to_vec()).unwrap();
+        db.delete(b"deleted".to_vec()).unwrap();
+
+        let flushed = db.query_pinned(b"flushed").unwrap().unwrap();
+        assert!(flushed.is_pinned());
+        assert_eq!(&*flushed, &[7; 4096][..]);
+        let fresh = db.query_pinned(b"fresh").unwrap().unwrap();
+        assert!(fresh.is_pinned());
+        assert_eq!(fresh.into_vec(), b"1".to_vec());
+        assert!(db.query_pinned(b"deleted").unwrap().is_none());
+        assert!(db.query_pinned(b"missing").unwrap().is_none());
+        drop(flushed);
+        drop(db);
+
+        // without mapping values are read into new buffers
+        let db = options.init().unwrap();
+        let flushed = db.query_pinned(b"flushed").unwrap().unwrap();
+        assert!(!flushed.is_pinned());
+        assert_eq!(flushed.len(), 4096);
+    }
+
+    #[test]
+    fn delete_range_hides_covered_keys() {
+        let test_dir = &PathBuf::from("./tests/delete_range_hides_covered_keys");
+        if test_dir.exists() {
+            fs::remove_dir_all(test_dir).unwrap();
+        }
+        let options = Database::options().set_working_dir(test_dir);
+        let mut db = options.clone().init().unwrap();
+        let key = |i: u8| vec![i];
+        for i in 0..10 {
+            db.put(key(i), vec![i]).unwrap();
+        }
+        db.flush().unwrap();
+        for i in 10..20 {
+            db.put(key(i), vec![i]).unwrap();
+        }
+        db.delete_range(key(5), key(15)).unwrap();
+        db.put(key(7), b"again".to_vec()).unwrap();
+
+        let expected: Vec<_> = (0..5)
+            .map(|i| (key(i), vec![i]))
+            .chain([(key(7), b"again".to_vec())])
+            .chain((15..20).map(|i| (key(i), vec![i])))
+            .collect();
+        let check = |db: &Database| {
+            assert_eq!(db.scan(..).unwrap(), expected);
+            assert_eq!(db.query(key(5)).unwrap(), None);
+            assert_eq!(db.query(key(12)).unwrap(), None);
+            assert_eq!(db.query(key(15)).unwrap(), Some(vec![15]));
+            assert!(db.query_pinned(key(8)).unwrap().is_none());
+            let mut cursor = db.cursor();
+            cursor.seek_for_prev(key(14)).unwrap();
+            assert_eq!(cursor.key(), Some(&key(7)[..]));
+            cursor.prev().unwrap();
+            assert_eq!(cursor.key(), Some(&key(4)[..]));
+        };
+        check(&db);
+        let versions = db.get_versions(key(7)).unwrap();
+        assert_eq!(versions.len(), 3);
+        assert_eq!(versions[1].1, None);
+
+        // tombstone is replayed from wal, then kept by flushed and compacted tables
+        drop(db);
+        let mut db = options.init().unwrap();
+        check(&db);
+        db.flush().unwrap();
+        check(&db);
+        db.compact().unwrap();
+        check(&db);
+        db.put(key(10), vec![10]).unwrap();
+        assert_eq!(db.query(key(10)).unwrap(), Some(vec![10]));
+    }
+
+    #[test]
+    fn compaction_filter_drops_and_rewrites() {
+        let test_dir = &PathBuf::from("./tests/compaction_filter_drops_and_rewrites");
+        if test_dir.exists() {
+            fs::remove_dir_all(test_dir).unwrap();
+        }
+        struct SchemaFilter;
+        impl CompactionFilter for SchemaFilter {
+            fn filter(&self, _level: usize, _key: &[u8], value: &[u8]) -> FilterDecision {
+                match value {
+                    [b'1', ..] => FilterDecision::Remove,
+                    [b'2', rest @ ..] => FilterDecision::ChangeValue([b"3", rest].concat()),
+                    _ => FilterDecision::Keep,
+                }
+            }
+        }
+        let mut db = Database::options()
+            .set_working_dir(test_dir)
+            .set_compaction_filter(Arc::new(SchemaFilter))
+            .init()
+            .unwrap();
+        db.put(b"expired".to_vec(), b"1a".to_vec()).unwrap();
+        db.put(b"migrated".to_vec(), b"2b".to_vec()).unwrap();
+        db.put(b"current".to_vec(), b"3c".to_vec()).unwrap();
+        db.flush().unwrap();
+        // flushes keep values as written, the next one leaves them only in tables
+        db.put(b"other".to_vec(), b"3d".to_vec()).unwrap();
+        db.flush().unwrap();
+        assert_eq!(db.query(b"expired").unwrap(), Some(b"1a".to_vec()));
+
+        db.compact().unwrap();
+        assert_eq!(db.query(b"expired").unwrap(), None);
+        assert_eq!(db.query(b"migrated").unwrap(), Some(b"3b".to_vec()));
+        assert_eq!(db.query(b"current").unwrap(), Some(b"3c".to_vec()));
+    }
+
+    #[test]
+    fn interrupted_flush_is_recovered() {
+        let test_dir = &PathBuf::from("./tests/interrupted_flush_is_recovered");
+        if test_dir.exists() {
+            fs::remove_dir_all(test_dir).unwrap();
+        }
+        let options = Database::options().set_working_dir(test_dir);
+        let mut db = options.clone().init().unwrap();
+        db.put(b"flushed".to_vec(), b"1".to_vec()).unwrap();
+        db.log().wal.sync().unwrap();
+        let wal = db.log().wal.path.clone();
+        let saved_wal = fs::read(&wal).unwrap();
+        db.flush().unwrap();
+        let table = db.on_disk_levels[0][0].path.clone();
+        db.put(b"unflushed".to_vec(), b"2".to_vec()).unwrap();
+        drop(db);
+
+        // crashed after table was written but before its wal was deleted
+        fs::write(&wal, &saved_wal).unwrap();
+        flush_marker_file(test_dir, &wal, &table);
+        let db = options.clone().init().unwrap();
+        assert!(!wal.exists());
+        assert_eq!(db.immutable_memtables.len(), 1);
+        assert_eq!(db.immutable_memtables[0].len(), 1);
+        assert_eq!(db.rw_memtable.len(), 1);
+        assert_eq!(db.query(b"flushed").unwrap(), Some(b"1".to_vec()));
+        assert_eq!(db.query(b"unflushed").unwrap(), Some(b"2".to_vec()));
+        drop(db);
+
+        // crashed while writing the table, wal is replayed instead
+        let partial = test_dir.join("partial.sst");
+        fs::write(&partial, b"torn").unwrap();
+        fs::write(&wal, &saved_wal).unwrap();
+        flush_marker_file(test_dir, &wal, &partial);
+        // wal holds flushed records only, garbage collection would delete it
+        let db = options.set_delete_orphan_files(false).init().unwrap();
+        assert!(!partial.exists());
+        assert!(!test_dir.join(FLUSH_MARKER).exists());
+        assert_eq!(db.rw_memtable.len(), 2);
+        assert_eq!(db.query(b"flushed").unwrap(), Some(b"1".to_vec()));
+        assert_eq!(db.query(b"unflushed").unwrap(), Some(b"2".to_vec()));
+    }
+
+    fn flush_marker_file(dir: &Path, wal: &Path, table: &Path) {
+        let name = |path: &Path| path.file_name().unwrap().to_string_lossy().into_owned();
+        let contents = format!("{}\n{}\n", name(wal), name(table));
+        fs::write(dir.join(FLUSH_MARKER), contents).unwrap();
+    }
+
+    #[test]
+    fn orphan_files_are_deleted_on_open() {
+        let test_dir = &PathBuf::from("./tests/orphan_files_are_deleted_on_open");
+        if test_dir.exists() {
+            fs::remove_dir_all(test_dir).unwrap();
+        }
+        let options = Database::options().set_working_dir(test_dir);
+        let mut db = options.clone().init().unwrap();
+        db.put(b"flushed".to_vec(), b"1".to_vec()).unwrap();
+        db.log().wal.sync().unwrap();
+        let flushed_wal = test_dir.join("1.wal");
+        fs::copy(&db.log().wal.path, &flushed_wal).unwrap();
+        db.flush().unwrap();
+        db.put(b"unflushed".to_vec(), b"2".to_vec()).unwrap();
+        drop(db);
+        let temp = test_dir.join("2.sst.tmp");
+        let partial = test_dir.join("3.sst");
+        fs::write(&temp, b"").unwrap();
+        fs::write(&partial, b"torn").unwrap();
+
+        let orphans = options.find_orphan_files().unwrap();
+        assert_eq!(orphans.temp_files, vec![temp.clone()]);
+        assert_eq!(orphans.partial_tables, vec![partial.clone()]);
+        assert_eq!(orphans.flushed_wals, vec![flushed_wal.clone()]);
+        let db = options.clone().init().unwrap();
+        assert!(options.find_orphan_files().unwrap().is_empty());
+        assert!(!temp.exists() && !partial.exists() && !flushed_wal.exists());
+        assert_eq!(db.query(b"flushed").unwrap(), Some(b"1".to_vec()));
+        assert_eq!(db.query(b"unflushed").unwrap(), Some(b"2".to_vec()));
+    }
+
+    #[test]
+    fn simulated_clock_and_injected_faults() {
+        let test_dir = &PathBuf::from("./tests/simulated_clock_and_injected_faults");
+        if test_dir.exists() {
+            fs::remove_dir_all(test_dir).unwrap();
+        }
+        let clock = Arc::new(SimulatedClock::new(1_000));
+        let faults = FaultInjectionFs::new();
+        let options = Database::options()
+            .set_working_dir(test_dir)
+            .set_wal_sync_policy(WalSyncPolicy::Manual)
+            .set_clock(clock.clone())
+            .set_storage(Arc::new(faults.clone()));
+        let mut db = options.clone().init().unwrap();
+        db.put(b"a".to_vec(), b"1".to_vec()).unwrap();
+        db.put(b"a".to_vec(), b"2".to_vec()).unwrap();
+        clock.advance(Duration::from_secs(2));
+        db.put(b"b".to_vec(), b"3".to_vec()).unwrap();
+        db.sync_wal().unwrap();
+        assert_eq!(db.get_versions(b"a").unwrap()[0].0, 2);
+        assert_eq!(db.approximate_commit_time(1), Some(2_001_000));
+
+        faults.set_fail_syncs(true);
+        db.delete(b"a".to_vec()).unwrap();
+        assert!(db.sync_wal().is_err());
+        // unsynced delete reaches the file on drop but not the disk
+        drop(db);
+        faults.drop_unsynced().unwrap();
+        faults.heal();
+
+        let mut db = options.clone().init().unwrap();
+        assert_eq!(db.query(b"a").unwrap(), Some(b"2".to_vec()));
+        assert_eq!(db.get_versions(b"b").unwrap()[0].0, 3);
+
+        // torn record is cut off at recovery
+        faults.fail_writes_after(10);
+        db.put(b"c".to_vec(), vec![0; 100]).unwrap();
+        assert!(db.sync_wal().is_err());
+        drop(db);
+        faults.heal();
+        let db = options.init().unwrap();
+        assert_eq!(db.query(b"c").unwrap(), None);
+        assert_eq!(db.query(b"b").unwrap(), Some(b"3".to_vec()));
+    }
+
+    #[test]
+    fn background_flushes_and_compactions() {
+        let test_dir = &PathBuf::from("./tests/background_flushes_and_compactions");
+        if test_dir.exists() {
+            fs::remove_dir_all(test_dir).unwrap();
+        }
+
+        let options = Database::options()
+            .set_working_dir(test_dir)
+            .set_memtable_threshold(512)
+            .set_level_zero_memtables_limit(2)
+            .set_level_num(3)
+            .set_background_threads(2);
+        let mut db = options.clone().init().unwrap();
+        for i in 0..200u8 {
+            db.put(vec![i], vec![i; 20]).unwrap();
+            // reads see data whether it's flushed yet or not
+            assert_eq!(db.query([i / 2]).unwrap(), Some(vec![i / 2; 20]));
+        }
+        for i in (0..200u8).step_by(3) {
+            db.delete(vec![i]).unwrap();
+        }
+        db.wait_for_background_work().unwrap();
+        let stats = db.stats().unwrap();
+        assert!(stats.levels[0].files <= 2);
+        assert!(stats.levels[1].files > 0);
+        // only wal of the current memtable is left
+        assert_eq!(utils::scan_dir(test_dir, &["wal"]).unwrap().len(), 1);
+        assert_eq!(db.scan(..).unwrap().len(), 133);
+        drop(db);
+
+        let db = options.init().unwrap();
+        assert_eq!(db.query([3]).unwrap(), None);
+        assert_eq!(db.query([4]).unwrap(), Some(vec![4; 20]));
+        assert_eq!(db.scan(..).unwrap().len(), 133);
+    }
+
+    #[test]
+    fn queues_immutable_memtables() {
+        let test_dir = &PathBuf::from("./tests/queues_immutable_memtables");
+        if test_dir.exists() {
+            fs::remove_dir_all(test_dir).unwrap();
+        }
+
+        let options = Database::options()
+            .set_working_dir(test_dir)
+            .set_max_immutable_memtables(3)
+            .set_background_threads(1);
+        let mut db = options.clone().init().unwrap();
+        for i in 0..10u8 {
+            db.put(vec![i], vec![i]).unwrap();
+            db.swap_memtable().unwrap();
+            assert!(db.stats().unwrap().queued_flushes <= 3);
+            assert!(db.immutable_memtables.len() <= 4);
+            assert_eq!(db.query([i]).unwrap(), Some(vec![i]));
+            // queued memtables are consulted newest first
+            assert_eq!(db.query([0]).unwrap(), Some(vec![i.saturating_sub(1)]));
+            db.put(vec![0], vec![i]).unwrap();
+        }
+        db.flush().unwrap();
+        assert_eq!(db.stats().unwrap().queued_flushes, 0);
+        assert_eq!(db.scan(..).unwrap().len(), 10);
+        drop(db);
+
+        // crashed with two flushes running, the first one finished
+        let wals: Vec<_> = (0..2u8)
+            .map(|i| {
+                let mut log = WriteAheadLog::new(test_dir).unwrap();
+                log.put(100 + i as u128, vec![b'q', i], vec![1]).unwrap();
+                log.sync().unwrap();
+                log.path.clone()
+            })
+            .collect();
+        let mut writer = SstWriter::new(0);
+        writer
+            .push(CommonBinaryFormatRef::new(100, &[b'q', 0], Some(&[1])))
+            .unwrap();
+        let finished = test_dir.join("q1.sst");
+        writer.finish(&finished).unwrap();
+        let partial = test_dir.join("q2.sst");
+        fs::write(&partial, b"torn").unwrap();
+        let name = |path: &PathBuf| path.file_name().unwrap().to_string_lossy().into_owned();
+        let contents = [&wals[0], &finished, &wals[1], &partial]
+            .map(name)
+            .join("\n")
+            + "\n";
+        fs::write(test_dir.join(FLUSH_MARKER), contents).unwrap();
+        let db = options.set_delete_orphan_files(false).init().unwrap();
+        assert!(!wals[0].exists() && !partial.exists());
+        assert_eq!(db.immutable_memtables[0].len(), 1);
+        assert_eq!(db.query([b'q', 0]).unwrap(), Some(vec![1]));
+        assert_eq!(db.query([b'q', 1]).unwrap(), Some(vec![1]));
+    }
+
+    #[test]
+    fn preloads_index_partitions_on_open() {
+        let test_dir = &PathBuf::from("./tests/preloads_index_partitions_on_open");
+        if test_dir.exists() {
+            fs::remove_dir_all(test_dir).unwrap();
+        }
+
+        let options = Database::options()
+            .set_working_dir(test_dir)
+            .set_index_interval(1)
+            .set_partitioned_index(10);
+        let mut db = options.clone().init().unwrap();
+        for i in 0..100u8 {
+            db.put(vec![i], vec![i]).unwrap();
+        }
+        db.flush().unwrap();
+        assert_eq!(db.warm_cache().unwrap(), 0);
+        drop(db);
+
+        let cache = Arc::new(BlockCache::new(1 << 20));
+        let db = options
+            .clone()
+            .set_block_cache(cache.clone())
+            .set_preload_index(true)
+            .init()
+            .unwrap();
+        let preloaded = cache.usage();
+        assert!(preloaded > 0);
+        // cached partitions are loaded once
+        assert_eq!(db.warm_cache().unwrap(), 0);
+        assert_eq!(cache.usage(), preloaded);
+        drop(db);
+
+        // stops once cache is full
+        let small = Arc::new(BlockCache::new(preloaded / 2));
+        let db = options.set_block_cache(small.clone()).init().unwrap();
+        let loaded = db.warm_cache().unwrap();
+        assert!(loaded > 0 && loaded < 10);
+        assert!(small.usage() <= preloaded / 2);
+    }
+
+    #[test]
+    fn flush_persists_memtable() {
+        let test_dir = &PathBuf::from("./tests/flush_persists_memtable");
+        if test_dir.exists() {
+            fs::remove_dir_all(test_dir).unwrap();
+        }
+
+        let options = Database::options()
+            .set_working_dir(test_dir)
+            .set_background_threads(1);
+        let mut db = options.clone().init().unwrap();
+        db.put(b"a".to_vec(), b"1".to_vec()).unwrap();
+        db.delete(b"b".to_vec()).unwrap();
+        db.flush().unwrap();
+        // nothing to flush
+        db.flush().unwrap();
+        let stats = db.stats().unwrap();
+        assert_eq!(stats.rw_memtable_entries, 0);
+        assert_eq!(stats.levels[0].files, 1);
+        assert_eq!(stats.levels[0].entries, 2);
+        assert_eq!(stats.latencies.put.count, 1);
+        assert_eq!(stats.latencies.delete.count, 1);
+        assert_eq!(stats.latencies.flush.count, 1);
+        db.reset_latencies();
+        assert_eq!(db.stats().unwrap().latencies.flush.count, 0);
+        assert_eq!(utils::scan_dir(test_dir, &["wal"]).unwrap().len(), 1);
+        drop(db);
+
+        let db = options.init().unwrap();
+        assert_eq!(db.query(b"a").unwrap(), Some(b"1".to_vec()));
+    }
+
+    #[test]
+    fn approximates_range_size_and_key_count() {
+        let test_dir = &PathBuf::from("./tests/approximates_range_size_and_key_count");
+        if test_dir.exists() {
+            fs::remove_dir_all(test_dir).unwrap();
+        }
+
+        let mut db = Database::options()
+            .set_working_dir(test_dir)
+            .init()
+            .unwrap();
+        for i in 0..1000u32 {
+            db.put(i.to_be_bytes().to_vec(), vec![0; 100]).unwrap();
+        }
+        db.flush().unwrap();
+        for i in 1000..1100u32 {
+            db.put(i.to_be_bytes().to_vec(), vec![0; 100]).unwrap();
+        }
+        assert_eq!(db.approximate_key_count(..), 1100);
+        let half = 250u32.to_be_bytes().to_vec()..750u32.to_be_bytes().to_vec();
+        let count = db.approximate_key_count(half.clone());
+        assert!((500 - DEFAULT_INDEX_INTERVAL..=500 + DEFAULT_INDEX_INTERVAL).contains(&count));
+        let size = db.approximate_size(half);
+        assert!((count as u64 * 100..count as u64 * 150).contains(&size));
+        assert_eq!(
+            db.approximate_key_count(1050u32.to_be_bytes().to_vec()..),
+            50
+        );
+        assert_eq!(db.approximate_size(vec![0xff]..), 0);
+    }
+
+    #[test]
+    fn cache_advisor_simulates_hit_rates() {
+        let test_dir = &PathBuf::from("./tests/cache_advisor_simulates_hit_rates");
+        if test_dir.exists() {
+            fs::remove_dir_all(test_dir).unwrap();
+        }
+
+        let mut db = Database::options()
+            .set_working_dir(test_dir)
+            .set_cache_advisor([0, 1 << 20], 1)
+            .init()
+            .unwrap();
+        db.put(b"a".to_vec(), b"1".to_vec()).unwrap();
+        db.flush().unwrap();
+        db.put(b"b".to_vec(), b"2".to_vec()).unwrap();
+        db.flush().unwrap();
+        // memtable reads and filtered out tables don't touch blocks
+        db.query(b"b").unwrap();
+        db.query(b"x").unwrap();
+        for _ in 0..4 {
+            db.query(b"a").unwrap();
+        }
+        let advice = db.cache_advice();
+        assert_eq!(advice[0].hit_rate, 0.0);
+        assert_eq!(advice[1].capacity, 1 << 20);
+        assert_eq!(advice[1].hit_rate, 0.75);
+    }
+
+    #[test]
+    fn reports_long_running_iterators() {
+        let test_dir = &PathBuf::from("./tests/reports_long_running_iterators");
+        if test_dir.exists() {
+            fs::remove_dir_all(test_dir).unwrap();
+        }
+
+        struct Counter(Arc<Mutex<Vec<u64>>>);
+        impl EventListener for Counter {
+            fn on_long_running_iterator(&self, iterator: &IteratorInfo) {
+                self.0.lock().unwrap().push(iterator.id);
+            }
+        }
+        let reported = Arc::new(Mutex::new(Vec::new()));
+        let db = Database::options()
+            .set_working_dir(test_dir)
+            .set_iterator_leak_threshold(Duration::from_millis(20))
+            .set_capture_iterator_backtraces(true)
+            .set_event_listener(Counter(reported.clone()))
+            .init()
+            .unwrap();
+        let old = db.scan_chunks(.., 1024).unwrap();
+        std::thread::sleep(Duration::from_millis(30));
+        let fresh = db.scan_chunks(.., 1024).unwrap();
+        let found = db.long_running_iterators();
+        assert_eq!(found.len(), 1);
+        assert_eq!(found[0].location.file(), file!());
+        assert!(found[0].backtrace.is_some());
+        // listener hears about each iterator once
+        assert_eq!(*reported.lock().unwrap(), vec![found[0].id]);
+
+        drop(old);
+        drop(fresh);
+        assert!(db.long_running_iterators().is_empty());
+    }
+
+    #[test]
+    fn custom_comparator_orders_keys() {
+        let test_dir = &PathBuf::from("./tests/custom_comparator_orders_keys");
+        if test_dir.exists() {
+            fs::remove_dir_all(test_dir).unwrap();
+        }
+        struct Reverse;
+        impl Comparator for Reverse {
+            fn name(&self) -> &str {
+                "test.Reverse"
+            }
+
+            fn compare(&self, a: &[u8], b: &[u8]) -> std::cmp::Ordering {
+                b.cmp(a)
+            }
+        }
+        let options = Database::options()
+            .set_working_dir(test_dir)
+            .set_comparator(Arc::new(Reverse));
+        let mut db = options.clone().init().unwrap();
+        let key = |i: u8| vec![i];
+        for i in 0..5 {
+            db.put(key(i), vec![i]).unwrap();
+        }
+        db.flush().unwrap();
+        for i in 5..10 {
+            db.put(key(i), vec![i]).unwrap();
+        }
+
+        let descending: Vec<_> = (0..10).rev().map(|i| (key(i), vec![i])).collect();
+        let check = |db: &Database| {
+            assert_eq!(db.scan(..).unwrap(), descending);
+            assert_eq!(db.scan(key(7)..key(2)).unwrap(), descending[2..7]);
+            assert_eq!(db.query(key(3)).unwrap(), Some(vec![3]));
+            let chunks: Vec<_> = db.scan_chunks(.., 4).unwrap().map(Result::unwrap).collect();
+            assert_eq!(chunks[0].resume_key, Some(key(7)));
+            let chunked: Vec<_> = chunks.into_iter().flat_map(|c| c.entries).collect();
+            assert_eq!(chunked, descending);
+            let mut cursor = db.cursor();
+            cursor.seek_to_first().unwrap();
+            assert_eq!(cursor.key(), Some(&key(9)[..]));
+            cursor.seek_for_prev(key(3)).unwrap();
+            assert_eq!(cursor.key(), Some(&key(3)[..]));
+            cursor.next().unwrap();
+            assert_eq!(cursor.key(), Some(&key(2)[..]));
+            cursor.prev().unwrap();
+            cursor.prev().unwrap();
+            assert_eq!(cursor.key(), Some(&key(4)[..]));
+        };
+        check(&db);
+        db.compact().unwrap();
+        check(&db);
+
+        drop(db);
+        let err = Database::options()
+            .set_working_dir(test_dir)
+            .init()
+            .err()
+            .unwrap();
+        assert!(matches!(
+            &err,
+            DBError::ComparatorMismatch { stored, .. } if stored == "test.Reverse"
+        ));
+        check(&options.init().unwrap());
+    }
+
+    #[test]
+    fn entry_meta_survives_flush_and_reopen() {
+        let test_dir = &PathBuf::from("./tests/entry_meta_survives_flush_and_reopen");
+        if test_dir.exists() {
+            fs::remove_dir_all(test_dir).unwrap();
+        }
+        let options = Database::options().set_working_dir(test_dir);
+        let mut db = options.clone().init().unwrap();
+        db.put_with_meta(b"a".to_vec(), b"1".to_vec(), b"replica-1".to_vec())
+            .unwrap();
+        db.flush().unwrap();
+        db.put_with_meta(b"b".to_vec(), b"2".to_vec(), b"replica-2".to_vec())
+            .unwrap();
+        db.put(b"c".to_vec(), b"3".to_vec()).unwrap();
+        let err = db
+            .put_with_meta(b"d".to_vec(), Vec::new(), vec![0; MAX_META_SIZE + 1])
+            .unwrap_err();
+        assert!(matches!(
+            &err,
+            DBError::MetaTooLarge(size) if *size == MAX_META_SIZE + 1
+        ));
+
+        let check = |db: &Database| {
+            assert_eq!(
+                db.query_with_meta(b"a").unwrap(),
+                Some((b"1".to_vec(), b"replica-1".to_vec()))
+            );
+            assert_eq!(
+                db.query_with_meta(b"c").unwrap(),
+                Some((b"3".to_vec(), Vec::new()))
+            );
+            assert_eq!(db.query_with_meta(b"d").unwrap(), None);
+            let mut cursor = db.cursor();
+            cursor.seek(b"b").unwrap();
+            let entry = cursor.entry().unwrap();
+            assert_eq!((entry.key, entry.meta), (&b"b"[..], &b"replica-2"[..]));
+            cursor.prev().unwrap();
+            assert_eq!(cursor.entry().unwrap().meta, b"replica-1");
+        };
+        check(&db);
+        drop(db);
+        // metadata is replayed from wal and kept by compaction
+        let mut db = options.init().unwrap();
+        check(&db);
+        db.compact().unwrap();
+        check(&db);
+    }
+
+    proptest! {
+        #![proptest_config(ProptestConfig::with_cases(16))]
+
+        #[test]
+        fn recovers_arbitrary_writes_after_crash(
+            ops in prop::collection::vec(
+                (prop::collection::vec(0..4u8, 0..3), prop::option::of(prop::collection::vec(any::<u8>(), 0..64))),
+                0..200,
+            ),
+        ) {
+            let test_dir = &PathBuf::from("./tests/recovers_arbitrary_writes_after_crash");
+            if test_dir.exists() {
+                fs::remove_dir_all(test_dir).unwrap();
+            }
+            let options = Database::options()
+                .set_working_dir(test_dir)
+                .set_memtable_threshold(1024)
+                .set_level_zero_memtables_limit(2)
+                .set_level_num(3);
+            let mut db = options.clone().init().unwrap();
+            let mut model = BTreeMap::new();
+            for (key, value) in ops {
+                match value {
+                    Some(value) => {
+                        db.put(key.clone(), value.clone()).unwrap();
+                        model.insert(key, value);
+                    }
+                    None => {
+                        db.delete(key.clone()).unwrap();
+                        model.remove(&key);
+                    }
+                }
+            }
+            db.sync_wal().unwrap();
+            // no destructors run on crash, exit of process releases lock
+            db._lock.take();
+            mem::forget(db);
+
+            let db = options.init().unwrap();
+            let scanned: BTreeMap<_, _> = db.scan(..).unwrap().into_iter().collect();
+            prop_assert_eq!(scanned, model);
+        }
     }
 }
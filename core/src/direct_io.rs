@@ -0,0 +1,105 @@
+use std::fs::File;
+use std::io;
+use std::io::Write;
+use std::path::Path;
+
+/// Alignment of buffers, sizes and offsets required by direct io
+const BLOCK_SIZE: usize = 4096;
+/// Number of blocks written at once
+const BUFFER_BLOCKS: usize = 256;
+
+/// Writer of a new file bypassing page cache, so large sequential writes don't evict pages
+/// used by reads
+///
+/// Direct io only accepts whole aligned blocks, data is staged in an aligned buffer and the
+/// padding of the last block is cut off by `finish`.
+pub(crate) struct DirectWriter {
+    file: File,
+    /// over-allocated so that an aligned region of `BUFFER_BLOCKS` blocks fits in it
+    buffer: Vec<u8>,
+    /// start of aligned region in buffer
+    start: usize,
+    /// bytes staged in aligned region
+    filled: usize,
+    /// bytes written to file so far
+    written: u64,
+}
+
+impl DirectWriter {
+    /// Returns None if platform or filesystem doesn't support direct io
+    pub fn create_new(path: &Path) -> io::Result<Option<Self>> {
+        let Some(file) = open_direct(path)? else {
+            return Ok(None);
+        };
+        let buffer = vec![0; (BUFFER_BLOCKS + 1) * BLOCK_SIZE];
+        let start = buffer.as_ptr().align_offset(BLOCK_SIZE);
+        Ok(Some(Self {
+            file,
+            buffer,
+            start,
+            filled: 0,
+            written: 0,
+        }))
+    }
+
+    /// Writes staged tail padded to block size and trims file to actual data size
+    pub fn finish(mut self) -> io::Result<()> {
+        let size = self.written + self.filled as u64;
+        let padded = self.filled.next_multiple_of(BLOCK_SIZE);
+        self.buffer[self.start + self.filled..self.start + padded].fill(0);
+        self.write_staged(padded)?;
+        self.file.set_len(size)?;
+        self.file.sync_data()
+    }
+
+    fn write_staged(&mut self, len: usize) -> io::Result<()> {
+        self.file
+            .write_all(&self.buffer[self.start..self.start + len])?;
+        self.written += self.filled as u64;
+        self.filled = 0;
+        Ok(())
+    }
+}
+
+impl Write for DirectWriter {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        let capacity = BUFFER_BLOCKS * BLOCK_SIZE;
+        let len = data.len().min(capacity - self.filled);
+        let at = self.start + self.filled;
+        self.buffer[at..at + len].copy_from_slice(&data[..len]);
+        self.filled += len;
+        if self.filled == capacity {
+            self.write_staged(capacity)?;
+        }
+        Ok(len)
+    }
+
+    /// Partial blocks can't be written directly, staged data goes to disk on `finish`
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(all(target_os = "linux", feature = "std-fs"))]
+fn open_direct(path: &Path) -> io::Result<Option<File>> {
+    use std::os::unix::fs::OpenOptionsExt;
+    let opened = File::options()
+        .write(true)
+        .create_new(true)
+        .custom_flags(libc::O_DIRECT)
+        .open(path);
+    match opened {
+        Ok(file) => Ok(Some(file)),
+        // e.g. tmpfs rejects O_DIRECT, possibly after file got created
+        Err(e) if e.raw_os_error() == Some(libc::EINVAL) => match std::fs::remove_file(path) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+            _ => Ok(None),
+        },
+        Err(e) => Err(e),
+    }
+}
+
+#[cfg(not(all(target_os = "linux", feature = "std-fs")))]
+fn open_direct(_path: &Path) -> io::Result<Option<File>> {
+    Ok(None)
+}
@@ -1,7 +1,127 @@
+use crate::utils::MAX_META_SIZE;
+use std::io;
+use std::path::PathBuf;
 use thiserror::Error;
 
+/// Result of database operations
+pub type Result<T, E = DBError> = std::result::Result<T, E>;
+
 #[derive(Error, Debug)]
 pub enum DBError {
+    #[error("io error{}", .path.as_ref().map(|path| format!(" on {}", path.display())).unwrap_or_default())]
+    Io {
+        path: Option<PathBuf>,
+        #[source]
+        source: io::Error,
+    },
     #[error("sstable could not be loaded, data is corrupted")]
     MalformedSSTable,
+    #[error("sstable {} is corrupted", .path.display())]
+    CorruptedTable {
+        path: PathBuf,
+        #[source]
+        error: std::io::Error,
+    },
+    #[error("{} is corrupted at offset {offset}", .path.display())]
+    Corruption {
+        path: PathBuf,
+        offset: u64,
+        #[source]
+        error: std::io::Error,
+    },
+    #[error("sstable {0} key range in metadata doesn't match its data")]
+    SstKeyRangeMismatch(PathBuf),
+    #[error("ingested sstables {0} and {1} have overlapping key ranges")]
+    IngestedTablesOverlap(PathBuf, PathBuf),
+    #[error("ingest behind requires bottom level reserved in options")]
+    IngestBehindDisabled,
+    #[error("write to key `{}` denied by write guard", .0.escape_ascii())]
+    PermissionDenied(Vec<u8>),
+    #[error("key `{}` belongs to internal keyspace", .0.escape_ascii())]
+    ReservedKey(Vec<u8>),
+    #[error("key `{}` is invalid: {reason}", .key.escape_ascii())]
+    InvalidKey { key: Vec<u8>, reason: String },
+    #[error("transaction conflicts with a later write to key `{}`", .0.escape_ascii())]
+    TransactionConflict(Vec<u8>),
+    #[error("timed out waiting for lock on key `{}`", .0.escape_ascii())]
+    LockTimeout(Vec<u8>),
+    #[error("database is at sequence {actual}, expected {expected}")]
+    SequenceMismatch { expected: u128, actual: u128 },
+    #[error("database was created with comparator {stored}, opened with {configured}")]
+    ComparatorMismatch { stored: String, configured: String },
+    #[error("entry metadata of {0} bytes exceeds limit of {MAX_META_SIZE} bytes")]
+    MetaTooLarge(usize),
+    #[error("invalid options: {0}")]
+    InvalidOptions(String),
+    #[error("invalid data: {0}")]
+    InvalidData(String),
+    #[error("working directory {} is locked by another instance", .0.display())]
+    LockHeld(PathBuf),
+    #[error("write of epoch {stale} rejected, current epoch is {current}")]
+    StaleEpoch { stale: u64, current: u64 },
+    #[error("wal records from sequence {0} were already removed by flush")]
+    WalTruncated(u128),
+    #[error("database is read-only")]
+    ReadOnly,
+    #[error("database is busy with {0}")]
+    Busy(&'static str),
+    #[error("database is shutting down")]
+    Shutdown,
+}
+
+impl DBError {
+    /// Io error on file at path
+    pub fn io(path: impl Into<PathBuf>, source: io::Error) -> Self {
+        Self::Io {
+            path: Some(path.into()),
+            source,
+        }
+    }
+}
+
+impl From<io::Error> for DBError {
+    fn from(source: io::Error) -> Self {
+        Self::Io { path: None, source }
+    }
+}
+
+impl From<serde_json::Error> for DBError {
+    fn from(error: serde_json::Error) -> Self {
+        match error.is_io() {
+            true => io::Error::from(error).into(),
+            false => Self::InvalidData(error.to_string()),
+        }
+    }
+}
+
+impl From<csv::Error> for DBError {
+    fn from(error: csv::Error) -> Self {
+        match error.is_io_error() {
+            true => io::Error::from(error).into(),
+            false => Self::InvalidData(error.to_string()),
+        }
+    }
+}
+
+impl From<base64::DecodeError> for DBError {
+    fn from(error: base64::DecodeError) -> Self {
+        Self::InvalidData(error.to_string())
+    }
+}
+
+#[cfg(feature = "parquet")]
+impl From<parquet::errors::ParquetError> for DBError {
+    fn from(error: parquet::errors::ParquetError) -> Self {
+        Self::InvalidData(error.to_string())
+    }
+}
+
+#[cfg(feature = "parquet")]
+impl From<arrow_schema::ArrowError> for DBError {
+    fn from(error: arrow_schema::ArrowError) -> Self {
+        match error {
+            arrow_schema::ArrowError::IoError(_, error) => error.into(),
+            error => Self::InvalidData(error.to_string()),
+        }
+    }
 }
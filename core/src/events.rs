@@ -0,0 +1,15 @@
+use crate::iterators::IteratorInfo;
+use crate::utils::Corruption;
+use std::path::Path;
+
+/// Receives notable database events, every method has an empty default implementation
+pub trait EventListener: Send + Sync {
+    /// Iterator outlived configured threshold, called once per iterator
+    fn on_long_running_iterator(&self, _iterator: &IteratorInfo) {}
+
+    /// Maintenance scrubbing found corrupted table
+    fn on_table_corruption(&self, _table: &Path, _corruption: &Corruption) {}
+
+    /// Corrupted table was rewritten from repair source and deleted
+    fn on_table_repaired(&self, _damaged: &Path, _repaired: &Path) {}
+}
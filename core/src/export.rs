@@ -0,0 +1,162 @@
+use crate::error::{DBError, Result};
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use serde_json::{json, Value};
+use std::io;
+use std::io::BufRead;
+
+/// Text formats for moving key-value pairs in and out of database
+///
+/// Keys and values are written as text when they are valid utf-8, binary ones are base64 encoded:
+/// - json: one `{"key": .., "value": ..}` object per line, binary field is `{"base64": ".."}`
+/// - csv: `key,value` header followed by records, binary field is prefixed with `base64:`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    Json,
+    Csv,
+}
+
+const CSV_BASE64_PREFIX: &str = "base64:";
+
+#[cfg(feature = "parquet")]
+mod columnar;
+#[cfg(feature = "parquet")]
+pub(crate) use columnar::{read_record_batches, write_parquet};
+
+/// Writes pairs in given format as they are produced, returns number of written records
+pub(crate) fn write_records(
+    entries: impl IntoIterator<Item = Result<(Vec<u8>, Vec<u8>)>>,
+    writer: impl io::Write,
+    format: Format,
+) -> Result<usize> {
+    let mut count = 0;
+    match format {
+        Format::Json => {
+            let mut writer = writer;
+            for entry in entries {
+                let (key, value) = entry?;
+                let record = json!({"key": to_json(&key), "value": to_json(&value)});
+                serde_json::to_writer(&mut writer, &record)?;
+                writer.write_all(b"\n")?;
+                count += 1;
+            }
+            writer.flush()?;
+        }
+        Format::Csv => {
+            let mut writer = csv::Writer::from_writer(writer);
+            writer.write_record(["key", "value"])?;
+            for entry in entries {
+                let (key, value) = entry?;
+                writer.write_record([to_csv(&key), to_csv(&value)])?;
+                count += 1;
+            }
+            writer.flush()?;
+        }
+    }
+    Ok(count)
+}
+
+/// Parses records in given format passing each pair to `apply`, returns number of records
+pub(crate) fn read_records(
+    reader: impl io::Read,
+    format: Format,
+    mut apply: impl FnMut(Vec<u8>, Vec<u8>) -> Result<()>,
+) -> Result<usize> {
+    let mut count = 0;
+    match format {
+        Format::Json => {
+            for (line_num, line) in io::BufReader::new(reader).lines().enumerate() {
+                let line = line?;
+                if line.trim().is_empty() {
+                    continue;
+                }
+                let record: Value = serde_json::from_str(&line)?;
+                let field = |name| {
+                    from_json(&record[name]).ok_or_else(|| {
+                        DBError::InvalidData(format!(
+                            "line {}: malformed `{name}` field",
+                            line_num + 1
+                        ))
+                    })
+                };
+                apply(field("key")?, field("value")?)?;
+                count += 1;
+            }
+        }
+        Format::Csv => {
+            for record in csv::Reader::from_reader(reader).records() {
+                let record = record?;
+                let (Some(key), Some(value), 2) = (record.get(0), record.get(1), record.len())
+                else {
+                    let reason = format!("expected 2 fields in csv record {count}");
+                    return Err(DBError::InvalidData(reason));
+                };
+                apply(from_csv(key)?, from_csv(value)?)?;
+                count += 1;
+            }
+        }
+    }
+    Ok(count)
+}
+
+fn to_json(data: &[u8]) -> Value {
+    match std::str::from_utf8(data) {
+        Ok(text) => Value::from(text),
+        Err(_) => json!({"base64": BASE64.encode(data)}),
+    }
+}
+
+fn from_json(value: &Value) -> Option<Vec<u8>> {
+    match value {
+        Value::String(text) => Some(text.as_bytes().to_vec()),
+        Value::Object(object) => BASE64.decode(object.get("base64")?.as_str()?).ok(),
+        _ => None,
+    }
+}
+
+/// Text which happens to start with the base64 prefix is encoded as well to stay unambiguous
+fn to_csv(data: &[u8]) -> String {
+    match std::str::from_utf8(data) {
+        Ok(text) if !text.starts_with(CSV_BASE64_PREFIX) => text.to_string(),
+        _ => format!("{CSV_BASE64_PREFIX}{}", BASE64.encode(data)),
+    }
+}
+
+fn from_csv(field: &str) -> Result<Vec<u8>> {
+    match field.strip_prefix(CSV_BASE64_PREFIX) {
+        Some(encoded) => Ok(BASE64.decode(encoded)?),
+        None => Ok(field.as_bytes().to_vec()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trips_text_and_binary() {
+        let entries = vec![
+            (
+                b"plain".to_vec(),
+                b"with \"quotes\", commas\nand newlines".to_vec(),
+            ),
+            (b"base64:looks encoded".to_vec(), b"".to_vec()),
+            (vec![0xff, 0x00, 0xfe], vec![0x80]),
+        ];
+        for format in [Format::Json, Format::Csv] {
+            let mut buffer = Vec::new();
+            assert_eq!(
+                write_records(entries.iter().cloned().map(Ok), &mut buffer, format).unwrap(),
+                3
+            );
+            let mut parsed = Vec::new();
+            let count = read_records(buffer.as_slice(), format, |key, value| {
+                parsed.push((key, value));
+                Ok(())
+            })
+            .unwrap();
+            assert_eq!(count, 3);
+            assert_eq!(parsed, entries);
+        }
+    }
+}
@@ -0,0 +1,192 @@
+use crate::error::{DBError, Result};
+use crate::utils::CommonBinaryFormat;
+use arrow_array::cast::AsArray;
+use arrow_array::{Array, RecordBatch};
+use arrow_schema::{ArrowError, DataType};
+use parquet::data_type::{ByteArray, ByteArrayType, Int64Type};
+use parquet::file::properties::WriterProperties;
+use parquet::file::writer::SerializedFileWriter;
+use parquet::schema::parser::parse_message_type;
+use std::io;
+use std::sync::Arc;
+
+/// Columns of exported parquet files, timestamp is the write time of the exported version
+const SCHEMA: &str = "
+message lsmdb_export {
+    REQUIRED BYTE_ARRAY key;
+    REQUIRED BYTE_ARRAY value;
+    REQUIRED INT64 timestamp (TIMESTAMP(MICROS, true));
+}";
+
+/// Rows buffered in memory before they are written out as a row group
+const ROW_GROUP_SIZE: usize = 64 * 1024;
+
+/// Writes live entries as a single parquet file, returns number of written rows
+pub(crate) fn write_parquet(
+    entries: impl Iterator<Item = Result<CommonBinaryFormat>>,
+    writer: impl io::Write + Send,
+) -> Result<usize> {
+    let schema = Arc::new(parse_message_type(SCHEMA)?);
+    let properties = Arc::new(WriterProperties::builder().build());
+    let mut writer = SerializedFileWriter::new(writer, schema, properties)?;
+    let mut group = RowGroup::default();
+    let mut count = 0;
+    for entry in entries {
+        let entry = entry?;
+        let timestamp = i64::try_from(entry.timestamp).map_err(|_| {
+            DBError::InvalidData(format!("timestamp {} out of range", entry.timestamp))
+        })?;
+        group.keys.push(ByteArray::from(entry.key));
+        group
+            .values
+            .push(ByteArray::from(entry.value.unwrap_or_default()));
+        group.timestamps.push(timestamp);
+        count += 1;
+        if group.timestamps.len() == ROW_GROUP_SIZE {
+            group.write(&mut writer)?;
+        }
+    }
+    if !group.timestamps.is_empty() {
+        group.write(&mut writer)?;
+    }
+    writer.close()?;
+    Ok(count)
+}
+
+/// Passes rows of record batches with `key` and `value` binary or string columns to `apply`,
+/// null value corresponds to delete, returns number of rows
+pub(crate) fn read_record_batches(
+    batches: impl IntoIterator<Item = Result<RecordBatch, ArrowError>>,
+    mut apply: impl FnMut(&[u8], Option<&[u8]>) -> Result<()>,
+) -> Result<usize> {
+    let mut count = 0;
+    for batch in batches {
+        let batch = batch?;
+        let column = |name| -> Result<_> {
+            let column = batch.column_by_name(name).ok_or_else(|| {
+                DBError::InvalidData(format!("record batch has no `{name}` column"))
+            })?;
+            Ok(arrow_cast::cast(column, &DataType::Binary)?)
+        };
+        let (keys, values) = (column("key")?, column("value")?);
+        let (keys, values) = (keys.as_binary::<i32>(), values.as_binary::<i32>());
+        for row in 0..batch.num_rows() {
+            if keys.is_null(row) {
+                return Err(DBError::InvalidData(format!(
+                    "null key in row {}",
+                    count + row
+                )));
+            }
+            let value = values.is_valid(row).then(|| values.value(row));
+            apply(keys.value(row), value)?;
+        }
+        count += batch.num_rows();
+    }
+    Ok(count)
+}
+
+#[derive(Default)]
+struct RowGroup {
+    keys: Vec<ByteArray>,
+    values: Vec<ByteArray>,
+    timestamps: Vec<i64>,
+}
+
+impl RowGroup {
+    /// Writes buffered rows in schema column order and clears buffers
+    fn write<W: io::Write + Send>(&mut self, writer: &mut SerializedFileWriter<W>) -> Result<()> {
+        let mut row_group = writer.next_row_group()?;
+        for data in [&self.keys, &self.values] {
+            let mut column = row_group
+                .next_column()?
+                .ok_or_else(|| DBError::InvalidData("missing byte array column".to_string()))?;
+            column
+                .typed::<ByteArrayType>()
+                .write_batch(data, None, None)?;
+            column.close()?;
+        }
+        let mut column = row_group
+            .next_column()?
+            .ok_or_else(|| DBError::InvalidData("missing timestamp column".to_string()))?;
+        column
+            .typed::<Int64Type>()
+            .write_batch(&self.timestamps, None, None)?;
+        column.close()?;
+        row_group.close()?;
+        *self = Self::default();
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use parquet::arrow::arrow_reader::ParquetRecordBatchReaderBuilder;
+    use parquet::file::reader::{FileReader, SerializedFileReader};
+    use parquet::record::Field;
+    use std::fs::{self, File};
+    use std::path::PathBuf;
+
+    #[test]
+    fn parquet_round_trip() {
+        let entries = vec![
+            CommonBinaryFormat {
+                timestamp: 1,
+                key: b"a".to_vec(),
+                value: Some(b"1".to_vec()),
+                meta: Vec::new(),
+            },
+            CommonBinaryFormat {
+                timestamp: 5,
+                key: vec![0xff],
+                value: Some(Vec::new()),
+                meta: Vec::new(),
+            },
+        ];
+        let test_dir = &PathBuf::from("./tests/parquet_round_trip");
+        if test_dir.exists() {
+            fs::remove_dir_all(test_dir).unwrap();
+        }
+        fs::create_dir_all(test_dir).unwrap();
+        let path = test_dir.join("export.parquet");
+        let file = File::create(&path).unwrap();
+        let count = write_parquet(entries.into_iter().map(Ok), file).unwrap();
+        assert_eq!(count, 2);
+
+        let reader = SerializedFileReader::new(File::open(&path).unwrap()).unwrap();
+        assert_eq!(reader.metadata().file_metadata().num_rows(), 2);
+        let rows: Vec<_> = reader
+            .get_row_iter(None)
+            .unwrap()
+            .map(|row| {
+                let row = row.unwrap();
+                row.get_column_iter()
+                    .map(|(_, field)| field.clone())
+                    .collect::<Vec<_>>()
+            })
+            .collect();
+        assert!(
+            matches!(&rows[1][..], [Field::Bytes(key), Field::Bytes(value), Field::TimestampMicros(5)]
+            if key.data() == [0xff] && value.data().is_empty())
+        );
+
+        let batches = ParquetRecordBatchReaderBuilder::try_new(File::open(&path).unwrap())
+            .unwrap()
+            .build()
+            .unwrap();
+        let mut imported = Vec::new();
+        let count = read_record_batches(batches, |key, value| {
+            imported.push((key.to_vec(), value.map(<[u8]>::to_vec)));
+            Ok(())
+        })
+        .unwrap();
+        assert_eq!(count, 2);
+        assert_eq!(
+            imported,
+            vec![
+                (b"a".to_vec(), Some(b"1".to_vec())),
+                (vec![0xff], Some(Vec::new()))
+            ]
+        );
+    }
+}
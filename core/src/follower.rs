@@ -0,0 +1,190 @@
+use crate::database::{query_sources, scan_sources, DatabaseOptions};
+use crate::error::Result;
+use crate::keyspace;
+use crate::memtable::MemTable;
+use crate::sstable::SstReader;
+use crate::utils;
+use crate::wal::WriteAheadLog;
+use std::collections::{BTreeMap, HashMap};
+use std::io;
+use std::ops::RangeBounds;
+use std::path::{Path, PathBuf};
+
+/// Read-only view of database owned by another process in the same directory,
+/// `catch_up` has to be called periodically to pick up changes made by the primary
+///
+/// Primary writes become visible once they are flushed from its wal buffer,
+/// see `WalSyncPolicy`.
+pub struct Follower {
+    options: DatabaseOptions,
+    /// wal file -> bytes consumed so far and memtable built from them
+    wals: BTreeMap<PathBuf, (u64, MemTable)>,
+    /// all wal memtables merged, newer wal files override older ones
+    memtable: MemTable,
+    /// level num -> tables sorted from oldest to newest
+    on_disk_levels: Vec<Vec<SstReader>>,
+}
+
+impl Follower {
+    pub(crate) fn init(options: DatabaseOptions) -> Result<Self> {
+        let mut follower = Self {
+            on_disk_levels: vec![Vec::new(); options.level_num.max(1)],
+            memtable: MemTable::with_order(options.comparator.clone()),
+            options,
+            wals: BTreeMap::new(),
+        };
+        follower.catch_up()?;
+        Ok(follower)
+    }
+
+    /// Reads wal records appended since the last call and reloads the set of tables
+    pub fn catch_up(&mut self) -> Result<()> {
+        // wals are listed before tables: primary writes table before removing its wal,
+        // so data can be seen twice but never missed
+        self.tail_wals()?;
+        self.reload_tables()
+    }
+
+    pub fn query(&self, key: impl AsRef<[u8]>) -> Result<Option<Vec<u8>>> {
+        let key = key.as_ref();
+        let order = &self.options.comparator;
+        query_sources(order, &[&self.memtable], &self.on_disk_levels, key)?
+            .map(|value| self.options.value_transformers.decode(key, value))
+            .transpose()
+    }
+
+    pub fn scan(&self, range: impl RangeBounds<Vec<u8>>) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        let order = &self.options.comparator;
+        let mut entries = scan_sources(order, &[&self.memtable], &self.on_disk_levels, range)?;
+        entries.retain(|(key, _)| !keyspace::is_internal_key(key));
+        self.options.value_transformers.decode_pairs(entries)
+    }
+
+    fn tail_wals(&mut self) -> Result<()> {
+        let existing = utils::scan_dir(&self.options.working_dir, &["wal"])?;
+        self.wals.retain(|path, _| existing.contains(path));
+        for path in existing {
+            let (consumed, memtable) = self
+                .wals
+                .entry(path.clone())
+                .or_insert_with(|| (0, MemTable::with_order(self.options.comparator.clone())));
+            let inspection = match WriteAheadLog::inspect_from(&path, *consumed) {
+                Ok(inspection) => inspection,
+                Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                    self.wals.remove(&path);
+                    continue;
+                }
+                Err(e) => return Err(e.into()),
+            };
+            // incomplete tail is left for the next round
+            *consumed = inspection.valid_len;
+            for (_, entry) in inspection.records {
+                match entry.value {
+                    Some(value) => {
+                        memtable.put_with_meta(entry.timestamp, entry.key, value, entry.meta)
+                    }
+                    None => memtable.delete(entry.timestamp, entry.key),
+                }
+            }
+        }
+
+        self.memtable = MemTable::with_order(self.options.comparator.clone());
+        for (_, memtable) in self.wals.values() {
+            for entry in memtable.iter() {
+                match entry.value {
+                    Some(value) => self.memtable.put_with_meta(
+                        entry.timestamp,
+                        entry.key.to_vec(),
+                        value.to_vec(),
+                        entry.meta.to_vec(),
+                    ),
+                    None => self.memtable.delete(entry.timestamp, entry.key.to_vec()),
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn open_table(&self, path: &Path) -> io::Result<SstReader> {
+        let mut table = SstReader::open(path)?;
+        table.set_key_order(self.options.comparator.clone())?;
+        Ok(table)
+    }
+
+    fn reload_tables(&mut self) -> Result<()> {
+        let mut known: HashMap<_, _> = self
+            .on_disk_levels
+            .iter_mut()
+            .flat_map(std::mem::take)
+            .map(|table| (table.path.clone(), table))
+            .collect();
+        for path in utils::scan_dir(&self.options.working_dir, &["sst"])? {
+            let table = match known.remove(&path) {
+                Some(table) => table,
+                None => match self.open_table(&path) {
+                    Ok(table) => table,
+                    // removed by compaction or still being written by primary
+                    Err(e)
+                        if matches!(
+                            e.kind(),
+                            io::ErrorKind::NotFound | io::ErrorKind::UnexpectedEof
+                        ) =>
+                    {
+                        continue
+                    }
+                    Err(e) => return Err(e.into()),
+                },
+            };
+            let level = table.metadata.level;
+            if level >= self.on_disk_levels.len() {
+                self.on_disk_levels.resize(level + 1, Vec::new());
+            }
+            self.on_disk_levels[level].push(table);
+        }
+        for level in self.on_disk_levels.iter_mut() {
+            level.sort_by(|a, b| utils::compare_file_names(&a.path, &b.path));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{Database, WalSyncPolicy};
+    use std::fs;
+    use std::path::PathBuf;
+
+    #[test]
+    fn follows_primary_writes_and_flushes() {
+        let test_dir = &PathBuf::from("./tests/follows_primary_writes_and_flushes");
+        if test_dir.exists() {
+            fs::remove_dir_all(test_dir).unwrap();
+        }
+        let options = Database::options()
+            .set_working_dir(test_dir)
+            .set_wal_sync_policy(WalSyncPolicy::Always);
+        let mut primary = options.clone().init().unwrap();
+        primary.put(b"a".to_vec(), b"1".to_vec()).unwrap();
+
+        let mut follower = options.init_follower().unwrap();
+        assert_eq!(follower.query(b"a").unwrap(), Some(b"1".to_vec()));
+
+        primary.put(b"b".to_vec(), b"2".to_vec()).unwrap();
+        primary.delete(b"a".to_vec()).unwrap();
+        assert_eq!(follower.query(b"b").unwrap(), None);
+        follower.catch_up().unwrap();
+        assert_eq!(follower.query(b"a").unwrap(), None);
+        assert_eq!(follower.query(b"b").unwrap(), Some(b"2".to_vec()));
+
+        primary.swap_memtable().unwrap();
+        primary.put(b"c".to_vec(), b"3".to_vec()).unwrap();
+        follower.catch_up().unwrap();
+        assert_eq!(
+            follower.scan(..).unwrap(),
+            vec![
+                (b"b".to_vec(), b"2".to_vec()),
+                (b"c".to_vec(), b"3".to_vec())
+            ]
+        );
+    }
+}
@@ -0,0 +1,332 @@
+//! Machine-readable description of on-disk layouts, derived by running the encoders on sample
+//! data, so it follows the encoding code as the format evolves
+
+use crate::bloom::BloomFilter;
+use crate::sstable::{PartitionHandle, PartitionIndex, SstLookupTable, SstMetadata};
+use crate::utils::CommonBinaryFormatRef;
+use serde_json::{json, Value};
+use std::io;
+
+/// Bumped on every change of on-disk layouts
+pub const FORMAT_VERSION: u32 = 7;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FieldSize {
+    Fixed(usize),
+    /// size is stored in the named field
+    SizedBy(&'static str),
+    /// LEB128 encoded number taking 1 to 10 bytes
+    Varint,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FieldLayout {
+    pub name: &'static str,
+    /// offset from layout start, None after the first variable or conditional field
+    pub offset: Option<usize>,
+    pub size: FieldSize,
+    /// when field is present or repeated, None if always present once
+    pub condition: Option<&'static str>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Layout {
+    pub name: &'static str,
+    pub fields: Vec<FieldLayout>,
+}
+
+/// Field as known without running encoder
+struct Spec {
+    name: &'static str,
+    sized_by: Option<&'static str>,
+    varint: bool,
+    condition: Option<&'static str>,
+}
+
+const fn fixed(name: &'static str) -> Spec {
+    Spec {
+        name,
+        sized_by: None,
+        varint: false,
+        condition: None,
+    }
+}
+
+const fn sized_by(name: &'static str, size_field: &'static str) -> Spec {
+    Spec {
+        sized_by: Some(size_field),
+        ..fixed(name)
+    }
+}
+
+const fn varint(name: &'static str) -> Spec {
+    Spec {
+        varint: true,
+        ..fixed(name)
+    }
+}
+
+const fn only_if(spec: Spec, condition: &'static str) -> Spec {
+    Spec {
+        condition: Some(condition),
+        ..spec
+    }
+}
+
+const SAMPLE_KEY: &[u8] = b"key";
+const SAMPLE_VALUE: &[u8] = b"value";
+const SAMPLE_META: &[u8] = b"meta";
+const PUT: &str = "tombstone flag is unset";
+const WITH_META: &str = "meta flag is set";
+const PER_ENTRY: &str = "repeated for each entry";
+
+/// Layouts of record shared by wal and sst values, sst metadata, partition index,
+/// lookup table and bloom filter
+pub fn layouts() -> Vec<Layout> {
+    let record = derive(
+        "record",
+        &[
+            fixed("timestamp"),
+            fixed("flags"),
+            varint("key size"),
+            only_if(varint("value size"), PUT),
+            only_if(fixed("meta size"), WITH_META),
+            sized_by("key", "key size"),
+            only_if(sized_by("value", "value size"), PUT),
+            only_if(sized_by("meta", "meta size"), WITH_META),
+            fixed("crc32"),
+        ],
+        |out| {
+            CommonBinaryFormatRef::new(0, SAMPLE_KEY, Some(SAMPLE_VALUE))
+                .with_meta(SAMPLE_META)
+                .write(out)
+        },
+    );
+    let metadata = derive(
+        "sst metadata",
+        &[
+            fixed("level"),
+            fixed("lookup table offset"),
+            fixed("values table offset"),
+            fixed("filter offset"),
+            fixed("entry count"),
+            fixed("index interval"),
+            fixed("low key size"),
+            sized_by("low key", "low key size"),
+            fixed("high key size"),
+            sized_by("high key", "high key size"),
+            fixed("max timestamp"),
+            fixed("tombstone count"),
+            fixed("min timestamp"),
+            fixed("raw key size"),
+            fixed("raw value size"),
+            fixed("created at"),
+        ],
+        |out| {
+            SstMetadata {
+                level: 0,
+                lookup_table_offset: 0,
+                values_table_offset: 0,
+                filter_offset: 0,
+                entry_count: 0,
+                index_interval: 0,
+                low_key: SAMPLE_KEY.to_vec(),
+                high_key: SAMPLE_KEY.to_vec(),
+                max_timestamp: 0,
+                tombstone_count: 0,
+                min_timestamp: 0,
+                raw_key_size: 0,
+                raw_value_size: 0,
+                created_at: 0,
+            }
+            .write(out)
+        },
+    );
+    let lookup_table = derive(
+        "sst lookup table",
+        &[
+            fixed("entries count"),
+            only_if(fixed("key size"), PER_ENTRY),
+            only_if(sized_by("key", "key size"), PER_ENTRY),
+            only_if(fixed("value offset"), PER_ENTRY),
+        ],
+        |out| {
+            SstLookupTable {
+                entries: vec![(SAMPLE_KEY.to_vec(), 0)],
+            }
+            .write(out)
+        },
+    );
+    let partition_index = derive(
+        "sst partition index",
+        &[
+            fixed("partition entries"),
+            fixed("bits per key"),
+            fixed("partitions count"),
+            only_if(fixed("key size"), PER_ENTRY),
+            only_if(sized_by("key", "key size"), PER_ENTRY),
+            only_if(fixed("data offset"), PER_ENTRY),
+            only_if(fixed("lookup offset"), PER_ENTRY),
+            only_if(fixed("filter offset"), PER_ENTRY),
+            only_if(fixed("filter size"), PER_ENTRY),
+        ],
+        |out| {
+            PartitionIndex {
+                partition_entries: 1,
+                bits_per_key: 10,
+                partitions: vec![PartitionHandle {
+                    key: SAMPLE_KEY.to_vec(),
+                    ..Default::default()
+                }],
+            }
+            .write(out)
+        },
+    );
+    let filter = derive(
+        "bloom filter",
+        &[
+            fixed("bits per key"),
+            fixed("hash count"),
+            fixed("bits size"),
+            sized_by("bits", "bits size"),
+        ],
+        |out| BloomFilter::build([SAMPLE_KEY].into_iter(), 10).write(out),
+    );
+    vec![record, metadata, partition_index, lookup_table, filter]
+}
+
+/// Format version and layouts as json
+pub fn descriptor() -> Value {
+    let layouts: Vec<_> = layouts()
+        .iter()
+        .map(|layout| {
+            let fields: Vec<_> = layout
+                .fields
+                .iter()
+                .map(|field| {
+                    let size = match field.size {
+                        FieldSize::Fixed(size) => json!(size),
+                        FieldSize::SizedBy(size_field) => json!(size_field),
+                        FieldSize::Varint => json!("varint"),
+                    };
+                    json!({
+                        "name": field.name,
+                        "offset": field.offset,
+                        "size": size,
+                        "condition": field.condition,
+                    })
+                })
+                .collect();
+            json!({ "name": layout.name, "fields": fields })
+        })
+        .collect();
+    json!({ "version": FORMAT_VERSION, "layouts": layouts })
+}
+
+/// Differences between previously emitted descriptor and the current format,
+/// files written by a build with the stored descriptor are readable if there are none
+pub fn incompatibilities(stored: &Value) -> Vec<String> {
+    let current = descriptor();
+    let mut found = Vec::new();
+    if stored["version"] != current["version"] {
+        found.push(format!(
+            "format version {} differs from current {}",
+            stored["version"], current["version"]
+        ));
+    }
+    let empty = Vec::new();
+    let stored_layouts = stored["layouts"].as_array().unwrap_or(&empty);
+    for layout in current["layouts"].as_array().expect("layouts are array") {
+        let name = &layout["name"];
+        match stored_layouts.iter().find(|other| other["name"] == *name) {
+            None => found.push(format!("layout {name} is missing")),
+            Some(other) if other["fields"] != layout["fields"] => {
+                found.push(format!("fields of layout {name} differ"))
+            }
+            Some(_) => {}
+        }
+    }
+    found
+}
+
+/// Sizes of consecutive writes, encoders write one field per call
+#[derive(Default)]
+struct FieldSizes(Vec<usize>);
+
+impl io::Write for FieldSizes {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.push(buf.len());
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+fn derive(
+    name: &'static str,
+    specs: &[Spec],
+    encode: impl FnOnce(&mut FieldSizes) -> io::Result<()>,
+) -> Layout {
+    let mut sizes = FieldSizes::default();
+    encode(&mut sizes).expect("writes to memory don't fail");
+    assert_eq!(
+        sizes.0.len(),
+        specs.len(),
+        "{name} encoder writes a field not described in layout"
+    );
+    let mut offset = Some(0);
+    let fields = specs
+        .iter()
+        .zip(sizes.0)
+        .map(|(spec, size)| {
+            let field = FieldLayout {
+                name: spec.name,
+                offset,
+                size: match (spec.sized_by, spec.varint) {
+                    (Some(size_field), _) => FieldSize::SizedBy(size_field),
+                    (None, true) => FieldSize::Varint,
+                    (None, false) => FieldSize::Fixed(size),
+                },
+                condition: spec.condition,
+            };
+            offset = match (offset, &field.size, field.condition) {
+                (Some(offset), FieldSize::Fixed(size), None) => Some(offset + size),
+                _ => None,
+            };
+            field
+        })
+        .collect();
+    Layout { name, fields }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::mem;
+
+    #[test]
+    fn layouts_follow_encoders() {
+        let layouts = layouts();
+        let metadata = &layouts[1];
+        // the first variable field starts right after fixed header
+        let low_key = &metadata.fields[7];
+        assert_eq!(low_key.name, "low key");
+        assert_eq!(low_key.offset, Some(7 * mem::size_of::<usize>()));
+        assert_eq!(metadata.fields[8].offset, None);
+        let record = &layouts[0];
+        assert_eq!(record.fields[2].offset, Some(17));
+        assert_eq!(record.fields[2].size, FieldSize::Varint);
+        assert_eq!(record.fields[3].offset, None);
+        assert_eq!(record.fields[3].condition, Some(PUT));
+
+        let mut stored = descriptor();
+        assert!(incompatibilities(&stored).is_empty());
+        stored["layouts"][0]["fields"][0]["size"] = json!(8);
+        assert_eq!(
+            incompatibilities(&stored),
+            vec!["fields of layout \"record\" differ".to_string()]
+        );
+    }
+}
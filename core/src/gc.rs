@@ -0,0 +1,96 @@
+use crate::comparator::KeyOrder;
+use crate::sstable::SstReader;
+use crate::utils;
+use crate::vfs::Storage;
+use crate::wal::WriteAheadLogIterator;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+/// Files of working directory no table level or memtable refers to, left behind by
+/// interrupted writes. Database deletes them on open
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct OrphanFiles {
+    /// `.tmp` files of unfinished writes
+    pub temp_files: Vec<PathBuf>,
+    /// tables whose metadata can't be read, written partially before a crash
+    pub partial_tables: Vec<PathBuf>,
+    /// wals whose every record is persisted in tables already
+    pub flushed_wals: Vec<PathBuf>,
+}
+
+impl OrphanFiles {
+    pub(crate) fn find(
+        working_dir: &Path,
+        storage: &Arc<dyn Storage>,
+        order: &KeyOrder,
+    ) -> io::Result<Self> {
+        if !storage.exists(working_dir) {
+            return Ok(Self::default());
+        }
+        let mut orphans = Self {
+            temp_files: utils::scan_storage(&**storage, working_dir, &["tmp"])?,
+            ..Self::default()
+        };
+        let mut tables = Vec::new();
+        for path in utils::scan_storage(&**storage, working_dir, &["sst"])? {
+            let opened = SstReader::open_with_storage(&path, storage.clone())
+                .and_then(|mut table| table.set_key_order(order.clone()).map(|_| table));
+            match opened {
+                Ok(table) => tables.push(table),
+                Err(error)
+                    if matches!(
+                        error.kind(),
+                        io::ErrorKind::InvalidData | io::ErrorKind::UnexpectedEof
+                    ) =>
+                {
+                    orphans.partial_tables.push(path)
+                }
+                Err(error) => return Err(error),
+            }
+        }
+        for path in utils::scan_storage(&**storage, working_dir, &["wal"])? {
+            if is_flushed(&path, &tables, &**storage)? {
+                orphans.flushed_wals.push(path);
+            }
+        }
+        orphans.temp_files.sort();
+        orphans.partial_tables.sort();
+        orphans.flushed_wals.sort();
+        Ok(orphans)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.iter().next().is_none()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &PathBuf> {
+        self.temp_files
+            .iter()
+            .chain(&self.partial_tables)
+            .chain(&self.flushed_wals)
+    }
+
+    pub(crate) fn delete(&self, storage: &dyn Storage) -> io::Result<()> {
+        for path in self.iter() {
+            storage.delete(path)?;
+        }
+        Ok(())
+    }
+}
+
+/// Whether tables hold every record of wal or a newer version of its key
+fn is_flushed(wal: &Path, tables: &[SstReader], storage: &dyn Storage) -> io::Result<bool> {
+    for entry in WriteAheadLogIterator::new_with_storage(wal, storage)? {
+        let mut persisted = false;
+        for table in tables.iter().filter(|table| table.in_key_range(&entry.key)) {
+            if let Some(stored) = table.get(&entry.key)? {
+                persisted |= stored.timestamp >= entry.timestamp;
+            }
+        }
+        if !persisted {
+            return Ok(false);
+        }
+    }
+    Ok(true)
+}
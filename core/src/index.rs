@@ -0,0 +1,63 @@
+use crate::batch::WriteBatch;
+
+/// Subsystem key prefix of secondary index entries in internal keyspace
+const INDEX_PREFIX: &[u8] = b"index/";
+
+/// Write of a primary record together with mutations of secondary indexes derived from it,
+/// everything goes into a single batch so index never disagrees with data, even after crash
+///
+/// Index entry is stored in internal keyspace as
+/// > "index/" | index name size (4 bytes) | index name | indexed value size (4 bytes) | indexed value | primary key
+#[derive(Debug, Clone)]
+pub struct IndexedWrite {
+    key: Vec<u8>,
+    batch: WriteBatch,
+}
+
+impl IndexedWrite {
+    pub fn put(key: Vec<u8>, value: Vec<u8>) -> Self {
+        let mut batch = WriteBatch::new();
+        batch.put(key.clone(), value);
+        Self { key, batch }
+    }
+
+    pub fn delete(key: Vec<u8>) -> Self {
+        let mut batch = WriteBatch::new();
+        batch.delete(key.clone());
+        Self { key, batch }
+    }
+
+    /// Makes primary key findable by `indexed_value` in index
+    pub fn add_index(mut self, index: &[u8], indexed_value: &[u8]) -> Self {
+        let entry = index_entry_key(index, indexed_value, &self.key);
+        self.batch.put_internal(&entry, Vec::new());
+        self
+    }
+
+    /// Removes previously indexed value, e.g. the old one when indexed field changes
+    pub fn remove_index(mut self, index: &[u8], indexed_value: &[u8]) -> Self {
+        let entry = index_entry_key(index, indexed_value, &self.key);
+        self.batch.delete_internal(&entry);
+        self
+    }
+
+    pub fn into_batch(self) -> WriteBatch {
+        self.batch
+    }
+}
+
+/// Prefix shared by all entries of index with given indexed value
+pub(crate) fn index_lookup_prefix(index: &[u8], indexed_value: &[u8]) -> Vec<u8> {
+    let mut key = INDEX_PREFIX.to_vec();
+    key.extend_from_slice(&(index.len() as u32).to_le_bytes());
+    key.extend_from_slice(index);
+    key.extend_from_slice(&(indexed_value.len() as u32).to_le_bytes());
+    key.extend_from_slice(indexed_value);
+    key
+}
+
+fn index_entry_key(index: &[u8], indexed_value: &[u8], primary_key: &[u8]) -> Vec<u8> {
+    let mut key = index_lookup_prefix(index, indexed_value);
+    key.extend_from_slice(primary_key);
+    key
+}
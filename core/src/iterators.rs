@@ -0,0 +1,124 @@
+use crate::events::EventListener;
+use crate::utils::Stopwatch;
+use std::backtrace::Backtrace;
+use std::cmp::Reverse;
+use std::collections::HashMap;
+use std::panic::Location;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// Open iterator as seen by leak detector
+#[derive(Debug, Clone)]
+pub struct IteratorInfo {
+    pub id: u64,
+    pub age: Duration,
+    /// call site which created iterator
+    pub location: &'static Location<'static>,
+    /// captured only if enabled in options
+    pub backtrace: Option<String>,
+}
+
+struct Tracked {
+    created: Stopwatch,
+    location: &'static Location<'static>,
+    backtrace: Option<String>,
+    /// listener was already notified about this iterator
+    reported: bool,
+}
+
+/// Open iterators of database, long-living ones pin tables and memtables they read from
+pub(crate) struct IteratorRegistry {
+    threshold: Duration,
+    capture_backtraces: bool,
+    listener: Option<Arc<dyn EventListener>>,
+    /// next id and open iterators by id
+    open: Mutex<(u64, HashMap<u64, Tracked>)>,
+}
+
+impl IteratorRegistry {
+    pub fn new(
+        threshold: Duration,
+        capture_backtraces: bool,
+        listener: Option<Arc<dyn EventListener>>,
+    ) -> Self {
+        Self {
+            threshold,
+            capture_backtraces,
+            listener,
+            open: Mutex::new((0, HashMap::new())),
+        }
+    }
+
+    /// Tracks iterator until returned guard is dropped, also checks for long-running iterators
+    pub fn register(&self, location: &'static Location<'static>) -> IteratorGuard<'_> {
+        let backtrace = self
+            .capture_backtraces
+            .then(|| Backtrace::force_capture().to_string());
+        let id = {
+            let mut open = self.open.lock().expect("iterator registry mutex poisoned");
+            let (next_id, iterators) = &mut *open;
+            let id = *next_id;
+            *next_id += 1;
+            iterators.insert(
+                id,
+                Tracked {
+                    created: Stopwatch::start(),
+                    location,
+                    backtrace,
+                    reported: false,
+                },
+            );
+            id
+        };
+        self.long_running();
+        IteratorGuard { registry: self, id }
+    }
+
+    /// Iterators older than threshold from the oldest one, listener is notified about new ones
+    pub fn long_running(&self) -> Vec<IteratorInfo> {
+        let mut found = Vec::new();
+        let mut unreported = Vec::new();
+        {
+            let mut open = self.open.lock().expect("iterator registry mutex poisoned");
+            for (&id, tracked) in open.1.iter_mut() {
+                let age = tracked.created.elapsed();
+                if age < self.threshold {
+                    continue;
+                }
+                let info = IteratorInfo {
+                    id,
+                    age,
+                    location: tracked.location,
+                    backtrace: tracked.backtrace.clone(),
+                };
+                if !tracked.reported {
+                    tracked.reported = true;
+                    unreported.push(info.clone());
+                }
+                found.push(info);
+            }
+        }
+        // listener may call back into database, so it runs without the lock
+        if let Some(listener) = &self.listener {
+            for info in unreported.iter() {
+                listener.on_long_running_iterator(info);
+            }
+        }
+        found.sort_by_key(|info| Reverse(info.age));
+        found
+    }
+}
+
+/// Removes iterator from registry when dropped
+pub(crate) struct IteratorGuard<'a> {
+    registry: &'a IteratorRegistry,
+    id: u64,
+}
+
+impl Drop for IteratorGuard<'_> {
+    fn drop(&mut self) {
+        if let Ok(mut open) = self.registry.open.lock() {
+            open.1.remove(&self.id);
+        }
+    }
+}
@@ -0,0 +1,189 @@
+//! Order-preserving key encodings, encoded keys compare bytewise the same way as the values
+//! they were made from, so ranges of typed keys map to key ranges of database
+//!
+//! Composite key is a tuple encoded as concatenation of its segments, each one as
+//! > segment size (4 bytes, big endian) | encoded segment
+//!
+//! Numbers have fixed size so tuples of numbers keep the order of tuples. Byte and string
+//! segments of different lengths order by length first. Any leading segments of a tuple
+//! encode the same as a shorter tuple, which makes them usable as scan prefix
+
+/// Value encodable as one segment of a key
+pub trait KeySegment: Sized {
+    fn encode_segment(&self, out: &mut Vec<u8>);
+
+    /// None if data isn't valid encoding of this type
+    fn decode_segment(data: &[u8]) -> Option<Self>;
+}
+
+pub fn encode_u64(value: u64) -> [u8; 8] {
+    value.to_be_bytes()
+}
+
+pub fn decode_u64(data: &[u8]) -> Option<u64> {
+    Some(u64::from_be_bytes(data.try_into().ok()?))
+}
+
+/// Sign bit is flipped so negative numbers sort first
+pub fn encode_i64(value: i64) -> [u8; 8] {
+    ((value as u64) ^ (1 << 63)).to_be_bytes()
+}
+
+pub fn decode_i64(data: &[u8]) -> Option<i64> {
+    Some((decode_u64(data)? ^ (1 << 63)) as i64)
+}
+
+/// Negative numbers have every bit flipped, positive ones only the sign bit. NaNs sort
+/// after infinities, or before negative infinity if their sign bit is set
+pub fn encode_f64(value: f64) -> [u8; 8] {
+    let bits = value.to_bits();
+    let bits = if bits >> 63 == 1 {
+        !bits
+    } else {
+        bits | (1 << 63)
+    };
+    bits.to_be_bytes()
+}
+
+pub fn decode_f64(data: &[u8]) -> Option<f64> {
+    let bits = decode_u64(data)?;
+    let bits = if bits >> 63 == 1 {
+        bits & !(1 << 63)
+    } else {
+        !bits
+    };
+    Some(f64::from_bits(bits))
+}
+
+impl KeySegment for u64 {
+    fn encode_segment(&self, out: &mut Vec<u8>) {
+        out.extend_from_slice(&encode_u64(*self));
+    }
+
+    fn decode_segment(data: &[u8]) -> Option<Self> {
+        decode_u64(data)
+    }
+}
+
+impl KeySegment for i64 {
+    fn encode_segment(&self, out: &mut Vec<u8>) {
+        out.extend_from_slice(&encode_i64(*self));
+    }
+
+    fn decode_segment(data: &[u8]) -> Option<Self> {
+        decode_i64(data)
+    }
+}
+
+impl KeySegment for f64 {
+    fn encode_segment(&self, out: &mut Vec<u8>) {
+        out.extend_from_slice(&encode_f64(*self));
+    }
+
+    fn decode_segment(data: &[u8]) -> Option<Self> {
+        decode_f64(data)
+    }
+}
+
+impl KeySegment for Vec<u8> {
+    fn encode_segment(&self, out: &mut Vec<u8>) {
+        out.extend_from_slice(self);
+    }
+
+    fn decode_segment(data: &[u8]) -> Option<Self> {
+        Some(data.to_vec())
+    }
+}
+
+impl KeySegment for String {
+    fn encode_segment(&self, out: &mut Vec<u8>) {
+        out.extend_from_slice(self.as_bytes());
+    }
+
+    fn decode_segment(data: &[u8]) -> Option<Self> {
+        String::from_utf8(data.to_vec()).ok()
+    }
+}
+
+/// Tuple of key segments
+pub trait Key: Sized {
+    fn encode_key(&self) -> Vec<u8>;
+
+    /// None if data isn't encoded key of this type
+    fn decode_key(data: &[u8]) -> Option<Self>;
+}
+
+fn push_segment(segment: &impl KeySegment, out: &mut Vec<u8>) {
+    let size_at = out.len();
+    out.extend_from_slice(&[0; 4]);
+    segment.encode_segment(out);
+    let size = (out.len() - size_at - 4) as u32;
+    out[size_at..size_at + 4].copy_from_slice(&size.to_be_bytes());
+}
+
+/// Splits off the next segment, advancing `data` past it
+fn take_segment<T: KeySegment>(data: &mut &[u8]) -> Option<T> {
+    let size = u32::from_be_bytes(data.get(..4)?.try_into().ok()?) as usize;
+    let segment = data.get(4..4 + size)?;
+    *data = &data[4 + size..];
+    T::decode_segment(segment)
+}
+
+macro_rules! impl_tuple_key {
+    ($($segment:ident),+) => {
+        impl<$($segment: KeySegment),+> Key for ($($segment,)+) {
+            #[allow(non_snake_case)]
+            fn encode_key(&self) -> Vec<u8> {
+                let ($($segment,)+) = self;
+                let mut out = Vec::new();
+                $(push_segment($segment, &mut out);)+
+                out
+            }
+
+            fn decode_key(mut data: &[u8]) -> Option<Self> {
+                let key = ($(take_segment::<$segment>(&mut data)?,)+);
+                data.is_empty().then_some(key)
+            }
+        }
+    };
+}
+
+impl_tuple_key!(A);
+impl_tuple_key!(A, B);
+impl_tuple_key!(A, B, C);
+impl_tuple_key!(A, B, C, D);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::prelude::*;
+
+    proptest! {
+        #[test]
+        fn encodings_preserve_order(
+            a in (any::<u64>(), any::<i64>(), any::<f64>().prop_filter("not nan", |v| !v.is_nan())),
+            b in (any::<u64>(), any::<i64>(), any::<f64>().prop_filter("not nan", |v| !v.is_nan())),
+        ) {
+            prop_assert_eq!(a.0.cmp(&b.0), encode_u64(a.0).cmp(&encode_u64(b.0)));
+            prop_assert_eq!(a.1.cmp(&b.1), encode_i64(a.1).cmp(&encode_i64(b.1)));
+            prop_assert_eq!(a.2.total_cmp(&b.2), encode_f64(a.2).cmp(&encode_f64(b.2)));
+            prop_assert_eq!(
+                (a.0, a.1).cmp(&(b.0, b.1)),
+                (a.0, a.1).encode_key().cmp(&(b.0, b.1).encode_key())
+            );
+            prop_assert_eq!(<(u64, i64, f64)>::decode_key(&a.encode_key()), Some(a));
+        }
+    }
+
+    #[test]
+    fn leading_segments_are_prefix() {
+        let tenant = "tenant".to_string();
+        let key = (tenant.clone(), 1_700_000_000u64).encode_key();
+        assert!(key.starts_with(&(tenant.clone(),).encode_key()));
+        assert_eq!(<(String,)>::decode_key(&key), None);
+        assert_eq!(
+            <(String, u64)>::decode_key(&key),
+            Some((tenant, 1_700_000_000))
+        );
+    }
+}
@@ -0,0 +1,14 @@
+/// Prefix of keys reserved for engine subsystems (indexes, accounting, replication positions),
+/// such keys are hidden from user scans and can't be written through user api
+///
+/// Starts with 0xff bytes so internal keys sort after typical user keys.
+pub const INTERNAL_KEY_PREFIX: &[u8] = b"\xff\xfflsmdb/";
+
+/// Full key under which subsystem key is stored
+pub fn internal_key(key: &[u8]) -> Vec<u8> {
+    [INTERNAL_KEY_PREFIX, key].concat()
+}
+
+pub fn is_internal_key(key: &[u8]) -> bool {
+    key.starts_with(INTERNAL_KEY_PREFIX)
+}
@@ -0,0 +1,138 @@
+use crate::utils::Stopwatch;
+use hdrhistogram::Histogram;
+use std::sync::{Mutex, MutexGuard};
+use std::time::Duration;
+
+/// Operations with recorded latency
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Operation {
+    Get,
+    Put,
+    Delete,
+    Scan,
+    Flush,
+    Compaction,
+}
+
+const OPERATIONS: [Operation; 6] = [
+    Operation::Get,
+    Operation::Put,
+    Operation::Delete,
+    Operation::Scan,
+    Operation::Flush,
+    Operation::Compaction,
+];
+
+/// Longest tracked latency, slower operations are recorded as this value
+const MAX_LATENCY: Duration = Duration::from_secs(3600);
+
+/// Latency distribution of one operation since creation or the last reset
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct LatencyPercentiles {
+    pub count: u64,
+    pub p50: Duration,
+    pub p95: Duration,
+    pub p99: Duration,
+    pub p999: Duration,
+    pub max: Duration,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct OperationLatencies {
+    pub get: LatencyPercentiles,
+    pub put: LatencyPercentiles,
+    pub delete: LatencyPercentiles,
+    pub scan: LatencyPercentiles,
+    pub flush: LatencyPercentiles,
+    pub compaction: LatencyPercentiles,
+}
+
+/// Histogram per operation with microsecond resolution and 3 significant digits,
+/// shared with background jobs
+#[derive(Debug)]
+pub(crate) struct LatencyRecorder {
+    histograms: [Mutex<Histogram<u64>>; OPERATIONS.len()],
+}
+
+impl LatencyRecorder {
+    pub fn new() -> Self {
+        Self {
+            histograms: OPERATIONS.map(|_| {
+                let histogram = Histogram::new_with_bounds(1, MAX_LATENCY.as_micros() as u64, 3)
+                    .expect("valid histogram bounds");
+                Mutex::new(histogram)
+            }),
+        }
+    }
+
+    pub fn record(&self, operation: Operation, latency: Duration) {
+        self.histogram(operation)
+            .saturating_record(latency.as_micros().max(1) as u64);
+    }
+
+    /// Records time elapsed since `start`
+    pub fn record_since(&self, operation: Operation, start: Stopwatch) {
+        self.record(operation, start.elapsed());
+    }
+
+    pub fn reset(&self) {
+        for operation in OPERATIONS {
+            self.histogram(operation).reset();
+        }
+    }
+
+    pub fn percentiles(&self) -> OperationLatencies {
+        let percentiles = |operation| {
+            let histogram = self.histogram(operation);
+            let at = |quantile| Duration::from_micros(histogram.value_at_quantile(quantile));
+            LatencyPercentiles {
+                count: histogram.len(),
+                p50: at(0.5),
+                p95: at(0.95),
+                p99: at(0.99),
+                p999: at(0.999),
+                max: Duration::from_micros(histogram.max()),
+            }
+        };
+        OperationLatencies {
+            get: percentiles(Operation::Get),
+            put: percentiles(Operation::Put),
+            delete: percentiles(Operation::Delete),
+            scan: percentiles(Operation::Scan),
+            flush: percentiles(Operation::Flush),
+            compaction: percentiles(Operation::Compaction),
+        }
+    }
+
+    fn histogram(&self, operation: Operation) -> MutexGuard<'_, Histogram<u64>> {
+        self.histograms[operation as usize]
+            .lock()
+            .expect("latency histogram mutex poisoned")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn percentiles_expose_tail_latency() {
+        let recorder = LatencyRecorder::new();
+        for _ in 0..990 {
+            recorder.record(Operation::Get, Duration::from_micros(100));
+        }
+        for _ in 0..10 {
+            recorder.record(Operation::Get, Duration::from_millis(50));
+        }
+        let get = recorder.percentiles().get;
+        assert_eq!(get.count, 1000);
+        assert_eq!(get.p50, Duration::from_micros(100));
+        assert_eq!(get.p99, Duration::from_micros(100));
+        // within 3 significant digits
+        assert!(get.p999.abs_diff(Duration::from_millis(50)) < Duration::from_micros(50));
+        assert_eq!(recorder.percentiles().put, LatencyPercentiles::default());
+
+        recorder.reset();
+        assert_eq!(recorder.percentiles().get.count, 0);
+    }
+}
@@ -1,6 +1,87 @@
+mod batch;
+mod block_cache;
+mod bloom;
+mod cache_advisor;
+mod clock;
+mod compaction_filter;
+mod comparator;
+mod compression;
+mod concurrent;
+mod cursor;
 mod database;
+mod direct_io;
 mod error;
-mod memtable;
-mod sstable;
+mod events;
+pub mod export;
+#[cfg(feature = "std-fs")]
+mod follower;
+pub mod format;
+mod gc;
+mod index;
+mod iterators;
+pub mod keys;
+mod keyspace;
+mod latency;
+mod maintenance;
+pub mod memtable;
+mod merge;
+#[cfg(feature = "object-store")]
+pub mod object_storage;
+mod pinned;
+mod range_del;
+mod rate_limiter;
+mod repair;
+#[cfg(feature = "std-fs")]
+pub mod replication;
+mod scheduler;
+mod sequence;
+pub mod sstable;
+mod subscription;
+mod trace;
+mod transform;
+mod txn;
+#[cfg(feature = "serde")]
+mod typed;
 mod utils;
-mod wal;
+mod validation;
+pub mod vfs;
+pub mod wal;
+mod workload;
+
+pub use batch::WriteBatch;
+pub use block_cache::BlockCache;
+pub use bloom::FilterStats;
+pub use cache_advisor::CacheAdvice;
+pub use clock::{Clock, SimulatedClock, SystemClock};
+pub use compaction_filter::{CompactionFilter, FilterDecision};
+pub use comparator::{BytewiseComparator, Comparator};
+pub use compression::Compression;
+pub use concurrent::ConcurrentDb;
+pub use cursor::{DbCursor, EntryRef};
+pub use database::{
+    CompactionStyle, Database, DatabaseOptions, DatabaseStats, LevelStats, ScanChunk, ScanChunks,
+    ScanPage, WalRecord, WalTail, WriteKind,
+};
+pub use error::{DBError, Result};
+pub use events::EventListener;
+#[cfg(feature = "std-fs")]
+pub use follower::Follower;
+pub use gc::OrphanFiles;
+pub use index::IndexedWrite;
+pub use iterators::IteratorInfo;
+pub use keyspace::INTERNAL_KEY_PREFIX;
+pub use latency::{LatencyPercentiles, OperationLatencies};
+pub use maintenance::{MaintenanceReport, MaintenanceSchedule, MaintenanceWindow};
+pub use pinned::PinnedValue;
+pub use range_del::RangeTombstone;
+pub use rate_limiter::RateLimiter;
+pub use repair::{RepairReport, RepairSource, LOST_DIR};
+pub use subscription::ChangeEvent;
+pub use transform::ValueTransformer;
+pub use txn::{LockingTxn, TransactionDb, Txn};
+#[cfg(feature = "serde")]
+pub use typed::TypedDb;
+pub use utils::{CommonBinaryFormat, CommonBinaryFormatRef, Corruption, MAX_META_SIZE};
+pub use validation::KeyRules;
+pub use wal::{RecordType, UnknownRecordPolicy, WalSyncPolicy};
+pub use workload::{ReplayReport, TraceOp, TraceRecord};
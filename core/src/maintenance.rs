@@ -0,0 +1,94 @@
+use std::fmt;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+
+const DAY: u128 = 86_400_000_000;
+
+/// Daily time range in UTC when heavy maintenance may run, wraps over midnight
+/// if `end` is before `start`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MaintenanceWindow {
+    /// since midnight
+    start: Duration,
+    end: Duration,
+}
+
+impl MaintenanceWindow {
+    /// Offsets are taken modulo one day
+    pub fn new(start: Duration, end: Duration) -> Self {
+        let day = Duration::from_secs(86_400);
+        Self {
+            start: Duration::from_nanos((start.as_nanos() % day.as_nanos()) as u64),
+            end: Duration::from_nanos((end.as_nanos() % day.as_nanos()) as u64),
+        }
+    }
+
+    /// Whether `now` in microseconds since unix epoch falls within window
+    pub fn contains(&self, now: u128) -> bool {
+        let time_of_day = now % DAY;
+        let (start, end) = (self.start.as_micros(), self.end.as_micros());
+        if start <= end {
+            start <= time_of_day && time_of_day < end
+        } else {
+            start <= time_of_day || time_of_day < end
+        }
+    }
+}
+
+/// Decides when `Database::run_maintenance` does its work, maintenance runs within any of
+/// the windows or whenever idle check reports idle system. Empty schedule allows it at any time
+#[derive(Clone, Default)]
+pub struct MaintenanceSchedule {
+    windows: Vec<MaintenanceWindow>,
+    idle_check: Option<Arc<dyn Fn() -> bool + Send + Sync>>,
+}
+
+impl MaintenanceSchedule {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn set_windows(mut self, windows: Vec<MaintenanceWindow>) -> Self {
+        self.windows = windows;
+        self
+    }
+
+    /// Called outside of windows, maintenance runs if it returns true
+    pub fn set_idle_check(mut self, idle_check: impl Fn() -> bool + Send + Sync + 'static) -> Self {
+        self.idle_check = Some(Arc::new(idle_check));
+        self
+    }
+
+    pub fn allows(&self, now: u128) -> bool {
+        if self.windows.is_empty() && self.idle_check.is_none() {
+            return true;
+        }
+        self.windows.iter().any(|window| window.contains(now))
+            || self.idle_check.as_ref().is_some_and(|is_idle| is_idle())
+    }
+}
+
+impl fmt::Debug for MaintenanceSchedule {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("MaintenanceSchedule")
+            .field("windows", &self.windows)
+            .field("idle_check", &self.idle_check.is_some())
+            .finish()
+    }
+}
+
+/// Work done by one call of `Database::run_maintenance`
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct MaintenanceReport {
+    /// false if schedule didn't allow maintenance
+    pub ran: bool,
+    /// tables pushed down by periodic compaction
+    pub compacted_tables: usize,
+    /// tables whose checksums were verified
+    pub scrubbed_tables: usize,
+    /// scrubbed tables found corrupted
+    pub corrupted_tables: Vec<PathBuf>,
+    /// tables rewritten from repair source, both found by scrubbing and by failed reads
+    pub repaired_tables: Vec<PathBuf>,
+}
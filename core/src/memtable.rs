@@ -1,90 +1,444 @@
-use std::mem;
+use crate::comparator::KeyOrder;
+use crate::range_del::RangeTombstone;
+use crate::utils::{CommonBinaryFormat, CommonBinaryFormatRef};
+use std::cmp::Ordering;
+use std::sync::atomic::{self, AtomicPtr, AtomicU64, AtomicUsize};
+use std::{fmt, iter, mem, ptr};
 
-#[derive(Debug, Clone, PartialEq, Eq)]
+/// Levels of skip list, enough for billions of entries with branching of 4
+const MAX_HEIGHT: usize = 12;
+
+/// Entries sorted by key in a lock-free skip list, writers insert through shared reference
+/// without locking each other out.
+///
+/// Nodes are never unlinked, overwrite of key installs a new version in its node and the
+/// replaced one stays allocated, so readers hold plain references until memtable is dropped
 pub struct MemTable {
-    // Vector of entries sorted by key
-    pub entries: Vec<MemTableEntry>, //TODO: replace with skip list
-    pub data_size: usize,
+    /// first node on each level
+    head: [AtomicPtr<Node>; MAX_HEIGHT],
+    /// tombstones of range tombstone records among entries
+    range_tombstones: AtomicPtr<TombstoneNode>,
+    len: AtomicUsize,
+    size: AtomicUsize,
+    /// state of generator of node heights
+    seed: AtomicU64,
+    order: KeyOrder,
+}
+
+struct Node {
+    key: Box<[u8]>,
+    /// the newest version of key
+    version: AtomicPtr<Version>,
+    /// next node on each level node is linked at
+    tower: Box<[AtomicPtr<Node>]>,
 }
 
-#[derive(Debug, Clone, PartialEq, Eq)]
-pub struct MemTableEntry {
-    pub key: Vec<u8>,
+struct Version {
+    timestamp: u128,
     /// None if corresponds to delete
-    pub value: Option<Vec<u8>>,
+    value: Option<Box<[u8]>>,
+    meta: Box<[u8]>,
+    /// version this one replaced, freed together with it
+    replaced: *mut Version,
+}
+
+struct TombstoneNode {
+    tombstone: RangeTombstone,
+    next: *mut TombstoneNode,
+}
+
+/// Entry of memtable borrowing its bytes
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MemTableEntry<'a> {
+    pub key: &'a [u8],
+    /// None if corresponds to delete
+    pub value: Option<&'a [u8]>,
     pub timestamp: u128,
+    /// user metadata, empty if not set
+    pub meta: &'a [u8],
+}
+
+/// Fixed per-entry cost on top of key and value bytes
+pub const ENTRY_OVERHEAD: usize = mem::size_of::<Node>() + VERSION_OVERHEAD;
+/// Fixed cost of every installed version, overwrites and deletes of present keys pay it as well
+const VERSION_OVERHEAD: usize = mem::size_of::<Version>();
+
+impl<'a> MemTableEntry<'a> {
+    pub fn as_cbf_ref(&self) -> CommonBinaryFormatRef<'a> {
+        CommonBinaryFormatRef::new(self.timestamp, self.key, self.value).with_meta(self.meta)
+    }
+
+    /// Memory entry would take in a fresh memtable, tombstones pay for key and overhead only
+    pub fn cost(&self) -> usize {
+        self.key.len() + self.value.map_or(0, <[u8]>::len) + self.meta.len() + ENTRY_OVERHEAD
+    }
 }
 
 impl MemTable {
     pub fn new() -> Self {
+        Self::with_order(KeyOrder::default())
+    }
+
+    pub(crate) fn with_order(order: KeyOrder) -> Self {
         Self {
-            entries: Vec::new(),
-            data_size: 0,
+            head: Default::default(),
+            range_tombstones: AtomicPtr::default(),
+            len: AtomicUsize::new(0),
+            size: AtomicUsize::new(0),
+            seed: AtomicU64::new(0),
+            order,
         }
     }
 
-    // returns Ok() with found index, Err() with index for insert
-    pub fn get_index(&self, key: impl AsRef<[u8]>) -> Result<usize, usize> {
-        self.entries
-            .binary_search_by_key(&key.as_ref(), |e| e.key.as_slice())
+    /// Re-sorts entries by new order, e.g. after memtable was replayed from wal
+    pub(crate) fn set_order(&mut self, order: KeyOrder) {
+        let old = mem::replace(self, Self::with_order(order));
+        for entry in old.iter() {
+            self.insert(entry.timestamp, entry.key, entry.value, entry.meta);
+        }
     }
 
-    pub fn put(&mut self, timestamp: u128, key: Vec<u8>, value: Vec<u8>) {
-        match self.get_index(&key) {
-            Ok(idx) => {
-                let elem = &mut self.entries[idx];
-                if let Some(current_value) = elem.value.as_ref() {
-                    if current_value.len() < value.len() {
-                        self.data_size += value.len() - current_value.len();
-                    } else {
-                        self.data_size -= current_value.len() - value.len();
-                    }
+    pub fn put(&self, timestamp: u128, key: Vec<u8>, value: Vec<u8>) {
+        self.insert(timestamp, &key, Some(&value), &[]);
+    }
+
+    pub fn put_with_meta(&self, timestamp: u128, key: Vec<u8>, value: Vec<u8>, meta: Vec<u8>) {
+        self.insert(timestamp, &key, Some(&value), &meta);
+    }
+
+    pub fn delete(&self, timestamp: u128, key: Vec<u8>) {
+        self.insert(timestamp, &key, None, &[]);
+    }
+
+    /// Adds entry or replaces entry of key unless it has a newer timestamp, entries of equal
+    /// timestamps are replaced so the last op of batch wins. Replaced version stays allocated
+    fn insert(&self, timestamp: u128, key: &[u8], value: Option<&[u8]>, meta: &[u8]) {
+        if value.is_some() {
+            if let Some(tombstone) = RangeTombstone::from_record(timestamp, key) {
+                self.push_range_tombstone(tombstone);
+            }
+        }
+        let version = Box::into_raw(Box::new(Version {
+            timestamp,
+            value: value.map(Box::from),
+            meta: meta.into(),
+            replaced: ptr::null_mut(),
+        }));
+        let cost = value.map_or(0, <[u8]>::len) + meta.len() + VERSION_OVERHEAD;
+        let mut position = self.search(|node| self.order.lt(node, key));
+        if let Some(node) = position.next_with_key(&self.order, key) {
+            if node.install(version) {
+                self.size.fetch_add(cost, atomic::Ordering::Relaxed);
+            }
+            return;
+        }
+        let height = self.random_height();
+        let node = Box::into_raw(Box::new(Node {
+            key: key.into(),
+            version: AtomicPtr::new(version),
+            tower: iter::repeat_with(AtomicPtr::default).take(height).collect(),
+        }));
+        // SAFETY: node is owned here until it's linked at the bottom level
+        let tower = unsafe { &(*node).tower };
+        // bottom level decides whether key is new, the first of racing inserts links its node
+        loop {
+            tower[0].store(position.nexts[0], atomic::Ordering::Relaxed);
+            let link = position.link(&self.head, 0);
+            if link
+                .compare_exchange(
+                    position.nexts[0],
+                    node,
+                    atomic::Ordering::Release,
+                    atomic::Ordering::Relaxed,
+                )
+                .is_ok()
+            {
+                break;
+            }
+            position = self.search(|node| self.order.lt(node, key));
+            if let Some(existing) = position.next_with_key(&self.order, key) {
+                // SAFETY: node was never linked, its version is moved to the existing node
+                let node = unsafe { Box::from_raw(node) };
+                let version = node
+                    .version
+                    .swap(ptr::null_mut(), atomic::Ordering::Relaxed);
+                if existing.install(version) {
+                    self.size.fetch_add(cost, atomic::Ordering::Relaxed);
                 }
-                elem.value = Some(value);
-                elem.timestamp = timestamp;
+                return;
             }
-            Err(idx) => {
-                self.data_size += key.len() + value.len() + mem::size_of::<MemTableEntry>();
-                let entry = MemTableEntry {
-                    key,
-                    value: Some(value),
-                    timestamp,
-                };
-                self.entries.insert(idx, entry);
+        }
+        self.len.fetch_add(1, atomic::Ordering::Relaxed);
+        self.size.fetch_add(
+            key.len() + cost + mem::size_of::<Node>(),
+            atomic::Ordering::Relaxed,
+        );
+        for level in 1..height {
+            loop {
+                tower[level].store(position.nexts[level], atomic::Ordering::Relaxed);
+                let link = position.link(&self.head, level);
+                if link
+                    .compare_exchange(
+                        position.nexts[level],
+                        node,
+                        atomic::Ordering::Release,
+                        atomic::Ordering::Relaxed,
+                    )
+                    .is_ok()
+                {
+                    break;
+                }
+                position = self.search(|node| self.order.lt(node, key));
             }
         }
     }
 
-    pub fn delete(&mut self, timestamp: u128, key: Vec<u8>) {
-        match self.get_index(&key) {
-            Ok(idx) => {
-                let elem = &mut self.entries[idx];
-                if let Some(value) = elem.value.as_ref() {
-                    self.data_size -= value.len();
+    fn push_range_tombstone(&self, tombstone: RangeTombstone) {
+        let node = Box::into_raw(Box::new(TombstoneNode {
+            tombstone,
+            next: ptr::null_mut(),
+        }));
+        let mut head = self.range_tombstones.load(atomic::Ordering::Relaxed);
+        loop {
+            // SAFETY: node isn't published until exchange succeeds
+            unsafe { (*node).next = head };
+            match self.range_tombstones.compare_exchange_weak(
+                head,
+                node,
+                atomic::Ordering::Release,
+                atomic::Ordering::Relaxed,
+            ) {
+                Ok(_) => return,
+                Err(actual) => head = actual,
+            }
+        }
+    }
+
+    /// Height of a new node, each level is taken with probability of 1/4
+    fn random_height(&self) -> usize {
+        // splitmix64 over a shared counter, racing writers still get distinct values
+        let mut z = self
+            .seed
+            .fetch_add(0x9e37_79b9_7f4a_7c15, atomic::Ordering::Relaxed);
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^= z >> 31;
+        (1 + z.trailing_zeros() as usize / 2).min(MAX_HEIGHT)
+    }
+
+    /// Nodes preceding the first node whose key isn't `before` on each level
+    fn search(&self, before: impl Fn(&[u8]) -> bool) -> Position<'_> {
+        let mut position = Position {
+            preds: [None; MAX_HEIGHT],
+            nexts: [ptr::null_mut(); MAX_HEIGHT],
+        };
+        let mut pred: Option<&Node> = None;
+        for level in (0..MAX_HEIGHT).rev() {
+            loop {
+                let next = position
+                    .link_of(pred, &self.head, level)
+                    .load(atomic::Ordering::Acquire);
+                // SAFETY: linked nodes are freed only with memtable
+                match unsafe { next.as_ref() } {
+                    Some(node) if before(&node.key) => pred = Some(node),
+                    _ => {
+                        position.preds[level] = pred;
+                        position.nexts[level] = next;
+                        break;
+                    }
                 }
-                elem.value = None;
-                elem.timestamp = timestamp;
             }
-            Err(idx) => {
-                self.data_size += key.len() + mem::size_of::<MemTableEntry>();
-                let entry = MemTableEntry {
-                    key,
-                    value: None,
-                    timestamp,
-                };
-                self.entries.insert(idx, entry);
+        }
+        position
+    }
+
+    /// Removes entries with keys in [start, end) and returns them in key order, the rest is
+    /// moved to a fresh memtable so memory of taken entries is released
+    pub fn take_range(&mut self, start: &[u8], end: &[u8]) -> Vec<CommonBinaryFormat> {
+        let old = mem::replace(self, Self::with_order(self.order.clone()));
+        let mut taken = Vec::new();
+        for entry in old.iter() {
+            if self.order.le(start, entry.key) && self.order.lt(entry.key, end) {
+                taken.push(entry.as_cbf_ref().into_owned());
+            } else {
+                self.insert(entry.timestamp, entry.key, entry.value, entry.meta);
             }
         }
+        taken
+    }
+
+    pub fn range_tombstones(&self) -> impl Iterator<Item = &RangeTombstone> + '_ {
+        let head = self.range_tombstones.load(atomic::Ordering::Acquire);
+        // SAFETY: tombstones are freed only with memtable
+        iter::successors(unsafe { head.as_ref() }, |node| unsafe {
+            node.next.as_ref()
+        })
+        .map(|node| &node.tombstone)
+    }
+
+    pub fn get(&self, key: impl AsRef<[u8]>) -> Option<MemTableEntry<'_>> {
+        let key = key.as_ref();
+        self.search(|node| self.order.lt(node, key))
+            .next_with_key(&self.order, key)
+            .map(Node::entry)
+    }
+
+    /// Entries in key order
+    pub fn iter(&self) -> impl Iterator<Item = MemTableEntry<'_>> + '_ {
+        self.nodes_from(self.head[0].load(atomic::Ordering::Acquire))
+    }
+
+    /// Entries in key order starting at the first key not less than `from`
+    pub fn iter_from(&self, from: &[u8]) -> impl Iterator<Item = MemTableEntry<'_>> + '_ {
+        let position = self.search(|node| self.order.lt(node, from));
+        self.nodes_from(position.nexts[0])
+    }
+
+    /// The greatest key less than `bound`, the greatest key at all if unbounded
+    pub fn key_before(&self, bound: Option<&[u8]>) -> Option<&[u8]> {
+        let position = self.search(|node| bound.is_none_or(|bound| self.order.lt(node, bound)));
+        position.preds[0].map(|node| &*node.key)
     }
 
-    pub fn get(&self, key: impl AsRef<[u8]>) -> Option<&MemTableEntry> {
-        self.get_index(key.as_ref())
-            .ok()
-            .map(|idx| &self.entries[idx])
+    fn nodes_from(&self, first: *mut Node) -> impl Iterator<Item = MemTableEntry<'_>> + '_ {
+        // SAFETY: linked nodes are freed only with memtable
+        iter::successors(unsafe { first.as_ref() }, |node| unsafe {
+            node.tower[0].load(atomic::Ordering::Acquire).as_ref()
+        })
+        .map(Node::entry)
     }
 
+    pub fn len(&self) -> usize {
+        self.len.load(atomic::Ordering::Relaxed)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Bytes of keys and values including overwritten ones, together with `ENTRY_OVERHEAD`
+    /// of each entry and version overhead of each overwrite
     pub fn size(&self) -> usize {
-        self.data_size
+        self.size.load(atomic::Ordering::Relaxed)
+    }
+}
+
+/// Result of skip list search, nodes before the searched position and after it on each level
+struct Position<'a> {
+    /// None stands for head of list
+    preds: [Option<&'a Node>; MAX_HEIGHT],
+    nexts: [*mut Node; MAX_HEIGHT],
+}
+
+impl<'a> Position<'a> {
+    fn link(&self, head: &'a [AtomicPtr<Node>; MAX_HEIGHT], level: usize) -> &'a AtomicPtr<Node> {
+        self.link_of(self.preds[level], head, level)
+    }
+
+    fn link_of(
+        &self,
+        pred: Option<&'a Node>,
+        head: &'a [AtomicPtr<Node>; MAX_HEIGHT],
+        level: usize,
+    ) -> &'a AtomicPtr<Node> {
+        match pred {
+            Some(node) => &node.tower[level],
+            None => &head[level],
+        }
+    }
+
+    /// Node right after position if it holds key
+    fn next_with_key(&self, order: &KeyOrder, key: &[u8]) -> Option<&'a Node> {
+        // SAFETY: linked nodes are freed only with memtable
+        unsafe { self.nexts[0].as_ref() }
+            .filter(|node| order.cmp(&node.key, key) == Ordering::Equal)
+    }
+}
+
+impl Node {
+    /// Makes version the newest one unless node has a newer version, returns whether it did
+    fn install(&self, version: *mut Version) -> bool {
+        let mut current = self.version.load(atomic::Ordering::Acquire);
+        loop {
+            // SAFETY: installed versions are freed only with memtable, the new one is owned
+            // here until exchange succeeds
+            unsafe {
+                if (*current).timestamp > (*version).timestamp {
+                    drop(Box::from_raw(version));
+                    return false;
+                }
+                (*version).replaced = current;
+            }
+            match self.version.compare_exchange_weak(
+                current,
+                version,
+                atomic::Ordering::AcqRel,
+                atomic::Ordering::Acquire,
+            ) {
+                Ok(_) => return true,
+                Err(actual) => current = actual,
+            }
+        }
+    }
+
+    fn entry(&self) -> MemTableEntry<'_> {
+        // SAFETY: installed versions are freed only with memtable
+        let version = unsafe { &*self.version.load(atomic::Ordering::Acquire) };
+        MemTableEntry {
+            key: &self.key,
+            value: version.value.as_deref(),
+            timestamp: version.timestamp,
+            meta: &version.meta,
+        }
+    }
+}
+
+impl Drop for MemTable {
+    fn drop(&mut self) {
+        // SAFETY: memtable is exclusively owned, every node, version and tombstone is reachable
+        // exactly once from the bottom level, version chains and tombstone list
+        unsafe {
+            let mut node = *self.head[0].get_mut();
+            while !node.is_null() {
+                let mut owned = Box::from_raw(node);
+                node = *owned.tower[0].get_mut();
+                let mut version = *owned.version.get_mut();
+                while !version.is_null() {
+                    let owned = Box::from_raw(version);
+                    version = owned.replaced;
+                }
+            }
+            let mut tombstone = *self.range_tombstones.get_mut();
+            while !tombstone.is_null() {
+                tombstone = Box::from_raw(tombstone).next;
+            }
+        }
+    }
+}
+
+impl Default for MemTable {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Memtables are equal when they hold the same entries in the same order,
+/// overwritten versions aren't compared
+impl PartialEq for MemTable {
+    fn eq(&self, other: &Self) -> bool {
+        self.order == other.order && self.iter().eq(other.iter())
+    }
+}
+
+impl Eq for MemTable {}
+
+impl fmt::Debug for MemTable {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("MemTable")
+            .field("entries", &self.iter().collect::<Vec<_>>())
+            .field("size", &self.size())
+            .field("order", &self.order)
+            .finish()
     }
 }
 
@@ -98,69 +452,124 @@ mod tests {
         assert_eq!(memtable.get(vec![1, 1, 1]), None);
 
         memtable.put(1, vec![1, 1, 1], vec![0, 0, 0]);
-        assert_eq!(memtable.data_size, 70);
+        assert_eq!(memtable.size(), 6 + ENTRY_OVERHEAD);
         assert_eq!(
             memtable.get(vec![1, 1, 1]),
-            Some(&MemTableEntry {
-                key: vec![1, 1, 1],
-                value: Some(vec![0, 0, 0]),
+            Some(MemTableEntry {
+                key: &[1, 1, 1],
+                value: Some(&[0, 0, 0]),
                 timestamp: 1,
+                meta: &[],
             })
         );
 
         memtable.put(2, vec![3, 3, 3], vec![0, 1, 0, 1]);
-        assert_eq!(memtable.data_size, 141);
+        assert_eq!(memtable.size(), 13 + 2 * ENTRY_OVERHEAD);
         assert_eq!(
             memtable.get(vec![3, 3, 3]),
-            Some(&MemTableEntry {
-                key: vec![3, 3, 3],
-                value: Some(vec![0, 1, 0, 1]),
+            Some(MemTableEntry {
+                key: &[3, 3, 3],
+                value: Some(&[0, 1, 0, 1]),
                 timestamp: 2,
+                meta: &[],
             })
         );
 
         memtable.put(3, vec![2, 2, 2], vec![1, 0, 1, 0, 1]);
-        assert_eq!(memtable.data_size, 213);
+        assert_eq!(memtable.size(), 21 + 3 * ENTRY_OVERHEAD);
         assert_eq!(
             memtable.get(vec![2, 2, 2]),
-            Some(&MemTableEntry {
-                key: vec![2, 2, 2],
-                value: Some(vec![1, 0, 1, 0, 1]),
+            Some(MemTableEntry {
+                key: &[2, 2, 2],
+                value: Some(&[1, 0, 1, 0, 1]),
                 timestamp: 3,
+                meta: &[],
             })
         );
 
+        // key bytes are reused, replaced version stays allocated
+        let version = VERSION_OVERHEAD;
         memtable.delete(4, vec![2, 2, 2]);
-        assert_eq!(memtable.data_size, 208);
+        assert_eq!(memtable.size(), 21 + 3 * ENTRY_OVERHEAD + version);
         assert_eq!(
             memtable.get(vec![2, 2, 2]),
-            Some(&MemTableEntry {
-                key: vec![2, 2, 2],
+            Some(MemTableEntry {
+                key: &[2, 2, 2],
                 value: None,
                 timestamp: 4,
+                meta: &[],
             })
         );
 
         memtable.delete(5, vec![1, 1, 1]);
-        assert_eq!(memtable.data_size, 205);
+        assert_eq!(memtable.size(), 21 + 3 * ENTRY_OVERHEAD + 2 * version);
         assert_eq!(
             memtable.get(vec![1, 1, 1]),
-            Some(&MemTableEntry {
-                key: vec![1, 1, 1],
+            Some(MemTableEntry {
+                key: &[1, 1, 1],
                 value: None,
                 timestamp: 5,
+                meta: &[],
             })
         );
 
         memtable.delete(6, vec![3, 3, 3]);
-        assert_eq!(memtable.data_size, 201);
+        assert_eq!(memtable.size(), 21 + 3 * ENTRY_OVERHEAD + 3 * version);
         assert_eq!(
             memtable.get(vec![3, 3, 3]),
-            Some(&MemTableEntry {
-                key: vec![3, 3, 3],
+            Some(MemTableEntry {
+                key: &[3, 3, 3],
                 value: None,
                 timestamp: 6,
+                meta: &[],
             })
         );
+
+        memtable.put(7, vec![3, 3, 3], vec![1, 1]);
+        assert_eq!(memtable.size(), 23 + 3 * ENTRY_OVERHEAD + 4 * version);
+        assert_eq!(memtable.take_range(&[2], &[4]).len(), 2);
+        assert_eq!(memtable.size(), 3 + ENTRY_OVERHEAD);
+    }
+
+    #[test]
+    fn seeks_keys_around_bound() {
+        let memtable = MemTable::new();
+        for key in [1, 3, 5] {
+            memtable.put(key as u128, vec![key], vec![key]);
+        }
+        let keys = |from: &[u8]| -> Vec<_> { memtable.iter_from(from).map(|e| e.key[0]).collect() };
+        assert_eq!(keys(&[3]), vec![3, 5]);
+        assert_eq!(keys(&[4]), vec![5]);
+        assert!(keys(&[6]).is_empty());
+        assert_eq!(memtable.key_before(Some(&[3])), Some(&[1][..]));
+        assert_eq!(memtable.key_before(Some(&[1])), None);
+        assert_eq!(memtable.key_before(None), Some(&[5][..]));
+        // older version doesn't replace newer one
+        memtable.put(0, vec![3], vec![0]);
+        assert_eq!(memtable.get([3]).unwrap().value, Some(&[3][..]));
+    }
+
+    #[test]
+    fn concurrent_writers_keep_order_and_newest_versions() {
+        const WRITERS: u128 = 8;
+        let memtable = MemTable::new();
+        std::thread::scope(|scope| {
+            for writer in 0..WRITERS {
+                let memtable = &memtable;
+                scope.spawn(move || {
+                    for key in 0..1000u16 {
+                        let timestamp = key as u128 * WRITERS + writer;
+                        let value = vec![writer as u8];
+                        memtable.put(timestamp, key.to_be_bytes().to_vec(), value);
+                    }
+                });
+            }
+        });
+        assert_eq!(memtable.len(), 1000);
+        let entries: Vec<_> = memtable.iter().collect();
+        assert!(entries.windows(2).all(|pair| pair[0].key < pair[1].key));
+        for entry in entries {
+            assert_eq!(entry.value, Some(&[WRITERS as u8 - 1][..]));
+        }
     }
 }
@@ -0,0 +1,173 @@
+use crate::comparator::KeyOrder;
+use crate::memtable::MemTable;
+use crate::range_del::{self, RangeTombstone};
+use crate::sstable::SstReader;
+use crate::utils::CommonBinaryFormat;
+use std::io;
+use std::iter::Peekable;
+
+type Source<'a> = Peekable<Box<dyn Iterator<Item = io::Result<CommonBinaryFormat>> + 'a>>;
+
+/// Merges key-ordered sources into a single key-ordered stream with the newest version of each key,
+/// sources must be ordered from newest to oldest, tombstones are yielded as well.
+/// Versions deleted by range tombstones of sources are yielded as tombstones
+pub(crate) struct MergingIterator<'a> {
+    sources: Vec<Source<'a>>,
+    range_tombstones: Vec<RangeTombstone>,
+    order: KeyOrder,
+    /// key of the last yielded entry, a source yielding out of order keys is reported as error
+    last_key: Option<Vec<u8>>,
+}
+
+impl<'a> MergingIterator<'a> {
+    /// Merges memtables ordered from newest to oldest and levels from top to bottom,
+    /// starting from the first key not less than `from`
+    pub fn new(
+        order: &KeyOrder,
+        memtables: &[&'a MemTable],
+        levels: &'a [Vec<SstReader>],
+        from: &[u8],
+    ) -> io::Result<Self> {
+        Self::with_mode(order, memtables, levels, from, false)
+    }
+
+    /// Same as `new` with values of puts left empty, table values are not read at all
+    pub fn keys(
+        order: &KeyOrder,
+        memtables: &[&'a MemTable],
+        levels: &'a [Vec<SstReader>],
+        from: &[u8],
+    ) -> io::Result<Self> {
+        Self::with_mode(order, memtables, levels, from, true)
+    }
+
+    fn with_mode(
+        order: &KeyOrder,
+        memtables: &[&'a MemTable],
+        levels: &'a [Vec<SstReader>],
+        from: &[u8],
+        key_only: bool,
+    ) -> io::Result<Self> {
+        let mut sources = Vec::new();
+        for memtable in memtables {
+            let entries = memtable.iter_from(from).map(move |mut entry| {
+                if key_only {
+                    entry.value = entry.value.map(|_| &[][..]);
+                    entry.meta = &[];
+                }
+                Ok(entry.as_cbf_ref().into_owned())
+            });
+            sources.push(Box::new(entries) as Box<dyn Iterator<Item = _>>);
+        }
+        for level in levels {
+            for table in level.iter().rev() {
+                let entries = match key_only {
+                    true => table.iter_keys_from(from)?,
+                    false => table.iter_from(from)?,
+                };
+                sources.push(Box::new(entries));
+            }
+        }
+        Ok(Self {
+            sources: sources.into_iter().map(Iterator::peekable).collect(),
+            range_tombstones: range_del::in_sources(memtables, levels),
+            order: order.clone(),
+            last_key: None,
+        })
+    }
+}
+
+impl Iterator for MergingIterator<'_> {
+    type Item = io::Result<CommonBinaryFormat>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let mut newest: Option<(usize, &[u8])> = None;
+        for (idx, source) in self.sources.iter_mut().enumerate() {
+            match source.peek() {
+                None => continue,
+                Some(Err(_)) => {
+                    newest = Some((idx, &[]));
+                    break;
+                }
+                // the first source with the smallest key is the newest one
+                Some(Ok(entry)) => {
+                    if newest.is_none_or(|(_, key)| self.order.lt(&entry.key, key)) {
+                        newest = Some((idx, &entry.key));
+                    }
+                }
+            }
+        }
+        let (newest, _) = newest?;
+        let mut entry = match self.sources[newest].next()? {
+            Ok(entry) => entry,
+            Err(e) => return Some(Err(e)),
+        };
+        let order = &self.order;
+        if let Some(last_key) = self
+            .last_key
+            .as_ref()
+            .filter(|last| order.le(&entry.key, last))
+        {
+            return Some(Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "merged key {} yielded after {}",
+                    entry.key.escape_ascii(),
+                    last_key.escape_ascii()
+                ),
+            )));
+        }
+        self.last_key = Some(entry.key.clone());
+        // older versions of the same key are skipped
+        for source in self.sources.iter_mut() {
+            while let Some(Ok(older)) = source.peek() {
+                if older.key != entry.key {
+                    break;
+                }
+                source.next();
+            }
+        }
+        let covering =
+            range_del::newest_covering(order, &self.range_tombstones, &entry.key, entry.timestamp);
+        if let Some(sequence) = covering {
+            entry.timestamp = sequence;
+            entry.value = None;
+        }
+        Some(Ok(entry))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::comparator::Comparator;
+    use std::sync::Arc;
+
+    struct Reverse;
+
+    impl Comparator for Reverse {
+        fn name(&self) -> &str {
+            "test.Reverse"
+        }
+
+        fn compare(&self, a: &[u8], b: &[u8]) -> std::cmp::Ordering {
+            b.cmp(a)
+        }
+    }
+
+    #[test]
+    fn reports_out_of_order_source() {
+        let sorted = MemTable::new();
+        sorted.put(1, vec![2], vec![2]);
+        // yields keys in reverse of bytewise order merge expects
+        let unsorted = MemTable::with_order(KeyOrder::new(Arc::new(Reverse)));
+        for key in [1, 3] {
+            unsorted.delete(2, vec![key]);
+        }
+        let mut merged =
+            MergingIterator::new(&KeyOrder::default(), &[&sorted, &unsorted], &[], &[]).unwrap();
+        assert_eq!(merged.next().unwrap().unwrap().key, vec![2]);
+        assert_eq!(merged.next().unwrap().unwrap().key, vec![3]);
+        assert!(matches!(merged.next(), Some(Err(e)) if e.kind() == io::ErrorKind::InvalidData));
+    }
+}
@@ -0,0 +1,315 @@
+//! Storage keeping immutable tables in object store such as S3 bucket, with local copies
+//! cached for reads. Wal and every other file stay on local disk
+
+use crate::vfs::{LocalStorage, RandomAccessFile, Storage, WritableFile};
+use percent_encoding::percent_decode_str;
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::{fmt, io};
+
+/// Flat namespace of immutable objects, client of the actual object store
+pub trait ObjectStore: Send + Sync + fmt::Debug {
+    fn put(&self, key: &str, data: Vec<u8>) -> io::Result<()>;
+
+    fn get(&self, key: &str) -> io::Result<Vec<u8>>;
+
+    fn delete(&self, key: &str) -> io::Result<()>;
+
+    /// Keys starting with `prefix`
+    fn list(&self, prefix: &str) -> io::Result<Vec<String>>;
+
+    fn exists(&self, key: &str) -> io::Result<bool>;
+}
+
+/// Object store keeping objects in memory. Clones share objects
+#[derive(Debug, Default, Clone)]
+pub struct MemObjectStore {
+    objects: Arc<Mutex<HashMap<String, Vec<u8>>>>,
+}
+
+impl MemObjectStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, HashMap<String, Vec<u8>>> {
+        self.objects.lock().expect("object store mutex poisoned")
+    }
+}
+
+fn not_found(key: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::NotFound, format!("object {key} not found"))
+}
+
+impl ObjectStore for MemObjectStore {
+    fn put(&self, key: &str, data: Vec<u8>) -> io::Result<()> {
+        self.lock().insert(key.to_string(), data);
+        Ok(())
+    }
+
+    fn get(&self, key: &str) -> io::Result<Vec<u8>> {
+        self.lock().get(key).cloned().ok_or_else(|| not_found(key))
+    }
+
+    fn delete(&self, key: &str) -> io::Result<()> {
+        self.lock()
+            .remove(key)
+            .map(drop)
+            .ok_or_else(|| not_found(key))
+    }
+
+    fn list(&self, prefix: &str) -> io::Result<Vec<String>> {
+        Ok(self
+            .lock()
+            .keys()
+            .filter(|key| key.starts_with(prefix))
+            .cloned()
+            .collect())
+    }
+
+    fn exists(&self, key: &str) -> io::Result<bool> {
+        Ok(self.lock().contains_key(key))
+    }
+}
+
+/// Client of object store from `object_store` crate, such as `AmazonS3`, `GoogleCloudStorage`
+/// or `MicrosoftAzure` enabled by `aws`, `gcp` and `azure` features. Objects are kept directly
+/// under `root`, named by percent-encoded keys. Requests run on own runtime, so methods must
+/// not be called from async context
+#[derive(Debug)]
+pub struct CloudObjectStore {
+    store: Arc<dyn object_store::ObjectStore>,
+    root: object_store::path::Path,
+    runtime: tokio::runtime::Runtime,
+}
+
+impl CloudObjectStore {
+    pub fn new(store: Arc<dyn object_store::ObjectStore>, root: &str) -> io::Result<Self> {
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()?;
+        Ok(Self {
+            store,
+            root: object_store::path::Path::from(root),
+            runtime,
+        })
+    }
+
+    fn location(&self, key: &str) -> object_store::path::Path {
+        self.root.child(key)
+    }
+}
+
+fn into_io(error: object_store::Error) -> io::Error {
+    let kind = match error {
+        object_store::Error::NotFound { .. } => io::ErrorKind::NotFound,
+        object_store::Error::AlreadyExists { .. } => io::ErrorKind::AlreadyExists,
+        _ => io::ErrorKind::Other,
+    };
+    io::Error::new(kind, error)
+}
+
+impl ObjectStore for CloudObjectStore {
+    fn put(&self, key: &str, data: Vec<u8>) -> io::Result<()> {
+        let location = self.location(key);
+        let put = self.store.put(&location, data.into());
+        self.runtime.block_on(put).map(drop).map_err(into_io)
+    }
+
+    fn get(&self, key: &str) -> io::Result<Vec<u8>> {
+        let location = self.location(key);
+        let get = async { self.store.get(&location).await?.bytes().await };
+        self.runtime.block_on(get).map(Vec::from).map_err(into_io)
+    }
+
+    fn delete(&self, key: &str) -> io::Result<()> {
+        let location = self.location(key);
+        self.runtime
+            .block_on(self.store.delete(&location))
+            .map_err(into_io)
+    }
+
+    fn list(&self, prefix: &str) -> io::Result<Vec<String>> {
+        let list = self.store.list_with_delimiter(Some(&self.root));
+        let listed = self.runtime.block_on(list).map_err(into_io)?;
+        Ok(listed
+            .objects
+            .iter()
+            .filter_map(|object| object.location.filename())
+            .map(|name| percent_decode_str(name).decode_utf8_lossy().into_owned())
+            .filter(|key| key.starts_with(prefix))
+            .collect())
+    }
+
+    fn exists(&self, key: &str) -> io::Result<bool> {
+        let location = self.location(key);
+        match self.runtime.block_on(self.store.head(&location)) {
+            Ok(_) => Ok(true),
+            Err(object_store::Error::NotFound { .. }) => Ok(false),
+            Err(error) => Err(into_io(error)),
+        }
+    }
+}
+
+/// Tables are uploaded once written and synced, reads go to local copy in cache directory
+/// downloaded on first open. Cache directory must not be shared with other databases
+#[derive(Debug, Clone)]
+pub struct ObjectStorage {
+    store: Arc<dyn ObjectStore>,
+    cache_dir: PathBuf,
+}
+
+impl ObjectStorage {
+    pub fn new(store: Arc<dyn ObjectStore>, cache_dir: impl AsRef<Path>) -> Self {
+        Self {
+            store,
+            cache_dir: cache_dir.as_ref().to_path_buf(),
+        }
+    }
+
+    fn is_table(path: &Path) -> bool {
+        path.extension().is_some_and(|ext| ext == "sst")
+    }
+
+    /// Object key is the table path
+    fn key(path: &Path) -> String {
+        path.to_string_lossy().into_owned()
+    }
+
+    fn cached(&self, path: &Path) -> PathBuf {
+        self.cache_dir
+            .join(path.file_name().expect("table path has file name"))
+    }
+
+    /// Local copy of table, downloaded if it isn't cached yet
+    fn fetch(&self, path: &Path) -> io::Result<PathBuf> {
+        let cached = self.cached(path);
+        if !cached.exists() {
+            let data = self.store.get(&Self::key(path))?;
+            fs::create_dir_all(&self.cache_dir)?;
+            // readers never see partially downloaded copy
+            let partial = cached.with_extension("download");
+            fs::write(&partial, data)?;
+            fs::rename(&partial, &cached)?;
+        }
+        Ok(cached)
+    }
+}
+
+/// Table written to cache directory, uploaded on sync
+struct UploadFile {
+    file: File,
+    cached: PathBuf,
+    key: String,
+    store: Arc<dyn ObjectStore>,
+}
+
+impl io::Write for UploadFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.file.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
+
+impl WritableFile for UploadFile {
+    fn sync_data(&mut self) -> io::Result<()> {
+        self.file.sync_data()?;
+        self.store.put(&self.key, fs::read(&self.cached)?)
+    }
+}
+
+impl Storage for ObjectStorage {
+    fn open(&self, path: &Path) -> io::Result<Box<dyn RandomAccessFile>> {
+        if !Self::is_table(path) {
+            return LocalStorage.open(path);
+        }
+        LocalStorage.open(&self.fetch(path)?)
+    }
+
+    fn open_append(&self, path: &Path, create: bool) -> io::Result<Box<dyn WritableFile>> {
+        if Self::is_table(path) {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "tables in object store are immutable",
+            ));
+        }
+        LocalStorage.open_append(path, create)
+    }
+
+    fn create_new(&self, path: &Path) -> io::Result<Box<dyn WritableFile>> {
+        if !Self::is_table(path) {
+            return LocalStorage.create_new(path);
+        }
+        let key = Self::key(path);
+        if self.store.exists(&key)? {
+            return Err(io::ErrorKind::AlreadyExists.into());
+        }
+        fs::create_dir_all(&self.cache_dir)?;
+        let cached = self.cached(path);
+        let file = File::options().write(true).create_new(true).open(&cached)?;
+        Ok(Box::new(UploadFile {
+            file,
+            cached,
+            key,
+            store: self.store.clone(),
+        }))
+    }
+
+    fn rename(&self, from: &Path, to: &Path) -> io::Result<()> {
+        if !Self::is_table(from) && !Self::is_table(to) {
+            return LocalStorage.rename(from, to);
+        }
+        if !Self::is_table(from) {
+            // local file, e.g. temp file of finished table, becomes a table
+            self.store.put(&Self::key(to), fs::read(from)?)?;
+            fs::create_dir_all(&self.cache_dir)?;
+            return fs::rename(from, self.cached(to));
+        }
+        let data = fs::read(self.fetch(from)?)?;
+        self.store.put(&Self::key(to), data)?;
+        fs::rename(self.cached(from), self.cached(to))?;
+        self.store.delete(&Self::key(from))
+    }
+
+    fn delete(&self, path: &Path) -> io::Result<()> {
+        if !Self::is_table(path) {
+            return LocalStorage.delete(path);
+        }
+        self.store.delete(&Self::key(path))?;
+        match fs::remove_file(self.cached(path)) {
+            Err(err) if err.kind() != io::ErrorKind::NotFound => Err(err),
+            _ => Ok(()),
+        }
+    }
+
+    fn list(&self, dir: &Path) -> io::Result<Vec<PathBuf>> {
+        let mut files: Vec<_> = LocalStorage
+            .list(dir)?
+            .into_iter()
+            .filter(|path| !Self::is_table(path))
+            .collect();
+        for key in self.store.list(&Self::key(dir))? {
+            let path = PathBuf::from(key);
+            if path.parent() == Some(dir) {
+                files.push(path);
+            }
+        }
+        Ok(files)
+    }
+
+    fn exists(&self, path: &Path) -> bool {
+        if !Self::is_table(path) {
+            return LocalStorage.exists(path);
+        }
+        self.cached(path).exists() || self.store.exists(&Self::key(path)).unwrap_or(false)
+    }
+
+    fn create_dir_all(&self, dir: &Path) -> io::Result<()> {
+        LocalStorage.create_dir_all(dir)
+    }
+}
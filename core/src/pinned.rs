@@ -0,0 +1,69 @@
+use crate::sstable::MappedBytes;
+use std::fmt;
+use std::ops::Deref;
+
+/// Value read without copying when possible, borrows from memtable or keeps memory-mapped table
+/// alive until dropped. Memtable borrow holds the database borrowed as well
+pub struct PinnedValue<'a>(Pinned<'a>);
+
+enum Pinned<'a> {
+    Memtable(&'a [u8]),
+    Mapped(MappedBytes),
+    /// read from file or decoded by value transformer
+    Owned(Vec<u8>),
+}
+
+impl<'a> PinnedValue<'a> {
+    pub(crate) fn memtable(value: &'a [u8]) -> Self {
+        Self(Pinned::Memtable(value))
+    }
+
+    pub(crate) fn mapped(value: MappedBytes) -> Self {
+        Self(Pinned::Mapped(value))
+    }
+
+    pub(crate) fn owned(value: Vec<u8>) -> Self {
+        Self(Pinned::Owned(value))
+    }
+
+    /// Whether value was read without copying
+    pub fn is_pinned(&self) -> bool {
+        match &self.0 {
+            Pinned::Memtable(_) => true,
+            Pinned::Mapped(value) => value.is_mapped(),
+            Pinned::Owned(_) => false,
+        }
+    }
+
+    /// Copies pinned value, owned one is returned as is
+    pub fn into_vec(self) -> Vec<u8> {
+        match self.0 {
+            Pinned::Owned(value) => value,
+            _ => self.to_vec(),
+        }
+    }
+}
+
+impl Deref for PinnedValue<'_> {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        match &self.0 {
+            Pinned::Memtable(value) => value,
+            Pinned::Mapped(value) => value,
+            Pinned::Owned(value) => value,
+        }
+    }
+}
+
+impl AsRef<[u8]> for PinnedValue<'_> {
+    fn as_ref(&self) -> &[u8] {
+        self
+    }
+}
+
+impl fmt::Debug for PinnedValue<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.deref().escape_ascii())
+    }
+}
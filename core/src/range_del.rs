@@ -0,0 +1,104 @@
+//! Range tombstones are stored as records of internal keyspace, so wal, memtables and tables
+//! carry them without format changes, the record of tombstone deleting [start, end) is
+//! > "range_del/" | start size (4 bytes, big endian) | start | end
+//!
+//! with empty value and timestamp of the delete. Memtables and tables keep tombstones found
+//! among their records in memory, readers consult them for every key
+
+use crate::comparator::KeyOrder;
+use crate::keyspace;
+use crate::memtable::MemTable;
+use crate::sstable::SstReader;
+
+/// Subsystem key prefix of range tombstone records in internal keyspace
+const RANGE_DEL_PREFIX: &[u8] = b"range_del/";
+
+/// Deletes every user key within [start, end) written before `sequence`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RangeTombstone {
+    pub start: Vec<u8>,
+    pub end: Vec<u8>,
+    pub sequence: u128,
+}
+
+impl RangeTombstone {
+    /// Whether version of key written at `timestamp` is deleted, internal keys are never covered
+    pub(crate) fn covers(&self, order: &KeyOrder, key: &[u8], timestamp: u128) -> bool {
+        self.sequence > timestamp
+            && order.le(&self.start, key)
+            && order.lt(key, &self.end)
+            && !keyspace::is_internal_key(key)
+    }
+
+    /// Subsystem key of tombstone record, without internal keyspace prefix
+    pub(crate) fn record_key(start: &[u8], end: &[u8]) -> Vec<u8> {
+        let size = (start.len() as u32).to_be_bytes();
+        [RANGE_DEL_PREFIX, &size, start, end].concat()
+    }
+
+    /// Tombstone stored in record, None if record isn't a range tombstone
+    pub(crate) fn from_record(timestamp: u128, key: &[u8]) -> Option<Self> {
+        let record = key
+            .strip_prefix(keyspace::INTERNAL_KEY_PREFIX)?
+            .strip_prefix(RANGE_DEL_PREFIX)?;
+        let size = u32::from_be_bytes(record.get(..4)?.try_into().ok()?) as usize;
+        let start = record.get(4..4 + size)?;
+        Some(Self {
+            start: start.to_vec(),
+            end: record[4 + size..].to_vec(),
+            sequence: timestamp,
+        })
+    }
+}
+
+/// Full key where records of range tombstones begin
+pub(crate) fn records_start() -> Vec<u8> {
+    keyspace::internal_key(RANGE_DEL_PREFIX)
+}
+
+/// Every range tombstone held by sources
+pub(crate) fn in_sources(
+    memtables: &[&MemTable],
+    levels: &[Vec<SstReader>],
+) -> Vec<RangeTombstone> {
+    let in_memtables = memtables
+        .iter()
+        .flat_map(|memtable| memtable.range_tombstones());
+    let in_tables = levels
+        .iter()
+        .flatten()
+        .flat_map(|table| table.range_tombstones());
+    in_memtables.chain(in_tables).cloned().collect()
+}
+
+/// Sequence of the newest tombstone among sources deleting version of key written at
+/// `timestamp`
+pub(crate) fn covering(
+    order: &KeyOrder,
+    memtables: &[&MemTable],
+    levels: &[Vec<SstReader>],
+    key: &[u8],
+    timestamp: u128,
+) -> Option<u128> {
+    let in_memtables = memtables
+        .iter()
+        .flat_map(|memtable| memtable.range_tombstones());
+    let in_tables = levels
+        .iter()
+        .flatten()
+        .flat_map(|table| table.range_tombstones());
+    newest_covering(order, in_memtables.chain(in_tables), key, timestamp)
+}
+
+pub(crate) fn newest_covering<'a>(
+    order: &KeyOrder,
+    tombstones: impl IntoIterator<Item = &'a RangeTombstone>,
+    key: &[u8],
+    timestamp: u128,
+) -> Option<u128> {
+    tombstones
+        .into_iter()
+        .filter(|tombstone| tombstone.covers(order, key, timestamp))
+        .map(|tombstone| tombstone.sequence)
+        .max()
+}
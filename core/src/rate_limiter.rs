@@ -0,0 +1,84 @@
+use std::io;
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Token bucket limiting throughput of flush and compaction writes,
+/// one limiter can be shared by several databases to cap their total background io
+#[derive(Debug)]
+pub struct RateLimiter {
+    bytes_per_sec: u64,
+    /// available tokens and time of the last refill
+    bucket: Mutex<(f64, Instant)>,
+}
+
+impl RateLimiter {
+    /// Bucket holds one second worth of tokens and starts full
+    pub fn new(bytes_per_sec: u64) -> Self {
+        let bytes_per_sec = bytes_per_sec.max(1);
+        Self {
+            bytes_per_sec,
+            bucket: Mutex::new((bytes_per_sec as f64, Instant::now())),
+        }
+    }
+
+    pub fn bytes_per_sec(&self) -> u64 {
+        self.bytes_per_sec
+    }
+
+    /// Blocks until `bytes` tokens are available and takes them,
+    /// requests larger than bucket capacity are capped to it
+    pub fn request(&self, bytes: usize) {
+        let rate = self.bytes_per_sec as f64;
+        let needed = (bytes as f64).min(rate);
+        let mut bucket = self.bucket.lock().expect("rate limiter mutex poisoned");
+        loop {
+            let (tokens, last_refill) = &mut *bucket;
+            let now = Instant::now();
+            *tokens = (*tokens + now.duration_since(*last_refill).as_secs_f64() * rate).min(rate);
+            *last_refill = now;
+            if *tokens >= needed {
+                *tokens -= needed;
+                return;
+            }
+            let wait = Duration::from_secs_f64((needed - *tokens) / rate);
+            // other writers wait for the lock meanwhile, so tokens are granted in request order
+            thread::sleep(wait);
+        }
+    }
+}
+
+/// Passes writes through once limiter grants them
+pub(crate) struct Throttled<'a, W> {
+    pub inner: W,
+    pub limiter: &'a RateLimiter,
+}
+
+impl<W: io::Write> io::Write for Throttled<'_, W> {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        let len = data.len().min(self.limiter.bytes_per_sec as usize);
+        self.limiter.request(len);
+        self.inner.write_all(&data[..len])?;
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn throttles_after_burst() {
+        let limiter = RateLimiter::new(100_000);
+        let start = Instant::now();
+        limiter.request(100_000);
+        assert!(start.elapsed() < Duration::from_millis(100));
+        limiter.request(30_000);
+        limiter.request(20_000);
+        assert!(start.elapsed() >= Duration::from_millis(450));
+    }
+}
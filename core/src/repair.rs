@@ -0,0 +1,88 @@
+use crate::comparator::KeyOrder;
+use crate::sstable::SstReader;
+use crate::utils::{self, CommonBinaryFormat};
+use crate::vfs::Storage;
+use crate::wal::{UnknownRecordPolicy, WriteAheadLog, WriteAheadLogIterator};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+/// Subdirectory of working directory damaged files are moved to by `Database::repair`
+pub const LOST_DIR: &str = "lost";
+
+/// Healthy copy of the data, such as replica or backup, used to rewrite corrupted tables.
+/// Timestamps of returned records must be commit sequences of the repaired database
+pub trait RepairSource: Send + Sync {
+    /// Every retained version of keys within [low, high] including tombstones, in any order
+    fn fetch_range(&self, low: &[u8], high: &[u8]) -> crate::Result<Vec<CommonBinaryFormat>>;
+}
+
+/// Outcome of `Database::repair`
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct RepairReport {
+    /// records written into rebuilt table, versions found in several files count once
+    pub salvaged_records: usize,
+    /// damaged files at their new paths in `LOST_DIR`
+    pub quarantined: Vec<PathBuf>,
+}
+
+/// Reads every intact record of tables and wals in working directory, then removes the files:
+/// intact ones are deleted and damaged ones moved to `LOST_DIR`. Records are returned in key
+/// order, newest versions first, without duplicates
+pub(crate) fn salvage(
+    working_dir: &Path,
+    storage: &Arc<dyn Storage>,
+    order: &KeyOrder,
+) -> io::Result<(Vec<CommonBinaryFormat>, Vec<PathBuf>)> {
+    let mut records = Vec::new();
+    let mut damaged = Vec::new();
+    for path in utils::scan_storage(&**storage, working_dir, &["sst"])? {
+        let intact = match SstReader::open_with_storage(&path, storage.clone()) {
+            Ok(mut table) => {
+                table.set_key_order(order.clone())?;
+                // records are read up to the first bad one
+                for entry in table.iter().into_iter().flatten() {
+                    match entry {
+                        Ok(entry) => records.push(entry),
+                        Err(_) => break,
+                    }
+                }
+                table.verify().ok().flatten().is_none()
+            }
+            Err(_) => false,
+        };
+        if intact {
+            storage.delete(&path)?;
+        } else {
+            damaged.push(path);
+        }
+    }
+    for path in utils::scan_storage(&**storage, working_dir, &["wal"])? {
+        records.extend(WriteAheadLogIterator::new_with_storage(&path, &**storage)?.map(Into::into));
+        // torn tail is a regular leftover of crash, not damage
+        let corruption =
+            WriteAheadLog::verify_with_storage(&path, &**storage, UnknownRecordPolicy::default())?;
+        match corruption {
+            Some(corruption) if corruption.error.kind() != io::ErrorKind::UnexpectedEof => {
+                damaged.push(path)
+            }
+            _ => storage.delete(&path)?,
+        }
+    }
+    for path in utils::scan_storage(&**storage, working_dir, &["tmp"])? {
+        storage.delete(&path)?;
+    }
+    let mut quarantined = Vec::with_capacity(damaged.len());
+    if !damaged.is_empty() {
+        let lost_dir = working_dir.join(LOST_DIR);
+        storage.create_dir_all(&lost_dir)?;
+        for path in damaged {
+            let target = lost_dir.join(path.file_name().expect("listed file has name"));
+            storage.rename(&path, &target)?;
+            quarantined.push(target);
+        }
+    }
+    records.sort_by(|a, b| (order.cmp(&a.key, &b.key)).then(b.timestamp.cmp(&a.timestamp)));
+    records.dedup_by(|a, b| a.key == b.key && a.timestamp == b.timestamp);
+    Ok((records, quarantined))
+}
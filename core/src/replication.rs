@@ -0,0 +1,551 @@
+//! Primary/replica replication over tcp
+//!
+//! Replica keeps its own copy of database and tells primary the sequence it needs next.
+//! Primary streams committed writes from its wal starting there. If that part of wal
+//! is already flushed, primary sends a checkpoint of its tables first and continues
+//! with writes committed after it.
+//!
+//! Both sides carry fencing epoch of their database. Replica promoted to primary bumps
+//! the epoch, so replicas which follow it reject writes of the old primary and
+//! the old primary stops serving once a replica of the newer epoch connects to it.
+//!
+//! Messages are framed as:
+//! > tag (1 byte) | payload length (8 bytes) | payload
+
+use crate::database::{Database, DatabaseOptions, WalRecord};
+use crate::error::{DBError, Result};
+use crate::utils::{CommonBinaryFormat, CommonBinaryFormatRef};
+use crate::wal::WriteAheadLogEntry;
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+use std::ops::RangeBounds;
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+use std::{env, fs, process};
+
+const HELLO: u8 = 1;
+const RECORD: u8 = 2;
+const SNAPSHOT_FILE: u8 = 3;
+const SNAPSHOT_END: u8 = 4;
+
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Checkpoints of concurrently served snapshots get distinct directories
+static SNAPSHOT_ID: AtomicU64 = AtomicU64::new(0);
+
+/// Message of replication protocol
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Message {
+    /// replica asks for writes from this sequence on
+    Hello { next_sequence: u128, epoch: u64 },
+    /// write committed by primary of given epoch
+    Record { epoch: u64, record: WalRecord },
+    /// file of primary checkpoint, replica drops its own files before the first one
+    SnapshotFile { name: String, data: Vec<u8> },
+    /// checkpoint is complete, it contains writes up to this sequence
+    SnapshotEnd(u128),
+}
+
+impl Message {
+    fn write(&self, writer: &mut impl Write) -> io::Result<()> {
+        let mut payload = Vec::new();
+        let tag = match self {
+            Self::Hello {
+                next_sequence,
+                epoch,
+            } => {
+                payload.extend_from_slice(&next_sequence.to_le_bytes());
+                payload.extend_from_slice(&epoch.to_le_bytes());
+                HELLO
+            }
+            Self::Record { epoch, record } => {
+                payload.extend_from_slice(&epoch.to_le_bytes());
+                payload.extend_from_slice(&record.sequence.to_le_bytes());
+                payload.extend_from_slice(&(record.entries.len() as u64).to_le_bytes());
+                for entry in &record.entries {
+                    CommonBinaryFormatRef::new(entry.timestamp, &entry.key, entry.value.as_deref())
+                        .with_meta(&entry.meta)
+                        .write(&mut payload)?;
+                }
+                RECORD
+            }
+            Self::SnapshotFile { name, data } => {
+                payload.extend_from_slice(&(name.len() as u64).to_le_bytes());
+                payload.extend_from_slice(name.as_bytes());
+                payload.extend_from_slice(data);
+                SNAPSHOT_FILE
+            }
+            Self::SnapshotEnd(sequence) => {
+                payload.extend_from_slice(&sequence.to_le_bytes());
+                SNAPSHOT_END
+            }
+        };
+        writer.write_all(&[tag])?;
+        writer.write_all(&(payload.len() as u64).to_le_bytes())?;
+        writer.write_all(&payload)
+    }
+
+    /// Reads the rest of message whose tag is already read
+    fn read_after_tag(tag: u8, reader: &mut impl Read) -> io::Result<Self> {
+        let len = read_u64(reader)?;
+        let mut payload = Vec::new();
+        reader.take(len).read_to_end(&mut payload)?;
+        if payload.len() as u64 != len {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        let mut payload = payload.as_slice();
+        let message = match tag {
+            HELLO => Self::Hello {
+                next_sequence: read_u128(&mut payload)?,
+                epoch: read_u64(&mut payload)?,
+            },
+            RECORD => {
+                let epoch = read_u64(&mut payload)?;
+                let sequence = read_u128(&mut payload)?;
+                let count = read_u64(&mut payload)?;
+                let entries = (0..count)
+                    .map(|_| CommonBinaryFormat::read(&mut payload).map(WriteAheadLogEntry::from))
+                    .collect::<io::Result<_>>()?;
+                Self::Record {
+                    epoch,
+                    record: WalRecord { sequence, entries },
+                }
+            }
+            SNAPSHOT_FILE => {
+                let name_len = read_u64(&mut payload)? as usize;
+                if name_len > payload.len() {
+                    return Err(invalid_data("snapshot file name exceeds message"));
+                }
+                let (name, data) = payload.split_at(name_len);
+                let name = String::from_utf8(name.to_vec())
+                    .map_err(|_| invalid_data("snapshot file name is not utf-8"))?;
+                // names come from the network and must not leave replica directory
+                if name.contains(['/', '\\']) || name.starts_with('.') {
+                    return Err(invalid_data("snapshot file name is not a plain file name"));
+                }
+                return Ok(Self::SnapshotFile {
+                    name,
+                    data: data.to_vec(),
+                });
+            }
+            SNAPSHOT_END => Self::SnapshotEnd(read_u128(&mut payload)?),
+            tag => return Err(invalid_data(&format!("unknown message tag {tag}"))),
+        };
+        match payload.is_empty() {
+            true => Ok(message),
+            false => Err(invalid_data("trailing bytes in message")),
+        }
+    }
+
+    fn read(reader: &mut impl Read) -> io::Result<Self> {
+        let mut tag = [0];
+        reader.read_exact(&mut tag)?;
+        Self::read_after_tag(tag[0], reader)
+    }
+}
+
+fn read_u64(reader: &mut impl Read) -> io::Result<u64> {
+    let mut bytes = [0; 8];
+    reader.read_exact(&mut bytes)?;
+    Ok(u64::from_le_bytes(bytes))
+}
+
+fn read_u128(reader: &mut impl Read) -> io::Result<u128> {
+    let mut bytes = [0; 16];
+    reader.read_exact(&mut bytes)?;
+    Ok(u128::from_le_bytes(bytes))
+}
+
+fn invalid_data(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+/// Connected replica as seen by primary
+struct ReplicaConnection {
+    stream: BufWriter<TcpStream>,
+    /// sequence of the next write replica needs
+    next_sequence: u128,
+    /// epoch of replica database when it connected
+    epoch: u64,
+}
+
+/// Serves writes of database to connected replicas, `poll` has to be called periodically
+/// with the database to accept replicas and send them new writes
+pub struct Primary {
+    listener: TcpListener,
+    replicas: Vec<ReplicaConnection>,
+}
+
+impl Primary {
+    pub fn bind(addr: impl ToSocketAddrs) -> Result<Self> {
+        let listener = TcpListener::bind(addr)?;
+        listener.set_nonblocking(true)?;
+        Ok(Self {
+            listener,
+            replicas: Vec::new(),
+        })
+    }
+
+    pub fn local_addr(&self) -> Result<SocketAddr> {
+        Ok(self.listener.local_addr()?)
+    }
+
+    /// Number of connected replicas
+    pub fn replicas(&self) -> usize {
+        self.replicas.len()
+    }
+
+    /// Accepts new replicas and sends every connected one writes it hasn't received yet,
+    /// replicas whose connection fails are dropped and have to reconnect. Fails with
+    /// `StaleEpoch` if a replica has seen a newer epoch, this primary is fenced then
+    pub fn poll(&mut self, db: &mut Database) -> Result<()> {
+        loop {
+            match self.listener.accept() {
+                Ok((stream, _)) => {
+                    let Ok(replica) = Self::handshake(stream) else {
+                        continue;
+                    };
+                    if replica.epoch > db.epoch() {
+                        return Err(DBError::StaleEpoch {
+                            stale: db.epoch(),
+                            current: replica.epoch,
+                        });
+                    }
+                    self.replicas.push(replica);
+                }
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                Err(e) => return Err(e.into()),
+            }
+        }
+        let mut connected = Vec::with_capacity(self.replicas.len());
+        for mut replica in self.replicas.drain(..) {
+            match Self::send_updates(db, &mut replica) {
+                Ok(()) => connected.push(replica),
+                Err(DBError::Io { .. }) => {}
+                Err(e) => return Err(e),
+            }
+        }
+        self.replicas = connected;
+        Ok(())
+    }
+
+    fn handshake(stream: TcpStream) -> io::Result<ReplicaConnection> {
+        stream.set_nonblocking(false)?;
+        // replica sends hello right after connecting, a silent peer must not stall primary
+        stream.set_read_timeout(Some(HANDSHAKE_TIMEOUT))?;
+        let mut reader = BufReader::new(&stream);
+        let Message::Hello {
+            next_sequence,
+            epoch,
+        } = Message::read(&mut reader)?
+        else {
+            return Err(invalid_data("replica didn't start with hello"));
+        };
+        Ok(ReplicaConnection {
+            stream: BufWriter::new(stream),
+            next_sequence,
+            epoch,
+        })
+    }
+
+    fn send_updates(db: &mut Database, replica: &mut ReplicaConnection) -> Result<()> {
+        let next_sequence = replica.next_sequence;
+        let records = db.wal_tail(next_sequence)?.collect::<Result<Vec<_>>>();
+        // sequences of wal records are consecutive, writes which bypass wal such as ingestion
+        // leave gaps only a snapshot can fill
+        let records = match records {
+            Err(DBError::WalTruncated(_)) => None,
+            Err(e) => return Err(e),
+            Ok(records) => match records.first() {
+                Some(first) if first.sequence > next_sequence => None,
+                None if next_sequence <= db.last_sequence() => None,
+                _ => Some(records),
+            },
+        };
+        match records {
+            Some(records) => {
+                for record in records {
+                    let next_sequence = record.sequence + 1;
+                    let epoch = db.epoch();
+                    Message::Record { epoch, record }.write(&mut replica.stream)?;
+                    replica.next_sequence = next_sequence;
+                }
+            }
+            None => Self::send_snapshot(db, replica)?,
+        }
+        Ok(replica.stream.flush()?)
+    }
+
+    /// Sends checkpoint of database, replica continues with writes following it
+    fn send_snapshot(db: &mut Database, replica: &mut ReplicaConnection) -> Result<()> {
+        let id = SNAPSHOT_ID.fetch_add(1, Ordering::Relaxed);
+        let dir = env::temp_dir().join(format!("lsm-db-snapshot-{}-{id}", process::id()));
+        let sequence = db.last_sequence();
+        db.checkpoint(&dir)?;
+        let sent = Self::send_dir(&dir, &mut replica.stream);
+        fs::remove_dir_all(&dir)?;
+        sent?;
+        Message::SnapshotEnd(sequence).write(&mut replica.stream)?;
+        replica.next_sequence = sequence + 1;
+        Ok(())
+    }
+
+    fn send_dir(dir: &Path, stream: &mut impl Write) -> Result<()> {
+        for entry in fs::read_dir(dir)? {
+            let path = entry?.path();
+            let name = path
+                .file_name()
+                .and_then(|name| name.to_str())
+                .expect("checkpoint files have utf-8 names")
+                .to_string();
+            let data = fs::read(&path)?;
+            Message::SnapshotFile { name, data }.write(stream)?;
+        }
+        Ok(())
+    }
+}
+
+/// Read-only copy of database kept up to date by primary, `catch_up` has to be called
+/// periodically to apply writes received since the last call
+pub struct Replica {
+    db: Database,
+    options: DatabaseOptions,
+    stream: BufReader<TcpStream>,
+    /// files of snapshot being received, written into working directory at its end
+    snapshot: Option<Vec<(String, Vec<u8>)>>,
+}
+
+impl Replica {
+    /// Opens local copy of database in working directory of options and asks primary
+    /// for writes it doesn't have
+    pub fn connect(addr: impl ToSocketAddrs, options: DatabaseOptions) -> Result<Self> {
+        let db = Database::init(options.clone())?;
+        let stream = TcpStream::connect(addr)?;
+        let mut writer = &stream;
+        let hello = Message::Hello {
+            next_sequence: db.last_sequence() + 1,
+            epoch: db.epoch(),
+        };
+        hello.write(&mut writer)?;
+        Ok(Self {
+            db,
+            options,
+            stream: BufReader::new(stream),
+            snapshot: None,
+        })
+    }
+
+    /// Applies messages received so far without waiting for new ones,
+    /// returns number of applied writes. Writes of primary with epoch older than the one
+    /// seen by replica fail with `StaleEpoch`
+    pub fn catch_up(&mut self) -> Result<usize> {
+        let mut applied = 0;
+        while let Some(message) = self.try_read()? {
+            match message {
+                Message::Record { epoch, record } => {
+                    if epoch < self.db.epoch() {
+                        return Err(DBError::StaleEpoch {
+                            stale: epoch,
+                            current: self.db.epoch(),
+                        });
+                    }
+                    self.db.advance_epoch(epoch)?;
+                    if self.db.apply_replicated(record)? {
+                        applied += 1;
+                    }
+                }
+                Message::SnapshotFile { name, data } => self
+                    .snapshot
+                    .get_or_insert_with(Vec::new)
+                    .push((name, data)),
+                Message::SnapshotEnd(_) => self.install_snapshot()?,
+                Message::Hello { .. } => {
+                    return Err(DBError::InvalidData("unexpected hello from primary".into()));
+                }
+            }
+        }
+        Ok(applied)
+    }
+
+    /// The next message if its first byte has arrived, the rest of it is awaited
+    fn try_read(&mut self) -> Result<Option<Message>> {
+        let mut tag = [0];
+        if self.stream.buffer().is_empty() {
+            self.stream.get_ref().set_nonblocking(true)?;
+            let read = self.stream.read(&mut tag);
+            self.stream.get_ref().set_nonblocking(false)?;
+            match read {
+                Ok(0) => return Err(io::Error::from(io::ErrorKind::ConnectionAborted).into()),
+                Ok(_) => {}
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => return Ok(None),
+                Err(e) => return Err(e.into()),
+            }
+        } else {
+            self.stream.read_exact(&mut tag)?;
+        }
+        Ok(Some(Message::read_after_tag(tag[0], &mut self.stream)?))
+    }
+
+    /// Replaces local files with received checkpoint and reopens database
+    fn install_snapshot(&mut self) -> Result<()> {
+        let files = self.snapshot.take().unwrap_or_default();
+        let dir = self.options.working_dir.clone();
+        // local database has to release its files before they are replaced
+        let placeholder = Database::options().set_in_memory(true).init()?;
+        drop(std::mem::replace(&mut self.db, placeholder));
+        fs::remove_dir_all(&dir)?;
+        fs::create_dir_all(&dir)?;
+        for (name, data) in files {
+            fs::write(dir.join(name), data)?;
+        }
+        self.db = Database::init(self.options.clone())?;
+        Ok(())
+    }
+
+    pub fn query(&self, key: impl AsRef<[u8]>) -> Result<Option<Vec<u8>>> {
+        self.db.query(key)
+    }
+
+    pub fn scan(&self, range: impl RangeBounds<Vec<u8>>) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        self.db.scan(range)
+    }
+
+    /// Sequence of the last applied write
+    pub fn last_sequence(&self) -> u128 {
+        self.db.last_sequence()
+    }
+
+    /// Disconnects from primary and takes over as primary under a new epoch,
+    /// returned database accepts writes and can be served by `Primary`
+    pub fn promote(self) -> Result<Database> {
+        let mut db = self.db;
+        db.bump_epoch()?;
+        Ok(db)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Message, Primary, Replica};
+    use crate::{DBError, Database};
+    use std::fs;
+    use std::io::BufReader;
+    use std::net::TcpListener;
+    use std::path::PathBuf;
+    use std::thread;
+    use std::time::Duration;
+
+    #[test]
+    fn replica_catches_up_from_snapshot_and_wal() {
+        let test_dir = &PathBuf::from("./tests/replica_catches_up_from_snapshot_and_wal");
+        if test_dir.exists() {
+            fs::remove_dir_all(test_dir).unwrap();
+        }
+        let mut db = Database::options()
+            .set_working_dir(test_dir.join("primary"))
+            .init()
+            .unwrap();
+        for i in 0..10u8 {
+            db.put(vec![i], vec![i]).unwrap();
+        }
+        db.swap_memtable().unwrap();
+        db.delete_range(vec![2], vec![4]).unwrap();
+        db.put_with_meta(vec![20], vec![20], vec![1]).unwrap();
+
+        let mut primary = Primary::bind("127.0.0.1:0").unwrap();
+        let options = Database::options().set_working_dir(test_dir.join("replica"));
+        let mut replica = Replica::connect(primary.local_addr().unwrap(), options).unwrap();
+        let mut sync = |db: &mut Database, replica: &mut Replica| {
+            primary.poll(db).unwrap();
+            wait_for(db, replica);
+        };
+        sync(&mut db, &mut replica);
+        assert_eq!(replica.scan(..).unwrap(), db.scan(..).unwrap());
+
+        db.delete(vec![5]).unwrap();
+        db.put(vec![30], vec![30]).unwrap();
+        sync(&mut db, &mut replica);
+        assert_eq!(replica.query([5]).unwrap(), None);
+        assert_eq!(replica.scan(..).unwrap(), db.scan(..).unwrap());
+        assert_eq!(primary.replicas(), 1);
+    }
+
+    fn wait_for(db: &Database, replica: &mut Replica) {
+        for _ in 0..500 {
+            replica.catch_up().unwrap();
+            if replica.last_sequence() == db.last_sequence() {
+                return;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        panic!("replica didn't catch up");
+    }
+
+    #[test]
+    fn promoted_replica_fences_old_primary() {
+        let test_dir = &PathBuf::from("./tests/promoted_replica_fences_old_primary");
+        if test_dir.exists() {
+            fs::remove_dir_all(test_dir).unwrap();
+        }
+        let mut old = Database::options()
+            .set_working_dir(test_dir.join("old"))
+            .init()
+            .unwrap();
+        old.put(vec![1], vec![1]).unwrap();
+        let mut old_primary = Primary::bind("127.0.0.1:0").unwrap();
+        let options = Database::options().set_working_dir(test_dir.join("new"));
+        let mut replica = Replica::connect(old_primary.local_addr().unwrap(), options).unwrap();
+        old_primary.poll(&mut old).unwrap();
+        wait_for(&old, &mut replica);
+
+        let mut new = replica.promote().unwrap();
+        assert_eq!((old.epoch(), new.epoch()), (0, 1));
+        new.put(vec![2], vec![2]).unwrap();
+        let mut new_primary = Primary::bind("127.0.0.1:0").unwrap();
+        let options = Database::options().set_working_dir(test_dir.join("follower"));
+        let addr = new_primary.local_addr().unwrap();
+        let mut follower = Replica::connect(addr, options.clone()).unwrap();
+        new_primary.poll(&mut new).unwrap();
+        wait_for(&new, &mut follower);
+        assert_eq!(follower.query([2]).unwrap(), Some(vec![2]));
+        drop(follower);
+
+        // follower which saw the new epoch turns to the old primary
+        let follower = Replica::connect(old_primary.local_addr().unwrap(), options.clone());
+        let fenced = old_primary.poll(&mut old);
+        assert!(matches!(
+            fenced,
+            Err(DBError::StaleEpoch {
+                stale: 0,
+                current: 1
+            })
+        ));
+        drop(follower);
+
+        // stray write of the old primary sent over an existing connection
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut follower = Replica::connect(listener.local_addr().unwrap(), options).unwrap();
+        let (stream, _) = listener.accept().unwrap();
+        Message::read(&mut BufReader::new(&stream)).unwrap();
+        old.put(vec![3], vec![3]).unwrap();
+        let record = old.wal_tail(old.last_sequence()).unwrap().next().unwrap();
+        let stray = Message::Record {
+            epoch: 0,
+            record: record.unwrap(),
+        };
+        stray.write(&mut &stream).unwrap();
+        let rejected = (0..500).find_map(|_| {
+            thread::sleep(Duration::from_millis(10));
+            follower.catch_up().err()
+        });
+        assert!(matches!(
+            rejected,
+            Some(DBError::StaleEpoch {
+                stale: 0,
+                current: 1
+            })
+        ));
+        assert_eq!(follower.query([3]).unwrap(), None);
+    }
+}
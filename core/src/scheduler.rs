@@ -0,0 +1,148 @@
+use std::collections::VecDeque;
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::thread::{self, JoinHandle};
+
+type Job = Box<dyn FnOnce() + Send>;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum JobPriority {
+    /// memtable flushes, they unblock writers
+    High,
+    /// compactions
+    Low,
+}
+
+/// Bounded pool of worker threads running background jobs, queued high priority jobs
+/// are always picked before low priority ones
+pub(crate) struct JobScheduler {
+    shared: Arc<Shared>,
+    workers: Vec<JoinHandle<()>>,
+}
+
+struct Shared {
+    state: Mutex<State>,
+    /// notified when job is queued or shutdown starts
+    job_queued: Condvar,
+}
+
+#[derive(Default)]
+struct State {
+    high: VecDeque<Job>,
+    low: VecDeque<Job>,
+    shutdown: bool,
+}
+
+impl JobScheduler {
+    pub fn new(threads: usize) -> Self {
+        let shared = Arc::new(Shared {
+            state: Mutex::new(State::default()),
+            job_queued: Condvar::new(),
+        });
+        let workers = (0..threads.max(1))
+            .map(|_| {
+                let shared = shared.clone();
+                thread::spawn(move || shared.work())
+            })
+            .collect();
+        Self { shared, workers }
+    }
+
+    pub fn submit(&self, priority: JobPriority, job: impl FnOnce() + Send + 'static) {
+        let mut state = self.shared.lock();
+        match priority {
+            JobPriority::High => state.high.push_back(Box::new(job)),
+            JobPriority::Low => state.low.push_back(Box::new(job)),
+        }
+        self.shared.job_queued.notify_one();
+    }
+
+    /// Drops queued jobs and waits for running ones, returns number of cancelled jobs
+    pub fn shutdown(&mut self) -> usize {
+        let cancelled = {
+            let mut state = self.shared.lock();
+            state.shutdown = true;
+            let cancelled = state.high.len() + state.low.len();
+            state.high.clear();
+            state.low.clear();
+            cancelled
+        };
+        self.shared.job_queued.notify_all();
+        for worker in self.workers.drain(..) {
+            let _ = worker.join();
+        }
+        cancelled
+    }
+}
+
+impl Drop for JobScheduler {
+    fn drop(&mut self) {
+        self.shutdown();
+    }
+}
+
+impl Shared {
+    fn lock(&self) -> MutexGuard<'_, State> {
+        self.state.lock().expect("scheduler mutex poisoned")
+    }
+
+    fn work(&self) {
+        let mut state = self.lock();
+        loop {
+            if state.shutdown {
+                return;
+            }
+            let Some(job) = state.high.pop_front().or_else(|| state.low.pop_front()) else {
+                state = self
+                    .job_queued
+                    .wait(state)
+                    .expect("scheduler mutex poisoned");
+                continue;
+            };
+            drop(state);
+            job();
+            state = self.lock();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::mpsc;
+
+    #[test]
+    fn runs_high_priority_first_and_cancels_on_shutdown() {
+        let mut scheduler = JobScheduler::new(1);
+        let (gate_tx, gate_rx) = mpsc::channel::<()>();
+        let (order_tx, order_rx) = mpsc::channel();
+        // occupies the only worker until the rest is queued
+        scheduler.submit(JobPriority::Low, move || gate_rx.recv().unwrap());
+        for (priority, name) in [
+            (JobPriority::Low, "compaction"),
+            (JobPriority::High, "flush"),
+        ] {
+            let order_tx = order_tx.clone();
+            scheduler.submit(priority, move || order_tx.send(name).unwrap());
+        }
+        gate_tx.send(()).unwrap();
+        let order: Vec<_> = order_rx.iter().take(2).collect();
+        assert_eq!(order, vec!["flush", "compaction"]);
+
+        let (gate_tx, gate_rx) = mpsc::channel::<()>();
+        let (started_tx, started_rx) = mpsc::channel();
+        scheduler.submit(JobPriority::High, move || {
+            started_tx.send(()).unwrap();
+            gate_rx.recv().unwrap();
+        });
+        scheduler.submit(JobPriority::Low, move || order_tx.send("late").unwrap());
+        started_rx.recv().unwrap();
+        let release = thread::spawn(move || {
+            thread::sleep(std::time::Duration::from_millis(50));
+            gate_tx.send(()).unwrap();
+        });
+        // running job is awaited, queued one never runs
+        assert_eq!(scheduler.shutdown(), 1);
+        release.join().unwrap();
+        assert!(order_rx.try_recv().is_err());
+    }
+}
@@ -0,0 +1,51 @@
+use std::collections::VecDeque;
+
+/// Minimal wall clock distance between samples, in microseconds
+const SAMPLE_INTERVAL: u128 = 1_000_000;
+/// Oldest samples are dropped above this count
+const MAX_SAMPLES: usize = 65_536;
+
+/// Sparse in-memory mapping of commit sequences to wall clock time, versions are ordered
+/// by sequences alone so clock jumps can't reorder them, the time is kept only for tombstone
+/// grace and reporting. Sequences committed before database was opened map to the open time
+#[derive(Debug)]
+pub(crate) struct SequenceTimes {
+    /// sequence and time it was committed at, both increasing
+    samples: VecDeque<(u128, u128)>,
+}
+
+impl SequenceTimes {
+    pub fn new(last_sequence: u128, now: u128) -> Self {
+        Self {
+            samples: VecDeque::from([(last_sequence, now)]),
+        }
+    }
+
+    /// Records commit of `sequence` at `now`, a clock going backwards is ignored
+    pub fn record(&mut self, sequence: u128, now: u128) {
+        let (_, last_time) = self.samples.back().copied().unwrap_or_default();
+        if now < last_time.saturating_add(SAMPLE_INTERVAL) {
+            return;
+        }
+        if self.samples.len() == MAX_SAMPLES {
+            self.samples.pop_front();
+        }
+        self.samples.push_back((sequence, now));
+    }
+
+    /// The newest sequence known to be committed not later than `time`, zero if there is none
+    pub fn sequence_at(&self, time: u128) -> u128 {
+        let idx = self
+            .samples
+            .partition_point(|(_, sampled)| *sampled <= time);
+        idx.checked_sub(1).map_or(0, |idx| self.samples[idx].0)
+    }
+
+    /// Time by which `sequence` was committed, None if it's newer than the last sample
+    pub fn time_of(&self, sequence: u128) -> Option<u128> {
+        let idx = self
+            .samples
+            .partition_point(|(sampled, _)| *sampled < sequence);
+        self.samples.get(idx).map(|(_, time)| *time)
+    }
+}
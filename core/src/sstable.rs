@@ -1,18 +1,66 @@
-use std::{io, mem};
+use crate::block_cache::BlockCache;
+use crate::bloom::BloomFilter;
+use crate::comparator::KeyOrder;
+use crate::compression::{self, Compression};
+use crate::direct_io::DirectWriter;
+use crate::format::FORMAT_VERSION;
+use crate::range_del::{self, RangeTombstone};
+use crate::rate_limiter::{RateLimiter, Throttled};
+use crate::utils::{timestamp_now, CommonBinaryFormat, CommonBinaryFormatRef, Corruption};
+#[cfg(feature = "std-fs")]
+use crate::vfs::LocalStorage;
+use crate::vfs::{self, Storage, StorageReader};
+#[cfg(feature = "std-fs")]
+use memmap2::Mmap;
+use std::borrow::Cow;
+#[cfg(feature = "std-fs")]
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::ops::{Bound, Deref, Range};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::{fmt, io, mem};
 
+/// Tables are never mapped without local files, the type only keeps mapped reads compiling
+#[cfg(not(feature = "std-fs"))]
+type Mmap = Box<[u8]>;
+
+/// Sorted string table layout on disk:
+/// > metadata | lookup table | values table | bloom filter
+///
+/// Filter is the last block so it can be rebuilt while the rest of file is copied unchanged.
+/// Partitioned tables store partition index between metadata and lookup table
+/// and one bloom filter per partition in place of the filter.
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct SstMetadata {
     /// level in sst hierarchy
-    level: usize,
+    pub level: usize,
     /// offset from file start in bytes to lookup table
-    lookup_table_offset: usize,
+    pub lookup_table_offset: usize,
     /// offset from file start in bytes to values table
-    values_table_offset: usize,
-    // /// bloom filter to optimize redundant search in keys
-    // bloom_filter: ???
-    /// lowest key in table
-    low_key: Vec<u8>,
+    pub values_table_offset: usize,
+    /// offset from file start in bytes to bloom filter
+    pub filter_offset: usize,
+    /// number of records in values table
+    pub entry_count: usize,
+    /// lookup table holds every `index_interval`-th record
+    pub index_interval: usize,
+    /// lowest key in table, empty key is a valid key so emptiness of table is told by `entry_count`
+    pub low_key: Vec<u8>,
     /// highest key in table
-    high_key: Vec<u8>,
+    pub high_key: Vec<u8>,
+    /// newest timestamp among entries
+    pub max_timestamp: u128,
+    /// number of deletion records, point and range ones
+    pub tombstone_count: usize,
+    /// oldest timestamp among entries
+    pub min_timestamp: u128,
+    /// size in bytes of keys as pushed, before encoding
+    pub raw_key_size: usize,
+    /// size in bytes of values as pushed, before encoding
+    pub raw_value_size: usize,
+    /// wall clock time in microseconds table was written at
+    pub created_at: u128,
 }
 
 impl SstMetadata {
@@ -20,10 +68,19 @@ impl SstMetadata {
         writer.write_all(&self.level.to_le_bytes())?;
         writer.write_all(&self.lookup_table_offset.to_le_bytes())?;
         writer.write_all(&self.values_table_offset.to_le_bytes())?;
+        writer.write_all(&self.filter_offset.to_le_bytes())?;
+        writer.write_all(&self.entry_count.to_le_bytes())?;
+        writer.write_all(&self.index_interval.to_le_bytes())?;
         writer.write_all(&self.low_key.len().to_le_bytes())?;
         writer.write_all(&self.low_key)?;
         writer.write_all(&self.high_key.len().to_le_bytes())?;
         writer.write_all(&self.high_key)?;
+        writer.write_all(&self.max_timestamp.to_le_bytes())?;
+        writer.write_all(&self.tombstone_count.to_le_bytes())?;
+        writer.write_all(&self.min_timestamp.to_le_bytes())?;
+        writer.write_all(&self.raw_key_size.to_le_bytes())?;
+        writer.write_all(&self.raw_value_size.to_le_bytes())?;
+        writer.write_all(&self.created_at.to_le_bytes())?;
         Ok(())
     }
 
@@ -38,6 +95,15 @@ impl SstMetadata {
         reader.read_exact(&mut usize_buf)?;
         let values_table_offset = usize::from_le_bytes(usize_buf);
 
+        reader.read_exact(&mut usize_buf)?;
+        let filter_offset = usize::from_le_bytes(usize_buf);
+
+        reader.read_exact(&mut usize_buf)?;
+        let entry_count = usize::from_le_bytes(usize_buf);
+
+        reader.read_exact(&mut usize_buf)?;
+        let index_interval = usize::from_le_bytes(usize_buf);
+
         reader.read_exact(&mut usize_buf)?;
         let low_key_size = usize::from_le_bytes(usize_buf);
         let mut low_key = vec![0; low_key_size];
@@ -48,22 +114,2056 @@ impl SstMetadata {
         let mut high_key = vec![0; high_key_size];
         reader.read_exact(&mut high_key)?;
 
+        let mut u128_buf = [0; mem::size_of::<u128>()];
+        reader.read_exact(&mut u128_buf)?;
+        let max_timestamp = u128::from_le_bytes(u128_buf);
+
+        reader.read_exact(&mut usize_buf)?;
+        let tombstone_count = usize::from_le_bytes(usize_buf);
+
+        reader.read_exact(&mut u128_buf)?;
+        let min_timestamp = u128::from_le_bytes(u128_buf);
+
+        reader.read_exact(&mut usize_buf)?;
+        let raw_key_size = usize::from_le_bytes(usize_buf);
+
+        reader.read_exact(&mut usize_buf)?;
+        let raw_value_size = usize::from_le_bytes(usize_buf);
+
+        reader.read_exact(&mut u128_buf)?;
+        let created_at = u128::from_le_bytes(u128_buf);
+
         let meta = Self {
             level,
             lookup_table_offset,
             values_table_offset,
+            filter_offset,
+            entry_count,
+            index_interval,
             low_key,
             high_key,
+            max_timestamp,
+            tombstone_count,
+            min_timestamp,
+            raw_key_size,
+            raw_value_size,
+            created_at,
         };
         Ok(meta)
     }
+
+    /// size in bytes of serialized metadata
+    pub fn encoded_size(&self) -> usize {
+        11 * mem::size_of::<usize>()
+            + self.low_key.len()
+            + self.high_key.len()
+            + 3 * mem::size_of::<u128>()
+    }
 }
 
+/// > entries count | (key size | key | value offset)*
+///
+/// Index is sparse, it points at every `index_interval`-th record so lookups scan at most
+/// one interval of records. Records are sorted by key, versions of the same key go from newest to oldest.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
 pub struct SstLookupTable {
-    // sorted vec of entries (key -> value offset)
+    // sorted vec of entries (key -> value offset from file start)
+    pub entries: Vec<(Vec<u8>, usize)>,
+}
+
+impl SstLookupTable {
+    pub fn write(&self, mut writer: impl io::Write) -> io::Result<()> {
+        writer.write_all(&self.entries.len().to_le_bytes())?;
+        for (key, offset) in self.entries.iter() {
+            writer.write_all(&key.len().to_le_bytes())?;
+            writer.write_all(key)?;
+            writer.write_all(&offset.to_le_bytes())?;
+        }
+        Ok(())
+    }
+
+    pub fn read(mut reader: impl io::Read) -> io::Result<Self> {
+        let mut usize_buf = [0; mem::size_of::<usize>()];
+        reader.read_exact(&mut usize_buf)?;
+        let count = usize::from_le_bytes(usize_buf);
+        let entries = Self::read_entries(reader, count)?;
+        Ok(Self { entries })
+    }
+
+    /// Reads `count` entries without the leading count
+    fn read_entries(mut reader: impl io::Read, count: usize) -> io::Result<Vec<(Vec<u8>, usize)>> {
+        let mut usize_buf = [0; mem::size_of::<usize>()];
+        let mut entries = Vec::new();
+        for _ in 0..count {
+            reader.read_exact(&mut usize_buf)?;
+            let mut key = vec![0; usize::from_le_bytes(usize_buf)];
+            reader.read_exact(&mut key)?;
+            reader.read_exact(&mut usize_buf)?;
+            entries.push((key, usize::from_le_bytes(usize_buf)));
+        }
+        Ok(entries)
+    }
+
+    /// size in bytes of serialized lookup table
+    pub fn encoded_size(&self) -> usize {
+        mem::size_of::<usize>() + self.entries.iter().map(Self::entry_size).sum::<usize>()
+    }
+
+    fn entry_size((key, _): &(Vec<u8>, usize)) -> usize {
+        2 * mem::size_of::<usize>() + key.len()
+    }
+
+    /// Index of entry starting the interval where scan for the first version of key begins,
+    /// versions of key might start in the interval before the first entry with that key
+    pub(crate) fn interval_start(&self, order: &KeyOrder, key: impl AsRef<[u8]>) -> usize {
+        let key = key.as_ref();
+        self.entries
+            .partition_point(|(k, _)| order.lt(k, key))
+            .saturating_sub(1)
+    }
+}
+
+/// > partition entries | bits per key | partitions count |
+/// > (key size | key | data offset | lookup offset | filter offset | filter size)*
+///
+/// Top-level index of partitioned table, every partition covers `partition_entries` lookup
+/// table entries and has its own bloom filter. Only this index is kept in memory, lookup entries
+/// and filters of partitions are read on demand through block cache.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct PartitionIndex {
+    pub partition_entries: usize,
+    /// parameter partition filters were built with
+    pub bits_per_key: usize,
+    pub partitions: Vec<PartitionHandle>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct PartitionHandle {
+    /// first key of partition
+    pub key: Vec<u8>,
+    /// offset from file start in bytes to the first record of partition
+    pub data_offset: usize,
+    /// offset from file start in bytes to the first lookup table entry of partition
+    pub lookup_offset: usize,
+    /// offset from file start in bytes to filter of partition
+    pub filter_offset: usize,
+    pub filter_size: usize,
+}
+
+impl PartitionIndex {
+    pub fn write(&self, mut writer: impl io::Write) -> io::Result<()> {
+        writer.write_all(&self.partition_entries.to_le_bytes())?;
+        writer.write_all(&self.bits_per_key.to_le_bytes())?;
+        writer.write_all(&self.partitions.len().to_le_bytes())?;
+        for partition in self.partitions.iter() {
+            writer.write_all(&partition.key.len().to_le_bytes())?;
+            writer.write_all(&partition.key)?;
+            writer.write_all(&partition.data_offset.to_le_bytes())?;
+            writer.write_all(&partition.lookup_offset.to_le_bytes())?;
+            writer.write_all(&partition.filter_offset.to_le_bytes())?;
+            writer.write_all(&partition.filter_size.to_le_bytes())?;
+        }
+        Ok(())
+    }
+
+    pub fn read(mut reader: impl io::Read) -> io::Result<Self> {
+        let partition_entries = read_usize(&mut reader)?;
+        let bits_per_key = read_usize(&mut reader)?;
+        let count = read_usize(&mut reader)?;
+        if partition_entries == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "partition index with empty partitions",
+            ));
+        }
+        let mut partitions = Vec::new();
+        for _ in 0..count {
+            let key_size = read_usize(&mut reader)?;
+            let mut key = Vec::new();
+            (&mut reader).take(key_size as u64).read_to_end(&mut key)?;
+            if key.len() != key_size {
+                return Err(io::ErrorKind::UnexpectedEof.into());
+            }
+            partitions.push(PartitionHandle {
+                key,
+                data_offset: read_usize(&mut reader)?,
+                lookup_offset: read_usize(&mut reader)?,
+                filter_offset: read_usize(&mut reader)?,
+                filter_size: read_usize(&mut reader)?,
+            });
+        }
+        Ok(Self {
+            partition_entries,
+            bits_per_key,
+            partitions,
+        })
+    }
+
+    /// size in bytes of serialized partition index
+    pub fn encoded_size(&self) -> usize {
+        3 * mem::size_of::<usize>()
+            + self
+                .partitions
+                .iter()
+                .map(|partition| 5 * mem::size_of::<usize>() + partition.key.len())
+                .sum::<usize>()
+    }
+
+    /// Fills in absolute offsets of partitions laid out by `metadata`
+    fn locate(
+        &mut self,
+        metadata: &SstMetadata,
+        lookup_table: &SstLookupTable,
+        filters: &[BloomFilter],
+    ) -> io::Result<()> {
+        if filters.len() != self.partitions.len() {
+            return Err(io::Error::other("partition filters don't match partitions"));
+        }
+        // count of lookup table entries precedes them
+        let mut lookup_offset = metadata.lookup_table_offset + mem::size_of::<usize>();
+        let mut filter_offset = metadata.filter_offset;
+        let chunks = lookup_table.entries.chunks(self.partition_entries);
+        for ((partition, filter), entries) in self.partitions.iter_mut().zip(filters).zip(chunks) {
+            partition.data_offset += metadata.values_table_offset;
+            partition.lookup_offset = lookup_offset;
+            partition.filter_offset = filter_offset;
+            partition.filter_size = filter.encoded_size();
+            lookup_offset += entries
+                .iter()
+                .map(SstLookupTable::entry_size)
+                .sum::<usize>();
+            filter_offset += partition.filter_size;
+        }
+        Ok(())
+    }
+
+    /// Corruption if partitions don't cover lookup table of table the way it was written
+    fn check(&self, metadata: &SstMetadata, lookup_table: &SstLookupTable) -> Option<Corruption> {
+        let chunks = lookup_table.entries.chunks(self.partition_entries);
+        if chunks.len() != self.partitions.len() {
+            let message = "partition count doesn't match lookup table";
+            return Some(Corruption::new(0, message));
+        }
+        let mut lookup_offset = metadata.lookup_table_offset + mem::size_of::<usize>();
+        for (partition, entries) in self.partitions.iter().zip(chunks) {
+            if partition.key != entries[0].0
+                || partition.data_offset != entries[0].1
+                || partition.lookup_offset != lookup_offset
+            {
+                let message = "partition index doesn't match lookup table";
+                return Some(Corruption::new(lookup_offset as u64, message));
+            }
+            lookup_offset += entries
+                .iter()
+                .map(SstLookupTable::entry_size)
+                .sum::<usize>();
+        }
+        None
+    }
+
+    /// Partition where scan for the first version of key begins, as in `interval_start`
+    fn scan_start(&self, order: &KeyOrder, key: &[u8]) -> usize {
+        self.partitions
+            .partition_point(|partition| order.lt(&partition.key, key))
+            .saturating_sub(1)
+    }
+}
+
+fn read_usize(mut reader: impl io::Read) -> io::Result<usize> {
+    let mut usize_buf = [0; mem::size_of::<usize>()];
+    reader.read_exact(&mut usize_buf)?;
+    Ok(usize::from_le_bytes(usize_buf))
+}
+
+/// Lookup entries and filter of one partition as loaded from file
+#[derive(Debug)]
+pub(crate) struct IndexPartition {
     entries: Vec<(Vec<u8>, usize)>,
+    filter: BloomFilter,
 }
 
-pub struct SstValuesTable {
-    entries: Vec<Vec<u8>>,
+pub const DEFAULT_BLOOM_BITS_PER_KEY: usize = 10;
+pub const DEFAULT_INDEX_INTERVAL: usize = 16;
+pub const DEFAULT_TWO_LEVEL_INDEX_THRESHOLD: usize = 4 << 20;
+/// approximate size in bytes of partitions of table switched to two-level index
+const TWO_LEVEL_PARTITION_SIZE: usize = 4 << 10;
+
+/// Accumulates sorted entries and writes them as a single sst file
+pub struct SstWriter {
+    level: usize,
+    bloom_bits_per_key: usize,
+    index_interval: usize,
+    /// write file bypassing page cache
+    direct_io: bool,
+    /// throttles writing of file
+    rate_limiter: Option<Arc<RateLimiter>>,
+    /// lookup table entries per partition, zero writes a single index and filter
+    partition_entries: usize,
+    /// size in bytes of lookup table above which table is partitioned anyway
+    two_level_threshold: usize,
+    compression: Compression,
+    /// cache of partitions handed to the reader of written table
+    block_cache: Option<Arc<BlockCache>>,
+    /// where table is written unless direct io is used
+    storage: Arc<dyn Storage>,
+    order: KeyOrder,
+    /// key and offset relative to values start of every pushed record
+    records: Vec<(Vec<u8>, usize)>,
+    min_timestamp: u128,
+    max_timestamp: u128,
+    tombstone_count: usize,
+    raw_key_size: usize,
+    raw_value_size: usize,
+    created_at: u128,
+    /// tombstones of pushed range tombstone records
+    range_tombstones: Vec<RangeTombstone>,
+    /// serialized entries
+    values: Vec<u8>,
+}
+
+impl SstWriter {
+    pub fn new(level: usize) -> Self {
+        Self {
+            level,
+            bloom_bits_per_key: DEFAULT_BLOOM_BITS_PER_KEY,
+            index_interval: DEFAULT_INDEX_INTERVAL,
+            direct_io: false,
+            rate_limiter: None,
+            partition_entries: 0,
+            two_level_threshold: DEFAULT_TWO_LEVEL_INDEX_THRESHOLD,
+            compression: Compression::None,
+            block_cache: None,
+            storage: vfs::default_storage(),
+            order: KeyOrder::default(),
+            records: Vec::new(),
+            min_timestamp: 0,
+            max_timestamp: 0,
+            tombstone_count: 0,
+            raw_key_size: 0,
+            raw_value_size: 0,
+            created_at: timestamp_now(),
+            range_tombstones: Vec::new(),
+            values: Vec::new(),
+        }
+    }
+
+    /// Zero disables bloom filter of table
+    pub fn set_bloom_bits_per_key(mut self, bits_per_key: usize) -> Self {
+        self.bloom_bits_per_key = bits_per_key;
+        self
+    }
+
+    /// One lookup table entry per `interval` records, one makes index dense
+    pub fn set_index_interval(mut self, interval: usize) -> Self {
+        self.index_interval = interval.max(1);
+        self
+    }
+
+    /// Falls back to regular writes where direct io is unsupported
+    pub fn set_direct_io(mut self, enabled: bool) -> Self {
+        self.direct_io = enabled;
+        self
+    }
+
+    pub fn set_rate_limiter(mut self, rate_limiter: Option<Arc<RateLimiter>>) -> Self {
+        self.rate_limiter = rate_limiter;
+        self
+    }
+
+    /// Splits lookup table and bloom filter into partitions of `entries` lookup table entries,
+    /// only the top-level partition index of such table is kept in memory, zero disables
+    pub fn set_partition_entries(mut self, entries: usize) -> Self {
+        self.partition_entries = entries;
+        self
+    }
+
+    /// Table whose flat lookup table would exceed `bytes` is written with two-level index,
+    /// its partitions hold about 4 KiB of lookup entries each. Zero keeps index flat
+    pub fn set_two_level_index_threshold(mut self, bytes: usize) -> Self {
+        self.two_level_threshold = bytes;
+        self
+    }
+
+    pub fn set_block_cache(mut self, block_cache: Option<Arc<BlockCache>>) -> Self {
+        self.block_cache = block_cache;
+        self
+    }
+
+    /// Values are compressed one by one, those that don't shrink are stored as is
+    pub fn set_compression(mut self, compression: Compression) -> Self {
+        self.compression = compression;
+        self
+    }
+
+    /// Direct io bypasses storage and writes local file
+    pub fn set_storage(mut self, storage: Arc<dyn Storage>) -> Self {
+        self.storage = storage;
+        self
+    }
+
+    pub(crate) fn set_key_order(mut self, order: KeyOrder) -> Self {
+        self.order = order;
+        self
+    }
+
+    /// Creation time stored in metadata, time of writer construction by default
+    pub fn set_created_at(mut self, micros: u128) -> Self {
+        self.created_at = micros;
+        self
+    }
+
+    /// Empty writer with the same settings for the next table of output split into several
+    pub fn next_table(&self) -> Self {
+        Self {
+            bloom_bits_per_key: self.bloom_bits_per_key,
+            index_interval: self.index_interval,
+            direct_io: self.direct_io,
+            rate_limiter: self.rate_limiter.clone(),
+            partition_entries: self.partition_entries,
+            two_level_threshold: self.two_level_threshold,
+            compression: self.compression,
+            block_cache: self.block_cache.clone(),
+            storage: self.storage.clone(),
+            order: self.order.clone(),
+            created_at: self.created_at,
+            ..Self::new(self.level)
+        }
+    }
+
+    /// Entries must be pushed in increasing key order, versions of the same key from newest to oldest,
+    /// entry with key lower than the previous one is rejected before it corrupts table
+    pub fn push(&mut self, entry: CommonBinaryFormatRef) -> io::Result<()> {
+        if let Some((last_key, _)) = self
+            .records
+            .last()
+            .filter(|(last, _)| self.order.lt(entry.key, last))
+        {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "key {} pushed after {}",
+                    entry.key.escape_ascii(),
+                    last_key.escape_ascii()
+                ),
+            ));
+        }
+        self.min_timestamp = match self.records.is_empty() {
+            true => entry.timestamp,
+            false => self.min_timestamp.min(entry.timestamp),
+        };
+        self.max_timestamp = self.max_timestamp.max(entry.timestamp);
+        self.raw_key_size += entry.key.len();
+        self.raw_value_size += entry.value.map_or(0, <[u8]>::len);
+        if entry.value.is_none() {
+            self.tombstone_count += 1;
+        } else if let Some(tombstone) = RangeTombstone::from_record(entry.timestamp, entry.key) {
+            self.tombstone_count += 1;
+            self.range_tombstones.push(tombstone);
+        }
+        self.records.push((entry.key.to_vec(), self.values.len()));
+        let compressed = match entry.value {
+            Some(value) => self.compression.compress(value)?,
+            None => None,
+        };
+        match &compressed {
+            Some(value) => CommonBinaryFormatRef {
+                value: Some(value),
+                ..entry
+            }
+            .with_compressed_value()
+            .write(&mut self.values),
+            None => entry.write(&mut self.values),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.records.is_empty()
+    }
+
+    /// size in bytes of pushed records
+    pub fn data_size(&self) -> usize {
+        self.values.len()
+    }
+
+    pub fn finish(self, path: impl AsRef<Path>) -> io::Result<SstReader> {
+        let path = path.as_ref().to_path_buf();
+        let mut lookup_table = SstLookupTable {
+            entries: self
+                .records
+                .iter()
+                .step_by(self.index_interval)
+                .cloned()
+                .collect(),
+        };
+        let mut metadata = SstMetadata {
+            level: self.level,
+            lookup_table_offset: 0,
+            values_table_offset: 0,
+            filter_offset: 0,
+            entry_count: self.records.len(),
+            index_interval: self.index_interval,
+            low_key: self
+                .records
+                .first()
+                .map(|(key, _)| key.clone())
+                .unwrap_or_default(),
+            high_key: self
+                .records
+                .last()
+                .map(|(key, _)| key.clone())
+                .unwrap_or_default(),
+            max_timestamp: self.max_timestamp,
+            tombstone_count: self.tombstone_count,
+            min_timestamp: self.min_timestamp,
+            raw_key_size: self.raw_key_size,
+            raw_value_size: self.raw_value_size,
+            created_at: self.created_at,
+        };
+        let mut partitions = self.partition_index(&lookup_table);
+        let index_size = partitions.as_ref().map_or(0, PartitionIndex::encoded_size);
+        metadata.lookup_table_offset = metadata.encoded_size() + index_size;
+        metadata.values_table_offset = metadata.lookup_table_offset + lookup_table.encoded_size();
+        metadata.filter_offset = metadata.values_table_offset + self.values.len();
+        for (_, offset) in lookup_table.entries.iter_mut() {
+            *offset += metadata.values_table_offset;
+        }
+        let build_filter = |records: &[(Vec<u8>, usize)]| {
+            BloomFilter::build(
+                records.iter().map(|(key, _)| key.as_slice()),
+                self.bloom_bits_per_key,
+            )
+        };
+        let (filter, partition_filters) = match &mut partitions {
+            Some(index) => {
+                let records_per_partition = index.partition_entries * self.index_interval;
+                let filters: Vec<_> = self
+                    .records
+                    .chunks(records_per_partition)
+                    .map(build_filter)
+                    .collect();
+                index.locate(&metadata, &lookup_table, &filters)?;
+                (BloomFilter::default(), filters)
+            }
+            None => (build_filter(&self.records), Vec::new()),
+        };
+
+        let write_table = |writer: &mut dyn io::Write| -> io::Result<()> {
+            let mut throttled;
+            let writer: &mut dyn io::Write = match &self.rate_limiter {
+                Some(limiter) => {
+                    throttled = Throttled {
+                        inner: writer,
+                        limiter,
+                    };
+                    &mut throttled
+                }
+                None => writer,
+            };
+            metadata.write(&mut *writer)?;
+            if let Some(index) = &partitions {
+                index.write(&mut *writer)?;
+            }
+            lookup_table.write(&mut *writer)?;
+            writer.write_all(&self.values)?;
+            if partitions.is_some() {
+                for filter in partition_filters.iter() {
+                    filter.write(&mut *writer)?;
+                }
+            } else {
+                filter.write(&mut *writer)?;
+            }
+            writer.flush()
+        };
+        // table is written under temp name and renamed once complete, so neither readers
+        // nor recovery see partially written table
+        let temp_path = temp_path(&path);
+        let direct = if self.direct_io {
+            DirectWriter::create_new(&temp_path)?
+        } else {
+            None
+        };
+        if let Some(mut writer) = direct {
+            write_table(&mut writer)?;
+            writer.finish()?;
+        } else {
+            let mut writer = BufWriter::new(self.storage.create_new(&temp_path)?);
+            write_table(&mut writer)?;
+            writer
+                .into_inner()
+                .map_err(|err| err.into_error())?
+                .sync_data()?;
+        }
+        if self.storage.exists(&path) {
+            return Err(io::ErrorKind::AlreadyExists.into());
+        }
+        self.storage.rename(&temp_path, &path)?;
+        if let Some(dir) = path.parent() {
+            self.storage.sync_dir(dir)?;
+        }
+
+        if partitions.is_some() {
+            // partitions are read back on demand
+            lookup_table = SstLookupTable::default();
+        }
+        Ok(SstReader {
+            path,
+            metadata,
+            lookup_table,
+            filter,
+            partitions: partitions.map(Arc::new),
+            block_cache: self.block_cache,
+            storage: self.storage,
+            mapping: None,
+            range_tombstones: Arc::new(self.range_tombstones),
+            order: self.order,
+        })
+    }
+
+    /// Index with partition keys and data offsets relative to values start, None if
+    /// partitioning is disabled. Other offsets are filled in by `PartitionIndex::locate`
+    fn partition_index(&self, lookup_table: &SstLookupTable) -> Option<PartitionIndex> {
+        let partition_entries = match self.partition_entries {
+            0 => self.two_level_partition_entries(lookup_table)?,
+            entries => entries,
+        };
+        let partitions = lookup_table
+            .entries
+            .chunks(partition_entries)
+            .map(|chunk| PartitionHandle {
+                key: chunk[0].0.clone(),
+                data_offset: chunk[0].1,
+                ..Default::default()
+            })
+            .collect();
+        Some(PartitionIndex {
+            partition_entries,
+            bits_per_key: self.bloom_bits_per_key,
+            partitions,
+        })
+    }
+
+    /// Lookup table entries per partition of table whose lookup table is over the threshold
+    fn two_level_partition_entries(&self, lookup_table: &SstLookupTable) -> Option<usize> {
+        let size = lookup_table.encoded_size();
+        if self.two_level_threshold == 0 || size <= self.two_level_threshold {
+            return None;
+        }
+        let entry_size = size / lookup_table.entries.len().max(1);
+        Some((TWO_LEVEL_PARTITION_SIZE / entry_size).max(1))
+    }
+}
+
+/// Builds sst from externally produced data for bulk loading with `Database::ingest_sst`,
+/// entries get their sequence when table is ingested
+pub struct SstBuilder {
+    writer: SstWriter,
+    last_key: Option<Vec<u8>>,
+}
+
+impl SstBuilder {
+    pub fn new() -> Self {
+        Self {
+            writer: SstWriter::new(0),
+            last_key: None,
+        }
+    }
+
+    /// Keys must be added in strictly increasing order
+    pub fn put(&mut self, key: &[u8], value: &[u8]) -> io::Result<()> {
+        self.add(key, Some(value))
+    }
+
+    pub fn delete(&mut self, key: &[u8]) -> io::Result<()> {
+        self.add(key, None)
+    }
+
+    fn add(&mut self, key: &[u8], value: Option<&[u8]>) -> io::Result<()> {
+        if self.last_key.as_deref().is_some_and(|last| last >= key) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "keys must be added in strictly increasing order",
+            ));
+        }
+        self.last_key = Some(key.to_vec());
+        self.writer.push(CommonBinaryFormatRef::new(0, key, value))
+    }
+
+    pub fn finish(self, path: impl AsRef<Path>) -> io::Result<SstReader> {
+        self.writer.finish(path)
+    }
+}
+
+impl Default for SstBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Handle to sst file on disk, keeps metadata, sparse lookup table and bloom filter in memory,
+/// partitioned table keeps only partition index and leaves lookup table and filter empty
+#[derive(Debug, Clone)]
+pub struct SstReader {
+    pub path: PathBuf,
+    pub metadata: SstMetadata,
+    pub lookup_table: SstLookupTable,
+    pub filter: BloomFilter,
+    pub partitions: Option<Arc<PartitionIndex>>,
+    /// caches partitions of partitioned table, they are read from file on every lookup if absent
+    block_cache: Option<Arc<BlockCache>>,
+    /// holds table file, rewrites of level and filter in place work with local files only
+    storage: Arc<dyn Storage>,
+    /// whole file mapped into memory, reads go through file seeks if absent
+    mapping: Option<Arc<Mmap>>,
+    /// tombstones of range tombstone records in table
+    range_tombstones: Arc<Vec<RangeTombstone>>,
+    /// order table was written in, bytewise until database sets its own
+    order: KeyOrder,
+}
+
+/// Slice of memory-mapped table, cheap to clone and stays valid after table is dropped or deleted.
+/// Compressed values can't be borrowed from mapping and are held decompressed instead
+#[derive(Clone)]
+pub struct MappedBytes {
+    backing: Backing,
+    range: Range<usize>,
+}
+
+#[derive(Clone)]
+enum Backing {
+    Mapping(Arc<Mmap>),
+    Decompressed(Arc<[u8]>),
+}
+
+impl MappedBytes {
+    /// Range of `sub` within mapping, `sub` must be borrowed from it
+    fn from_subslice(mapping: &Arc<Mmap>, sub: &[u8]) -> Self {
+        let start = sub.as_ptr() as usize - mapping.as_ptr() as usize;
+        Self {
+            backing: Backing::Mapping(mapping.clone()),
+            range: start..start + sub.len(),
+        }
+    }
+
+    fn decompressed(value: Vec<u8>) -> Self {
+        Self {
+            range: 0..value.len(),
+            backing: Backing::Decompressed(value.into()),
+        }
+    }
+
+    /// Whether bytes point into mapping rather than being copied out of it
+    pub fn is_mapped(&self) -> bool {
+        matches!(self.backing, Backing::Mapping(_))
+    }
+}
+
+impl Deref for MappedBytes {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        match &self.backing {
+            Backing::Mapping(mapping) => &mapping[self.range.clone()],
+            Backing::Decompressed(value) => &value[self.range.clone()],
+        }
+    }
+}
+
+impl AsRef<[u8]> for MappedBytes {
+    fn as_ref(&self) -> &[u8] {
+        self
+    }
+}
+
+impl fmt::Debug for MappedBytes {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.deref().escape_ascii())
+    }
+}
+
+/// Record of memory-mapped table returned without copying key and value
+#[derive(Debug, Clone)]
+pub struct MappedEntry {
+    pub timestamp: u128,
+    pub key: MappedBytes,
+    /// None if corresponds to delete
+    pub value: Option<MappedBytes>,
+}
+
+impl SstReader {
+    #[cfg(feature = "std-fs")]
+    pub fn open(path: impl AsRef<Path>) -> io::Result<Self> {
+        Self::open_with_storage(path, Arc::new(LocalStorage))
+    }
+
+    pub fn open_with_storage(
+        path: impl AsRef<Path>,
+        storage: Arc<dyn Storage>,
+    ) -> io::Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        let mut reader = BufReader::new(StorageReader::open_at(&*storage, &path, 0)?);
+        let metadata = SstMetadata::read(&mut reader)?;
+        // partition index is the only block between metadata and lookup table
+        if metadata.encoded_size() < metadata.lookup_table_offset {
+            let partitions = PartitionIndex::read(&mut reader)?;
+            return Self {
+                path,
+                metadata,
+                lookup_table: SstLookupTable::default(),
+                filter: BloomFilter::default(),
+                partitions: Some(Arc::new(partitions)),
+                block_cache: None,
+                storage,
+                mapping: None,
+                range_tombstones: Arc::default(),
+                order: KeyOrder::default(),
+            }
+            .with_range_tombstones();
+        }
+        reader.seek(SeekFrom::Start(metadata.lookup_table_offset as u64))?;
+        let lookup_table = SstLookupTable::read(&mut reader)?;
+        reader.seek(SeekFrom::Start(metadata.filter_offset as u64))?;
+        // filter is only an optimization, one lost by interrupted rebuild just disables filtering
+        let filter = BloomFilter::read(&mut reader).unwrap_or_default();
+        Self {
+            path,
+            metadata,
+            lookup_table,
+            filter,
+            partitions: None,
+            block_cache: None,
+            storage,
+            mapping: None,
+            range_tombstones: Arc::default(),
+            order: KeyOrder::default(),
+        }
+        .with_range_tombstones()
+    }
+
+    /// Loads range tombstones, their records sort after user keys at the end of table
+    fn with_range_tombstones(mut self) -> io::Result<Self> {
+        let start = range_del::records_start();
+        if self.is_empty() || self.order.lt(&self.metadata.high_key, &start) {
+            return Ok(self);
+        }
+        let mut tombstones = Vec::new();
+        for entry in self.iter_from(&start)? {
+            let entry = entry?;
+            if !entry.key.starts_with(&start) {
+                break;
+            }
+            if entry.value.is_some() {
+                tombstones.extend(RangeTombstone::from_record(entry.timestamp, &entry.key));
+            }
+        }
+        self.range_tombstones = Arc::new(tombstones);
+        Ok(self)
+    }
+
+    pub fn range_tombstones(&self) -> &[RangeTombstone] {
+        &self.range_tombstones
+    }
+
+    /// Partitions of partitioned table are read through `block_cache`, several tables
+    /// and databases may share one cache
+    pub fn set_block_cache(&mut self, block_cache: Option<Arc<BlockCache>>) {
+        self.block_cache = block_cache;
+    }
+
+    /// Order of database table belongs to, range tombstones are reloaded under it
+    pub(crate) fn set_key_order(&mut self, order: KeyOrder) -> io::Result<()> {
+        if self.order != order {
+            self.order = order;
+            let table = self.clone().with_range_tombstones()?;
+            self.range_tombstones = table.range_tombstones;
+        }
+        Ok(())
+    }
+
+    pub fn is_partitioned(&self) -> bool {
+        self.partitions.is_some()
+    }
+
+    /// Whether lookups of table are filtered, by single or partitioned filter
+    pub fn has_filter(&self) -> bool {
+        match &self.partitions {
+            Some(index) => index.bits_per_key > 0,
+            None => self.filter.is_enabled(),
+        }
+    }
+
+    pub fn filter_bits_per_key(&self) -> usize {
+        self.partitions
+            .as_ref()
+            .map_or(self.filter.bits_per_key, |index| index.bits_per_key)
+    }
+
+    /// False means key is definitely absent, partitioned table checks filter of partition
+    /// whose key range covers key
+    pub fn filter_may_contain(&self, key: &[u8]) -> bool {
+        let Some(index) = &self.partitions else {
+            return self.filter.may_contain(key);
+        };
+        let covering = index
+            .partitions
+            .partition_point(|partition| self.order.le(&partition.key, key));
+        let Some(covering) = covering.checked_sub(1) else {
+            return false;
+        };
+        // versions of key span partitions only when one of them starts with key
+        if index.partitions[covering].key == key {
+            return true;
+        }
+        // unreadable partition can't rule key out
+        self.partition(index, covering)
+            .map_or(true, |partition| partition.filter.may_contain(key))
+    }
+
+    /// Loads partitions of partitioned table into block cache while it has free space, returns
+    /// number of loaded partitions and whether cache filled up. Index and filter of other tables
+    /// are always in memory
+    pub(crate) fn warm_cache(&self) -> io::Result<(usize, bool)> {
+        let (Some(index), Some(cache)) = (&self.partitions, &self.block_cache) else {
+            return Ok((0, false));
+        };
+        let mut loaded = 0;
+        for idx in 0..index.partitions.len() {
+            if cache.contains(&self.path, idx) {
+                continue;
+            }
+            if !cache.preload(&self.path, idx, || self.load_partition(index, idx))? {
+                return Ok((loaded, true));
+            }
+            loaded += 1;
+        }
+        Ok((loaded, false))
+    }
+
+    /// Lookup entries and filter of partition, read through block cache if table has one
+    fn partition(&self, index: &PartitionIndex, idx: usize) -> io::Result<Arc<IndexPartition>> {
+        let load = || self.load_partition(index, idx);
+        match &self.block_cache {
+            Some(cache) => cache.get_or_load(&self.path, idx, load),
+            None => load().map(|(partition, _)| Arc::new(partition)),
+        }
+    }
+
+    /// Reads partition and its size in memory
+    fn load_partition(
+        &self,
+        index: &PartitionIndex,
+        idx: usize,
+    ) -> io::Result<(IndexPartition, usize)> {
+        let handle = &index.partitions[idx];
+        let total = self.len().div_ceil(self.metadata.index_interval.max(1));
+        let count = index
+            .partition_entries
+            .min(total.saturating_sub(idx * index.partition_entries));
+        let entries = SstLookupTable::read_entries(self.source_at(handle.lookup_offset)?, count)?;
+        // filter left inconsistent with index by interrupted rebuild just disables filtering
+        let filter = BloomFilter::read(self.source_at(handle.filter_offset)?)
+            .ok()
+            .filter(|filter| {
+                filter.bits_per_key == index.bits_per_key
+                    && filter.encoded_size() == handle.filter_size
+            })
+            .unwrap_or_default();
+        let size = entries
+            .iter()
+            .map(SstLookupTable::entry_size)
+            .sum::<usize>()
+            + filter.size();
+        Ok((IndexPartition { entries, filter }, size))
+    }
+
+    /// Lookup table entry where scan for the first version of key begins and offset of its record
+    fn interval_of(&self, key: &[u8]) -> io::Result<(usize, usize)> {
+        let Some(index) = &self.partitions else {
+            let idx = self.lookup_table.interval_start(&self.order, key);
+            return Ok((idx, self.entry_offset(idx)?));
+        };
+        if index.partitions.is_empty() {
+            return Ok((0, self.metadata.filter_offset));
+        }
+        let partition_idx = index.scan_start(&self.order, key);
+        let partition = self.partition(index, partition_idx)?;
+        let idx = partition
+            .entries
+            .partition_point(|(k, _)| self.order.lt(k, key))
+            .saturating_sub(1);
+        let offset = partition
+            .entries
+            .get(idx)
+            .map_or(self.metadata.filter_offset, |e| e.1);
+        Ok((partition_idx * index.partition_entries + idx, offset))
+    }
+
+    /// Offset of record pointed by lookup table entry, end of values table past the last entry
+    fn entry_offset(&self, idx: usize) -> io::Result<usize> {
+        let offset = match &self.partitions {
+            None => self.lookup_table.entries.get(idx).map(|e| e.1),
+            Some(index) => {
+                let partition_idx = idx / index.partition_entries;
+                match index.partitions.get(partition_idx) {
+                    None => None,
+                    Some(handle) if idx.is_multiple_of(index.partition_entries) => {
+                        Some(handle.data_offset)
+                    }
+                    Some(_) => self
+                        .partition(index, partition_idx)?
+                        .entries
+                        .get(idx % index.partition_entries)
+                        .map(|e| e.1),
+                }
+            }
+        };
+        Ok(offset.unwrap_or(self.metadata.filter_offset))
+    }
+
+    /// Lookup table of table, read from file if table is partitioned
+    fn full_lookup_table(&self) -> io::Result<Cow<'_, SstLookupTable>> {
+        if self.partitions.is_none() {
+            return Ok(Cow::Borrowed(&self.lookup_table));
+        }
+        let source = self.source_at(self.metadata.lookup_table_offset)?;
+        Ok(Cow::Owned(SstLookupTable::read(source)?))
+    }
+
+    /// Maps whole file into memory, further reads are served without seeks and read syscalls
+    #[cfg(feature = "std-fs")]
+    pub fn map(&mut self) -> io::Result<()> {
+        let file = File::open(&self.path)?;
+        // SAFETY: sst files are never modified in place, level and filter rewrites replace
+        // the file and the mapping is refreshed after them
+        let mapping = unsafe { Mmap::map(&file)? };
+        self.mapping = Some(Arc::new(mapping));
+        Ok(())
+    }
+
+    /// Local files are compiled out, so there is nothing to map
+    #[cfg(not(feature = "std-fs"))]
+    pub fn map(&mut self) -> io::Result<()> {
+        Err(io::ErrorKind::Unsupported.into())
+    }
+
+    pub fn is_mapped(&self) -> bool {
+        self.mapping.is_some()
+    }
+
+    /// Replaces bloom filter of table, the rest of file is copied unchanged
+    pub fn rebuild_filter(&mut self, bits_per_key: usize) -> io::Result<()> {
+        let keys = self
+            .iter()?
+            .map(|entry| entry.map(|entry| entry.key))
+            .collect::<io::Result<Vec<_>>>()?;
+        if let Some(index) = self.partitions.clone() {
+            return self.rebuild_partition_filters(&index, &keys, bits_per_key);
+        }
+        let filter = BloomFilter::build(keys.iter().map(|key| key.as_slice()), bits_per_key);
+        let mut encoded = Vec::new();
+        filter.write(&mut encoded)?;
+        self.rewrite(self.metadata.filter_offset, &[], &encoded)?;
+        self.filter = filter;
+        if self.is_mapped() {
+            self.map()?;
+        }
+        Ok(())
+    }
+
+    /// Rewrites partition filters and then partition index, whose size doesn't depend
+    /// on filters, cached partitions of table are dropped
+    fn rebuild_partition_filters(
+        &mut self,
+        index: &PartitionIndex,
+        keys: &[Vec<u8>],
+        bits_per_key: usize,
+    ) -> io::Result<()> {
+        let records_per_partition = index.partition_entries * self.metadata.index_interval;
+        let mut rebuilt = PartitionIndex {
+            bits_per_key,
+            ..index.clone()
+        };
+        let mut encoded = Vec::new();
+        for (partition, keys) in rebuilt
+            .partitions
+            .iter_mut()
+            .zip(keys.chunks(records_per_partition))
+        {
+            let filter = BloomFilter::build(keys.iter().map(|key| key.as_slice()), bits_per_key);
+            partition.filter_offset = self.metadata.filter_offset + encoded.len();
+            partition.filter_size = filter.encoded_size();
+            filter.write(&mut encoded)?;
+        }
+        let mut encoded_index = Vec::new();
+        rebuilt.write(&mut encoded_index)?;
+        self.rewrite(
+            self.metadata.filter_offset,
+            &[(self.metadata.encoded_size(), &encoded_index)],
+            &encoded,
+        )?;
+        self.partitions = Some(Arc::new(rebuilt));
+        if let Some(cache) = &self.block_cache {
+            cache.evict_table(&self.path);
+        }
+        if self.is_mapped() {
+            self.map()?;
+        }
+        Ok(())
+    }
+
+    pub fn len(&self) -> usize {
+        self.metadata.entry_count
+    }
+
+    /// size of table file in bytes
+    pub fn file_size(&self) -> io::Result<u64> {
+        self.storage.open(&self.path)?.size()
+    }
+
+    /// Deletes table file from its storage
+    pub fn delete(self) -> io::Result<()> {
+        self.storage.delete(&self.path)
+    }
+
+    /// Estimated number of records and their size in bytes within bounds, computed from
+    /// lookup table alone so the precision is one index interval, or one partition
+    /// of partitioned table
+    pub fn approximate_range(&self, start: Bound<&[u8]>, end: Bound<&[u8]>) -> (usize, usize) {
+        let (entries, records_per_entry): (Vec<_>, _) = match &self.partitions {
+            Some(index) => (
+                index
+                    .partitions
+                    .iter()
+                    .map(|partition| (partition.key.as_slice(), partition.data_offset))
+                    .collect(),
+                index.partition_entries * self.metadata.index_interval,
+            ),
+            None => (
+                self.lookup_table
+                    .entries
+                    .iter()
+                    .map(|(key, offset)| (key.as_slice(), *offset))
+                    .collect(),
+                self.metadata.index_interval,
+            ),
+        };
+        let first = match start {
+            Bound::Included(key) => entries.partition_point(|(k, _)| self.order.lt(k, key)),
+            Bound::Excluded(key) => entries.partition_point(|(k, _)| self.order.le(k, key)),
+            Bound::Unbounded => 0,
+        };
+        let last = match end {
+            Bound::Included(key) => entries.partition_point(|(k, _)| self.order.le(k, key)),
+            Bound::Excluded(key) => entries.partition_point(|(k, _)| self.order.lt(k, key)),
+            Bound::Unbounded => entries.len(),
+        };
+        if first >= last {
+            return (0, 0);
+        }
+        let record = |idx: usize| (idx * records_per_entry).min(self.len());
+        let offset = |idx: usize| {
+            entries
+                .get(idx)
+                .map_or(self.metadata.filter_offset, |e| e.1)
+        };
+        (record(last) - record(first), offset(last) - offset(first))
+    }
+
+    /// Rewrites level in metadata of file, level is stored at the very beginning
+    pub fn set_level(&mut self, level: usize) -> io::Result<()> {
+        let len = self.file_size()? as usize;
+        self.rewrite(len, &[(0, &level.to_le_bytes())], &[])?;
+        self.metadata.level = level;
+        if self.is_mapped() {
+            self.map()?;
+        }
+        Ok(())
+    }
+
+    /// Replaces file by copy of its first `len` bytes with patches written over them at their
+    /// offsets, followed by `tail`. Copy is written under temp name and renamed over table,
+    /// so a crash leaves either version and hard links of checkpoints keep the old one
+    fn rewrite(&self, len: usize, patches: &[(usize, &[u8])], tail: &[u8]) -> io::Result<()> {
+        let temp_path = temp_path(&self.path);
+        if self.storage.exists(&temp_path) {
+            self.storage.delete(&temp_path)?;
+        }
+        let mut source = StorageReader::open_at(&*self.storage, &self.path, 0)?;
+        let mut writer = BufWriter::new(self.storage.create_new(&temp_path)?);
+        let mut position = 0;
+        for &(offset, patch) in patches {
+            io::copy(
+                &mut (&mut source).take((offset - position) as u64),
+                &mut writer,
+            )?;
+            writer.write_all(patch)?;
+            position = offset + patch.len();
+            source.seek(SeekFrom::Start(position as u64))?;
+        }
+        let copied = io::copy(&mut source.take((len - position) as u64), &mut writer)?;
+        if copied as usize != len - position {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        writer.write_all(tail)?;
+        writer
+            .into_inner()
+            .map_err(|err| err.into_error())?
+            .sync_data()?;
+        self.storage.rename(&temp_path, &self.path)?;
+        if let Some(dir) = self.path.parent() {
+            self.storage.sync_dir(dir)?;
+        }
+        Ok(())
+    }
+
+    /// Whether key range of this table intersects with [low, high]
+    pub fn overlaps(&self, low: &[u8], high: &[u8]) -> bool {
+        !self.is_empty()
+            && self.order.le(&self.metadata.low_key, high)
+            && self.order.le(low, &self.metadata.high_key)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.metadata.entry_count == 0
+    }
+
+    /// Whether key range of this table intersects with [start, end)
+    pub fn overlaps_range(&self, start: &[u8], end: &[u8]) -> bool {
+        !self.is_empty()
+            && self.order.lt(&self.metadata.low_key, end)
+            && self.order.le(start, &self.metadata.high_key)
+    }
+
+    /// Whether `get` has to read records, false when key is out of range or rejected by filter
+    pub fn may_contain(&self, key: &[u8]) -> bool {
+        self.in_key_range(key) && self.filter_may_contain(key)
+    }
+
+    pub fn in_key_range(&self, key: &[u8]) -> bool {
+        !self.is_empty()
+            && self.order.le(&self.metadata.low_key, key)
+            && self.order.le(key, &self.metadata.high_key)
+    }
+
+    /// Index interval where `get` of key starts reading and its size in bytes
+    pub fn read_interval(&self, key: &[u8]) -> (usize, usize) {
+        let interval = || -> io::Result<(usize, usize)> {
+            let (idx, start) = self.interval_of(key)?;
+            Ok((idx, self.entry_offset(idx + 1)?.saturating_sub(start)))
+        };
+        // only an estimate, unreadable partition counts as empty interval
+        interval().unwrap_or((0, 0))
+    }
+
+    pub fn get(&self, key: impl AsRef<[u8]>) -> io::Result<Option<CommonBinaryFormat>> {
+        let key = key.as_ref();
+        if !self.may_contain(key) {
+            return Ok(None);
+        }
+        match self.iter_from(key)?.next().transpose()? {
+            Some(entry) if entry.key == key => Ok(Some(entry)),
+            _ => Ok(None),
+        }
+    }
+
+    /// The greatest key in table less than `key`
+    pub fn key_before(&self, key: &[u8]) -> io::Result<Option<Vec<u8>>> {
+        if self.is_empty() || self.order.le(key, &self.metadata.low_key) {
+            return Ok(None);
+        }
+        if self.order.lt(&self.metadata.high_key, key) {
+            return Ok(Some(self.metadata.high_key.clone()));
+        }
+        let (mut idx, mut offset) = self.interval_of(key)?;
+        loop {
+            let mut before = None;
+            for entry in self.iter_at(idx, offset)? {
+                let entry = entry?;
+                if self.order.le(key, &entry.key) {
+                    break;
+                }
+                before = Some(entry.key);
+            }
+            // interval starting with key holds no smaller keys, the previous one does
+            match (before, idx.checked_sub(1)) {
+                (Some(before), _) => return Ok(Some(before)),
+                (None, Some(prev)) => {
+                    idx = prev;
+                    offset = self.entry_offset(idx)?;
+                }
+                (None, None) => return Ok(None),
+            }
+        }
+    }
+
+    /// All versions of key stored in table from newest to oldest
+    pub fn get_versions(&self, key: impl AsRef<[u8]>) -> io::Result<Vec<CommonBinaryFormat>> {
+        let key = key.as_ref();
+        if !self.filter_may_contain(key) {
+            return Ok(Vec::new());
+        }
+        self.iter_from(key)?
+            .take_while(|entry| entry.as_ref().map_or(true, |entry| entry.key == key))
+            .collect()
+    }
+
+    /// Checks that metadata key range matches keys actually stored in table,
+    /// only the first and the last intervals are read unless `full` is set
+    pub fn check_key_range(&self, full: bool) -> io::Result<bool> {
+        let meta = &self.metadata;
+        let lookup_table = self.full_lookup_table()?;
+        let entries = &lookup_table.entries;
+        let (Some(first), Some(last)) = (entries.first(), entries.last()) else {
+            return Ok(meta.low_key.is_empty() && meta.high_key.is_empty());
+        };
+        if first.0 != meta.low_key || self.order.lt(&meta.high_key, &last.0) {
+            return Ok(false);
+        }
+        if !full {
+            let last_interval = self.iter_interval(entries.len() - 1)?;
+            let high_key = last_interval.last().transpose()?.map(|entry| entry.key);
+            return Ok(
+                self.read_at(first.1)?.key == first.0 && high_key.as_ref() == Some(&meta.high_key)
+            );
+        }
+        let mut prev_key: Option<Vec<u8>> = None;
+        for (idx, entry) in self.iter()?.enumerate() {
+            let entry = entry?;
+            if prev_key.is_some_and(|prev| self.order.lt(&entry.key, &prev)) {
+                return Ok(false);
+            }
+            if idx % meta.index_interval == 0 && entries[idx / meta.index_interval].0 != entry.key {
+                return Ok(false);
+            }
+            prev_key = Some(entry.key);
+        }
+        Ok(prev_key.as_ref() == Some(&meta.high_key))
+    }
+
+    /// Streams through the whole file checking record checksums and that records agree with
+    /// lookup table, key range and file layout, returns the first corruption found
+    pub fn verify(&self) -> io::Result<Option<Corruption>> {
+        let meta = &self.metadata;
+        let lookup_table = match self.full_lookup_table() {
+            Ok(lookup_table) => lookup_table,
+            Err(error) => {
+                let offset = meta.lookup_table_offset as u64;
+                return Ok(Some(Corruption { offset, error }));
+            }
+        };
+        let entries = &lookup_table.entries;
+        if meta.index_interval == 0
+            || entries.len() != meta.entry_count.div_ceil(meta.index_interval)
+        {
+            let message = "lookup table size doesn't match entry count";
+            return Ok(Some(Corruption::new(0, message)));
+        }
+        let file = StorageReader::open_at(&*self.storage, &self.path, 0)?;
+        let file_len = file.size()?;
+        let mut reader = BufReader::new(file);
+        let mut position = meta.values_table_offset as u64;
+        reader.seek(SeekFrom::Start(position))?;
+        let mut low_key: Option<Vec<u8>> = None;
+        let mut prev_key: Option<Vec<u8>> = None;
+        for idx in 0..meta.entry_count {
+            let indexed =
+                (idx % meta.index_interval == 0).then(|| &entries[idx / meta.index_interval]);
+            if indexed.is_some_and(|(_, offset)| *offset as u64 != position) {
+                let message = "lookup table offset doesn't match record position";
+                return Ok(Some(Corruption::new(position, message)));
+            }
+            let entry = match CommonBinaryFormat::read(&mut reader) {
+                Ok(entry) => entry,
+                Err(error) => {
+                    return Ok(Some(Corruption {
+                        offset: position,
+                        error,
+                    }))
+                }
+            };
+            if indexed.is_some_and(|(key, _)| entry.key != *key) {
+                let message = "record key doesn't match lookup table";
+                return Ok(Some(Corruption::new(position, message)));
+            }
+            if prev_key
+                .as_ref()
+                .is_some_and(|prev| self.order.lt(&entry.key, prev))
+            {
+                return Ok(Some(Corruption::new(position, "records out of key order")));
+            }
+            position = reader.stream_position()?;
+            low_key.get_or_insert_with(|| entry.key.clone());
+            prev_key = Some(entry.key);
+        }
+        if position != meta.filter_offset as u64 {
+            return Ok(Some(Corruption::new(
+                position,
+                "values table doesn't end at filter",
+            )));
+        }
+        if meta.low_key != low_key.unwrap_or_default()
+            || meta.high_key != prev_key.unwrap_or_default()
+        {
+            return Ok(Some(Corruption::new(
+                0,
+                "metadata key range doesn't match records",
+            )));
+        }
+        let filter_count = match &self.partitions {
+            Some(index) => {
+                if let Some(corruption) = index.check(meta, &lookup_table) {
+                    return Ok(Some(corruption));
+                }
+                index.partitions.len()
+            }
+            None => 1,
+        };
+        for idx in 0..filter_count {
+            let offset = reader.stream_position()?;
+            if let Err(error) = BloomFilter::read(&mut reader) {
+                return Ok(Some(Corruption { offset, error }));
+            }
+            let end = reader.stream_position()?;
+            let handle = self.partitions.as_ref().map(|index| &index.partitions[idx]);
+            if handle.is_some_and(|handle| {
+                handle.filter_offset as u64 != offset
+                    || (handle.filter_offset + handle.filter_size) as u64 != end
+            }) {
+                let message = "partition filter doesn't match partition index";
+                return Ok(Some(Corruption::new(offset, message)));
+            }
+        }
+        let end = reader.stream_position()?;
+        if end != file_len {
+            return Ok(Some(Corruption::new(end, "trailing bytes after filter")));
+        }
+        Ok(None)
+    }
+
+    /// Finds the newest version of key in mapped table, key and value point into the mapping
+    /// unless value is compressed
+    pub fn get_mapped(&self, key: impl AsRef<[u8]>) -> io::Result<Option<MappedEntry>> {
+        let key = key.as_ref();
+        let Some(mapping) = &self.mapping else {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "table is not memory-mapped",
+            ));
+        };
+        if !self.filter_may_contain(key) {
+            return Ok(None);
+        }
+        let (_, mut offset) = self.interval_of(key)?;
+        let values_end = self.metadata.filter_offset.min(mapping.len());
+        while offset < values_end {
+            let (entry, size) = CommonBinaryFormatRef::parse(&mapping[offset..values_end])?;
+            if self.order.lt(key, entry.key) {
+                break;
+            }
+            if entry.key == key {
+                let value = match entry.value {
+                    Some(value) if entry.compressed => {
+                        Some(MappedBytes::decompressed(compression::decompress(value)?))
+                    }
+                    value => value.map(|value| MappedBytes::from_subslice(mapping, value)),
+                };
+                return Ok(Some(MappedEntry {
+                    timestamp: entry.timestamp,
+                    key: MappedBytes::from_subslice(mapping, entry.key),
+                    value,
+                }));
+            }
+            offset += size;
+        }
+        Ok(None)
+    }
+
+    fn read_at(&self, offset: usize) -> io::Result<CommonBinaryFormat> {
+        CommonBinaryFormat::read(&mut self.source_at(offset)?)
+    }
+
+    /// Reader positioned at offset, either over mapping or over file
+    fn source_at(&self, offset: usize) -> io::Result<Box<dyn SeekRead>> {
+        if let Some(mapping) = &self.mapping {
+            let end = mapping.len().max(offset);
+            return Ok(Box::new(io::Cursor::new(MappedBytes {
+                backing: Backing::Mapping(mapping.clone()),
+                range: offset..end,
+            })));
+        }
+        let file = StorageReader::open_at(&*self.storage, &self.path, offset as u64)?;
+        Ok(Box::new(BufReader::new(file)))
+    }
+
+    /// Iterates entries in key order starting from the first key not less than `from`
+    pub fn iter_from(&self, from: impl AsRef<[u8]>) -> io::Result<SstIterator> {
+        let from = from.as_ref();
+        let (entry_idx, offset) = self.interval_of(from)?;
+        let mut iter = self.iter_at(entry_idx, offset)?;
+        iter.skip_below = Some(from.to_vec());
+        Ok(iter)
+    }
+
+    /// Same as `iter_from` with values skipped, see `CommonBinaryFormat::read_key_only`
+    pub fn iter_keys_from(&self, from: impl AsRef<[u8]>) -> io::Result<SstIterator> {
+        let mut iter = self.iter_from(from)?;
+        iter.key_only = true;
+        Ok(iter)
+    }
+
+    /// Iterates entries starting from the first record of interval pointed by lookup table entry
+    fn iter_interval(&self, entry_idx: usize) -> io::Result<SstIterator> {
+        self.iter_at(entry_idx, self.entry_offset(entry_idx)?)
+    }
+
+    fn iter_at(&self, entry_idx: usize, offset: usize) -> io::Result<SstIterator> {
+        Ok(SstIterator {
+            source: self.source_at(offset)?,
+            remaining: self
+                .len()
+                .saturating_sub(entry_idx * self.metadata.index_interval),
+            skip_below: None,
+            key_only: false,
+            order: self.order.clone(),
+        })
+    }
+
+    pub fn iter(&self) -> io::Result<SstIterator> {
+        self.iter_from([])
+    }
+
+    /// Writes human-readable description of table, all entries are included if `with_entries` is set
+    pub fn dump(&self, mut out: impl io::Write, with_entries: bool) -> io::Result<()> {
+        let meta = &self.metadata;
+        writeln!(out, "file: {}", self.path.display())?;
+        writeln!(out, "format version: {FORMAT_VERSION}")?;
+        writeln!(out, "size: {} bytes", self.file_size()?)?;
+        writeln!(out, "level: {}", meta.level)?;
+        writeln!(out, "lookup table offset: {}", meta.lookup_table_offset)?;
+        writeln!(out, "values table offset: {}", meta.values_table_offset)?;
+        writeln!(out, "filter offset: {}", meta.filter_offset)?;
+        writeln!(out, "filter bits per key: {}", self.filter_bits_per_key())?;
+        writeln!(
+            out,
+            "timestamps: [{}, {}]",
+            meta.min_timestamp, meta.max_timestamp
+        )?;
+        writeln!(out, "created at: {}", meta.created_at)?;
+        writeln!(
+            out,
+            "key range: [{}, {}]",
+            meta.low_key.escape_ascii(),
+            meta.high_key.escape_ascii()
+        )?;
+        writeln!(out, "entries: {}", self.len())?;
+        writeln!(out, "tombstones: {}", meta.tombstone_count)?;
+        writeln!(out, "raw key size: {} bytes", meta.raw_key_size)?;
+        writeln!(out, "raw value size: {} bytes", meta.raw_value_size)?;
+        writeln!(out, "index interval: {}", meta.index_interval)?;
+        if let Some(index) = &self.partitions {
+            writeln!(out, "partition entries: {}", index.partition_entries)?;
+            writeln!(out, "partitions:")?;
+            for partition in index.partitions.iter() {
+                writeln!(
+                    out,
+                    "  {} -> {} (lookup {}, filter {}+{})",
+                    partition.key.escape_ascii(),
+                    partition.data_offset,
+                    partition.lookup_offset,
+                    partition.filter_offset,
+                    partition.filter_size
+                )?;
+            }
+        }
+        writeln!(out, "lookup table:")?;
+        for (key, offset) in self.full_lookup_table()?.entries.iter() {
+            writeln!(out, "  {} -> {offset}", key.escape_ascii())?;
+        }
+        if with_entries {
+            writeln!(out, "data:")?;
+            for entry in self.iter()? {
+                let entry = entry?;
+                match &entry.value {
+                    Some(value) => writeln!(
+                        out,
+                        "  {} @ {} => {}",
+                        entry.key.escape_ascii(),
+                        entry.timestamp,
+                        value.escape_ascii()
+                    )?,
+                    None => writeln!(
+                        out,
+                        "  {} @ {} => <tombstone>",
+                        entry.key.escape_ascii(),
+                        entry.timestamp
+                    )?,
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Source of table records, value bytes are seeked over by key-only iteration
+trait SeekRead: io::Read + io::Seek {}
+
+impl<T: io::Read + io::Seek> SeekRead for T {}
+
+pub struct SstIterator {
+    source: Box<dyn SeekRead>,
+    remaining: usize,
+    /// records before this key are skipped, scan starts at interval boundary
+    skip_below: Option<Vec<u8>>,
+    /// values are skipped and yielded empty
+    key_only: bool,
+    order: KeyOrder,
+}
+
+impl Iterator for SstIterator {
+    type Item = io::Result<CommonBinaryFormat>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while self.remaining > 0 {
+            self.remaining -= 1;
+            let entry = match self.key_only {
+                true => CommonBinaryFormat::read_key_only(&mut self.source),
+                false => CommonBinaryFormat::read(&mut self.source),
+            };
+            let entry = match entry {
+                Ok(entry) => entry,
+                Err(e) => return Some(Err(e)),
+            };
+            if self
+                .skip_below
+                .as_ref()
+                .is_some_and(|from| self.order.lt(&entry.key, from))
+            {
+                continue;
+            }
+            self.skip_below = None;
+            return Some(Ok(entry));
+        }
+        None
+    }
+}
+
+/// Name table is written under before it's renamed into place
+fn temp_path(path: &Path) -> PathBuf {
+    let mut name = path.to_path_buf().into_os_string();
+    name.push(".tmp");
+    PathBuf::from(name)
+}
+
+#[cfg(all(test, feature = "std-fs"))]
+mod tests {
+    use super::*;
+    use crate::vfs::FaultInjectionFs;
+    use proptest::prelude::*;
+    use std::collections::BTreeMap;
+    use std::fs;
+
+    #[test]
+    fn write_read_cycle() {
+        let test_dir = &PathBuf::from("./tests/write_read_cycle");
+        if test_dir.exists() {
+            fs::remove_dir_all(test_dir).unwrap();
+        }
+        fs::create_dir_all(test_dir).unwrap();
+
+        let mut writer = SstWriter::new(2).set_created_at(42);
+        writer.push(CommonBinaryFormatRef::new(1, &[0, 1], Some(&[1, 1]))).unwrap();
+        writer.push(CommonBinaryFormatRef::new(5, &[0, 2], None)).unwrap();
+        writer.push(CommonBinaryFormatRef::new(3, &[1, 0, 0], Some(&[3]))).unwrap();
+        let path = test_dir.join("1.sst");
+        writer.finish(&path).unwrap();
+
+        let reader = SstReader::open(&path).unwrap();
+        assert_eq!(reader.metadata.level, 2);
+        assert_eq!(reader.metadata.low_key, vec![0, 1]);
+        assert_eq!(reader.metadata.high_key, vec![1, 0, 0]);
+        assert_eq!(reader.metadata.max_timestamp, 5);
+        assert_eq!(reader.metadata.min_timestamp, 1);
+        assert_eq!(reader.metadata.tombstone_count, 1);
+        assert_eq!(reader.metadata.raw_key_size, 7);
+        assert_eq!(reader.metadata.raw_value_size, 3);
+        assert_eq!(reader.metadata.created_at, 42);
+        assert_eq!(reader.len(), 3);
+
+        let entry = reader.get([0, 2]).unwrap().unwrap();
+        assert_eq!(entry.timestamp, 5);
+        assert_eq!(entry.value, None);
+        assert_eq!(reader.get([1, 0, 0]).unwrap().unwrap().value, Some(vec![3]));
+        assert!(reader.get([0, 3]).unwrap().is_none());
+
+        let mut unordered = SstWriter::new(0);
+        for timestamp in [2, 1] {
+            unordered
+                .push(CommonBinaryFormatRef::new(timestamp, &[0, 2], None))
+                .unwrap();
+        }
+        let err = unordered
+            .push(CommonBinaryFormatRef::new(3, &[0, 1], None))
+            .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+
+        let keys: Vec<_> = reader
+            .iter_from([0, 2])
+            .unwrap()
+            .map(|e| e.unwrap().key)
+            .collect();
+        assert_eq!(keys, vec![vec![0, 2], vec![1, 0, 0]]);
+
+        let mut reader = reader;
+        assert_eq!(reader.filter.bits_per_key, DEFAULT_BLOOM_BITS_PER_KEY);
+        reader.rebuild_filter(4).unwrap();
+        let reader = SstReader::open(&path).unwrap();
+        assert_eq!(reader.filter.bits_per_key, 4);
+        assert_eq!(reader.get([1, 0, 0]).unwrap().unwrap().value, Some(vec![3]));
+    }
+
+    #[test]
+    fn rewrites_replace_file() {
+        let test_dir = &PathBuf::from("./tests/rewrites_replace_file");
+        if test_dir.exists() {
+            fs::remove_dir_all(test_dir).unwrap();
+        }
+        fs::create_dir_all(test_dir).unwrap();
+        let mut writer = SstWriter::new(1);
+        for i in 0..100u8 {
+            writer
+                .push(CommonBinaryFormatRef::new(1, &[i], Some(&[i])))
+                .unwrap();
+        }
+        let path = test_dir.join("1.sst");
+        let mut table = writer.finish(&path).unwrap();
+        table.map().unwrap();
+        // checkpoints hard link tables
+        let linked = test_dir.join("linked.sst");
+        fs::hard_link(&path, &linked).unwrap();
+        let original = fs::read(&linked).unwrap();
+
+        table.set_level(3).unwrap();
+        table.rebuild_filter(4).unwrap();
+        assert_eq!(fs::read(&linked).unwrap(), original);
+        assert!(!test_dir.join("1.sst.tmp").exists());
+        assert_eq!(table.get([7]).unwrap().unwrap().value, Some(vec![7]));
+        let reopened = SstReader::open(&path).unwrap();
+        assert_eq!(reopened.metadata.level, 3);
+        assert_eq!(reopened.filter.bits_per_key, 4);
+        assert_eq!(reopened.get([99]).unwrap().unwrap().value, Some(vec![99]));
+        reopened.verify().unwrap();
+        let linked = SstReader::open(&linked).unwrap();
+        assert_eq!(linked.metadata.level, 1);
+        assert_eq!(linked.filter.bits_per_key, DEFAULT_BLOOM_BITS_PER_KEY);
+    }
+
+    #[test]
+    fn sparse_index_lookups() {
+        let test_dir = &PathBuf::from("./tests/sparse_index_lookups");
+        if test_dir.exists() {
+            fs::remove_dir_all(test_dir).unwrap();
+        }
+        fs::create_dir_all(test_dir).unwrap();
+
+        let mut writer = SstWriter::new(0).set_index_interval(4);
+        for i in 0..50u8 {
+            for timestamp in (0..3).rev() {
+                writer
+                    .push(CommonBinaryFormatRef::new(timestamp, &[i * 2], Some(&[i])))
+                    .unwrap();
+            }
+        }
+        let path = test_dir.join("1.sst");
+        writer.finish(&path).unwrap();
+
+        let reader = SstReader::open(&path).unwrap();
+        assert_eq!(reader.len(), 150);
+        assert_eq!(reader.lookup_table.entries.len(), 38);
+        assert!(reader.check_key_range(true).unwrap());
+        assert!(reader.verify().unwrap().is_none());
+        for i in 0..50u8 {
+            let entry = reader.get([i * 2]).unwrap().unwrap();
+            assert_eq!((entry.timestamp, entry.value), (2, Some(vec![i])));
+            assert_eq!(reader.get_versions([i * 2]).unwrap().len(), 3);
+            assert!(reader.get([i * 2 + 1]).unwrap().is_none());
+        }
+        let first = reader.iter_from([21]).unwrap().next().unwrap().unwrap();
+        assert_eq!((first.key, first.timestamp), (vec![22], 2));
+    }
+
+    #[test]
+    fn large_index_switches_to_two_level() {
+        let test_dir = &PathBuf::from("./tests/large_index_switches_to_two_level");
+        if test_dir.exists() {
+            fs::remove_dir_all(test_dir).unwrap();
+        }
+        fs::create_dir_all(test_dir).unwrap();
+
+        let write = |name: &str, threshold: usize| {
+            let mut writer = SstWriter::new(0)
+                .set_index_interval(1)
+                .set_two_level_index_threshold(threshold);
+            for i in 0..2000u16 {
+                let key = i.to_be_bytes();
+                writer
+                    .push(CommonBinaryFormatRef::new(1, &key, Some(&key)))
+                    .unwrap();
+            }
+            writer.finish(test_dir.join(name)).unwrap();
+            SstReader::open(test_dir.join(name)).unwrap()
+        };
+        let flat = write("1.sst", DEFAULT_TWO_LEVEL_INDEX_THRESHOLD);
+        assert!(!flat.is_partitioned());
+        let flat_index_size = flat.lookup_table.encoded_size();
+
+        let reader = write("2.sst", flat_index_size - 1);
+        let partitions = reader.partitions.clone().unwrap();
+        assert!(partitions.partitions.len() > 1);
+        assert!(partitions.encoded_size() < flat_index_size / 10);
+        assert!(reader.lookup_table.entries.is_empty());
+        assert!(reader.verify().unwrap().is_none());
+        for i in (0..2000u16).step_by(7) {
+            let entry = reader.get(i.to_be_bytes()).unwrap().unwrap();
+            assert_eq!(entry.value, Some(i.to_be_bytes().to_vec()));
+        }
+        assert!(reader.get(2000u16.to_be_bytes()).unwrap().is_none());
+
+        // explicit partitioning is kept as is
+        let mut writer = SstWriter::new(0)
+            .set_partition_entries(3)
+            .set_two_level_index_threshold(1);
+        writer
+            .push(CommonBinaryFormatRef::new(1, b"a", Some(b"1")))
+            .unwrap();
+        let reader = writer.finish(test_dir.join("3.sst")).unwrap();
+        assert_eq!(reader.partitions.unwrap().partition_entries, 3);
+    }
+
+    #[test]
+    fn partitioned_index_lookups() {
+        let test_dir = &PathBuf::from("./tests/partitioned_index_lookups");
+        if test_dir.exists() {
+            fs::remove_dir_all(test_dir).unwrap();
+        }
+        fs::create_dir_all(test_dir).unwrap();
+
+        let cache = Arc::new(BlockCache::new(1 << 20));
+        let mut writer = SstWriter::new(0)
+            .set_index_interval(4)
+            .set_partition_entries(3)
+            .set_block_cache(Some(cache.clone()));
+        for i in 0..50u8 {
+            for timestamp in (0..3).rev() {
+                writer
+                    .push(CommonBinaryFormatRef::new(timestamp, &[i * 2], Some(&[i])))
+                    .unwrap();
+            }
+        }
+        let path = test_dir.join("1.sst");
+        writer.finish(&path).unwrap();
+
+        let mut reader = SstReader::open(&path).unwrap();
+        reader.set_block_cache(Some(cache.clone()));
+        let partitions = reader.partitions.clone().unwrap();
+        assert_eq!(partitions.partitions.len(), 13);
+        assert!(reader.lookup_table.entries.is_empty());
+        assert_eq!(cache.usage(), 0);
+        assert!(reader.check_key_range(true).unwrap());
+        assert!(reader.verify().unwrap().is_none());
+        for i in 0..50u8 {
+            let entry = reader.get([i * 2]).unwrap().unwrap();
+            assert_eq!((entry.timestamp, entry.value), (2, Some(vec![i])));
+            assert_eq!(reader.get_versions([i * 2]).unwrap().len(), 3);
+            assert!(reader.get([i * 2 + 1]).unwrap().is_none());
+        }
+        assert!(cache.usage() > 0);
+        let keys: Vec<_> = reader
+            .iter_from([21])
+            .unwrap()
+            .map(|entry| entry.unwrap().key[0])
+            .step_by(3)
+            .collect();
+        assert_eq!(keys, (11..50).map(|i| i * 2).collect::<Vec<_>>());
+        assert_eq!(
+            reader
+                .approximate_range(Bound::Unbounded, Bound::Unbounded)
+                .0,
+            150
+        );
+
+        reader.rebuild_filter(4).unwrap();
+        assert_eq!(cache.usage(), 0);
+        assert!(reader.verify().unwrap().is_none());
+        let mut reopened = SstReader::open(&path).unwrap();
+        assert_eq!(reopened.filter_bits_per_key(), 4);
+        reopened.map().unwrap();
+        for i in 0..50u8 {
+            let entry = reopened.get_mapped([i * 2]).unwrap().unwrap();
+            assert_eq!(entry.value.unwrap().to_vec(), vec![i]);
+            assert!(reopened.get_mapped([i * 2 + 1]).unwrap().is_none());
+        }
+
+        let small = BlockCache::new(200);
+        reopened.set_block_cache(Some(Arc::new(small)));
+        for i in 0..50u8 {
+            assert!(reopened.get([i * 2]).unwrap().is_some());
+        }
+        assert!(reopened.block_cache.as_ref().unwrap().usage() <= 200);
+    }
+
+    #[test]
+    fn mapped_reads_match_file_reads() {
+        let test_dir = &PathBuf::from("./tests/mapped_reads_match_file_reads");
+        if test_dir.exists() {
+            fs::remove_dir_all(test_dir).unwrap();
+        }
+        fs::create_dir_all(test_dir).unwrap();
+        let mut builder = SstBuilder::new();
+        for i in 0..40u8 {
+            builder.put(&[i], &[i, i]).unwrap();
+        }
+        builder.delete(&[40]).unwrap();
+        let path = test_dir.join("1.sst");
+        let mut table = builder.finish(&path).unwrap();
+        assert!(table.get_mapped([1]).is_err());
+
+        table.map().unwrap();
+        let entry = table.get_mapped([25]).unwrap().unwrap();
+        assert_eq!(
+            (&*entry.key, entry.value.as_deref()),
+            (&[25][..], Some(&[25, 25][..]))
+        );
+        assert!(table.get_mapped([40]).unwrap().unwrap().value.is_none());
+        assert!(table.get_mapped([41]).unwrap().is_none());
+        assert_eq!(table.get([7]).unwrap().unwrap().value, Some(vec![7, 7]));
+        assert_eq!(table.iter().unwrap().count(), 41);
+
+        table.rebuild_filter(4).unwrap();
+        assert!(table.check_key_range(true).unwrap());
+        // handle keeps its part of mapping alive after table goes away
+        drop(table);
+        fs::remove_file(&path).unwrap();
+        assert_eq!(&*entry.value.unwrap(), &[25, 25]);
+    }
+
+    #[test]
+    fn direct_io_write_read_cycle() {
+        let test_dir = &PathBuf::from("./tests/direct_io_write_read_cycle");
+        if test_dir.exists() {
+            fs::remove_dir_all(test_dir).unwrap();
+        }
+        fs::create_dir_all(test_dir).unwrap();
+        // larger than staging buffer of direct writer
+        let mut writer = SstWriter::new(0).set_direct_io(true);
+        for i in 0..2000u32 {
+            writer
+                .push(CommonBinaryFormatRef::new(
+                    1,
+                    &i.to_be_bytes(),
+                    Some(&[7; 1000]),
+                ))
+                .unwrap();
+        }
+        let path = test_dir.join("1.sst");
+        writer.finish(&path).unwrap();
+
+        let table = SstReader::open(&path).unwrap();
+        assert!(table.verify().unwrap().is_none());
+        let entry = table.get(1999u32.to_be_bytes()).unwrap().unwrap();
+        assert_eq!(entry.value, Some(vec![7; 1000]));
+    }
+
+    #[test]
+    fn verify_finds_corrupt_record() {
+        let test_dir = &PathBuf::from("./tests/verify_finds_corrupt_record");
+        if test_dir.exists() {
+            fs::remove_dir_all(test_dir).unwrap();
+        }
+        fs::create_dir_all(test_dir).unwrap();
+        let mut builder = SstBuilder::new();
+        builder.put(b"a", b"1").unwrap();
+        builder.put(b"b", b"2").unwrap();
+        let path = test_dir.join("1.sst");
+        let table = builder.finish(&path).unwrap();
+        assert!(table.verify().unwrap().is_none());
+
+        let first_record = CommonBinaryFormatRef::new(0, b"a", Some(b"1")).encoded_size();
+        let second_record = table.metadata.values_table_offset + first_record;
+        let mut data = fs::read(&path).unwrap();
+        data[second_record + 20] ^= 1;
+        fs::write(&path, &data).unwrap();
+        let corruption = table.verify().unwrap().unwrap();
+        assert_eq!(corruption.offset, second_record as u64);
+    }
+
+    #[test]
+    fn failed_write_leaves_no_table() {
+        let test_dir = &PathBuf::from("./tests/failed_write_leaves_no_table");
+        if test_dir.exists() {
+            fs::remove_dir_all(test_dir).unwrap();
+        }
+        fs::create_dir_all(test_dir).unwrap();
+        let faults = FaultInjectionFs::new();
+        let mut writer = SstWriter::new(0).set_storage(Arc::new(faults.clone()));
+        for i in 0..100u8 {
+            writer
+                .push(CommonBinaryFormatRef::new(0, &[i], Some(&[i; 16])))
+                .unwrap();
+        }
+        faults.fail_writes_after(100);
+        let path = test_dir.join("1.sst");
+        assert!(writer.finish(&path).is_err());
+        assert!(!path.exists());
+        assert!(test_dir.join("1.sst.tmp").exists());
+    }
+
+    proptest! {
+        #![proptest_config(ProptestConfig::with_cases(32))]
+
+        #[test]
+        fn reads_back_arbitrary_tables(
+            entries in prop::collection::btree_map(
+                prop::collection::vec(any::<u8>(), 0..8),
+                (any::<u128>(), prop::option::of(prop::collection::vec(any::<u8>(), 0..32))),
+                0..100,
+            ),
+            index_interval in 1..20usize,
+            from in prop::collection::vec(any::<u8>(), 0..8),
+        ) {
+            let test_dir = &PathBuf::from("./tests/reads_back_arbitrary_tables");
+            if test_dir.exists() {
+                fs::remove_dir_all(test_dir).unwrap();
+            }
+            fs::create_dir_all(test_dir).unwrap();
+            let mut writer = SstWriter::new(0).set_index_interval(index_interval);
+            for (key, (timestamp, value)) in entries.iter() {
+                writer
+                    .push(CommonBinaryFormatRef::new(*timestamp, key, value.as_deref()))
+                    .unwrap();
+            }
+            let path = test_dir.join("1.sst");
+            writer.finish(&path).unwrap();
+
+            let reader = SstReader::open(&path).unwrap();
+            prop_assert!(reader.check_key_range(true).unwrap());
+            prop_assert_eq!(reader.len(), entries.len());
+            for (key, (timestamp, value)) in entries.iter() {
+                let entry = reader.get(key).unwrap().unwrap();
+                prop_assert_eq!(entry.timestamp, *timestamp);
+                prop_assert_eq!(&entry.value, value);
+            }
+            let read: BTreeMap<_, _> = reader
+                .iter_from(&from)
+                .unwrap()
+                .map(|entry| {
+                    let entry = entry.unwrap();
+                    (entry.key, (entry.timestamp, entry.value))
+                })
+                .collect();
+            let expected: BTreeMap<_, _> = entries
+                .range(from..)
+                .map(|(key, entry)| (key.clone(), entry.clone()))
+                .collect();
+            prop_assert_eq!(read, expected);
+        }
+    }
 }
@@ -0,0 +1,49 @@
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::{Mutex, MutexGuard};
+
+/// Committed change of a single key delivered to subscribers
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ChangeEvent {
+    pub key: Vec<u8>,
+    /// new value, None if key was deleted
+    pub value: Option<Vec<u8>>,
+    /// sequence of the write which made the change
+    pub timestamp: u128,
+}
+
+/// Channels of subscribers by key prefix, closed ones are dropped on the next matching write
+#[derive(Default)]
+pub(crate) struct Subscriptions {
+    subscribers: Mutex<Vec<(Vec<u8>, Sender<ChangeEvent>)>>,
+}
+
+impl Subscriptions {
+    pub fn subscribe(&self, prefix: Vec<u8>) -> Receiver<ChangeEvent> {
+        let (sender, receiver) = mpsc::channel();
+        self.lock().push((prefix, sender));
+        receiver
+    }
+
+    /// Whether any subscriber could be interested in key, lets writers skip copying values
+    pub fn is_watched(&self, key: &[u8]) -> bool {
+        self.lock()
+            .iter()
+            .any(|(prefix, _)| key.starts_with(prefix))
+    }
+
+    /// Sends changes of a single commit to matching subscribers in the order they were written
+    pub fn publish(&self, changes: impl IntoIterator<Item = ChangeEvent>) {
+        let mut subscribers = self.lock();
+        for change in changes {
+            subscribers.retain(|(prefix, sender)| {
+                !change.key.starts_with(prefix) || sender.send(change.clone()).is_ok()
+            });
+        }
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Vec<(Vec<u8>, Sender<ChangeEvent>)>> {
+        self.subscribers
+            .lock()
+            .expect("subscriptions mutex poisoned")
+    }
+}
@@ -0,0 +1,93 @@
+use crate::batch::WriteBatch;
+use crate::error::Result;
+use crate::keyspace;
+use std::fmt;
+use std::sync::Arc;
+
+/// Reversible encoding of values stored under a key prefix, e.g. compression or encryption
+///
+/// Values are encoded before they reach wal and decoded on every read, key is passed along
+/// so that it can be used as associated data or nonce.
+pub trait ValueTransformer: Send + Sync {
+    fn encode(&self, key: &[u8], value: Vec<u8>) -> Result<Vec<u8>>;
+
+    fn decode(&self, key: &[u8], stored: Vec<u8>) -> Result<Vec<u8>>;
+}
+
+/// Transformers by key prefix, key is handled by the one with the longest matching prefix
+#[derive(Clone, Default)]
+pub(crate) struct ValueTransformers(Vec<(Vec<u8>, Arc<dyn ValueTransformer>)>);
+
+impl fmt::Debug for ValueTransformers {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_list()
+            .entries(
+                self.0
+                    .iter()
+                    .map(|(prefix, _)| prefix.escape_ascii().to_string()),
+            )
+            .finish()
+    }
+}
+
+impl ValueTransformers {
+    /// Replaces transformer previously set for the same prefix
+    pub fn set(&mut self, prefix: Vec<u8>, transformer: Arc<dyn ValueTransformer>) {
+        self.0.retain(|(other, _)| *other != prefix);
+        self.0.push((prefix, transformer));
+    }
+
+    /// Internal keyspace belongs to subsystems and is never transformed
+    /// Whether stored values of key differ from written ones
+    pub fn transforms(&self, key: &[u8]) -> bool {
+        self.for_key(key).is_some()
+    }
+
+    fn for_key(&self, key: &[u8]) -> Option<&dyn ValueTransformer> {
+        if keyspace::is_internal_key(key) {
+            return None;
+        }
+        self.0
+            .iter()
+            .filter(|(prefix, _)| key.starts_with(prefix))
+            .max_by_key(|(prefix, _)| prefix.len())
+            .map(|(_, transformer)| transformer.as_ref())
+    }
+
+    pub fn encode(&self, key: &[u8], value: Vec<u8>) -> Result<Vec<u8>> {
+        match self.for_key(key) {
+            Some(transformer) => transformer.encode(key, value),
+            None => Ok(value),
+        }
+    }
+
+    pub fn decode(&self, key: &[u8], stored: Vec<u8>) -> Result<Vec<u8>> {
+        match self.for_key(key) {
+            Some(transformer) => transformer.decode(key, stored),
+            None => Ok(stored),
+        }
+    }
+
+    pub fn encode_batch(&self, batch: WriteBatch) -> Result<WriteBatch> {
+        if self.0.is_empty() {
+            return Ok(batch);
+        }
+        let mut ops = batch.into_ops();
+        for (key, value) in ops.iter_mut() {
+            if let Some(plain) = value.take() {
+                *value = Some(self.encode(key, plain)?);
+            }
+        }
+        Ok(WriteBatch::from_ops(ops))
+    }
+
+    pub fn decode_pairs(&self, pairs: Vec<(Vec<u8>, Vec<u8>)>) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        pairs
+            .into_iter()
+            .map(|(key, value)| {
+                let value = self.decode(&key, value)?;
+                Ok((key, value))
+            })
+            .collect()
+    }
+}
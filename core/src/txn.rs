@@ -0,0 +1,324 @@
+use crate::batch::WriteBatch;
+use crate::database::Database;
+use crate::error::DBError;
+use crate::error::Result;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::mem;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Condvar, Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+
+/// Optimistic transaction, writes are buffered until commit and applied as a single batch
+///
+/// Commit fails with `DBError::TransactionConflict` if any key read or written by transaction
+/// was committed by someone else after transaction had started.
+#[derive(Debug, Clone)]
+pub struct Txn {
+    /// database sequence at the start of transaction
+    start_sequence: u128,
+    /// keys read from database
+    reads: BTreeSet<Vec<u8>>,
+    /// key -> new value, None if corresponds to delete
+    writes: BTreeMap<Vec<u8>, Option<Vec<u8>>>,
+}
+
+impl Txn {
+    pub(crate) fn new(start_sequence: u128) -> Self {
+        Self {
+            start_sequence,
+            reads: BTreeSet::new(),
+            writes: BTreeMap::new(),
+        }
+    }
+
+    /// Reads own buffered write if any, otherwise reads database and remembers key for validation
+    pub fn get(&mut self, db: &Database, key: impl AsRef<[u8]>) -> Result<Option<Vec<u8>>> {
+        let key = key.as_ref();
+        if let Some(value) = self.writes.get(key) {
+            return Ok(value.clone());
+        }
+        self.reads.insert(key.to_vec());
+        db.query(key)
+    }
+
+    pub fn put(&mut self, key: Vec<u8>, value: Vec<u8>) {
+        self.writes.insert(key, Some(value));
+    }
+
+    pub fn delete(&mut self, key: Vec<u8>) {
+        self.writes.insert(key, None);
+    }
+
+    /// Validates every touched key and applies writes atomically, returns new database sequence
+    pub fn commit(self, db: &mut Database) -> Result<u128> {
+        for key in self.reads.iter().chain(self.writes.keys()) {
+            if db.last_commit_timestamp(key)? > self.start_sequence {
+                return Err(DBError::TransactionConflict(key.clone()));
+            }
+        }
+        let mut batch = WriteBatch::new();
+        for (key, value) in self.writes {
+            match value {
+                Some(value) => batch.put(key, value),
+                None => batch.delete(key),
+            };
+        }
+        db.write(batch)?;
+        Ok(db.last_sequence())
+    }
+
+    /// Discards buffered writes
+    pub fn rollback(self) {}
+}
+
+/// Database shared between threads running pessimistic transactions,
+/// writers take per-key locks which are held until commit or rollback
+///
+/// Deadlocks are resolved by timeouts: transaction waiting for a lock longer than
+/// lock timeout fails with `DBError::LockTimeout` and should be rolled back.
+pub struct TransactionDb {
+    db: Mutex<Database>,
+    /// key -> id of transaction holding the lock
+    locks: Mutex<HashMap<Vec<u8>, u64>>,
+    /// notified whenever locks are released
+    lock_released: Condvar,
+    lock_timeout: Duration,
+    next_txn_id: AtomicU64,
+}
+
+impl TransactionDb {
+    pub fn new(db: Database) -> Self {
+        Self {
+            db: Mutex::new(db),
+            locks: Mutex::new(HashMap::new()),
+            lock_released: Condvar::new(),
+            lock_timeout: Duration::from_secs(1),
+            next_txn_id: AtomicU64::new(0),
+        }
+    }
+
+    pub fn set_lock_timeout(mut self, timeout: Duration) -> Self {
+        self.lock_timeout = timeout;
+        self
+    }
+
+    pub fn begin(&self) -> LockingTxn<'_> {
+        LockingTxn {
+            owner: self,
+            id: self.next_txn_id.fetch_add(1, Ordering::Relaxed),
+            locked: Vec::new(),
+            writes: BTreeMap::new(),
+        }
+    }
+
+    /// Exclusive access to the underlying database for non-transactional operations
+    pub fn db(&self) -> MutexGuard<'_, Database> {
+        self.db.lock().expect("database mutex poisoned")
+    }
+
+    pub fn into_inner(self) -> Database {
+        self.db.into_inner().expect("database mutex poisoned")
+    }
+
+    /// Returns true if lock was newly taken, false if transaction already held it
+    fn lock(&self, txn_id: u64, key: &[u8]) -> Result<bool> {
+        let deadline = Instant::now() + self.lock_timeout;
+        let mut locks = self.locks.lock().expect("lock table mutex poisoned");
+        loop {
+            match locks.get(key) {
+                Some(owner) if *owner == txn_id => return Ok(false),
+                Some(_) => {
+                    let now = Instant::now();
+                    if now >= deadline {
+                        return Err(DBError::LockTimeout(key.to_vec()));
+                    }
+                    locks = self
+                        .lock_released
+                        .wait_timeout(locks, deadline - now)
+                        .expect("lock table mutex poisoned")
+                        .0;
+                }
+                None => {
+                    locks.insert(key.to_vec(), txn_id);
+                    return Ok(true);
+                }
+            }
+        }
+    }
+
+    fn unlock_all(&self, keys: &[Vec<u8>]) {
+        let mut locks = self.locks.lock().expect("lock table mutex poisoned");
+        for key in keys {
+            locks.remove(key);
+        }
+        self.lock_released.notify_all();
+    }
+}
+
+/// Pessimistic transaction, see `TransactionDb`
+///
+/// Locks are released on commit, rollback or drop.
+pub struct LockingTxn<'a> {
+    owner: &'a TransactionDb,
+    id: u64,
+    /// keys locked by transaction
+    locked: Vec<Vec<u8>>,
+    /// key -> new value, None if corresponds to delete
+    writes: BTreeMap<Vec<u8>, Option<Vec<u8>>>,
+}
+
+impl LockingTxn<'_> {
+    /// Reads own buffered write if any, otherwise reads database without locking
+    pub fn get(&self, key: impl AsRef<[u8]>) -> Result<Option<Vec<u8>>> {
+        let key = key.as_ref();
+        if let Some(value) = self.writes.get(key) {
+            return Ok(value.clone());
+        }
+        self.owner.db().query(key)
+    }
+
+    /// Locks key for the rest of transaction and buffers the write
+    pub fn put(&mut self, key: Vec<u8>, value: Vec<u8>) -> Result<()> {
+        self.lock(&key)?;
+        self.writes.insert(key, Some(value));
+        Ok(())
+    }
+
+    pub fn delete(&mut self, key: Vec<u8>) -> Result<()> {
+        self.lock(&key)?;
+        self.writes.insert(key, None);
+        Ok(())
+    }
+
+    /// Applies buffered writes atomically, returns new database sequence
+    pub fn commit(mut self) -> Result<u128> {
+        let mut batch = WriteBatch::new();
+        for (key, value) in mem::take(&mut self.writes) {
+            match value {
+                Some(value) => batch.put(key, value),
+                None => batch.delete(key),
+            };
+        }
+        let mut db = self.owner.db();
+        db.write(batch)?;
+        Ok(db.last_sequence())
+    }
+
+    pub fn rollback(self) {}
+
+    fn lock(&mut self, key: &[u8]) -> Result<()> {
+        if self.owner.lock(self.id, key)? {
+            self.locked.push(key.to_vec());
+        }
+        Ok(())
+    }
+}
+
+impl Drop for LockingTxn<'_> {
+    fn drop(&mut self) {
+        self.owner.unlock_all(&self.locked);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use std::path::PathBuf;
+
+    #[test]
+    fn detects_conflicting_commits() {
+        let test_dir = &PathBuf::from("./tests/detects_conflicting_commits");
+        if test_dir.exists() {
+            fs::remove_dir_all(test_dir).unwrap();
+        }
+        let mut db = Database::options()
+            .set_working_dir(test_dir)
+            .init()
+            .unwrap();
+        db.put(b"balance/a".to_vec(), b"10".to_vec()).unwrap();
+        db.put(b"balance/b".to_vec(), b"0".to_vec()).unwrap();
+
+        let mut transfer = db.transaction();
+        let mut concurrent = db.transaction();
+        assert_eq!(
+            transfer.get(&db, b"balance/a").unwrap(),
+            Some(b"10".to_vec())
+        );
+        transfer.put(b"balance/a".to_vec(), b"5".to_vec());
+        transfer.put(b"balance/b".to_vec(), b"5".to_vec());
+        assert_eq!(
+            transfer.get(&db, b"balance/b").unwrap(),
+            Some(b"5".to_vec())
+        );
+        assert_eq!(db.query(b"balance/b").unwrap(), Some(b"0".to_vec()));
+
+        concurrent.get(&db, b"balance/a").unwrap();
+        concurrent.delete(b"balance/a".to_vec());
+        concurrent.commit(&mut db).unwrap();
+
+        let err = transfer.commit(&mut db).unwrap_err();
+        assert!(matches!(
+            &err,
+            DBError::TransactionConflict(key) if key == b"balance/a"
+        ));
+        assert_eq!(db.query(b"balance/b").unwrap(), Some(b"0".to_vec()));
+
+        let mut retry = db.transaction();
+        assert_eq!(retry.get(&db, b"balance/a").unwrap(), None);
+        retry.put(b"balance/b".to_vec(), b"1".to_vec());
+        retry.commit(&mut db).unwrap();
+        assert_eq!(db.query(b"balance/b").unwrap(), Some(b"1".to_vec()));
+
+        let mut discarded = db.transaction();
+        discarded.put(b"balance/c".to_vec(), b"1".to_vec());
+        discarded.rollback();
+        assert_eq!(db.query(b"balance/c").unwrap(), None);
+    }
+
+    #[test]
+    fn locks_keys_until_commit() {
+        let test_dir = &PathBuf::from("./tests/locks_keys_until_commit");
+        if test_dir.exists() {
+            fs::remove_dir_all(test_dir).unwrap();
+        }
+        let db = Database::options()
+            .set_working_dir(test_dir)
+            .init()
+            .unwrap();
+        let db = TransactionDb::new(db).set_lock_timeout(Duration::from_millis(50));
+
+        let mut first = db.begin();
+        first.put(b"a".to_vec(), b"1".to_vec()).unwrap();
+        first.put(b"a".to_vec(), b"2".to_vec()).unwrap();
+        std::thread::scope(|scope| {
+            let blocked = scope.spawn(|| {
+                let mut second = db.begin();
+                second.delete(b"a".to_vec()).map(|_| ())
+            });
+            let err = blocked.join().unwrap().unwrap_err();
+            assert!(matches!(
+                &err,
+                DBError::LockTimeout(key) if key == b"a"
+            ));
+        });
+        assert_eq!(first.get(b"a").unwrap(), Some(b"2".to_vec()));
+        assert_eq!(db.db().query(b"a").unwrap(), None);
+        first.rollback();
+
+        let db = db.set_lock_timeout(Duration::from_secs(5));
+        let mut first = db.begin();
+        first.put(b"a".to_vec(), b"3".to_vec()).unwrap();
+        std::thread::scope(|scope| {
+            let waiting = scope.spawn(|| {
+                let mut second = db.begin();
+                second.put(b"a".to_vec(), b"4".to_vec()).unwrap();
+                second.commit().unwrap();
+            });
+            std::thread::sleep(Duration::from_millis(20));
+            first.commit().unwrap();
+            waiting.join().unwrap();
+        });
+        assert_eq!(db.into_inner().query(b"a").unwrap(), Some(b"4".to_vec()));
+    }
+}
@@ -0,0 +1,137 @@
+use crate::database::Database;
+use crate::error::{DBError, Result};
+use crate::keys::Key;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::any;
+use std::marker::PhantomData;
+use std::ops::{Bound, RangeBounds};
+
+/// Database with typed keys and values. Keys use order-preserving encoding of `keys` module,
+/// so scans return them in order of the key type, values are stored as json
+pub struct TypedDb<K, V> {
+    db: Database,
+    types: PhantomData<fn() -> (K, V)>,
+}
+
+impl<K: Key, V: Serialize + DeserializeOwned> TypedDb<K, V> {
+    pub fn new(db: Database) -> Self {
+        Self {
+            db,
+            types: PhantomData,
+        }
+    }
+
+    pub fn put(&mut self, key: &K, value: &V) -> Result<()> {
+        self.db.put(key.encode_key(), serde_json::to_vec(value)?)
+    }
+
+    pub fn get(&self, key: &K) -> Result<Option<V>> {
+        self.db
+            .query(key.encode_key())?
+            .map(|value| Ok(serde_json::from_slice(&value)?))
+            .transpose()
+    }
+
+    pub fn delete(&mut self, key: &K) -> Result<()> {
+        self.db.delete(key.encode_key())
+    }
+
+    /// Pairs within range in key order, fails on keys not encoded as `K`
+    pub fn scan(&self, range: impl RangeBounds<K>) -> Result<Vec<(K, V)>> {
+        let encode = |bound: Bound<&K>| bound.map(Key::encode_key);
+        let range = (encode(range.start_bound()), encode(range.end_bound()));
+        self.decode_pairs(self.db.scan(range)?)
+    }
+
+    /// Pairs whose keys start with leading segments `prefix`, e.g. `(tenant,)` of
+    /// `(tenant, timestamp)` keys
+    pub fn scan_prefix(&self, prefix: &impl Key) -> Result<Vec<(K, V)>> {
+        let start = prefix.encode_key();
+        let end = match start.iter().rposition(|byte| *byte != u8::MAX) {
+            Some(last) => {
+                let mut end = start[..=last].to_vec();
+                end[last] += 1;
+                Bound::Excluded(end)
+            }
+            None => Bound::Unbounded,
+        };
+        self.decode_pairs(self.db.scan((Bound::Included(start), end))?)
+    }
+
+    fn decode_pairs(&self, pairs: Vec<(Vec<u8>, Vec<u8>)>) -> Result<Vec<(K, V)>> {
+        pairs
+            .into_iter()
+            .map(|(key, value)| {
+                let typed = K::decode_key(&key).ok_or_else(|| {
+                    DBError::InvalidData(format!(
+                        "key `{}` is not encoded {}",
+                        key.escape_ascii(),
+                        any::type_name::<K>()
+                    ))
+                })?;
+                Ok((typed, serde_json::from_slice(&value)?))
+            })
+            .collect()
+    }
+
+    pub fn db(&mut self) -> &mut Database {
+        &mut self.db
+    }
+
+    pub fn into_inner(self) -> Database {
+        self.db
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde::Deserialize;
+    use std::fs;
+    use std::path::PathBuf;
+
+    #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+    struct Event {
+        kind: String,
+        size: u64,
+    }
+
+    #[test]
+    fn typed_pairs_round_trip_in_key_order() {
+        let test_dir = &PathBuf::from("./tests/typed_pairs_round_trip_in_key_order");
+        if test_dir.exists() {
+            fs::remove_dir_all(test_dir).unwrap();
+        }
+        let db = Database::options()
+            .set_working_dir(test_dir)
+            .init()
+            .unwrap();
+        let mut db = TypedDb::<(u64, i64), Event>::new(db);
+        let event = |size| Event {
+            kind: "write".to_string(),
+            size,
+        };
+        for (tenant, time) in [(2, -5), (1, 10), (1, -3), (2, 7), (10, 0)] {
+            db.put(&(tenant, time), &event(tenant)).unwrap();
+        }
+        db.delete(&(2, 7)).unwrap();
+        assert_eq!(db.get(&(1, 10)).unwrap(), Some(event(1)));
+        assert_eq!(db.get(&(2, 7)).unwrap(), None);
+
+        let keys = |pairs: Vec<((u64, i64), Event)>| -> Vec<_> {
+            pairs.into_iter().map(|(key, _)| key).collect()
+        };
+        assert_eq!(
+            keys(db.scan((1, 0)..(10, 0)).unwrap()),
+            vec![(1, 10), (2, -5)]
+        );
+        assert_eq!(
+            keys(db.scan_prefix(&(1u64,)).unwrap()),
+            vec![(1, -3), (1, 10)]
+        );
+
+        db.db().put(b"raw".to_vec(), b"1".to_vec()).unwrap();
+        assert!(db.scan(..).is_err());
+    }
+}
@@ -1,34 +1,224 @@
+use crate::compression;
+#[cfg(feature = "std-fs")]
+use crate::vfs::LocalStorage;
+use crate::vfs::Storage;
+use std::cmp::Ordering;
+#[cfg(feature = "std-fs")]
+use std::fs::{File, TryLockError};
+use std::io::{Read, Write};
 use std::path::{Path, PathBuf};
-use std::time::SystemTime;
-use std::{fs, io, mem};
+use std::time::{Duration, Instant, SystemTime};
+use std::{fmt, io, mem};
 
+#[cfg(feature = "std-fs")]
 pub fn scan_dir(path: impl AsRef<Path>, exts: &[&str]) -> io::Result<Vec<PathBuf>> {
-    let mut out = Vec::new();
-    for file in fs::read_dir(path)? {
-        let path = file?.path();
-        if path
-            .extension()
+    scan_storage(&LocalStorage, path.as_ref(), exts)
+}
+
+/// Files of directory in storage with one of extensions
+pub fn scan_storage(storage: &dyn Storage, dir: &Path, exts: &[&str]) -> io::Result<Vec<PathBuf>> {
+    let mut out = storage.list(dir)?;
+    out.retain(|path| {
+        path.extension()
             .and_then(|ext| ext.to_str().map(|s| exts.contains(&s)))
             .unwrap_or(false)
-        {
-            out.push(path);
+    });
+    Ok(out)
+}
+
+/// Order in which files named by `unique_storage_path` were created. Names are compared by
+/// number, as string order of names depends on their digit count
+pub fn compare_file_names(a: &Path, b: &Path) -> Ordering {
+    let number = |path: &Path| path.file_stem()?.to_str()?.parse::<u128>().ok();
+    number(a).cmp(&number(b)).then_with(|| a.cmp(b))
+}
+
+/// Takes exclusive advisory lock of file, created if missing, released once returned file is
+/// closed. Fails with `WouldBlock` while another handle holds it, in this process or another
+#[cfg(feature = "std-fs")]
+pub fn lock_file(path: impl AsRef<Path>) -> io::Result<File> {
+    let file = File::options()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(path)?;
+    match file.try_lock() {
+        Ok(()) => Ok(file),
+        Err(TryLockError::WouldBlock) => Err(io::ErrorKind::WouldBlock.into()),
+        Err(TryLockError::Error(e)) => Err(e),
+    }
+}
+
+/// Builds a path `<dir>/<timestamp>.<ext>` that doesn't exist in storage yet,
+/// timestamp is bumped on collision. Without system clock names continue after the newest
+/// file of directory
+pub fn unique_storage_path(storage: &dyn Storage, dir: &Path, ext: &str) -> PathBuf {
+    let mut timestamp = if HAS_SYSTEM_CLOCK {
+        timestamp_now()
+    } else {
+        let number = |path: &PathBuf| path.file_stem()?.to_str()?.parse::<u128>().ok();
+        let files = storage.list(dir).unwrap_or_default();
+        files
+            .iter()
+            .filter_map(number)
+            .max()
+            .map_or(0, |newest| newest + 1)
+    };
+    loop {
+        let path = dir.join(format!("{timestamp}.{ext}"));
+        if !storage.exists(&path) {
+            return path;
+        }
+        timestamp += 1;
+    }
+}
+
+/// Bad data found by file verification
+#[derive(Debug)]
+pub struct Corruption {
+    /// offset from file start in bytes
+    pub offset: u64,
+    pub error: io::Error,
+}
+
+impl Corruption {
+    pub fn new(offset: u64, message: &str) -> Self {
+        Self {
+            offset,
+            error: io::Error::new(io::ErrorKind::InvalidData, message),
         }
     }
-    Ok(out)
+}
+
+impl fmt::Display for Corruption {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "corruption at offset {}: {}", self.offset, self.error)
+    }
 }
 
 /// Common binary (de)serialization format used by wal and sstable
-/// > timestamp (16 bytes) | tombstone (1 byte) | key size (4 or 8 bytes) | value size (4 or 8 bytes) | key | value
+/// > timestamp (16 bytes) | flags (1 byte) | key size (varint) | value size (varint) | meta size (1 byte) | key | value | meta | crc32 (4 bytes)
+///
+/// Flags are `TOMBSTONE_FLAG`, `META_FLAG`, `VARINT_FLAG` and `COMPRESSED_FLAG`, value fields are
+/// present unless record is a tombstone and meta fields only if record has metadata. Sizes are
+/// LEB128 varints, records written before `VARINT_FLAG` existed store them as 4 or 8 bytes LE
+/// and are still read. Compressed value is decompressed by `read`, `parse` leaves it as stored
+#[derive(Clone)]
 pub struct CommonBinaryFormat {
     pub timestamp: u128,
     pub key: Vec<u8>,
     pub value: Option<Vec<u8>>,
+    /// user metadata of entry, empty if not set
+    pub meta: Vec<u8>,
 }
 
 pub struct CommonBinaryFormatRef<'a> {
     pub timestamp: u128,
     pub key: &'a [u8],
     pub value: Option<&'a [u8]>,
+    pub meta: &'a [u8],
+    /// value is stored compressed, see `Compression`
+    pub compressed: bool,
+}
+
+pub const TOMBSTONE_FLAG: u8 = 1;
+pub const META_FLAG: u8 = 1 << 1;
+/// Set on every written record, key and value sizes are varints instead of native usize
+pub const VARINT_FLAG: u8 = 1 << 2;
+/// Value is compressed, its first byte names the algorithm
+pub const COMPRESSED_FLAG: u8 = 1 << 3;
+/// Longest LEB128 encoding of u64
+const MAX_VARINT_SIZE: usize = 10;
+/// Metadata size is stored in a single byte
+pub const MAX_META_SIZE: usize = u8::MAX as usize;
+
+/// Checks flags byte, unknown flags are rejected so that every record has a single encoding
+fn check_flags(flags: u8) -> io::Result<()> {
+    if flags & !(TOMBSTONE_FLAG | META_FLAG | VARINT_FLAG | COMPRESSED_FLAG) != 0 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "unknown record flags",
+        ));
+    }
+    Ok(())
+}
+
+/// Number of bytes taken by LEB128 encoding of value
+fn varint_size(value: usize) -> usize {
+    (usize::BITS - value.max(1).leading_zeros()).div_ceil(7) as usize
+}
+
+fn write_varint(writer: &mut impl io::Write, mut value: usize) -> io::Result<()> {
+    let mut buffer = [0; MAX_VARINT_SIZE];
+    let mut len = 0;
+    while value >= 0x80 {
+        buffer[len] = value as u8 | 0x80;
+        value >>= 7;
+        len += 1;
+    }
+    buffer[len] = value as u8;
+    writer.write_all(&buffer[..=len])
+}
+
+/// Decodes LEB128 value at the start of data, returns it together with its encoded size.
+/// Overlong encodings are rejected so that every size has a single encoding
+fn decode_varint(data: &[u8]) -> io::Result<(usize, usize)> {
+    let invalid = |message| io::Error::new(io::ErrorKind::InvalidData, message);
+    let mut value = 0u64;
+    for (i, &byte) in data.iter().take(MAX_VARINT_SIZE).enumerate() {
+        if i == MAX_VARINT_SIZE - 1 && byte > 1 {
+            return Err(invalid("record size overflows"));
+        }
+        value |= u64::from(byte & 0x7f) << (7 * i);
+        if byte & 0x80 == 0 {
+            if byte == 0 && i > 0 {
+                return Err(invalid("overlong record size"));
+            }
+            let value = usize::try_from(value).map_err(|_| invalid("record size overflows"))?;
+            return Ok((value, i + 1));
+        }
+    }
+    match data.len() < MAX_VARINT_SIZE {
+        true => Err(io::ErrorKind::UnexpectedEof.into()),
+        false => Err(invalid("record size overflows")),
+    }
+}
+
+/// Reads record size stored as varint or as native usize in records without `VARINT_FLAG`,
+/// passing its bytes to checksum
+fn read_size(
+    reader: &mut impl io::Read,
+    flags: u8,
+    hasher: &mut crc32fast::Hasher,
+) -> io::Result<usize> {
+    if flags & VARINT_FLAG == 0 {
+        let mut buffer = [0; mem::size_of::<usize>()];
+        reader.read_exact(&mut buffer)?;
+        hasher.update(&buffer);
+        return Ok(usize::from_le_bytes(buffer));
+    }
+    let mut buffer = [0; MAX_VARINT_SIZE];
+    let mut len = 0;
+    while len < MAX_VARINT_SIZE {
+        reader.read_exact(&mut buffer[len..=len])?;
+        len += 1;
+        if buffer[len - 1] & 0x80 == 0 {
+            break;
+        }
+    }
+    hasher.update(&buffer[..len]);
+    decode_varint(&buffer[..len]).map(|(value, _)| value)
+}
+
+fn check_meta_size(size: usize) -> io::Result<()> {
+    if size == 0 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "record flagged with empty metadata",
+        ));
+    }
+    Ok(())
 }
 
 #[macro_export]
@@ -40,96 +230,415 @@ macro_rules! impl_cbf_conversion {
                     timestamp: value.timestamp,
                     key: value.key,
                     value: value.value,
+                    meta: value.meta,
                 }
             }
         }
     };
     ($other:ty) => {
-        crate::impl_cbf_conversion!(CommonBinaryFormat, $other);
-        crate::impl_cbf_conversion!($other, CommonBinaryFormat);
+        $crate::impl_cbf_conversion!(CommonBinaryFormat, $other);
+        $crate::impl_cbf_conversion!($other, CommonBinaryFormat);
     };
 }
 
 impl CommonBinaryFormat {
-    pub fn new(timestamp: u128, key: Vec<u8>, value: Option<Vec<u8>>) -> Self {
-        Self {
-            timestamp,
-            key,
-            value,
-        }
-    }
-
-    pub fn as_cbf_ref(&self) -> CommonBinaryFormatRef {
+    pub fn as_cbf_ref(&self) -> CommonBinaryFormatRef<'_> {
         CommonBinaryFormatRef {
             timestamp: self.timestamp,
             key: &self.key,
             value: self.value.as_ref().map(|vec| vec.as_ref()),
+            meta: &self.meta,
+            compressed: false,
         }
     }
 
     pub fn read(reader: &mut impl io::Read) -> io::Result<Self> {
+        Self::read_seeded(reader, 0)
+    }
+
+    /// Same as `read` for record written with `write_seeded`, records written with another
+    /// seed fail checksum
+    pub fn read_seeded(reader: &mut impl io::Read, seed: u32) -> io::Result<Self> {
+        let mut hasher = crc32fast::Hasher::new_with_initial(seed);
         let mut timestamp = [0; 16];
         reader.read_exact(&mut timestamp)?;
+        hasher.update(&timestamp);
         let timestamp = u128::from_le_bytes(timestamp);
 
-        let mut tombstone = [0; 1];
-        reader.read_exact(&mut tombstone)?;
-        let is_delete = tombstone[0] != 0;
-
-        let mut size_buffer = [0; mem::size_of::<usize>()];
-        reader.read_exact(&mut size_buffer)?;
-        let key_size = usize::from_le_bytes(size_buffer);
+        let mut flags = [0; 1];
+        reader.read_exact(&mut flags)?;
+        hasher.update(&flags);
+        check_flags(flags[0])?;
+        let is_delete = flags[0] & TOMBSTONE_FLAG != 0;
 
+        let key_size = read_size(reader, flags[0], &mut hasher)?;
         let mut value_size = 0;
         if !is_delete {
-            reader.read_exact(&mut size_buffer)?;
-            value_size = usize::from_le_bytes(size_buffer);
+            value_size = read_size(reader, flags[0], &mut hasher)?;
+        }
+
+        let mut meta_size = 0;
+        if flags[0] & META_FLAG != 0 {
+            let mut meta_size_buffer = [0; 1];
+            reader.read_exact(&mut meta_size_buffer)?;
+            hasher.update(&meta_size_buffer);
+            meta_size = meta_size_buffer[0] as usize;
+            check_meta_size(meta_size)?;
         }
 
-        let mut key = vec![0; key_size];
-        reader.read_exact(&mut key)?;
+        let key = read_sized(reader, key_size)?;
+        hasher.update(&key);
 
         let mut value = None;
         if !is_delete {
-            let mut value_data = vec![0; value_size];
-            reader.read_exact(&mut value_data)?;
+            let value_data = read_sized(reader, value_size)?;
+            hasher.update(&value_data);
             value = Some(value_data);
         }
+
+        let meta = read_sized(reader, meta_size)?;
+        hasher.update(&meta);
+
+        let mut checksum = [0; 4];
+        reader.read_exact(&mut checksum)?;
+        if u32::from_le_bytes(checksum) != hasher.finalize() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "record checksum mismatch",
+            ));
+        }
+        if flags[0] & COMPRESSED_FLAG != 0 {
+            value = value.as_deref().map(compression::decompress).transpose()?;
+        }
         Ok(Self {
             timestamp,
             key,
             value,
+            meta,
+        })
+    }
+}
+
+impl CommonBinaryFormat {
+    /// Same as `read` with value and metadata skipped, value of put is left empty. Checksum
+    /// covers skipped bytes so it's not verified
+    pub fn read_key_only(reader: &mut (impl io::Read + io::Seek)) -> io::Result<Self> {
+        let mut header = [0; 17];
+        reader.read_exact(&mut header)?;
+        let timestamp = u128::from_le_bytes(header[..16].try_into().expect("sized"));
+        let flags = header[16];
+        check_flags(flags)?;
+        let is_delete = flags & TOMBSTONE_FLAG != 0;
+
+        // checksum isn't verified here
+        let hasher = &mut crc32fast::Hasher::new();
+        let key_size = read_size(reader, flags, hasher)?;
+        let mut skipped = 0;
+        if !is_delete {
+            skipped += read_size(reader, flags, hasher)?;
+        }
+        if flags & META_FLAG != 0 {
+            let mut meta_size = [0; 1];
+            reader.read_exact(&mut meta_size)?;
+            check_meta_size(meta_size[0] as usize)?;
+            skipped += meta_size[0] as usize;
+        }
+        let key = read_sized(reader, key_size)?;
+        skip(reader, skipped.saturating_add(4))?;
+        Ok(Self {
+            timestamp,
+            key,
+            value: (!is_delete).then(Vec::new),
+            meta: Vec::new(),
         })
     }
 }
 
+/// Moves past `len` bytes, short skips are read through to keep buffer of reader
+fn skip(reader: &mut (impl io::Read + io::Seek), len: usize) -> io::Result<()> {
+    const SEEK_THRESHOLD: usize = 8192;
+    if len < SEEK_THRESHOLD {
+        let skipped = io::copy(&mut reader.by_ref().take(len as u64), &mut io::sink())?;
+        if skipped != len as u64 {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        return Ok(());
+    }
+    let offset = i64::try_from(len).map_err(|_| io::Error::from(io::ErrorKind::InvalidData))?;
+    reader.seek(io::SeekFrom::Current(offset))?;
+    Ok(())
+}
+
+/// Reads exactly `size` bytes without trusting `size` for preallocation, corrupted sizes end with eof
+fn read_sized(reader: &mut impl io::Read, size: usize) -> io::Result<Vec<u8>> {
+    let mut data = Vec::new();
+    reader.take(size as u64).read_to_end(&mut data)?;
+    if data.len() != size {
+        return Err(io::ErrorKind::UnexpectedEof.into());
+    }
+    Ok(data)
+}
+
 impl<'a> CommonBinaryFormatRef<'a> {
     pub fn new(timestamp: u128, key: &'a [u8], value: Option<&'a [u8]>) -> Self {
         Self {
             timestamp,
             key,
             value,
+            meta: &[],
+            compressed: false,
+        }
+    }
+
+    /// Marks value as compressed by `Compression::compress`
+    pub(crate) fn with_compressed_value(mut self) -> Self {
+        self.compressed = true;
+        self
+    }
+
+    /// Attaches user metadata, at most `MAX_META_SIZE` bytes
+    pub fn with_meta(mut self, meta: &'a [u8]) -> Self {
+        self.meta = meta;
+        self
+    }
+
+    /// Copies borrowed record into owned one, compressed value is copied as stored
+    pub fn into_owned(self) -> CommonBinaryFormat {
+        CommonBinaryFormat {
+            timestamp: self.timestamp,
+            key: self.key.to_vec(),
+            value: self.value.map(<[u8]>::to_vec),
+            meta: self.meta.to_vec(),
         }
     }
 
+    /// Zero-copy counterpart of `CommonBinaryFormat::read`, returns record borrowing from data
+    /// together with its encoded size
+    pub fn parse(data: &'a [u8]) -> io::Result<(Self, usize)> {
+        let take = |pos: usize, len: usize| {
+            pos.checked_add(len)
+                .and_then(|end| data.get(pos..end))
+                .ok_or_else(|| io::Error::from(io::ErrorKind::UnexpectedEof))
+        };
+        let timestamp = u128::from_le_bytes(take(0, 16)?.try_into().expect("sized"));
+        let flags = take(16, 1)?[0];
+        check_flags(flags)?;
+        let is_delete = flags & TOMBSTONE_FLAG != 0;
+        // returns size at pos together with the number of bytes it takes
+        let read_size = |pos: usize| -> io::Result<(usize, usize)> {
+            if flags & VARINT_FLAG != 0 {
+                return decode_varint(data.get(pos..).unwrap_or_default());
+            }
+            let bytes = take(pos, mem::size_of::<usize>())?;
+            let size = usize::from_le_bytes(bytes.try_into().expect("sized"));
+            Ok((size, mem::size_of::<usize>()))
+        };
+        let (key_size, len) = read_size(17)?;
+        let mut pos = 17 + len;
+        let mut value_size = 0;
+        if !is_delete {
+            let (size, len) = read_size(pos)?;
+            value_size = size;
+            pos += len;
+        }
+        let mut meta_size = 0;
+        if flags & META_FLAG != 0 {
+            meta_size = take(pos, 1)?[0] as usize;
+            check_meta_size(meta_size)?;
+            pos += 1;
+        }
+        let key = take(pos, key_size)?;
+        pos += key_size;
+        let value = if is_delete {
+            None
+        } else {
+            Some(take(pos, value_size)?)
+        };
+        pos += value_size;
+        let meta = take(pos, meta_size)?;
+        pos += meta_size;
+        let checksum = take(pos, 4)?;
+        if u32::from_le_bytes(checksum.try_into().expect("sized")) != crc32fast::hash(&data[..pos])
+        {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "record checksum mismatch",
+            ));
+        }
+        let mut record = Self::new(timestamp, key, value).with_meta(meta);
+        record.compressed = flags & COMPRESSED_FLAG != 0;
+        Ok((record, pos + 4))
+    }
+
+    /// size in bytes of serialized record
+    pub fn encoded_size(&self) -> usize {
+        let value_size = self
+            .value
+            .map_or(0, |value| varint_size(value.len()) + value.len());
+        let meta_size = if self.meta.is_empty() {
+            0
+        } else {
+            1 + self.meta.len()
+        };
+        16 + 1 + varint_size(self.key.len()) + self.key.len() + value_size + meta_size + 4
+    }
+
     pub fn write(self, writer: &mut impl io::Write) -> io::Result<()> {
+        self.write_seeded(writer, 0)
+    }
+
+    /// Same as `write` with checksum starting from `seed` instead of zero
+    pub fn write_seeded(self, writer: &mut impl io::Write, seed: u32) -> io::Result<()> {
+        let mut writer = ChecksumWriter {
+            inner: writer,
+            hasher: crc32fast::Hasher::new_with_initial(seed),
+        };
+        let meta_size = u8::try_from(self.meta.len()).map_err(|_| {
+            io::Error::new(io::ErrorKind::InvalidInput, "record metadata is too large")
+        })?;
+        let mut flags = VARINT_FLAG;
+        if self.value.is_none() {
+            flags |= TOMBSTONE_FLAG;
+        }
+        if meta_size > 0 {
+            flags |= META_FLAG;
+        }
+        if self.compressed {
+            flags |= COMPRESSED_FLAG;
+        }
         writer.write_all(&self.timestamp.to_le_bytes())?;
-        writer.write_all(&[if self.value.is_some() { 0 } else { 1 }])?;
-        writer.write_all(&self.key.len().to_le_bytes())?;
+        writer.write_all(&[flags])?;
+        write_varint(&mut writer, self.key.len())?;
         if let Some(value) = &self.value {
-            writer.write_all(&value.len().to_le_bytes())?;
+            write_varint(&mut writer, value.len())?;
+        }
+        if meta_size > 0 {
+            writer.write_all(&[meta_size])?;
         }
         writer.write_all(self.key)?;
         if let Some(value) = self.value {
             writer.write_all(value)?;
         }
-        Ok(())
+        if meta_size > 0 {
+            writer.write_all(self.meta)?;
+        }
+        let checksum = writer.hasher.finalize();
+        writer.inner.write_all(&checksum.to_le_bytes())
     }
 }
 
+/// Passes data through while accumulating crc32 of everything written
+struct ChecksumWriter<'a, W: io::Write> {
+    inner: &'a mut W,
+    hasher: crc32fast::Hasher,
+}
+
+impl<W: io::Write> io::Write for ChecksumWriter<'_, W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let written = self.inner.write(buf)?;
+        self.hasher.update(&buf[..written]);
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Whether std has clocks, `Instant` and `SystemTime` panic on wasm32-unknown-unknown
+pub(crate) const HAS_SYSTEM_CLOCK: bool = !cfg!(all(target_arch = "wasm32", target_os = "unknown"));
+
+/// Microseconds since unix epoch, zero without system clock where `Database` takes commit
+/// times from clock given to `DatabaseOptions::set_clock`
 pub fn timestamp_now() -> u128 {
+    if !HAS_SYSTEM_CLOCK {
+        return 0;
+    }
     SystemTime::now()
         .duration_since(SystemTime::UNIX_EPOCH)
         .unwrap()
         .as_micros()
 }
+
+/// Measures time since start, elapsed time is zero without system clock
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct Stopwatch(Option<Instant>);
+
+impl Stopwatch {
+    pub fn start() -> Self {
+        Self(HAS_SYSTEM_CLOCK.then(Instant::now))
+    }
+
+    pub fn elapsed(&self) -> Duration {
+        self.0.map_or(Duration::ZERO, |start| start.elapsed())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::prelude::*;
+
+    proptest! {
+        #[test]
+        fn cbf_round_trip_and_corruption(
+            timestamp: u128,
+            key: Vec<u8>,
+            value: Option<Vec<u8>>,
+            meta in prop::collection::vec(any::<u8>(), 0..=MAX_META_SIZE),
+            flipped: prop::sample::Index,
+            mask in 1..=u8::MAX,
+        ) {
+            let record = CommonBinaryFormatRef::new(timestamp, &key, value.as_deref()).with_meta(&meta);
+            let size = record.encoded_size();
+            let mut encoded = Vec::new();
+            record.write(&mut encoded).unwrap();
+            prop_assert_eq!(encoded.len(), size);
+
+            let read = CommonBinaryFormat::read(&mut encoded.as_slice()).unwrap();
+            prop_assert_eq!(read.timestamp, timestamp);
+            prop_assert_eq!(&read.key, &key);
+            prop_assert_eq!(&read.value, &value);
+            prop_assert_eq!(&read.meta, &meta);
+            let (parsed, parsed_size) = CommonBinaryFormatRef::parse(&encoded).unwrap();
+            prop_assert_eq!(parsed_size, size);
+            prop_assert_eq!(parsed.key, key.as_slice());
+            prop_assert_eq!(parsed.value, value.as_deref());
+            prop_assert_eq!(parsed.meta, meta.as_slice());
+
+            // any damaged byte is detected by checksum or framing
+            encoded[flipped.index(size)] ^= mask;
+            prop_assert!(CommonBinaryFormat::read(&mut encoded.as_slice()).is_err());
+            prop_assert!(CommonBinaryFormatRef::parse(&encoded).is_err());
+        }
+    }
+
+    #[test]
+    fn reads_records_with_fixed_size_lengths() {
+        let mut legacy = Vec::new();
+        legacy.extend_from_slice(&7u128.to_le_bytes());
+        legacy.push(0);
+        legacy.extend_from_slice(&3usize.to_le_bytes());
+        legacy.extend_from_slice(&5usize.to_le_bytes());
+        legacy.extend_from_slice(b"keyvalue");
+        legacy.extend_from_slice(&crc32fast::hash(&legacy).to_le_bytes());
+
+        let read = CommonBinaryFormat::read(&mut legacy.as_slice()).unwrap();
+        assert_eq!(read.key, b"key");
+        assert_eq!(read.value.as_deref(), Some(&b"value"[..]));
+        let (parsed, size) = CommonBinaryFormatRef::parse(&legacy).unwrap();
+        assert_eq!(
+            (parsed.timestamp, parsed.value, size),
+            (7, Some(&b"value"[..]), legacy.len())
+        );
+        let key_only = CommonBinaryFormat::read_key_only(&mut io::Cursor::new(&legacy)).unwrap();
+        assert_eq!(key_only.key, b"key");
+
+        // the same record is 14 bytes shorter with varint sizes on 64-bit targets
+        let current = CommonBinaryFormatRef::new(7, b"key", Some(b"value"));
+        assert_eq!(
+            current.encoded_size() + 2 * (mem::size_of::<usize>() - 1),
+            legacy.len()
+        );
+    }
+}
@@ -0,0 +1,59 @@
+/// Common key constraints, `validate` can be passed to `DatabaseOptions::set_key_validator`
+#[derive(Debug, Clone, Default)]
+pub struct KeyRules {
+    max_len: Option<usize>,
+    /// keys must start with one of these, any key is allowed if empty
+    allowed_prefixes: Vec<Vec<u8>>,
+    /// keys under these prefixes must be valid utf-8
+    utf8_prefixes: Vec<Vec<u8>>,
+}
+
+impl KeyRules {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn set_max_len(mut self, max_len: usize) -> Self {
+        self.max_len = Some(max_len);
+        self
+    }
+
+    pub fn set_allowed_prefixes(mut self, prefixes: Vec<Vec<u8>>) -> Self {
+        self.allowed_prefixes = prefixes;
+        self
+    }
+
+    /// Empty prefix requires utf-8 for all keys
+    pub fn set_utf8_prefixes(mut self, prefixes: Vec<Vec<u8>>) -> Self {
+        self.utf8_prefixes = prefixes;
+        self
+    }
+
+    /// Reason of rejection if key breaks any rule
+    pub fn validate(&self, key: &[u8]) -> Result<(), String> {
+        if let Some(max_len) = self.max_len.filter(|max_len| key.len() > *max_len) {
+            return Err(format!("longer than {max_len} bytes"));
+        }
+        if !self.allowed_prefixes.is_empty()
+            && !self
+                .allowed_prefixes
+                .iter()
+                .any(|prefix| key.starts_with(prefix))
+        {
+            return Err("outside of allowed prefixes".to_string());
+        }
+        if let Some(prefix) = self
+            .utf8_prefixes
+            .iter()
+            .find(|prefix| key.starts_with(prefix))
+        {
+            if std::str::from_utf8(key).is_err() {
+                return Err(format!(
+                    "not utf-8 under prefix `{}`",
+                    prefix.escape_ascii()
+                ));
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,536 @@
+#[cfg(feature = "std-fs")]
+use crate::utils;
+use std::collections::HashMap;
+#[cfg(feature = "std-fs")]
+use std::fs;
+use std::fs::File;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::{fmt, mem};
+
+/// File written sequentially and synced explicitly
+pub trait WritableFile: io::Write + Send {
+    fn sync_data(&mut self) -> io::Result<()>;
+
+    /// Reserves space for `len` bytes from file start without changing its size,
+    /// a hint that may be ignored
+    fn preallocate(&mut self, _len: u64) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// File read at arbitrary offsets
+pub trait RandomAccessFile: Send + Sync {
+    /// Reads into `buf` from `offset`, returns number of bytes read, zero at the end of file
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> io::Result<usize>;
+
+    /// size of file in bytes
+    fn size(&self) -> io::Result<u64>;
+}
+
+/// Backend holding wal and table files, lets tests keep data in memory or inject io faults.
+/// Memory mapping and direct io of tables work with local files only
+pub trait Storage: Send + Sync + fmt::Debug {
+    fn open(&self, path: &Path) -> io::Result<Box<dyn RandomAccessFile>>;
+
+    /// Opens file for appending, creates it if it doesn't exist and `create` is set
+    fn open_append(&self, path: &Path, create: bool) -> io::Result<Box<dyn WritableFile>>;
+
+    /// Creates file for appending, fails if it already exists
+    fn create_new(&self, path: &Path) -> io::Result<Box<dyn WritableFile>>;
+
+    /// Opens existing file for writing over its contents from the start, bytes past written
+    /// ones are kept. Storages that can't fail with `Unsupported` and files are not reused
+    fn open_overwrite(&self, _path: &Path) -> io::Result<Box<dyn WritableFile>> {
+        Err(io::ErrorKind::Unsupported.into())
+    }
+
+    /// Replaces existing `to` atomically
+    fn rename(&self, from: &Path, to: &Path) -> io::Result<()>;
+
+    fn delete(&self, path: &Path) -> io::Result<()>;
+
+    /// Shares contents of immutable file under another path. Storages that can't fail
+    /// with `Unsupported` and the file is copied instead
+    fn hard_link(&self, _original: &Path, _link: &Path) -> io::Result<()> {
+        Err(io::ErrorKind::Unsupported.into())
+    }
+
+    /// Copies file to `to` which must not exist and syncs the copy
+    fn copy(&self, from: &Path, to: &Path) -> io::Result<()> {
+        let mut reader = StorageReader::new(self.open(from)?);
+        let mut file = self.create_new(to)?;
+        io::copy(&mut reader, &mut file)?;
+        file.sync_data()
+    }
+
+    /// Files directly within `dir`
+    fn list(&self, dir: &Path) -> io::Result<Vec<PathBuf>>;
+
+    fn exists(&self, path: &Path) -> bool;
+
+    fn create_dir_all(&self, dir: &Path) -> io::Result<()>;
+
+    /// Makes created, renamed and deleted entries of `dir` durable, storages without
+    /// directory entries need nothing
+    fn sync_dir(&self, _dir: &Path) -> io::Result<()> {
+        Ok(())
+    }
+
+    /// Exclusive lock of file held until returned file is closed, fails with `WouldBlock`
+    /// while held elsewhere. Storages not shared between processes need no lock
+    fn lock(&self, _path: &Path) -> io::Result<Option<File>> {
+        Ok(None)
+    }
+}
+
+/// Sequential reader over random access file, buffering is left to the caller
+pub struct StorageReader {
+    file: Box<dyn RandomAccessFile>,
+    offset: u64,
+}
+
+impl StorageReader {
+    pub fn new(file: Box<dyn RandomAccessFile>) -> Self {
+        Self { file, offset: 0 }
+    }
+
+    /// Opens file of storage positioned at `offset`
+    pub fn open_at(storage: &dyn Storage, path: &Path, offset: u64) -> io::Result<Self> {
+        Ok(Self {
+            file: storage.open(path)?,
+            offset,
+        })
+    }
+
+    pub fn size(&self) -> io::Result<u64> {
+        self.file.size()
+    }
+}
+
+impl io::Read for StorageReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = self.file.read_at(buf, self.offset)?;
+        self.offset += read as u64;
+        Ok(read)
+    }
+}
+
+impl io::Seek for StorageReader {
+    fn seek(&mut self, pos: io::SeekFrom) -> io::Result<u64> {
+        let offset = match pos {
+            io::SeekFrom::Start(offset) => Some(offset),
+            io::SeekFrom::End(delta) => self.file.size()?.checked_add_signed(delta),
+            io::SeekFrom::Current(delta) => self.offset.checked_add_signed(delta),
+        };
+        self.offset = offset.ok_or_else(|| {
+            io::Error::new(io::ErrorKind::InvalidInput, "seek before start of file")
+        })?;
+        Ok(self.offset)
+    }
+}
+
+/// Storage of components not given another one, local file system unless it's compiled out
+pub(crate) fn default_storage() -> Arc<dyn Storage> {
+    #[cfg(feature = "std-fs")]
+    return Arc::new(LocalStorage);
+    #[cfg(not(feature = "std-fs"))]
+    Arc::new(MemStorage::new())
+}
+
+/// Operating system file system
+#[cfg(feature = "std-fs")]
+#[derive(Debug, Default, Clone, Copy)]
+pub struct LocalStorage;
+
+#[cfg(feature = "std-fs")]
+impl WritableFile for File {
+    fn sync_data(&mut self) -> io::Result<()> {
+        File::sync_data(self)
+    }
+
+    #[cfg(target_os = "linux")]
+    fn preallocate(&mut self, len: u64) -> io::Result<()> {
+        use std::os::fd::AsRawFd;
+        if len == 0 {
+            return Ok(());
+        }
+        let len = libc::off_t::try_from(len).unwrap_or(libc::off_t::MAX);
+        // SAFETY: descriptor is owned by file and stays open during the call
+        let result =
+            unsafe { libc::fallocate(self.as_raw_fd(), libc::FALLOC_FL_KEEP_SIZE, 0, len) };
+        if result == 0 {
+            return Ok(());
+        }
+        match io::Error::last_os_error() {
+            // file systems without fallocate allocate on write
+            e if e.raw_os_error() == Some(libc::EOPNOTSUPP) => Ok(()),
+            e => Err(e),
+        }
+    }
+}
+
+#[cfg(feature = "std-fs")]
+impl RandomAccessFile for File {
+    #[cfg(unix)]
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+        std::os::unix::fs::FileExt::read_at(self, buf, offset)
+    }
+
+    #[cfg(windows)]
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+        std::os::windows::fs::FileExt::seek_read(self, buf, offset)
+    }
+
+    fn size(&self) -> io::Result<u64> {
+        Ok(self.metadata()?.len())
+    }
+}
+
+#[cfg(feature = "std-fs")]
+impl Storage for LocalStorage {
+    fn open(&self, path: &Path) -> io::Result<Box<dyn RandomAccessFile>> {
+        Ok(Box::new(File::open(path)?))
+    }
+
+    fn open_append(&self, path: &Path, create: bool) -> io::Result<Box<dyn WritableFile>> {
+        let file = File::options().append(true).create(create).open(path)?;
+        Ok(Box::new(file))
+    }
+
+    fn create_new(&self, path: &Path) -> io::Result<Box<dyn WritableFile>> {
+        let file = File::options().write(true).create_new(true).open(path)?;
+        Ok(Box::new(file))
+    }
+
+    fn open_overwrite(&self, path: &Path) -> io::Result<Box<dyn WritableFile>> {
+        let file = File::options().write(true).open(path)?;
+        Ok(Box::new(file))
+    }
+
+    /// Rename of std replaces target on every platform, on Windows even while it's open
+    fn rename(&self, from: &Path, to: &Path) -> io::Result<()> {
+        fs::rename(from, to)
+    }
+
+    fn delete(&self, path: &Path) -> io::Result<()> {
+        fs::remove_file(path)
+    }
+
+    fn hard_link(&self, original: &Path, link: &Path) -> io::Result<()> {
+        fs::hard_link(original, link)
+    }
+
+    fn copy(&self, from: &Path, to: &Path) -> io::Result<()> {
+        fs::copy(from, to)?;
+        File::open(to)?.sync_data()
+    }
+
+    fn list(&self, dir: &Path) -> io::Result<Vec<PathBuf>> {
+        fs::read_dir(dir)?
+            .map(|entry| entry.map(|entry| entry.path()))
+            .collect()
+    }
+
+    fn exists(&self, path: &Path) -> bool {
+        path.exists()
+    }
+
+    fn create_dir_all(&self, dir: &Path) -> io::Result<()> {
+        fs::create_dir_all(dir)
+    }
+
+    /// Directories can't be opened for sync on other platforms
+    #[cfg(unix)]
+    fn sync_dir(&self, dir: &Path) -> io::Result<()> {
+        File::open(dir)?.sync_all()
+    }
+
+    fn lock(&self, path: &Path) -> io::Result<Option<File>> {
+        utils::lock_file(path).map(Some)
+    }
+}
+
+type MemFiles = Arc<Mutex<HashMap<PathBuf, Arc<Mutex<Vec<u8>>>>>>;
+
+/// Storage keeping every file in memory, directories exist implicitly. Clones share files
+#[derive(Debug, Default, Clone)]
+pub struct MemStorage {
+    files: MemFiles,
+}
+
+impl MemStorage {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, HashMap<PathBuf, Arc<Mutex<Vec<u8>>>>> {
+        self.files.lock().expect("memory storage mutex poisoned")
+    }
+
+    fn not_found(path: &Path) -> io::Error {
+        io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("{} not found", path.display()),
+        )
+    }
+}
+
+/// In-memory file, writers and readers share its content
+struct MemFile(Arc<Mutex<Vec<u8>>>);
+
+impl MemFile {
+    fn data(&self) -> std::sync::MutexGuard<'_, Vec<u8>> {
+        self.0.lock().expect("memory file mutex poisoned")
+    }
+}
+
+impl io::Write for MemFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.data().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl WritableFile for MemFile {
+    fn sync_data(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl RandomAccessFile for MemFile {
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+        let data = self.data();
+        let start = (offset as usize).min(data.len());
+        let read = buf.len().min(data.len() - start);
+        buf[..read].copy_from_slice(&data[start..start + read]);
+        Ok(read)
+    }
+
+    fn size(&self) -> io::Result<u64> {
+        Ok(self.data().len() as u64)
+    }
+}
+
+impl Storage for MemStorage {
+    fn open(&self, path: &Path) -> io::Result<Box<dyn RandomAccessFile>> {
+        let file = self.lock().get(path).cloned();
+        let file = file.ok_or_else(|| Self::not_found(path))?;
+        Ok(Box::new(MemFile(file)))
+    }
+
+    fn open_append(&self, path: &Path, create: bool) -> io::Result<Box<dyn WritableFile>> {
+        let mut files = self.lock();
+        let file = match files.get(path) {
+            Some(file) => file.clone(),
+            None if create => files.entry(path.to_path_buf()).or_default().clone(),
+            None => return Err(Self::not_found(path)),
+        };
+        Ok(Box::new(MemFile(file)))
+    }
+
+    fn create_new(&self, path: &Path) -> io::Result<Box<dyn WritableFile>> {
+        let mut files = self.lock();
+        if files.contains_key(path) {
+            return Err(io::ErrorKind::AlreadyExists.into());
+        }
+        let file = files.entry(path.to_path_buf()).or_default().clone();
+        Ok(Box::new(MemFile(file)))
+    }
+
+    fn rename(&self, from: &Path, to: &Path) -> io::Result<()> {
+        let mut files = self.lock();
+        let file = files.remove(from).ok_or_else(|| Self::not_found(from))?;
+        files.insert(to.to_path_buf(), file);
+        Ok(())
+    }
+
+    fn delete(&self, path: &Path) -> io::Result<()> {
+        let removed = self.lock().remove(path);
+        removed.map(mem::drop).ok_or_else(|| Self::not_found(path))
+    }
+
+    fn list(&self, dir: &Path) -> io::Result<Vec<PathBuf>> {
+        Ok(self
+            .lock()
+            .keys()
+            .filter(|path| path.parent() == Some(dir))
+            .cloned()
+            .collect())
+    }
+
+    fn exists(&self, path: &Path) -> bool {
+        self.lock().contains_key(path)
+    }
+
+    fn create_dir_all(&self, _dir: &Path) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(feature = "std-fs")]
+#[derive(Debug, Default)]
+struct Faults {
+    /// bytes that may be written before writes start failing
+    write_budget: Option<usize>,
+    fail_syncs: bool,
+    /// path -> length known to be on disk
+    synced: HashMap<PathBuf, u64>,
+}
+
+/// Local storage with injectable write and sync failures, also tracks synced length
+/// of every file opened for writing to simulate loss of unsynced data on power failure
+#[cfg(feature = "std-fs")]
+#[derive(Debug, Default, Clone)]
+pub struct FaultInjectionFs {
+    faults: Arc<Mutex<Faults>>,
+}
+
+#[cfg(feature = "std-fs")]
+impl FaultInjectionFs {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Writes fail after `bytes` more bytes, the write crossing the limit is cut short
+    pub fn fail_writes_after(&self, bytes: usize) {
+        self.lock().write_budget = Some(bytes);
+    }
+
+    pub fn set_fail_syncs(&self, enabled: bool) {
+        self.lock().fail_syncs = enabled;
+    }
+
+    /// Clears injected failures
+    pub fn heal(&self) {
+        let mut faults = self.lock();
+        faults.write_budget = None;
+        faults.fail_syncs = false;
+    }
+
+    /// Truncates every opened file to its synced length, as if power was lost
+    pub fn drop_unsynced(&self) -> io::Result<()> {
+        for (path, len) in self.lock().synced.iter() {
+            if path.exists() {
+                File::options().write(true).open(path)?.set_len(*len)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, Faults> {
+        self.faults.lock().expect("fault injection mutex poisoned")
+    }
+}
+
+#[cfg(feature = "std-fs")]
+impl FaultInjectionFs {
+    fn faulty(&self, file: File, path: &Path) -> io::Result<Box<dyn WritableFile>> {
+        // data present before opening is treated as durable
+        let len = file.metadata()?.len();
+        self.lock().synced.insert(path.to_path_buf(), len);
+        Ok(Box::new(FaultyFile {
+            file,
+            path: path.to_path_buf(),
+            len,
+            faults: self.faults.clone(),
+        }))
+    }
+}
+
+#[cfg(feature = "std-fs")]
+impl Storage for FaultInjectionFs {
+    fn open(&self, path: &Path) -> io::Result<Box<dyn RandomAccessFile>> {
+        LocalStorage.open(path)
+    }
+
+    fn open_append(&self, path: &Path, create: bool) -> io::Result<Box<dyn WritableFile>> {
+        let file = File::options().append(true).create(create).open(path)?;
+        self.faulty(file, path)
+    }
+
+    fn create_new(&self, path: &Path) -> io::Result<Box<dyn WritableFile>> {
+        let file = File::options().write(true).create_new(true).open(path)?;
+        self.faulty(file, path)
+    }
+
+    fn rename(&self, from: &Path, to: &Path) -> io::Result<()> {
+        LocalStorage.rename(from, to)?;
+        let mut faults = self.lock();
+        if let Some(len) = faults.synced.remove(from) {
+            faults.synced.insert(to.to_path_buf(), len);
+        }
+        Ok(())
+    }
+
+    fn delete(&self, path: &Path) -> io::Result<()> {
+        self.lock().synced.remove(path);
+        LocalStorage.delete(path)
+    }
+
+    fn list(&self, dir: &Path) -> io::Result<Vec<PathBuf>> {
+        LocalStorage.list(dir)
+    }
+
+    fn exists(&self, path: &Path) -> bool {
+        LocalStorage.exists(path)
+    }
+
+    fn create_dir_all(&self, dir: &Path) -> io::Result<()> {
+        LocalStorage.create_dir_all(dir)
+    }
+
+    fn lock(&self, path: &Path) -> io::Result<Option<File>> {
+        LocalStorage.lock(path)
+    }
+}
+
+#[cfg(feature = "std-fs")]
+struct FaultyFile {
+    file: File,
+    path: PathBuf,
+    /// bytes written so far
+    len: u64,
+    faults: Arc<Mutex<Faults>>,
+}
+
+#[cfg(feature = "std-fs")]
+impl io::Write for FaultyFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut faults = self.faults.lock().expect("fault injection mutex poisoned");
+        let allowed = match &mut faults.write_budget {
+            Some(0) => return Err(io::Error::other("injected write failure")),
+            Some(budget) => {
+                let allowed = buf.len().min(*budget);
+                *budget -= allowed;
+                allowed
+            }
+            None => buf.len(),
+        };
+        let written = self.file.write(&buf[..allowed])?;
+        self.len += written as u64;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
+
+#[cfg(feature = "std-fs")]
+impl WritableFile for FaultyFile {
+    fn sync_data(&mut self) -> io::Result<()> {
+        let mut faults = self.faults.lock().expect("fault injection mutex poisoned");
+        if faults.fail_syncs {
+            return Err(io::Error::other("injected sync failure"));
+        }
+        self.file.sync_data()?;
+        faults.synced.insert(self.path.clone(), self.len);
+        Ok(())
+    }
+}
@@ -1,69 +1,280 @@
+use crate::batch::WriteBatch;
+use crate::keyspace;
 use crate::memtable::MemTable;
-use crate::utils::{timestamp_now, CommonBinaryFormat, CommonBinaryFormatRef};
+use crate::range_del::RangeTombstone;
+use crate::trace;
+use crate::utils::{CommonBinaryFormat, CommonBinaryFormatRef, Corruption, Stopwatch};
+#[cfg(feature = "std-fs")]
+use crate::vfs::LocalStorage;
+use crate::vfs::{Storage, StorageReader, WritableFile};
 use crate::{impl_cbf_conversion, utils};
 use itertools::Itertools;
+use std::collections::VecDeque;
+#[cfg(feature = "std-fs")]
 use std::fs::File;
-use std::io::{BufReader, BufWriter, Read, Write};
+use std::io;
+use std::io::{BufReader, BufWriter, Read, Seek, Write};
+#[cfg(feature = "std-fs")]
+use std::io::{Cursor, SeekFrom};
 use std::path::{Path, PathBuf};
-use std::time::SystemTime;
-use std::{fs, io};
+use std::time::Duration;
+
+/// Subsystem key of records holding a whole serialized write batch, in wal files written
+/// before records were framed
+const BATCH_KEY: &[u8] = b"wal/batch";
+/// Subsystem key of the first record of recyclable wal written before records were framed, its
+/// value is checksum seed of the following records
+const HEADER_KEY: &[u8] = b"wal/header";
+/// First byte of every record frame
+/// > magic (1 byte) | record type (1 byte) | record in common binary format
+///
+/// Checksum of record continues from crc32 of magic and type, so a damaged type fails it.
+/// Every framed file starts with `RecordType::Header` frame holding checksum seed of the rest
+pub(crate) const RECORD_MAGIC: u8 = 0xa7;
+
+/// Type of wal record, tells readers how to apply it
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RecordType {
+    Put = 1,
+    Delete = 2,
+    /// reserved for merge operands, read as unknown type until merges are supported
+    Merge = 3,
+    /// deletion of keys within [key, value)
+    RangeDelete = 4,
+    /// starts batch, value holds number of its ops as 8 bytes LE, ops up to `BatchEnd` are
+    /// applied together or not at all
+    BatchBegin = 5,
+    BatchEnd = 6,
+    /// marks sequence at which checkpoint was taken, carries no data
+    Checkpoint = 7,
+    /// first record of every file, value holds checksum seed of the following records as 4 bytes LE
+    Header = 8,
+}
+
+impl RecordType {
+    fn from_byte(byte: u8) -> Option<Self> {
+        Some(match byte {
+            1 => Self::Put,
+            2 => Self::Delete,
+            3 => Self::Merge,
+            4 => Self::RangeDelete,
+            5 => Self::BatchBegin,
+            6 => Self::BatchEnd,
+            7 => Self::Checkpoint,
+            8 => Self::Header,
+            _ => return None,
+        })
+    }
+}
+
+/// Defines what wal readers do with records they can't apply, e.g. of types added by a newer version
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum UnknownRecordPolicy {
+    /// stop with `io::ErrorKind::Unsupported` error instead of dropping the rest of log
+    #[default]
+    Fail,
+    /// skip record, its checksum is still verified
+    Skip,
+}
 
 pub struct WriteAheadLog {
-    pub target: BufWriter<File>,
+    pub target: BufWriter<Box<dyn WritableFile>>,
     pub path: PathBuf,
+    /// checksum seed of records, zero unless wal is recyclable
+    seed: u32,
+    /// bytes written since last sync
+    unsynced_bytes: usize,
+    /// time of the oldest write that is not synced yet
+    oldest_unsynced: Option<Stopwatch>,
+}
+
+/// Defines when wal writes are forced to disk
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WalSyncPolicy {
+    /// sync after every write
+    Always,
+    /// sync once the oldest unsynced write is older than `interval` or at least
+    /// `max_unsynced_bytes` are pending, database checks the interval in background between
+    /// writes. Targets without clock like wasm32-unknown-unknown sync by size only
+    Periodic {
+        interval: Duration,
+        max_unsynced_bytes: usize,
+    },
+    /// sync only when requested explicitly
+    Manual,
+}
+
+impl Default for WalSyncPolicy {
+    fn default() -> Self {
+        Self::Periodic {
+            interval: Duration::from_secs(1),
+            max_unsynced_bytes: 1_048_576, // 1 MB
+        }
+    }
 }
 
 impl WriteAheadLog {
+    #[cfg(feature = "std-fs")]
     pub fn new(dir: impl AsRef<Path>) -> io::Result<Self> {
-        let timestamp = timestamp_now();
-        fs::create_dir_all(&dir)?;
-        let path = dir
-            .as_ref()
-            .to_path_buf()
-            .join(timestamp.to_string())
-            .with_extension("wal");
-        let file = File::options().append(true).create(true).open(&path)?;
-        let writer = BufWriter::new(file);
-        Ok(Self {
+        Self::new_with_storage(dir, &LocalStorage)
+    }
+
+    /// Same as `new` but the file is created in given storage
+    pub fn new_with_storage(dir: impl AsRef<Path>, storage: &dyn Storage) -> io::Result<Self> {
+        let dir = dir.as_ref();
+        storage.create_dir_all(dir)?;
+        let path = utils::unique_storage_path(storage, dir, "wal");
+        let writer = BufWriter::new(storage.open_append(&path, true)?);
+        storage.sync_dir(dir)?;
+        let mut wal = Self {
             target: writer,
             path,
-        })
+            seed: 0,
+            unsynced_bytes: 0,
+            oldest_unsynced: None,
+        };
+        wal.append_header(0)?;
+        wal.sync()?;
+        Ok(wal)
     }
 
+    /// Creates wal whose file can be reused once its memtable is flushed. File of such retired
+    /// wal is given as `recycled`, its records stay in place and end the log as they fail
+    /// checksum under the new seed. Space of `preallocate` bytes is reserved for new files
+    pub fn new_recyclable(
+        dir: impl AsRef<Path>,
+        storage: &dyn Storage,
+        recycled: Option<PathBuf>,
+        preallocate: u64,
+    ) -> io::Result<Self> {
+        let dir = dir.as_ref();
+        storage.create_dir_all(dir)?;
+        let path = utils::unique_storage_path(storage, dir, "wal");
+        let mut seed = 0;
+        let mut reused = None;
+        if let Some(recycled) = recycled {
+            seed = read_file_header(storage, &recycled)?.seed;
+            match storage.open_overwrite(&recycled) {
+                Ok(file) => reused = Some((file, recycled)),
+                Err(e) if e.kind() == io::ErrorKind::Unsupported => storage.delete(&recycled)?,
+                Err(e) => return Err(e),
+            }
+        }
+        let (file, reused) = match reused {
+            Some((file, recycled)) => (file, Some(recycled)),
+            None => (storage.create_new(&path)?, None),
+        };
+        let mut wal = Self {
+            target: BufWriter::new(file),
+            path,
+            seed: 0,
+            unsynced_bytes: 0,
+            oldest_unsynced: None,
+        };
+        wal.target.get_mut().preallocate(preallocate)?;
+        let seed = seed.wrapping_add(1).max(1);
+        wal.append_header(seed)?;
+        // old records must be invalidated before file is listed as wal again
+        wal.sync()?;
+        wal.seed = seed;
+        if let Some(recycled) = reused {
+            storage.rename(&recycled, &wal.path)?;
+        }
+        storage.sync_dir(dir)?;
+        Ok(wal)
+    }
+
+    #[cfg(feature = "std-fs")]
     pub fn load(path: impl AsRef<Path>) -> io::Result<Self> {
         let path = path.as_ref().to_path_buf();
-        let file = File::options().append(true).open(&path)?;
-        let writer = BufWriter::new(file);
+        let writer = BufWriter::new(LocalStorage.open_append(&path, false)?);
         Ok(Self {
             target: writer,
+            seed: read_file_header(&LocalStorage, &path)?.seed,
             path,
+            unsynced_bytes: 0,
+            oldest_unsynced: None,
         })
     }
 
+    #[cfg(feature = "std-fs")]
     pub fn load_dir(dir: impl AsRef<Path>) -> io::Result<(Self, MemTable)> {
+        Self::load_dir_with_storage(dir, &LocalStorage)
+    }
+
+    /// Same as `load_dir` over files of given storage
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(name = "recovery", skip_all, fields(dir = %dir.as_ref().display()))
+    )]
+    pub fn load_dir_with_storage(
+        dir: impl AsRef<Path>,
+        storage: &dyn Storage,
+    ) -> io::Result<(Self, MemTable)> {
         let dir = dir.as_ref();
-        fs::create_dir_all(dir)?;
-        let existing_wals: Vec<_> = utils::scan_dir(dir, &["wal"])?.into_iter().sorted().collect();
-        let mut memtable = MemTable::new();
-        let mut new_wal = WriteAheadLog::new(dir)?;
+        storage.create_dir_all(dir)?;
+        let existing_wals = utils::scan_storage(storage, dir, &["wal"])?;
+        let new_wal = WriteAheadLog::new_with_storage(dir, storage)?;
+        let policy = UnknownRecordPolicy::default();
+        Self::replay_into(new_wal, existing_wals, storage, policy)
+    }
+
+    /// Same as `load_dir_with_storage` with records replayed into given new wal of `dir`
+    pub fn load_dir_into(
+        dir: impl AsRef<Path>,
+        storage: &dyn Storage,
+        new_wal: Self,
+        policy: UnknownRecordPolicy,
+    ) -> io::Result<(Self, MemTable)> {
+        let existing_wals = utils::scan_storage(storage, dir.as_ref(), &["wal"])?
+            .into_iter()
+            .filter(|path| *path != new_wal.path)
+            .collect();
+        Self::replay_into(new_wal, existing_wals, storage, policy)
+    }
+
+    fn replay_into(
+        mut new_wal: Self,
+        existing_wals: Vec<PathBuf>,
+        storage: &dyn Storage,
+        policy: UnknownRecordPolicy,
+    ) -> io::Result<(Self, MemTable)> {
+        let memtable = MemTable::new();
         let mut remove_files = Vec::new();
 
-        for path in existing_wals {
-            for elem in Self::load(&path)?.into_iter()? {
+        for path in existing_wals
+            .into_iter()
+            .sorted_by(|a, b| utils::compare_file_names(a, b))
+        {
+            let mut entries = WriteAheadLogIterator::new_with_storage(&path, storage)?
+                .with_unknown_record_policy(policy);
+            for elem in entries.by_ref() {
                 if let Some(value) = elem.value {
-                    new_wal.put(elem.timestamp, &elem.key, &value)?;
-                    memtable.put(elem.timestamp, elem.key, value)
+                    new_wal.put_with_meta(elem.timestamp, &elem.key, &value, &elem.meta)?;
+                    memtable.put_with_meta(elem.timestamp, elem.key, value, elem.meta)
                 } else {
                     new_wal.delete(elem.timestamp, &elem.key)?;
                     memtable.delete(elem.timestamp, elem.key);
                 }
             }
+            if let Some(error) = entries.take_failure() {
+                return Err(error);
+            }
+            trace::debug!(wal = %path.display(), "replayed wal");
             remove_files.push(path);
         }
-        new_wal.flush()?;
+        new_wal.sync()?;
         for path in remove_files {
-            fs::remove_file(path)?;
+            storage.delete(&path)?;
+        }
+        if let Some(dir) = new_wal.path.parent() {
+            storage.sync_dir(dir)?;
         }
+        trace::info!(
+            entries = memtable.len(),
+            wal = %new_wal.path.display(),
+            "recovered memtable from wal"
+        );
 
         Ok((new_wal, memtable))
     }
@@ -74,24 +285,253 @@ impl WriteAheadLog {
         key: impl AsRef<[u8]>,
         value: impl AsRef<[u8]>,
     ) -> io::Result<()> {
-        CommonBinaryFormatRef::new(timestamp, key.as_ref(), Some(value.as_ref()))
-            .write(&mut self.target)?;
-        Ok(())
+        self.append(
+            RecordType::Put,
+            CommonBinaryFormatRef::new(timestamp, key.as_ref(), Some(value.as_ref())),
+        )
+    }
+
+    /// Same as `put` with user metadata attached to record
+    pub fn put_with_meta(
+        &mut self,
+        timestamp: u128,
+        key: impl AsRef<[u8]>,
+        value: impl AsRef<[u8]>,
+        meta: &[u8],
+    ) -> io::Result<()> {
+        let record = CommonBinaryFormatRef::new(timestamp, key.as_ref(), Some(value.as_ref()));
+        self.append(RecordType::Put, record.with_meta(meta))
     }
 
     pub fn delete(&mut self, timestamp: u128, key: &[u8]) -> io::Result<()> {
-        CommonBinaryFormatRef::new(timestamp, key, None).write(&mut self.target)?;
-        Ok(())
+        self.append(
+            RecordType::Delete,
+            CommonBinaryFormatRef::new(timestamp, key, None),
+        )
+    }
+
+    /// Appends ops of batch between begin and end records, readers drop batch whose end
+    /// is missing, so a torn write never leaves a part of batch in the log
+    pub fn write_batch(&mut self, timestamp: u128, batch: &WriteBatch) -> io::Result<()> {
+        let count = (batch.len() as u64).to_le_bytes();
+        let begin = CommonBinaryFormatRef::new(timestamp, &[], Some(&count));
+        self.append(RecordType::BatchBegin, begin)?;
+        for (key, value) in batch.iter() {
+            let tombstone = value.and(RangeTombstone::from_record(timestamp, key));
+            match (tombstone, value) {
+                (Some(tombstone), _) => self.append(
+                    RecordType::RangeDelete,
+                    CommonBinaryFormatRef::new(timestamp, &tombstone.start, Some(&tombstone.end)),
+                )?,
+                (None, Some(value)) => self.append(
+                    RecordType::Put,
+                    CommonBinaryFormatRef::new(timestamp, key, Some(value)),
+                )?,
+                (None, None) => self.delete(timestamp, key)?,
+            }
+        }
+        let end = CommonBinaryFormatRef::new(timestamp, &[], None);
+        self.append(RecordType::BatchEnd, end)
+    }
+
+    /// Marks in log that checkpoint was taken at sequence, readers skip the mark
+    pub fn checkpoint(&mut self, sequence: u128) -> io::Result<()> {
+        let record = CommonBinaryFormatRef::new(sequence, &[], None);
+        self.append(RecordType::Checkpoint, record)
+    }
+
+    fn append_header(&mut self, seed: u32) -> io::Result<()> {
+        let seed = seed.to_le_bytes();
+        self.append(
+            RecordType::Header,
+            CommonBinaryFormatRef::new(0, &[], Some(&seed)),
+        )
+    }
+
+    fn append(&mut self, kind: RecordType, record: CommonBinaryFormatRef) -> io::Result<()> {
+        self.unsynced_bytes += 2 + record.encoded_size();
+        self.oldest_unsynced.get_or_insert_with(Stopwatch::start);
+        self.target.write_all(&[RECORD_MAGIC, kind as u8])?;
+        record.write_seeded(&mut self.target, frame_seed(self.seed, kind as u8))
     }
 
     pub fn flush(&mut self) -> io::Result<()> {
         self.target.flush()
     }
 
+    /// Flushes buffered writes and forces them to disk
+    pub fn sync(&mut self) -> io::Result<()> {
+        self.target.flush()?;
+        self.target.get_mut().sync_data()?;
+        self.unsynced_bytes = 0;
+        self.oldest_unsynced = None;
+        Ok(())
+    }
+
+    pub fn sync_if_needed(&mut self, policy: WalSyncPolicy) -> io::Result<()> {
+        let needed = match policy {
+            WalSyncPolicy::Always => self.unsynced_bytes > 0,
+            WalSyncPolicy::Periodic {
+                interval,
+                max_unsynced_bytes,
+            } => {
+                self.unsynced_bytes >= max_unsynced_bytes
+                    || self
+                        .oldest_unsynced_age()
+                        .is_some_and(|age| age >= interval)
+            }
+            WalSyncPolicy::Manual => false,
+        };
+        if needed {
+            self.sync()?;
+        }
+        Ok(())
+    }
+
+    pub fn unsynced_bytes(&self) -> usize {
+        self.unsynced_bytes
+    }
+
+    /// Age of the oldest write that could be lost on power failure
+    pub fn oldest_unsynced_age(&self) -> Option<Duration> {
+        self.oldest_unsynced.map(|time| time.elapsed())
+    }
+
+    #[cfg(feature = "std-fs")]
+    #[allow(clippy::should_implement_trait)]
     pub fn into_iter(self) -> io::Result<impl Iterator<Item = WriteAheadLogEntry>> {
         drop(self.target);
         WriteAheadLogIterator::new(self.path)
     }
+
+    /// Reads all records of wal file along with their offsets, stops at the first bad record
+    #[cfg(feature = "std-fs")]
+    pub fn inspect(path: impl AsRef<Path>) -> io::Result<WalInspection> {
+        Self::inspect_from(path, 0)
+    }
+
+    /// Same as `inspect` but skips first `start` bytes of file, offsets are still from file start
+    #[cfg(feature = "std-fs")]
+    pub fn inspect_from(path: impl AsRef<Path>, start: u64) -> io::Result<WalInspection> {
+        let path = path.as_ref();
+        let header = read_file_header(&LocalStorage, path)?;
+        let start = start.max(header.len);
+        let mut reader = RecordReader::new(header, UnknownRecordPolicy::default());
+        let mut file = File::open(path)?;
+        file.seek(SeekFrom::Start(start))?;
+        let mut data = Vec::new();
+        file.read_to_end(&mut data)?;
+        let mut cursor = Cursor::new(data.as_slice());
+        let mut records = Vec::new();
+        let mut error = None;
+        // end of the last record outside of batch or of the last complete batch
+        let mut valid_len = 0;
+        let mut batch_start = 0;
+        while (cursor.position() as usize) < data.len() {
+            let offset = cursor.position();
+            if !reader.in_batch() {
+                batch_start = offset;
+            }
+            match reader.next(&mut cursor) {
+                Ok(Some(entries)) => {
+                    let offset = start + batch_start;
+                    records.extend(entries.into_iter().map(|entry| (offset, entry)));
+                    if !reader.in_batch() {
+                        valid_len = cursor.position();
+                    }
+                }
+                Ok(None) => break,
+                Err(e) => {
+                    error = Some(e);
+                    break;
+                }
+            }
+        }
+        if error.is_none() && reader.in_batch() {
+            error = Some(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "wal ends inside batch",
+            ));
+        }
+        Ok(WalInspection {
+            records,
+            valid_len: start + valid_len,
+            file_len: start + data.len() as u64,
+            error,
+        })
+    }
+
+    /// Streams through wal checking every record without keeping them, returns the first bad one
+    #[cfg(feature = "std-fs")]
+    pub fn verify(path: impl AsRef<Path>) -> io::Result<Option<Corruption>> {
+        Self::verify_with_storage(path, &LocalStorage, UnknownRecordPolicy::default())
+    }
+
+    /// Same as `verify` over file of given storage
+    pub fn verify_with_storage(
+        path: impl AsRef<Path>,
+        storage: &dyn Storage,
+        policy: UnknownRecordPolicy,
+    ) -> io::Result<Option<Corruption>> {
+        let header = read_file_header(storage, path.as_ref())?;
+        let file = StorageReader::open_at(storage, path.as_ref(), header.len)?;
+        let file_len = file.size()?;
+        let mut reader = BufReader::new(file);
+        let mut records = RecordReader::new(header, policy);
+        let mut batch_start = 0;
+        loop {
+            let offset = reader.stream_position()?;
+            if !records.in_batch() {
+                batch_start = offset;
+            }
+            let result = match offset >= file_len {
+                true => Ok(None),
+                false => records.next(&mut reader),
+            };
+            match result {
+                Ok(Some(_)) => {}
+                Ok(None) if records.in_batch() => {
+                    let error =
+                        io::Error::new(io::ErrorKind::UnexpectedEof, "wal ends inside batch");
+                    let offset = batch_start;
+                    return Ok(Some(Corruption { offset, error }));
+                }
+                Ok(None) => return Ok(None),
+                Err(error) => return Ok(Some(Corruption { offset, error })),
+            }
+        }
+    }
+
+    /// Trims wal file at the first bad record, returns number of bytes removed
+    #[cfg(feature = "std-fs")]
+    pub fn truncate_corrupt(path: impl AsRef<Path>) -> io::Result<u64> {
+        let inspection = Self::inspect(&path)?;
+        if inspection.is_intact() {
+            return Ok(0);
+        }
+        let file = File::options().write(true).open(path)?;
+        file.set_len(inspection.valid_len)?;
+        file.sync_all()?;
+        Ok(inspection.file_len - inspection.valid_len)
+    }
+}
+
+/// Result of reading wal file record by record
+#[derive(Debug)]
+pub struct WalInspection {
+    /// offset in file -> record, ops of a batch share offset of its begin record
+    pub records: Vec<(u64, WriteAheadLogEntry)>,
+    /// length of file prefix made of intact records
+    pub valid_len: u64,
+    pub file_len: u64,
+    /// reason why reading stopped before the end of file
+    pub error: Option<io::Error>,
+}
+
+impl WalInspection {
+    pub fn is_intact(&self) -> bool {
+        self.error.is_none()
+    }
 }
 
 impl_cbf_conversion!(WriteAheadLogEntry);
@@ -101,17 +541,252 @@ pub struct WriteAheadLogEntry {
     pub key: Vec<u8>,
     pub value: Option<Vec<u8>>,
     pub timestamp: u128,
+    pub meta: Vec<u8>,
+}
+
+/// Format of wal file, told by header frame every framed file starts with
+#[derive(Debug, Clone, Copy)]
+struct FileHeader {
+    /// false for files written before records were framed
+    framed: bool,
+    /// checksum seed of records, zero unless wal is recyclable
+    seed: u32,
+    /// size of header record, records start past it
+    len: u64,
+}
+
+/// Reads header of wal file, files written before records were framed are recognized by
+/// their first bytes failing to parse as header frame
+fn read_file_header(storage: &dyn Storage, path: &Path) -> io::Result<FileHeader> {
+    let mut reader = BufReader::new(StorageReader::open_at(storage, path, 0)?);
+    if let Ok((kind, record)) = read_frame(&mut reader, 0) {
+        let seed = record
+            .value
+            .as_deref()
+            .and_then(|seed| seed.try_into().ok());
+        if let (Some(RecordType::Header), Some(seed)) = (RecordType::from_byte(kind), seed) {
+            return Ok(FileHeader {
+                framed: true,
+                seed: u32::from_le_bytes(seed),
+                len: reader.stream_position()?,
+            });
+        }
+    }
+    reader.rewind()?;
+    let record = match CommonBinaryFormat::read(&mut reader) {
+        Ok(record) => record,
+        // file without intact first record is read as framed one, so that damaged
+        // header is reported as corruption at its start
+        Err(_) => {
+            return Ok(FileHeader {
+                framed: reader.get_ref().size()? > 0,
+                seed: 0,
+                len: 0,
+            })
+        }
+    };
+    Ok(match header_seed(&record) {
+        Some(seed) => FileHeader {
+            framed: false,
+            seed,
+            len: reader.stream_position()?,
+        },
+        None => FileHeader {
+            framed: false,
+            seed: 0,
+            len: 0,
+        },
+    })
+}
+
+/// Checksum seed of record in frame of given type
+fn frame_seed(seed: u32, kind: u8) -> u32 {
+    let mut hasher = crc32fast::Hasher::new_with_initial(seed);
+    hasher.update(&[RECORD_MAGIC, kind]);
+    hasher.finalize()
+}
+
+/// Reads frame of record, returns its type byte together with record checked against checksum
+fn read_frame(reader: &mut impl Read, seed: u32) -> io::Result<(u8, CommonBinaryFormat)> {
+    let mut frame = [0; 2];
+    reader.read_exact(&mut frame)?;
+    if frame[0] != RECORD_MAGIC {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "wal record without magic byte",
+        ));
+    }
+    let record = CommonBinaryFormat::read_seeded(reader, frame_seed(seed, frame[1]))?;
+    Ok((frame[1], record))
+}
+
+/// Reads records of wal file past its header one by one
+#[derive(Debug)]
+struct RecordReader {
+    header: FileHeader,
+    policy: UnknownRecordPolicy,
+    /// number of ops of batch whose end isn't read yet and ops read so far
+    batch: Option<(u64, Vec<WriteAheadLogEntry>)>,
+}
+
+impl RecordReader {
+    fn new(header: FileHeader, policy: UnknownRecordPolicy) -> Self {
+        Self {
+            header,
+            policy,
+            batch: None,
+        }
+    }
+
+    fn in_batch(&self) -> bool {
+        self.batch.is_some()
+    }
+
+    /// Entries of the next record, ops of batch are returned all at once with its end record.
+    /// Log of recyclable wal ends at the first bad record as records left from previous use of
+    /// the file fail checksum there
+    fn next(&mut self, reader: &mut impl Read) -> io::Result<Option<Vec<WriteAheadLogEntry>>> {
+        let seed = self.header.seed;
+        if !self.header.framed {
+            return match CommonBinaryFormat::read_seeded(reader, seed).and_then(expand_record) {
+                Ok(entries) => Ok(Some(entries)),
+                Err(_) if seed != 0 => Ok(None),
+                Err(e) => Err(e),
+            };
+        }
+        let (kind, record) = match read_frame(reader, seed) {
+            Ok(frame) => frame,
+            Err(_) if seed != 0 => return Ok(None),
+            Err(e) => return Err(e),
+        };
+        let invalid = |message| io::Error::new(io::ErrorKind::InvalidData, message);
+        let entries = match RecordType::from_byte(kind) {
+            Some(RecordType::Put | RecordType::Delete) => vec![record.into()],
+            Some(RecordType::RangeDelete) => {
+                let end = record
+                    .value
+                    .ok_or_else(|| invalid("range deletion without end"))?;
+                let key = RangeTombstone::record_key(&record.key, &end);
+                vec![WriteAheadLogEntry {
+                    key: keyspace::internal_key(&key),
+                    value: Some(Vec::new()),
+                    timestamp: record.timestamp,
+                    meta: Vec::new(),
+                }]
+            }
+            Some(RecordType::BatchBegin) => {
+                let count = record
+                    .value
+                    .as_deref()
+                    .and_then(|count| count.try_into().ok());
+                let count = count.ok_or_else(|| invalid("batch begin without ops count"))?;
+                if self.batch.is_some() {
+                    return Err(invalid("batch begins inside another batch"));
+                }
+                self.batch = Some((u64::from_le_bytes(count), Vec::new()));
+                return Ok(Some(Vec::new()));
+            }
+            Some(RecordType::BatchEnd) => {
+                let Some((count, ops)) = self.batch.take() else {
+                    return Err(invalid("batch end without begin"));
+                };
+                if ops.len() as u64 != count {
+                    return Err(invalid("batch ops don't match its count"));
+                }
+                return Ok(Some(ops));
+            }
+            Some(RecordType::Checkpoint | RecordType::Header) => Vec::new(),
+            Some(RecordType::Merge) | None => match self.policy {
+                UnknownRecordPolicy::Fail => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::Unsupported,
+                        format!("wal record of type {kind} is not supported"),
+                    ))
+                }
+                UnknownRecordPolicy::Skip => Vec::new(),
+            },
+        };
+        match &mut self.batch {
+            Some((_, ops)) => {
+                ops.extend(entries);
+                Ok(Some(Vec::new()))
+            }
+            None => Ok(Some(entries)),
+        }
+    }
+}
+
+/// Seed stored in header record of recyclable wal written before records were framed
+fn header_seed(record: &CommonBinaryFormat) -> Option<u32> {
+    if record.key.strip_prefix(keyspace::INTERNAL_KEY_PREFIX) != Some(HEADER_KEY) {
+        return None;
+    }
+    let seed = record.value.as_deref()?.try_into().ok()?;
+    Some(u32::from_le_bytes(seed))
+}
+
+/// Unpacks batch record of unframed wal into its ops stamped with batch timestamp, other records
+/// are returned as is
+fn expand_record(cbf: CommonBinaryFormat) -> io::Result<Vec<WriteAheadLogEntry>> {
+    if cbf.key.strip_prefix(keyspace::INTERNAL_KEY_PREFIX) != Some(BATCH_KEY) {
+        return Ok(vec![cbf.into()]);
+    }
+    let Some(value) = cbf.value else {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "batch record without value",
+        ));
+    };
+    let batch = WriteBatch::from_bytes(&value)?;
+    Ok(batch
+        .into_ops()
+        .into_iter()
+        .map(|(key, value)| WriteAheadLogEntry {
+            key,
+            value,
+            timestamp: cbf.timestamp,
+            meta: Vec::new(),
+        })
+        .collect())
 }
 
 pub struct WriteAheadLogIterator {
-    pub source: BufReader<File>,
+    pub source: BufReader<Box<dyn Read>>,
+    /// remaining ops of the last read batch
+    pending: VecDeque<WriteAheadLogEntry>,
+    records: RecordReader,
+    /// record of unsupported type which ended iteration, other bad records end the log
+    /// as torn writes
+    failure: Option<io::Error>,
 }
 
 impl WriteAheadLogIterator {
+    #[cfg(feature = "std-fs")]
     pub fn new(path: impl AsRef<Path>) -> io::Result<Self> {
-        let file = File::options().read(true).open(path)?;
-        let reader = BufReader::new(file);
-        Ok(Self { source: reader })
+        Self::new_with_storage(path, &LocalStorage)
+    }
+
+    pub fn new_with_storage(path: impl AsRef<Path>, storage: &dyn Storage) -> io::Result<Self> {
+        let header = read_file_header(storage, path.as_ref())?;
+        let file = StorageReader::open_at(storage, path.as_ref(), header.len)?;
+        let file: Box<dyn Read> = Box::new(file);
+        Ok(Self {
+            source: BufReader::new(file),
+            pending: VecDeque::new(),
+            records: RecordReader::new(header, UnknownRecordPolicy::default()),
+            failure: None,
+        })
+    }
+
+    pub fn with_unknown_record_policy(mut self, policy: UnknownRecordPolicy) -> Self {
+        self.records.policy = policy;
+        self
+    }
+
+    /// Error of record which couldn't be applied under `UnknownRecordPolicy::Fail`, iteration
+    /// ends at such record
+    pub fn take_failure(&mut self) -> Option<io::Error> {
+        self.failure.take()
     }
 }
 
@@ -119,22 +794,35 @@ impl Iterator for WriteAheadLogIterator {
     type Item = WriteAheadLogEntry;
 
     fn next(&mut self) -> Option<WriteAheadLogEntry> {
-        let cbf = CommonBinaryFormat::read(&mut self.source).ok()?;
-        let entry = WriteAheadLogEntry {
-            key: cbf.key,
-            value: cbf.value,
-            timestamp: cbf.timestamp,
-        };
-        Some(entry)
+        while self.pending.is_empty() {
+            match self.records.next(&mut self.source) {
+                Ok(entries) => self.pending.extend(entries?),
+                Err(e) => {
+                    if e.kind() == io::ErrorKind::Unsupported {
+                        self.failure = Some(e);
+                    }
+                    return None;
+                }
+            }
+        }
+        self.pending.pop_front()
     }
 }
 
-#[cfg(test)]
+#[cfg(all(test, feature = "std-fs"))]
 mod tests {
-    use crate::wal::{WriteAheadLog, WriteAheadLogEntry, WriteAheadLogIterator};
+    use crate::utils::scan_dir;
+    use crate::utils::CommonBinaryFormatRef;
+    use crate::vfs::LocalStorage;
+    use crate::wal::{
+        frame_seed, read_file_header, UnknownRecordPolicy, WriteAheadLog, WriteAheadLogEntry,
+        WriteAheadLogIterator, RECORD_MAGIC,
+    };
+    use proptest::prelude::*;
+    use std::collections::BTreeMap;
     use std::fs;
+    use std::io::{self, Write};
     use std::path::PathBuf;
-    use crate::utils::scan_dir;
 
     #[test]
     fn load_cycle() {
@@ -148,10 +836,10 @@ mod tests {
         wal.put(3, vec![0, 1, 0], vec![3, 3, 3]).unwrap();
         wal.put(4, vec![0, 1, 1], vec![4, 4, 4, 4]).unwrap();
         wal.put(10, vec![1, 0, 0], vec![5, 5, 5, 5, 5]).unwrap();
-        wal.delete(11, &vec![0, 1, 1]).unwrap();
-        wal.delete(25, &vec![0, 1, 0]).unwrap();
+        wal.delete(11, &[0, 1, 1]).unwrap();
+        wal.delete(25, &[0, 1, 0]).unwrap();
         wal.put(26, vec![0, 1, 1], vec![2, 1, 2]).unwrap();
-        wal.delete(30, &vec![0, 1, 1]).unwrap();
+        wal.delete(30, &[0, 1, 1]).unwrap();
         wal.flush().unwrap();
         let path = wal.path.clone();
         drop(wal);
@@ -164,41 +852,49 @@ mod tests {
                     key: vec![0, 0, 1],
                     value: Some(vec![2, 2]),
                     timestamp: 1,
+                    meta: Vec::new(),
                 },
                 WriteAheadLogEntry {
                     key: vec![0, 1, 0],
                     value: Some(vec![3, 3, 3]),
                     timestamp: 3,
+                    meta: Vec::new(),
                 },
                 WriteAheadLogEntry {
                     key: vec![0, 1, 1],
                     value: Some(vec![4, 4, 4, 4]),
                     timestamp: 4,
+                    meta: Vec::new(),
                 },
                 WriteAheadLogEntry {
                     key: vec![1, 0, 0],
                     value: Some(vec![5, 5, 5, 5, 5]),
                     timestamp: 10,
+                    meta: Vec::new(),
                 },
                 WriteAheadLogEntry {
                     key: vec![0, 1, 1],
                     value: None,
                     timestamp: 11,
+                    meta: Vec::new(),
                 },
                 WriteAheadLogEntry {
                     key: vec![0, 1, 0],
                     value: None,
                     timestamp: 25,
+                    meta: Vec::new(),
                 },
                 WriteAheadLogEntry {
                     key: vec![0, 1, 1],
                     value: Some(vec![2, 1, 2]),
                     timestamp: 26,
+                    meta: Vec::new(),
                 },
                 WriteAheadLogEntry {
                     key: vec![0, 1, 1],
                     value: None,
                     timestamp: 30,
+                    meta: Vec::new(),
                 },
             ],
             elems
@@ -230,6 +926,170 @@ mod tests {
 
         let (dir_wal, dir_memtable) = WriteAheadLog::load_dir(test_dir).unwrap();
         assert!(dir_wal.path.exists());
-        assert_eq!(dir_memtable.entries.len(), 6);
+        assert_eq!(dir_memtable.len(), 6);
+    }
+
+    #[test]
+    fn truncates_corrupt_tail() {
+        let test_dir = &PathBuf::from("./tests/truncates_corrupt_tail");
+        if test_dir.exists() {
+            fs::remove_dir_all(test_dir).unwrap();
+        }
+        let mut wal = WriteAheadLog::new(test_dir).unwrap();
+        wal.put(1, vec![0, 0, 1], vec![1, 10]).unwrap();
+        wal.put(2, vec![0, 1, 0], vec![2, 20]).unwrap();
+        wal.put(3, vec![0, 1, 1], vec![3, 30]).unwrap();
+        wal.flush().unwrap();
+        let path = wal.path.clone();
+        drop(wal);
+
+        let intact = WriteAheadLog::inspect(&path).unwrap();
+        assert!(intact.is_intact());
+        assert_eq!(intact.records.len(), 3);
+        let header = read_file_header(&LocalStorage, &path).unwrap();
+        assert_eq!(intact.records[0].0, header.len);
+
+        let mut data = fs::read(&path).unwrap();
+        let third_offset = intact.records[2].0 as usize;
+        *data.last_mut().unwrap() ^= 0xff;
+        fs::write(&path, data).unwrap();
+
+        let corrupt = WriteAheadLog::inspect(&path).unwrap();
+        assert!(!corrupt.is_intact());
+        assert_eq!(corrupt.records.len(), 2);
+        assert_eq!(corrupt.valid_len, third_offset as u64);
+
+        let removed = WriteAheadLog::truncate_corrupt(&path).unwrap();
+        assert_eq!(removed, intact.file_len - third_offset as u64);
+        assert!(WriteAheadLog::inspect(&path).unwrap().is_intact());
+    }
+
+    #[test]
+    fn unknown_records_follow_policy() {
+        let test_dir = &PathBuf::from("./tests/unknown_records_follow_policy");
+        if test_dir.exists() {
+            fs::remove_dir_all(test_dir).unwrap();
+        }
+        let mut wal = WriteAheadLog::new(test_dir).unwrap();
+        wal.put(1, vec![1], vec![10]).unwrap();
+        // record of kind added by newer writer
+        wal.target.write_all(&[RECORD_MAGIC, 99]).unwrap();
+        CommonBinaryFormatRef::new(2, &[2], Some(&[20]))
+            .write_seeded(&mut wal.target, frame_seed(0, 99))
+            .unwrap();
+        wal.put(3, vec![3], vec![30]).unwrap();
+        wal.flush().unwrap();
+        let path = wal.path.clone();
+        drop(wal);
+
+        let mut iter = WriteAheadLogIterator::new(&path).unwrap();
+        assert_eq!(iter.by_ref().count(), 1);
+        let failure = iter.take_failure().unwrap();
+        assert_eq!(failure.kind(), io::ErrorKind::Unsupported);
+
+        let keys: Vec<_> = WriteAheadLogIterator::new(&path)
+            .unwrap()
+            .with_unknown_record_policy(UnknownRecordPolicy::Skip)
+            .map(|entry| entry.key)
+            .collect();
+        assert_eq!(keys, vec![vec![1], vec![3]]);
+    }
+
+    #[test]
+    fn recycled_file_ends_at_stale_records() {
+        let test_dir = &PathBuf::from("./tests/recycled_file_ends_at_stale_records");
+        if test_dir.exists() {
+            fs::remove_dir_all(test_dir).unwrap();
+        }
+        let mut wal = WriteAheadLog::new_recyclable(test_dir, &LocalStorage, None, 4096).unwrap();
+        for i in 0..3u8 {
+            wal.put(i as u128, vec![i], vec![i; 10]).unwrap();
+        }
+        wal.sync().unwrap();
+        let retired = test_dir.join("1.recycled");
+        fs::rename(&wal.path, &retired).unwrap();
+        drop(wal);
+
+        let mut wal =
+            WriteAheadLog::new_recyclable(test_dir, &LocalStorage, Some(retired.clone()), 0)
+                .unwrap();
+        wal.put(5, vec![5], vec![5; 10]).unwrap();
+        wal.sync().unwrap();
+        assert!(!retired.exists());
+        let path = wal.path.clone();
+        drop(wal);
+
+        // the second and third records of previous use stay in file
+        let inspection = WriteAheadLog::inspect(&path).unwrap();
+        assert!(inspection.is_intact());
+        assert!(inspection.valid_len < inspection.file_len);
+        let keys: Vec<_> = inspection
+            .records
+            .iter()
+            .map(|(_, e)| e.key.clone())
+            .collect();
+        assert_eq!(keys, vec![vec![5]]);
+        assert!(WriteAheadLog::verify(&path).unwrap().is_none());
+        let replayed: Vec<_> = WriteAheadLog::load(&path)
+            .unwrap()
+            .into_iter()
+            .unwrap()
+            .collect();
+        assert_eq!(replayed.len(), 1);
+    }
+
+    proptest! {
+        #![proptest_config(ProptestConfig::with_cases(32))]
+
+        #[test]
+        fn replays_arbitrary_writes(
+            ops in prop::collection::vec(
+                (prop::collection::vec(0..4u8, 0..3), prop::option::of(prop::collection::vec(any::<u8>(), 0..16))),
+                0..64,
+            ),
+        ) {
+            let test_dir = &PathBuf::from("./tests/replays_arbitrary_writes");
+            if test_dir.exists() {
+                fs::remove_dir_all(test_dir).unwrap();
+            }
+            let mut wal = WriteAheadLog::new(test_dir).unwrap();
+            let mut expected = Vec::new();
+            for (timestamp, (key, value)) in ops.into_iter().enumerate() {
+                let timestamp = timestamp as u128 + 1;
+                match &value {
+                    Some(value) => wal.put(timestamp, &key, value).unwrap(),
+                    None => wal.delete(timestamp, &key).unwrap(),
+                }
+                expected.push(WriteAheadLogEntry {
+                    key,
+                    value,
+                    timestamp,
+                    meta: Vec::new(),
+                });
+            }
+            wal.flush().unwrap();
+            drop(wal);
+
+            let replayed: Vec<_> = WriteAheadLog::load(&scan_dir(test_dir, &["wal"]).unwrap()[0])
+                .unwrap()
+                .into_iter()
+                .unwrap()
+                .collect();
+            prop_assert_eq!(&replayed, &expected);
+
+            let newest: BTreeMap<_, _> = expected
+                .into_iter()
+                .map(|entry| (entry.key, (entry.timestamp, entry.value)))
+                .collect();
+            let (_, memtable) = WriteAheadLog::load_dir(test_dir).unwrap();
+            let recovered: BTreeMap<_, _> = memtable
+                .iter()
+                .map(|entry| {
+                    let value = entry.value.map(<[u8]>::to_vec);
+                    (entry.key.to_vec(), (entry.timestamp, value))
+                })
+                .collect();
+            prop_assert_eq!(recovered, newest);
+        }
     }
 }
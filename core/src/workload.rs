@@ -0,0 +1,206 @@
+use std::io::{self, Read, Write};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+
+/// First bytes of every trace
+const TRACE_MAGIC: &[u8; 8] = b"lsmtrace";
+
+/// Kind of traced operation
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TraceOp {
+    Get,
+    Put,
+    Delete,
+    /// deletion of keys within [key, end)
+    DeleteRange {
+        end: Vec<u8>,
+    },
+    /// scan from key, value size of record is the number of entries read
+    Scan,
+}
+
+/// Operation of workload trace, values are recorded by size only
+///
+/// Serialized layout:
+/// > timestamp in micros (8 bytes) | op (1 byte) | key size (4 bytes) | key | value size (8 bytes) | end size (4 bytes) | end
+///
+/// End fields are present only for range deletions
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TraceRecord {
+    /// time since start of trace
+    pub timestamp: Duration,
+    pub op: TraceOp,
+    pub key: Vec<u8>,
+    pub value_size: u64,
+}
+
+impl TraceRecord {
+    /// Reads the next record, none at the end of trace
+    pub fn read(reader: &mut impl Read) -> io::Result<Option<Self>> {
+        let mut timestamp = [0; 8];
+        if reader.read(&mut timestamp[..1])? == 0 {
+            return Ok(None);
+        }
+        reader.read_exact(&mut timestamp[1..])?;
+        let mut tag = [0];
+        reader.read_exact(&mut tag)?;
+        let key = read_bytes(reader)?;
+        let mut value_size = [0; 8];
+        reader.read_exact(&mut value_size)?;
+        let op = match tag[0] {
+            0 => TraceOp::Get,
+            1 => TraceOp::Put,
+            2 => TraceOp::Delete,
+            3 => TraceOp::DeleteRange {
+                end: read_bytes(reader)?,
+            },
+            4 => TraceOp::Scan,
+            _ => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "unknown trace operation",
+                ))
+            }
+        };
+        Ok(Some(Self {
+            timestamp: Duration::from_micros(u64::from_le_bytes(timestamp)),
+            op,
+            key,
+            value_size: u64::from_le_bytes(value_size),
+        }))
+    }
+}
+
+fn write_record(
+    writer: &mut impl Write,
+    timestamp: Duration,
+    op: &TraceOp,
+    key: &[u8],
+    value_size: u64,
+) -> io::Result<()> {
+    let tag: u8 = match op {
+        TraceOp::Get => 0,
+        TraceOp::Put => 1,
+        TraceOp::Delete => 2,
+        TraceOp::DeleteRange { .. } => 3,
+        TraceOp::Scan => 4,
+    };
+    writer.write_all(&(timestamp.as_micros() as u64).to_le_bytes())?;
+    writer.write_all(&[tag])?;
+    write_bytes(writer, key)?;
+    writer.write_all(&value_size.to_le_bytes())?;
+    if let TraceOp::DeleteRange { end } = op {
+        write_bytes(writer, end)?;
+    }
+    Ok(())
+}
+
+fn write_bytes(writer: &mut impl Write, bytes: &[u8]) -> io::Result<()> {
+    let size = u32::try_from(bytes.len())
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "traced key is too large"))?;
+    writer.write_all(&size.to_le_bytes())?;
+    writer.write_all(bytes)
+}
+
+fn read_bytes(reader: &mut impl Read) -> io::Result<Vec<u8>> {
+    let mut size = [0; 4];
+    reader.read_exact(&mut size)?;
+    let size = u32::from_le_bytes(size) as usize;
+    let mut bytes = Vec::new();
+    reader.take(size as u64).read_to_end(&mut bytes)?;
+    if bytes.len() != size {
+        return Err(io::ErrorKind::UnexpectedEof.into());
+    }
+    Ok(bytes)
+}
+
+/// Checks that reader starts with a trace
+pub(crate) fn read_header(reader: &mut impl Read) -> io::Result<()> {
+    let mut magic = [0; TRACE_MAGIC.len()];
+    reader.read_exact(&mut magic)?;
+    if &magic != TRACE_MAGIC {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "not a workload trace",
+        ));
+    }
+    Ok(())
+}
+
+/// Value of given size written in place of traced one on replay
+pub(crate) fn filler(size: u64) -> Vec<u8> {
+    vec![b'x'; size as usize]
+}
+
+struct ActiveTrace {
+    writer: io::BufWriter<Box<dyn Write + Send>>,
+    start: Instant,
+    /// first failed write, the rest of trace is dropped
+    error: Option<io::Error>,
+}
+
+/// Trace in progress, shared by readers and writers of database. Failed write to trace doesn't
+/// fail the traced operation, it is returned when trace ends
+#[derive(Default)]
+pub(crate) struct Tracer {
+    active: AtomicBool,
+    trace: Mutex<Option<ActiveTrace>>,
+}
+
+impl Tracer {
+    /// Starts tracing into writer, trace in progress is ended and its error is returned
+    pub fn start(&self, writer: Box<dyn Write + Send>) -> io::Result<()> {
+        let mut writer = io::BufWriter::new(writer);
+        writer.write_all(TRACE_MAGIC)?;
+        let previous = self.lock().replace(ActiveTrace {
+            writer,
+            start: Instant::now(),
+            error: None,
+        });
+        self.active.store(true, Ordering::Release);
+        previous.map_or(Ok(()), finish)
+    }
+
+    pub fn end(&self) -> io::Result<()> {
+        self.active.store(false, Ordering::Release);
+        self.lock().take().map_or(Ok(()), finish)
+    }
+
+    /// Whether operations are traced, lets callers skip building records
+    pub fn is_active(&self) -> bool {
+        self.active.load(Ordering::Acquire)
+    }
+
+    pub fn record(&self, op: TraceOp, key: &[u8], value_size: u64) {
+        if !self.is_active() {
+            return;
+        }
+        let mut trace = self.lock();
+        let Some(trace) = trace.as_mut().filter(|trace| trace.error.is_none()) else {
+            return;
+        };
+        let timestamp = trace.start.elapsed();
+        if let Err(error) = write_record(&mut trace.writer, timestamp, &op, key, value_size) {
+            trace.error = Some(error);
+        }
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Option<ActiveTrace>> {
+        self.trace.lock().expect("tracer mutex poisoned")
+    }
+}
+
+fn finish(mut trace: ActiveTrace) -> io::Result<()> {
+    match trace.error {
+        Some(error) => Err(error),
+        None => trace.writer.flush(),
+    }
+}
+
+/// Summary of replayed trace
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ReplayReport {
+    pub operations: u64,
+    pub elapsed: Duration,
+}
@@ -0,0 +1,52 @@
+/* C ABI of lsm-db, link against liblsmdb built from the lsmdb-ffi crate */
+#ifndef LSMDB_H
+#define LSMDB_H
+
+#include <stddef.h>
+#include <stdint.h>
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+typedef enum {
+    LSMDB_OK = 0,
+    LSMDB_NOT_FOUND = 1,
+    LSMDB_END = 2,
+    LSMDB_INVALID_ARGUMENT = 3,
+    LSMDB_IO = 4,
+    LSMDB_CORRUPTION = 5,
+    LSMDB_BUSY = 6,
+    LSMDB_INTERNAL = 7,
+} lsmdb_status;
+
+typedef struct LsmdbDb lsmdb_db;
+typedef struct LsmdbIter lsmdb_iter;
+
+lsmdb_status lsmdb_open(const char *path, lsmdb_db **out);
+void lsmdb_close(lsmdb_db *db);
+
+lsmdb_status lsmdb_put(lsmdb_db *db, const uint8_t *key, size_t key_len,
+                       const uint8_t *value, size_t value_len);
+/* value is released with lsmdb_free */
+lsmdb_status lsmdb_get(const lsmdb_db *db, const uint8_t *key, size_t key_len,
+                       uint8_t **value_out, size_t *value_len_out);
+lsmdb_status lsmdb_delete(lsmdb_db *db, const uint8_t *key, size_t key_len);
+void lsmdb_free(uint8_t *data, size_t len);
+
+/* keys in [start, end), NULL bound is unbounded */
+lsmdb_status lsmdb_iter_new(const lsmdb_db *db, const uint8_t *start, size_t start_len,
+                            const uint8_t *end, size_t end_len, lsmdb_iter **out);
+/* key and value stay valid until the next call, LSMDB_END once exhausted */
+lsmdb_status lsmdb_iter_next(lsmdb_iter *iter, const uint8_t **key_out, size_t *key_len_out,
+                             const uint8_t **value_out, size_t *value_len_out);
+void lsmdb_iter_free(lsmdb_iter *iter);
+
+/* message of the last failed call on this thread */
+const char *lsmdb_last_error(void);
+
+#ifdef __cplusplus
+}
+#endif
+
+#endif /* LSMDB_H */
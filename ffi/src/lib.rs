@@ -0,0 +1,392 @@
+//! C ABI of the database, see `include/lsmdb.h` for the matching declarations
+//!
+//! Database and iterators are opaque handles owned by the caller until passed to their
+//! free functions. Every call returns `LsmdbStatus`, message of the last failure on the
+//! calling thread is available from `lsmdb_last_error`. Byte strings are passed as
+//! pointer and length, null pointer is accepted for zero length.
+
+use lsm_db_core::{DBError, Database};
+use std::cell::RefCell;
+use std::ffi::{c_char, CStr, CString};
+use std::ops::Bound;
+use std::panic::{self, AssertUnwindSafe};
+use std::{ptr, slice, vec};
+
+/// Pairs fetched by iterator from database at once
+const ITER_PAGE_SIZE: usize = 256;
+
+thread_local! {
+    static LAST_ERROR: RefCell<CString> = RefCell::new(CString::default());
+}
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LsmdbStatus {
+    Ok = 0,
+    /// key is missing
+    NotFound = 1,
+    /// iterator is exhausted
+    End = 2,
+    /// null handle, bad utf-8 path or key rejected by database
+    InvalidArgument = 3,
+    Io = 4,
+    Corruption = 5,
+    /// working directory is locked by another instance or database is busy
+    Busy = 6,
+    /// any other failure including panics
+    Internal = 7,
+}
+
+impl From<&DBError> for LsmdbStatus {
+    fn from(error: &DBError) -> Self {
+        match error {
+            DBError::Io { .. } => Self::Io,
+            DBError::MalformedSSTable
+            | DBError::CorruptedTable { .. }
+            | DBError::Corruption { .. }
+            | DBError::SstKeyRangeMismatch(_)
+            | DBError::InvalidData(_) => Self::Corruption,
+            DBError::PermissionDenied(_)
+            | DBError::ReservedKey(_)
+            | DBError::InvalidKey { .. }
+            | DBError::MetaTooLarge(_)
+            | DBError::InvalidOptions(_) => Self::InvalidArgument,
+            DBError::LockHeld(_) | DBError::Busy(_) => Self::Busy,
+            _ => Self::Internal,
+        }
+    }
+}
+
+/// Opaque database handle
+pub struct LsmdbDb(Database);
+
+/// Opaque iterator over live pairs of key range in key order, reads database page by page
+pub struct LsmdbIter {
+    db: *const Database,
+    range: (Bound<Vec<u8>>, Bound<Vec<u8>>),
+    page: vec::IntoIter<(Vec<u8>, Vec<u8>)>,
+    /// continuation of the next page, None once range is exhausted
+    after: Option<Vec<u8>>,
+    started: bool,
+    /// pair returned by the last `lsmdb_iter_next`, its buffers are lent to the caller
+    current: Option<(Vec<u8>, Vec<u8>)>,
+}
+
+fn set_last_error(message: String) {
+    let message = CString::new(message.replace('\0', " ")).expect("nul bytes are replaced");
+    LAST_ERROR.with(|last| *last.borrow_mut() = message);
+}
+
+/// Runs call body, failures are recorded as the last error of the thread
+fn guarded(body: impl FnOnce() -> Result<LsmdbStatus, DBError>) -> LsmdbStatus {
+    match panic::catch_unwind(AssertUnwindSafe(body)) {
+        Ok(Ok(status)) => status,
+        Ok(Err(error)) => {
+            let status = LsmdbStatus::from(&error);
+            let mut message = error.to_string();
+            let mut source = std::error::Error::source(&error);
+            while let Some(cause) = source {
+                message = format!("{message}: {cause}");
+                source = cause.source();
+            }
+            set_last_error(message);
+            status
+        }
+        Err(_) => {
+            set_last_error("panic inside database call".to_string());
+            LsmdbStatus::Internal
+        }
+    }
+}
+
+fn invalid_argument(message: &str) -> LsmdbStatus {
+    set_last_error(message.to_string());
+    LsmdbStatus::InvalidArgument
+}
+
+/// Borrows byte string passed by caller
+unsafe fn bytes<'a>(data: *const u8, len: usize) -> &'a [u8] {
+    match data.is_null() || len == 0 {
+        true => &[],
+        false => slice::from_raw_parts(data, len),
+    }
+}
+
+/// Hands buffer over to caller, it's released by `lsmdb_free`
+unsafe fn give_bytes(data: Vec<u8>, out: *mut *mut u8, out_len: *mut usize) {
+    let data = Box::into_raw(data.into_boxed_slice());
+    *out_len = data.len();
+    *out = data.cast();
+}
+
+/// Opens or creates database in directory `path` with default options
+///
+/// # Safety
+/// `path` is a nul-terminated string and `out` is valid for writes
+#[no_mangle]
+pub unsafe extern "C" fn lsmdb_open(path: *const c_char, out: *mut *mut LsmdbDb) -> LsmdbStatus {
+    if path.is_null() || out.is_null() {
+        return invalid_argument("path and out must not be null");
+    }
+    let Ok(path) = CStr::from_ptr(path).to_str() else {
+        return invalid_argument("path is not valid utf-8");
+    };
+    guarded(|| {
+        let db = Database::options().set_working_dir(path).init()?;
+        *out = Box::into_raw(Box::new(LsmdbDb(db)));
+        Ok(LsmdbStatus::Ok)
+    })
+}
+
+/// Closes database, null is ignored. Iterators of database must be freed before
+///
+/// # Safety
+/// `db` comes from `lsmdb_open` and is not used afterwards
+#[no_mangle]
+pub unsafe extern "C" fn lsmdb_close(db: *mut LsmdbDb) {
+    if !db.is_null() {
+        let db = Box::from_raw(db);
+        let _ = panic::catch_unwind(AssertUnwindSafe(|| drop(db)));
+    }
+}
+
+/// # Safety
+/// `db` is an open handle, `key` and `value` point to `key_len` and `value_len` bytes
+#[no_mangle]
+pub unsafe extern "C" fn lsmdb_put(
+    db: *mut LsmdbDb,
+    key: *const u8,
+    key_len: usize,
+    value: *const u8,
+    value_len: usize,
+) -> LsmdbStatus {
+    let Some(LsmdbDb(db)) = db.as_mut() else {
+        return invalid_argument("db must not be null");
+    };
+    let (key, value) = (
+        bytes(key, key_len).to_vec(),
+        bytes(value, value_len).to_vec(),
+    );
+    guarded(|| {
+        db.put(key, value)?;
+        Ok(LsmdbStatus::Ok)
+    })
+}
+
+/// Looks up key, value is handed over to caller and has to be released with `lsmdb_free`.
+/// Returns `NotFound` for missing key leaving outputs untouched
+///
+/// # Safety
+/// `db` is an open handle, `key` points to `key_len` bytes, outputs are valid for writes
+#[no_mangle]
+pub unsafe extern "C" fn lsmdb_get(
+    db: *const LsmdbDb,
+    key: *const u8,
+    key_len: usize,
+    value_out: *mut *mut u8,
+    value_len_out: *mut usize,
+) -> LsmdbStatus {
+    let Some(LsmdbDb(db)) = db.as_ref() else {
+        return invalid_argument("db must not be null");
+    };
+    if value_out.is_null() || value_len_out.is_null() {
+        return invalid_argument("value outputs must not be null");
+    }
+    let key = bytes(key, key_len);
+    guarded(|| match db.query(key)? {
+        Some(value) => {
+            give_bytes(value, value_out, value_len_out);
+            Ok(LsmdbStatus::Ok)
+        }
+        None => Ok(LsmdbStatus::NotFound),
+    })
+}
+
+/// Deleting a missing key succeeds
+///
+/// # Safety
+/// `db` is an open handle, `key` points to `key_len` bytes
+#[no_mangle]
+pub unsafe extern "C" fn lsmdb_delete(
+    db: *mut LsmdbDb,
+    key: *const u8,
+    key_len: usize,
+) -> LsmdbStatus {
+    let Some(LsmdbDb(db)) = db.as_mut() else {
+        return invalid_argument("db must not be null");
+    };
+    let key = bytes(key, key_len).to_vec();
+    guarded(|| {
+        db.delete(key)?;
+        Ok(LsmdbStatus::Ok)
+    })
+}
+
+/// Releases buffer returned by `lsmdb_get`, null is ignored
+///
+/// # Safety
+/// `data` and `len` are exactly as returned and the buffer is not used afterwards
+#[no_mangle]
+pub unsafe extern "C" fn lsmdb_free(data: *mut u8, len: usize) {
+    if !data.is_null() {
+        drop(Box::from_raw(ptr::slice_from_raw_parts_mut(data, len)));
+    }
+}
+
+/// Iterator over keys in [start, end), null bound is unbounded. Writes made while iterating
+/// may or may not be seen, database must not be closed before iterator is freed
+///
+/// # Safety
+/// `db` is an open handle, non-null bounds point to their lengths in bytes, `out` is valid
+/// for writes
+#[no_mangle]
+pub unsafe extern "C" fn lsmdb_iter_new(
+    db: *const LsmdbDb,
+    start: *const u8,
+    start_len: usize,
+    end: *const u8,
+    end_len: usize,
+    out: *mut *mut LsmdbIter,
+) -> LsmdbStatus {
+    let Some(LsmdbDb(db)) = db.as_ref() else {
+        return invalid_argument("db must not be null");
+    };
+    if out.is_null() {
+        return invalid_argument("out must not be null");
+    }
+    let bound = |data: *const u8, len| match data.is_null() {
+        true => Bound::Unbounded,
+        false => Bound::Included(bytes(data, len).to_vec()),
+    };
+    let range = match bound(end, end_len) {
+        Bound::Included(end) => (bound(start, start_len), Bound::Excluded(end)),
+        unbounded => (bound(start, start_len), unbounded),
+    };
+    *out = Box::into_raw(Box::new(LsmdbIter {
+        db,
+        range,
+        page: Vec::new().into_iter(),
+        after: None,
+        started: false,
+        current: None,
+    }));
+    LsmdbStatus::Ok
+}
+
+/// Advances iterator, key and value stay valid until the next call or free of iterator.
+/// Returns `End` once range is exhausted
+///
+/// # Safety
+/// `iter` comes from `lsmdb_iter_new`, outputs are valid for writes
+#[no_mangle]
+pub unsafe extern "C" fn lsmdb_iter_next(
+    iter: *mut LsmdbIter,
+    key_out: *mut *const u8,
+    key_len_out: *mut usize,
+    value_out: *mut *const u8,
+    value_len_out: *mut usize,
+) -> LsmdbStatus {
+    let Some(iter) = iter.as_mut() else {
+        return invalid_argument("iter must not be null");
+    };
+    if key_out.is_null() || key_len_out.is_null() || value_out.is_null() || value_len_out.is_null()
+    {
+        return invalid_argument("outputs must not be null");
+    }
+    guarded(|| {
+        iter.current = iter.page.next();
+        if iter.current.is_none() && (!iter.started || iter.after.is_some()) {
+            let db = &*iter.db;
+            let page = db.scan_page(iter.range.clone(), ITER_PAGE_SIZE, iter.after.take())?;
+            iter.started = true;
+            iter.after = page.continuation;
+            iter.page = page.entries.into_iter();
+            iter.current = iter.page.next();
+        }
+        let Some((key, value)) = &iter.current else {
+            return Ok(LsmdbStatus::End);
+        };
+        (*key_out, *key_len_out) = (key.as_ptr(), key.len());
+        (*value_out, *value_len_out) = (value.as_ptr(), value.len());
+        Ok(LsmdbStatus::Ok)
+    })
+}
+
+/// Null is ignored
+///
+/// # Safety
+/// `iter` comes from `lsmdb_iter_new` and is not used afterwards
+#[no_mangle]
+pub unsafe extern "C" fn lsmdb_iter_free(iter: *mut LsmdbIter) {
+    if !iter.is_null() {
+        drop(Box::from_raw(iter));
+    }
+}
+
+/// Message of the last failed call on this thread, empty if none failed. Pointer stays valid
+/// until the next failing call on the same thread
+#[no_mangle]
+pub extern "C" fn lsmdb_last_error() -> *const c_char {
+    LAST_ERROR.with(|last| last.borrow().as_ptr())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use std::path::PathBuf;
+
+    #[test]
+    fn c_abi_cycle() {
+        let test_dir = &PathBuf::from("./tests/c_abi_cycle");
+        if test_dir.exists() {
+            fs::remove_dir_all(test_dir).unwrap();
+        }
+        let path = CString::new(test_dir.to_str().unwrap()).unwrap();
+        unsafe {
+            let mut db = ptr::null_mut();
+            assert_eq!(lsmdb_open(path.as_ptr(), &mut db), LsmdbStatus::Ok);
+            let mut locked = ptr::null_mut();
+            assert_eq!(lsmdb_open(path.as_ptr(), &mut locked), LsmdbStatus::Busy);
+            let error = CStr::from_ptr(lsmdb_last_error()).to_str().unwrap();
+            assert!(error.contains("locked"), "{error}");
+
+            for i in 0..600u16 {
+                let key = i.to_be_bytes();
+                assert_eq!(
+                    lsmdb_put(db, key.as_ptr(), 2, key.as_ptr(), 2),
+                    LsmdbStatus::Ok
+                );
+            }
+            assert_eq!(lsmdb_delete(db, [0, 1].as_ptr(), 2), LsmdbStatus::Ok);
+
+            let (mut value, mut value_len) = (ptr::null_mut(), 0);
+            let status = lsmdb_get(db, [0, 2].as_ptr(), 2, &mut value, &mut value_len);
+            assert_eq!(status, LsmdbStatus::Ok);
+            assert_eq!(slice::from_raw_parts(value, value_len), [0, 2]);
+            lsmdb_free(value, value_len);
+            let status = lsmdb_get(db, [0, 1].as_ptr(), 2, &mut value, &mut value_len);
+            assert_eq!(status, LsmdbStatus::NotFound);
+
+            let mut iter = ptr::null_mut();
+            let end = 550u16.to_be_bytes();
+            let status = lsmdb_iter_new(db, ptr::null(), 0, end.as_ptr(), 2, &mut iter);
+            assert_eq!(status, LsmdbStatus::Ok);
+            let mut keys = Vec::new();
+            let (mut key, mut key_len) = (ptr::null(), 0);
+            let (mut value, mut value_len) = (ptr::null(), 0);
+            while lsmdb_iter_next(iter, &mut key, &mut key_len, &mut value, &mut value_len)
+                == LsmdbStatus::Ok
+            {
+                let key = slice::from_raw_parts(key, key_len);
+                assert_eq!(key, slice::from_raw_parts(value, value_len));
+                keys.push(u16::from_be_bytes(key.try_into().unwrap()));
+            }
+            lsmdb_iter_free(iter);
+            let expected: Vec<u16> = (0..550).filter(|&i| i != 1).collect();
+            assert_eq!(keys, expected);
+
+            lsmdb_close(db);
+        }
+    }
+}